@@ -343,3 +343,156 @@ fn filters_1_bits_filter_5(b: &mut Bencher) {
         png.raw.filter_image(RowFilter::MinSum, false);
     });
 }
+
+#[bench]
+fn filters_16_bits_filter_10(b: &mut Bencher) {
+    let input = test::black_box(PathBuf::from("tests/files/rgb_16_should_be_rgb_16.png"));
+    let png = PngData::new(&input, &Options::default()).unwrap();
+
+    b.iter(|| {
+        png.raw.filter_image(RowFilter::WeightedSum, false);
+    });
+}
+
+#[bench]
+fn filters_8_bits_filter_10(b: &mut Bencher) {
+    let input = test::black_box(PathBuf::from("tests/files/rgb_8_should_be_rgb_8.png"));
+    let png = PngData::new(&input, &Options::default()).unwrap();
+
+    b.iter(|| {
+        png.raw.filter_image(RowFilter::WeightedSum, false);
+    });
+}
+
+#[bench]
+fn filters_4_bits_filter_10(b: &mut Bencher) {
+    let input = test::black_box(PathBuf::from(
+        "tests/files/palette_4_should_be_palette_4.png",
+    ));
+    let png = PngData::new(&input, &Options::default()).unwrap();
+
+    b.iter(|| {
+        png.raw.filter_image(RowFilter::WeightedSum, false);
+    });
+}
+
+#[bench]
+fn filters_2_bits_filter_10(b: &mut Bencher) {
+    let input = test::black_box(PathBuf::from(
+        "tests/files/palette_2_should_be_palette_2.png",
+    ));
+    let png = PngData::new(&input, &Options::default()).unwrap();
+
+    b.iter(|| {
+        png.raw.filter_image(RowFilter::WeightedSum, false);
+    });
+}
+
+#[bench]
+fn filters_1_bits_filter_10(b: &mut Bencher) {
+    let input = test::black_box(PathBuf::from(
+        "tests/files/palette_1_should_be_palette_1.png",
+    ));
+    let png = PngData::new(&input, &Options::default()).unwrap();
+
+    b.iter(|| {
+        png.raw.filter_image(RowFilter::WeightedSum, false);
+    });
+}
+
+/// A scanline and its previous line for a 4000 pixel wide RGBA image, filtered 4000 times per iteration
+fn large_rgba_lines() -> (Vec<u8>, Vec<u8>, Vec<u8>) {
+    let width = 4000 * 4;
+    let data = (0..width).map(|i| (i * 7 % 251) as u8).collect();
+    let prev_line = (0..width).map(|i| (i * 13 % 241) as u8).collect();
+    (data, prev_line, vec![0; width])
+}
+
+#[bench]
+fn filters_4000x4000_rgba_average(b: &mut Bencher) {
+    let (data, prev_line, mut out) = large_rgba_lines();
+
+    b.iter(|| {
+        for _ in 0..4000 {
+            average_filter(4, &data, &prev_line, &mut out);
+        }
+    });
+}
+
+#[bench]
+fn filters_4000x4000_rgba_average_scalar(b: &mut Bencher) {
+    let (data, prev_line, mut out) = large_rgba_lines();
+
+    b.iter(|| {
+        for _ in 0..4000 {
+            average_filter_scalar(4, &data, &prev_line, &mut out);
+        }
+    });
+}
+
+#[bench]
+fn filters_4000x4000_rgba_paeth(b: &mut Bencher) {
+    let (data, prev_line, mut out) = large_rgba_lines();
+
+    b.iter(|| {
+        for _ in 0..4000 {
+            paeth_filter(4, &data, &prev_line, &mut out);
+        }
+    });
+}
+
+#[bench]
+fn filters_4000x4000_rgba_paeth_scalar(b: &mut Bencher) {
+    let (data, prev_line, mut out) = large_rgba_lines();
+
+    b.iter(|| {
+        for _ in 0..4000 {
+            paeth_filter_scalar(4, &data, &prev_line, &mut out);
+        }
+    });
+}
+
+#[bench]
+fn filters_4000x4000_rgba_sum_abs(b: &mut Bencher) {
+    let (data, _, _) = large_rgba_lines();
+
+    b.iter(|| {
+        for _ in 0..4000 {
+            test::black_box(sum_abs(&data));
+        }
+    });
+}
+
+#[bench]
+fn filters_4000x4000_rgba_sum_abs_scalar(b: &mut Bencher) {
+    let (data, _, _) = large_rgba_lines();
+
+    b.iter(|| {
+        for _ in 0..4000 {
+            test::black_box(sum_abs_scalar(&data));
+        }
+    });
+}
+
+#[bench]
+fn filters_4000x100_rgba_minsum(b: &mut Bencher) {
+    let (data, _, _) = large_rgba_lines();
+    let mut image = Vec::new();
+    for y in 0..100u8 {
+        image.extend(data.iter().map(|&x| x.wrapping_mul(y)));
+    }
+    let png = PngImage {
+        ihdr: IhdrData {
+            width: 4000,
+            height: 100,
+            color_type: ColorType::RGBA,
+            bit_depth: BitDepth::Eight,
+            interlaced: Interlacing::None,
+        },
+        data: image,
+    };
+
+    b.iter(|| {
+        png.filter_image(RowFilter::MinSum, false);
+    });
+}
@@ -267,7 +267,7 @@ fn reductions_palette_duplicate_reduction(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| palette::reduced_palette(&png.raw, false));
+    b.iter(|| palette::reduced_palette(&png.raw, None));
 }
 
 #[bench]
@@ -277,7 +277,7 @@ fn reductions_palette_unused_reduction(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| palette::reduced_palette(&png.raw, false));
+    b.iter(|| palette::reduced_palette(&png.raw, None));
 }
 
 #[bench]
@@ -287,7 +287,7 @@ fn reductions_palette_full_reduction(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| palette::reduced_palette(&png.raw, false));
+    b.iter(|| palette::reduced_palette(&png.raw, None));
 }
 
 #[bench]
@@ -297,7 +297,7 @@ fn reductions_palette_sort(b: &mut Bencher) {
     ));
     let png = PngData::new(&input, &Options::default()).unwrap();
 
-    b.iter(|| palette::sorted_palette(&png.raw));
+    b.iter(|| palette::sorted_palette(&png.raw, PaletteSort::Luma));
 }
 
 #[bench]
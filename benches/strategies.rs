@@ -57,3 +57,15 @@ fn filters_brute(b: &mut Bencher) {
         png.raw.filter_image(RowFilter::Brute, false);
     });
 }
+
+#[bench]
+fn filters_brute_exact(b: &mut Bencher) {
+    let input = test::black_box(PathBuf::from("tests/files/rgb_8_should_be_rgb_8.png"));
+    let png = PngData::new(&input, &Options::default()).unwrap();
+    let deflater = Deflaters::Libdeflater { compression: 11 };
+
+    b.iter(|| {
+        png.raw
+            .filter_image_with_deflater(RowFilter::Brute, false, deflater);
+    });
+}
@@ -17,7 +17,13 @@ fn zopfli_16_bits_strategy_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        zopfli_deflate(png.raw.data.as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
+        zopfli_deflate(
+            png.raw.data.as_ref(),
+            DEFAULT_ZOPFLI_ITERATIONS,
+            false,
+            None,
+        )
+        .ok();
     });
 }
 
@@ -27,7 +33,13 @@ fn zopfli_8_bits_strategy_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        zopfli_deflate(png.raw.data.as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
+        zopfli_deflate(
+            png.raw.data.as_ref(),
+            DEFAULT_ZOPFLI_ITERATIONS,
+            false,
+            None,
+        )
+        .ok();
     });
 }
 
@@ -39,7 +51,13 @@ fn zopfli_4_bits_strategy_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        zopfli_deflate(png.raw.data.as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
+        zopfli_deflate(
+            png.raw.data.as_ref(),
+            DEFAULT_ZOPFLI_ITERATIONS,
+            false,
+            None,
+        )
+        .ok();
     });
 }
 
@@ -51,7 +69,13 @@ fn zopfli_2_bits_strategy_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        zopfli_deflate(png.raw.data.as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
+        zopfli_deflate(
+            png.raw.data.as_ref(),
+            DEFAULT_ZOPFLI_ITERATIONS,
+            false,
+            None,
+        )
+        .ok();
     });
 }
 
@@ -63,6 +87,12 @@ fn zopfli_1_bits_strategy_0(b: &mut Bencher) {
     let png = PngData::new(&input, &Options::default()).unwrap();
 
     b.iter(|| {
-        zopfli_deflate(png.raw.data.as_ref(), DEFAULT_ZOPFLI_ITERATIONS).ok();
+        zopfli_deflate(
+            png.raw.data.as_ref(),
+            DEFAULT_ZOPFLI_ITERATIONS,
+            false,
+            None,
+        )
+        .ok();
     });
 }
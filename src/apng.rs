@@ -0,0 +1,406 @@
+use log::{debug, warn};
+
+use crate::{
+    atomicmin::AtomicMin,
+    colors::{BitDepth, ColorType},
+    deflate,
+    headers::{read_be_u32, IhdrData},
+    interlace::{deinterlace_image, Interlacing},
+    png::{PngData, PngImage},
+    reduction::color::expanded_to_color_type,
+    PngError, PngResult,
+};
+
+const DISPOSE_BACKGROUND: u8 = 1;
+const DISPOSE_PREVIOUS: u8 = 2;
+const BLEND_OVER: u8 = 1;
+
+/// The state of a pixel on the animation canvas
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Canvas {
+    /// Fully transparent, regardless of color
+    Transparent,
+    /// The exact pixel at this position in the canvas data
+    Pixel,
+    /// The result of blending partial transparency, which we don't track
+    Unknown,
+}
+
+/// The transparency of a single pixel
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Alpha {
+    Transparent,
+    Opaque,
+    Partial,
+}
+
+/// A frame of the animation with its fcTL fields
+#[derive(Debug)]
+struct Frame {
+    fctl: usize,
+    fdat: Vec<usize>,
+    width: usize,
+    height: usize,
+    x: usize,
+    y: usize,
+    dispose: u8,
+    blend: u8,
+}
+
+/// Crop each APNG frame to the region that changes from the previous state of the canvas
+///
+/// The frames are decoded and composited to find which pixels actually change. Pixels that
+/// become partially transparent over a non-empty canvas are treated as always changing, as are
+/// pixels that depend on them. Frames which are disposed to the background are not cropped, as
+/// that would change the area cleared by the disposal.
+///
+/// Cropped frames are compressed quickly and should be recompressed afterwards.
+/// Returns true if any frames were cropped.
+pub fn crop_frames(png: &mut PngData) -> bool {
+    let ihdr = &png.raw.ihdr;
+    if ihdr.interlaced != Interlacing::None
+        || ihdr.bit_depth < BitDepth::Eight
+        || (matches!(ihdr.color_type, ColorType::Indexed { .. })
+            && ihdr.bit_depth != BitDepth::Eight)
+    {
+        debug!("Frame cropping is not supported for this image format");
+        return false;
+    }
+    let Some((frames, idat_frame)) = parse_frames(png) else {
+        warn!("Unable to parse APNG frames, skipping frame cropping");
+        return false;
+    };
+
+    let bytes = png.raw.bytes_per_channel();
+    let bpp = png.raw.channels_per_pixel() * bytes;
+    let width = ihdr.width as usize;
+    let height = ihdr.height as usize;
+    let mut canvas = vec![0; width * height * bpp];
+    let mut state = vec![Canvas::Transparent; width * height];
+    let mut cropped = false;
+    for (i, frame) in frames.iter().enumerate() {
+        if frame.width == 0
+            || frame.height == 0
+            || frame.x + frame.width > width
+            || frame.y + frame.height > height
+        {
+            warn!("APNG frame is out of bounds, skipping frame cropping");
+            return cropped;
+        }
+        let frame_ihdr = IhdrData {
+            width: frame.width as u32,
+            height: frame.height as u32,
+            ..ihdr.clone()
+        };
+        let data = if i == 0 && idat_frame {
+            png.raw.data.clone()
+        } else {
+            match decode_frame(png, &frame.fdat, frame_ihdr) {
+                Some(data) => data,
+                None => {
+                    warn!("Unable to decode APNG frame, skipping frame cropping");
+                    return cropped;
+                }
+            }
+        };
+
+        let saved = (frame.dispose == DISPOSE_PREVIOUS).then(|| (canvas.clone(), state.clone()));
+        // Find the bounds of the changed pixels while compositing the frame
+        let (mut min_x, mut min_y, mut max_x, mut max_y) = (usize::MAX, usize::MAX, 0, 0);
+        for fy in 0..frame.height {
+            for fx in 0..frame.width {
+                let src = &data[(fy * frame.width + fx) * bpp..][..bpp];
+                let pos = (frame.y + fy) * width + frame.x + fx;
+                let dest = &mut canvas[pos * bpp..][..bpp];
+                let new_state = match (alpha(&ihdr.color_type, bytes, src), frame.blend) {
+                    // A transparent pixel blended over the canvas leaves it as it is
+                    (Alpha::Transparent, BLEND_OVER) => continue,
+                    (Alpha::Transparent, _) => Canvas::Transparent,
+                    (Alpha::Partial, BLEND_OVER) if state[pos] != Canvas::Transparent => {
+                        Canvas::Unknown
+                    }
+                    _ => Canvas::Pixel,
+                };
+                let changed = match (state[pos], new_state) {
+                    (Canvas::Transparent, Canvas::Transparent) => false,
+                    (Canvas::Pixel, Canvas::Pixel) => dest != src,
+                    _ => true,
+                };
+                if new_state == Canvas::Pixel {
+                    dest.copy_from_slice(src);
+                }
+                state[pos] = new_state;
+                if changed {
+                    min_x = min_x.min(fx);
+                    min_y = min_y.min(fy);
+                    max_x = max_x.max(fx);
+                    max_y = max_y.max(fy);
+                }
+            }
+        }
+
+        let can_crop = (i > 0 || !idat_frame)
+            && frame.fdat.len() == 1
+            && frame.dispose != DISPOSE_BACKGROUND
+            && !(i == 0 && frame.dispose == DISPOSE_PREVIOUS);
+        if can_crop
+            && (min_x > 0 || min_y > 0 || max_x + 1 < frame.width || max_y + 1 < frame.height)
+        {
+            // A frame must contain at least one pixel, so use an unchanged one if necessary
+            if min_x > max_x {
+                (min_x, min_y, max_x, max_y) = (0, 0, 0, 0);
+            }
+            let crop_width = max_x - min_x + 1;
+            let crop_height = max_y - min_y + 1;
+            let mut filtered = Vec::with_capacity(crop_height * (crop_width * bpp + 1));
+            for fy in min_y..=max_y {
+                filtered.push(0);
+                let start = (fy * frame.width + min_x) * bpp;
+                filtered.extend_from_slice(&data[start..start + crop_width * bpp]);
+            }
+            let Ok(compressed) = deflate::deflate(&filtered, 8, &AtomicMin::new(None)) else {
+                return cropped;
+            };
+            debug!(
+                "Cropped APNG frame #{:<2}: {}x{} to {}x{}",
+                i, frame.width, frame.height, crop_width, crop_height
+            );
+            let fctl = &mut png.aux_chunks[frame.fctl].data;
+            fctl[4..8].copy_from_slice(&(crop_width as u32).to_be_bytes());
+            fctl[8..12].copy_from_slice(&(crop_height as u32).to_be_bytes());
+            fctl[12..16].copy_from_slice(&((frame.x + min_x) as u32).to_be_bytes());
+            fctl[16..20].copy_from_slice(&((frame.y + min_y) as u32).to_be_bytes());
+            let fdat = &mut png.aux_chunks[frame.fdat[0]].data;
+            fdat.truncate(4);
+            fdat.extend_from_slice(&compressed);
+            cropped = true;
+        }
+
+        // Dispose of the frame, using the original frame region
+        if let Some((prev_canvas, prev_state)) = saved.filter(|_| i > 0) {
+            canvas = prev_canvas;
+            state = prev_state;
+        } else if frame.dispose != 0 {
+            for fy in frame.y..frame.y + frame.height {
+                state[fy * width + frame.x..][..frame.width].fill(Canvas::Transparent);
+            }
+        }
+    }
+    cropped
+}
+
+/// Composite each APNG frame onto the canvas, returning the full canvas as it appears after each
+/// frame is rendered
+///
+/// The canvas is RGBA at the bit depth of the image, or 8-bit if the bit depth is lower. Frames
+/// are placed at their fcTL offsets and blended or disposed of as the animation specifies.
+pub fn composite_frames(png: &PngData) -> PngResult<Vec<PngImage>> {
+    let Some((frames, idat_frame)) = parse_frames(png).filter(|(frames, _)| !frames.is_empty())
+    else {
+        return Err(PngError::new("No valid APNG frames found"));
+    };
+    let ihdr = &png.raw.ihdr;
+    let width = ihdr.width as usize;
+    let height = ihdr.height as usize;
+    let canvas_ihdr = IhdrData {
+        color_type: ColorType::RGBA,
+        bit_depth: ihdr.bit_depth.max(BitDepth::Eight),
+        interlaced: Interlacing::None,
+        ..ihdr.clone()
+    };
+    let bytes = canvas_ihdr.bit_depth as usize / 8;
+    let bpp = 4 * bytes;
+    let mut canvas = vec![0; width * height * bpp];
+    let mut images = Vec::with_capacity(frames.len());
+    for (i, frame) in frames.iter().enumerate() {
+        if frame.width == 0
+            || frame.height == 0
+            || frame.x + frame.width > width
+            || frame.y + frame.height > height
+        {
+            return Err(PngError::new(&format!(
+                "APNG frame #{} is out of bounds",
+                i
+            )));
+        }
+        let frame_ihdr = IhdrData {
+            width: frame.width as u32,
+            height: frame.height as u32,
+            ..ihdr.clone()
+        };
+        let data = if i == 0 && idat_frame {
+            Some(png.raw.data.clone())
+        } else {
+            decode_frame(png, &frame.fdat, frame_ihdr.clone())
+        };
+        let Some(data) = data else {
+            return Err(PngError::new(&format!(
+                "Unable to decode APNG frame #{}",
+                i
+            )));
+        };
+        let mut image = PngImage {
+            ihdr: frame_ihdr,
+            data,
+        };
+        if image.ihdr.interlaced != Interlacing::None {
+            image = deinterlace_image(&image);
+        }
+        if let Ok(expanded) = expanded_to_color_type(&image, &ColorType::RGBA) {
+            image = expanded;
+        }
+
+        // A frame disposed to the previous state has nothing to return to if it is the first
+        let saved = (frame.dispose == DISPOSE_PREVIOUS && i > 0).then(|| canvas.clone());
+        for fy in 0..frame.height {
+            for fx in 0..frame.width {
+                let src = &image.data[(fy * frame.width + fx) * bpp..][..bpp];
+                let pos = (frame.y + fy) * width + frame.x + fx;
+                let dest = &mut canvas[pos * bpp..][..bpp];
+                if frame.blend == BLEND_OVER {
+                    blend_over(dest, src, bytes);
+                } else {
+                    dest.copy_from_slice(src);
+                }
+            }
+        }
+        images.push(PngImage {
+            ihdr: canvas_ihdr.clone(),
+            data: canvas.clone(),
+        });
+
+        if let Some(prev_canvas) = saved {
+            canvas = prev_canvas;
+        } else if frame.dispose != 0 {
+            for fy in frame.y..frame.y + frame.height {
+                let start = (fy * width + frame.x) * bpp;
+                canvas[start..start + frame.width * bpp].fill(0);
+            }
+        }
+    }
+    Ok(images)
+}
+
+/// Blend an RGBA pixel over another, with the given number of bytes per channel
+fn blend_over(dest: &mut [u8], src: &[u8], bytes: usize) {
+    let max = (1u64 << (bytes * 8)) - 1;
+    let sample = |pixel: &[u8], channel: usize| match bytes {
+        2 => u64::from(u16::from_be_bytes([
+            pixel[channel * 2],
+            pixel[channel * 2 + 1],
+        ])),
+        _ => u64::from(pixel[channel]),
+    };
+    let src_alpha = sample(src, 3);
+    if src_alpha == max {
+        dest.copy_from_slice(src);
+        return;
+    }
+    if src_alpha == 0 {
+        return;
+    }
+    // Work in units of max^2 to avoid rounding the destination's contribution
+    let dest_weight = sample(dest, 3) * (max - src_alpha);
+    let out_alpha = src_alpha * max + dest_weight;
+    let mut out = [0; 4];
+    for (c, value) in out.iter_mut().enumerate().take(3) {
+        let total = sample(src, c) * src_alpha * max + sample(dest, c) * dest_weight;
+        *value = (total + out_alpha / 2) / out_alpha;
+    }
+    out[3] = (out_alpha + max / 2) / max;
+    for (c, value) in out.into_iter().enumerate() {
+        match bytes {
+            2 => dest[c * 2..c * 2 + 2].copy_from_slice(&(value as u16).to_be_bytes()),
+            _ => dest[c] = value as u8,
+        }
+    }
+}
+
+/// Collect the frames from the fcTL and fdAT chunks, and whether the IDAT is the first frame
+fn parse_frames(png: &PngData) -> Option<(Vec<Frame>, bool)> {
+    let mut frames: Vec<Frame> = Vec::new();
+    let mut idat_frame = false;
+    for (i, c) in png.aux_chunks.iter().enumerate() {
+        match &c.name {
+            b"fcTL" => {
+                if c.data.len() != 26 {
+                    return None;
+                }
+                frames.push(Frame {
+                    fctl: i,
+                    fdat: Vec::new(),
+                    width: read_be_u32(&c.data[4..8]) as usize,
+                    height: read_be_u32(&c.data[8..12]) as usize,
+                    x: read_be_u32(&c.data[12..16]) as usize,
+                    y: read_be_u32(&c.data[16..20]) as usize,
+                    dispose: c.data[24],
+                    blend: c.data[25],
+                });
+            }
+            b"IDAT" => idat_frame = frames.len() == 1,
+            b"fdAT" => frames.last_mut()?.fdat.push(i),
+            _ => (),
+        }
+    }
+    let valid = frames
+        .iter()
+        .enumerate()
+        .all(|(i, f)| f.fdat.is_empty() == (i == 0 && idat_frame));
+    valid.then_some((frames, idat_frame))
+}
+
+/// Decompress and unfilter the data of a frame
+fn decode_frame(png: &PngData, fdat: &[usize], ihdr: IhdrData) -> Option<Vec<u8>> {
+    let compressed: Vec<u8> = fdat
+        .iter()
+        .flat_map(|&i| png.aux_chunks[i].data[4..].iter().copied())
+        .collect();
+    let size = ihdr.raw_data_size();
+    let frame = PngImage {
+        ihdr,
+        data: deflate::inflate(&compressed, size).ok()?,
+    };
+    if frame.data.len() != size {
+        return None;
+    }
+    frame.unfilter_image().ok()
+}
+
+/// Determine the transparency of a pixel with the given number of bytes per channel
+fn alpha(color_type: &ColorType, bytes: usize, pixel: &[u8]) -> Alpha {
+    let sample = |channel: usize| match bytes {
+        2 => u16::from_be_bytes([pixel[channel * 2], pixel[channel * 2 + 1]]),
+        _ => pixel[channel] as u16,
+    };
+    let transparent = match color_type {
+        ColorType::Grayscale {
+            transparent_shade: Some(shade),
+        } => sample(0) == *shade,
+        ColorType::RGB {
+            transparent_color: Some(trns),
+        } => sample(0) == trns.r && sample(1) == trns.g && sample(2) == trns.b,
+        ColorType::Indexed { palette } => {
+            return match palette.get(pixel[0] as usize).map_or(255, |c| c.a) {
+                0 => Alpha::Transparent,
+                255 => Alpha::Opaque,
+                _ => Alpha::Partial,
+            };
+        }
+        ColorType::GrayscaleAlpha | ColorType::RGBA => {
+            let alpha = &pixel[pixel.len() - bytes..];
+            return if alpha.iter().all(|&b| b == 0) {
+                Alpha::Transparent
+            } else if alpha.iter().all(|&b| b == 255) {
+                Alpha::Opaque
+            } else {
+                Alpha::Partial
+            };
+        }
+        _ => false,
+    };
+    if transparent {
+        Alpha::Transparent
+    } else {
+        Alpha::Opaque
+    }
+}
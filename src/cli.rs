@@ -49,6 +49,26 @@ preset, regardless of the order you write the arguments.")
                 .value_parser(["0", "1", "2", "3", "4", "5", "6", "max"])
                 .hide_possible_values(true),
         )
+        .arg(
+            Arg::new("effort")
+                .help("Compression effort (0-10), instead of an optimization level")
+                .long_help("\
+Set the compression effort, from 0 (fastest) to 10 (smallest), instead of an optimization \
+level. Efforts 0-6 are the same as the optimization levels. Higher efforts use Zopfli with \
+increasing iterations, and effort 10 also chooses interlacing automatically.
+
+    7   => -o max -Z --zi 15
+    8   => -o max -Z --zi 30   (also tries a single Huffman table)
+    9   => -o max -Z --zi 60   (also tries a single Huffman table)
+    10  => -o max -Z --zi 100 -i auto  (also tries a single Huffman table)
+
+Manually specifying a compression option (zc, f, etc.) will override the effort, as with the \
+optimization level.")
+                .long("effort")
+                .value_name("effort")
+                .value_parser(0..=10)
+                .conflicts_with("optimization"),
+        )
         .arg(
             Arg::new("backup")
                 .help("Back up modified files")
@@ -120,19 +140,23 @@ Note that this will not preserve the directory structure of the input files when
         )
         .arg(
             Arg::new("strip")
-                .help("Strip metadata (safe, all, or comma-separated list)\nCAUTION: 'all' will convert APNGs to standard PNGs")
+                .help("Strip metadata (safe, metadata, all, or comma-separated list)\nCAUTION: 'all' will convert APNGs to standard PNGs")
                 .long_help(format!("\
 Strip metadata chunks, where <mode> is one of:
 
-    safe    =>  Strip all non-critical chunks, except for the following:
-                    {}
-    all     =>  Strip all non-critical chunks
-    <list>  =>  Strip chunks in the comma-separated list, e.g. 'bKGD,cHRM'
+    safe     =>  Strip all non-critical chunks, except for the following:
+                     {}
+    metadata =>  Strip all non-critical chunks, except for those that affect color, stereo
+                 layout or animation:
+                     gAMA, cHRM, sRGB, iCCP, cICP, mDCV, cLLI, sBIT, sTER, acTL, fcTL, fdAT
+    all      =>  Strip all non-critical chunks
+    <list>   =>  Strip chunks in the comma-separated list, e.g. 'bKGD,cHRM'
 
 CAUTION: 'all' will convert APNGs to standard PNGs.
 
 Note that 'bKGD', 'sBIT' and 'hIST' will be forcibly stripped if the color type or bit \
-depth is changed, regardless of any options set.
+depth is changed, regardless of any options set. The exception is 'sBIT' on an indexed image \
+that remains indexed.
 
 The default when --strip is not passed is to keep all metadata.",
                        DISPLAY_CHUNKS
@@ -172,13 +196,16 @@ transformation and may be unsuitable for some applications.")
         )
         .arg(
             Arg::new("interlace")
-                .help("Set PNG interlacing type (0, 1, keep)")
+                .help("Set PNG interlacing type (0, 1, keep, auto, heuristic)")
                 .long_help("\
 Set the PNG interlacing type, where <type> is one of:
 
-    0     =>  Remove interlacing from all images that are processed
-    1     =>  Apply Adam7 interlacing on all images that are processed
-    keep  =>  Keep the existing interlacing type of each image
+    0          =>  Remove interlacing from all images that are processed
+    1          =>  Apply Adam7 interlacing on all images that are processed
+    keep       =>  Keep the existing interlacing type of each image
+    auto       =>  Try both with and without interlacing, keeping whichever is smaller
+    heuristic  =>  Predict from the image whether interlacing is smaller and try only that,
+                   which is faster than auto but less accurate
 
 Note that interlacing can add 25-50% to the size of an optimized image. Only use it if you \
 believe the benefits outweigh the costs for your use case.")
@@ -186,7 +213,7 @@ believe the benefits outweigh the costs for your use case.")
                 .long("interlace")
                 .value_name("type")
                 .default_value("0")
-                .value_parser(["0", "1", "keep"])
+                .value_parser(["0", "1", "keep", "auto", "heuristic"])
                 .hide_possible_values(true),
         )
         .arg(
@@ -221,24 +248,25 @@ losslessly.")
         )
         .arg(
             Arg::new("filters")
-                .help("Filters to try (0-9; see '--help' for details)")
+                .help("Filters to try (0-10; see '--help' for details)")
                 .long_help("\
 Perform compression trials with each of the given filter types. You can specify a \
 comma-separated list, or a range of values. E.g. '-f 0-3' is the same as '-f 0,1,2,3'.
 
 PNG delta filters (apply the same filter to every line)
-    0  =>  None      (recommended to always include this filter)
+    0  =>  None         (recommended to always include this filter)
     1  =>  Sub
     2  =>  Up
     3  =>  Average
     4  =>  Paeth
 
 Heuristic strategies (try to find the best delta filter for each line)
-    5  =>  MinSum    Minimum sum of absolute differences
-    6  =>  Entropy   Highest Shannon entropy
-    7  =>  Bigrams   Lowest count of distinct bigrams
-    8  =>  BigEnt    Highest Shannon entropy of bigrams
-    9  =>  Brute     Smallest compressed size (slow)
+    5  =>  MinSum       Minimum sum of absolute differences
+    6  =>  Entropy      Highest Shannon entropy
+    7  =>  Bigrams      Lowest count of distinct bigrams
+    8  =>  BigEnt       Highest Shannon entropy of bigrams
+    9  =>  Brute        Smallest compressed size (slow)
+    10 =>  WeightedSum  Minimum sum and count of sample changes (MinSum for RGBA)
 
 The default value depends on the optimization level preset.")
                 .short('f')
@@ -255,6 +283,26 @@ CPU cores.")
                 .long("fast")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("keep-filters")
+                .help("Also try the filters used by the input file")
+                .long_help("\
+Perform an additional compression trial that reapplies the filter type of each row in the \
+input file. This can help with files that were already well filtered by another tool. Only \
+applies to non-interlaced input.")
+                .long("keep-filters")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("exact-brute")
+                .help("Score the Brute filter with the main compression")
+                .long_help("\
+Score the filter attempts of each line in the Brute strategy (filter 9) by compressing them \
+with the main compression settings, rather than a fast compression level. This is more \
+accurate but much slower, particularly with Zopfli.")
+                .long("exact-brute")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("compression")
                 .help("Deflate compression level (1-12)")
@@ -286,6 +334,21 @@ The default value depends on the optimization level preset.")
                 .long("np")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("palette-sort")
+                .help("Palette sort order (luma, alpha, frequency, nearest)")
+                .long_help("\
+Set the order used when sorting the palette of indexed images, where <order> is one of:
+
+    luma       =>  Sort by ascending alpha and descending luma (default)
+    alpha      =>  Sort by ascending alpha only, for the shortest tRNS chunk
+    frequency  =>  Sort by descending frequency of use in the image
+    nearest    =>  Order the colors so that consecutive entries are as similar as possible")
+                .long("palette-sort")
+                .value_name("order")
+                .value_parser(["luma", "alpha", "frequency", "nearest"])
+                .hide_possible_values(true),
+        )
         .arg(
             Arg::new("no-grayscale-reduction")
                 .help("Do not change to or from grayscale")
@@ -319,12 +382,48 @@ be processed successfully.")
                 .long("fix")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("recover")
+                .help("Recover what is possible from corrupt image data")
+                .long_help("\
+Recover as much as possible of image data that is truncated or corrupt, rather than failing. \
+The image data is decoded up to the point of the error and any missing rows are filled with \
+zeros. A mismatched checksum at the end of the data is ignored. The recovered image is only \
+written if it is smaller than the input, unless --force is also set.")
+                .long("recover")
+                .action(ArgAction::SetTrue),
+        )
+        .arg(
+            Arg::new("verify")
+                .help("Check that the output decodes to the same pixels as the input")
+                .long_help("\
+Decode each output before it is written and check that its pixels match the input image, \
+allowing for changes of color type, bit depth and interlacing. With lossy options, the \
+output is compared with the reduced image instead. If they don't match, a warning is shown \
+and the original file is kept. This guards against bugs at the cost of some extra \
+processing time.")
+                .long("verify")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("force")
                 .help("Write the output even if it is larger than the input")
                 .long("force")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("mark")
+                .help("Mark the output as optimized, and skip files that are already marked")
+                .long_help("\
+Add a small private chunk to the output that marks it as optimized, and skip any file that \
+already has a valid marker. This avoids repeating the work on files that have not changed \
+since they were last optimized. The marker holds a checksum of the image data, so it becomes \
+invalid if the image is edited.
+
+Without this flag, any existing marker is removed from the output.")
+                .long("mark")
+                .action(ArgAction::SetTrue),
+        )
         .arg(
             Arg::new("zopfli")
                 .help("Use the much slower but stronger Zopfli compressor")
@@ -335,6 +434,58 @@ Recommended use is with '-o max' and '--fast'.")
                 .long("zopfli")
                 .action(ArgAction::SetTrue),
         )
+        .arg(
+            Arg::new("iterations")
+                .help("Number of Zopfli iterations (1-255)")
+                .long_help("\
+Number of iterations (1-255) to run the Zopfli compressor for. More iterations may give a \
+slightly smaller output at the cost of a proportionally longer run time.
+
+The default value is 15.")
+                .long("zi")
+                .value_name("iterations")
+                .value_parser(1..=255)
+                .requires("zopfli"),
+        )
+        .arg(
+            Arg::new("splits")
+                .help("Number of parts to compress in parallel with Zopfli")
+                .long_help("\
+Split the image data into the given number of parts, on row boundaries, and compress each \
+part with Zopfli in parallel. This is much faster on multicore machines, at the cost of \
+slightly larger output.
+
+The default value is 1 (no splitting).")
+                .long("zs")
+                .value_name("parts")
+                .value_parser(1..=255)
+                .requires("zopfli"),
+        )
+        .arg(
+            Arg::new("zlib-ng")
+                .help("Use the zlib-ng compressor at the given level (1-9)")
+                .long_help("\
+Use the zlib-ng compressor at the given level (1-9) for main compression trials, instead \
+of libdeflate. Requires oxipng to be built with the 'zlib-ng' feature.")
+                .long("zng")
+                .value_name("level")
+                .value_parser(1..=9)
+                .conflicts_with("zopfli")
+                .conflicts_with("compression"),
+        )
+        .arg(
+            Arg::new("idat-size")
+                .help("Maximum size of each IDAT chunk, in bytes")
+                .long_help("\
+Split the compressed image data into IDAT chunks of at most the given size, in bytes. Some \
+decoders and streaming consumers prefer a particular chunk size. Each additional chunk adds \
+12 bytes to the file.
+
+By default, the image data is written as a single IDAT chunk.")
+                .long("idat-size")
+                .value_name("bytes")
+                .value_parser(value_parser!(u32).range(1..=0x7FFF_FFFF)),
+        )
         .arg(
             Arg::new("timeout")
                 .help("Maximum amount of time to spend on optimizations")
@@ -1,9 +1,12 @@
 use libdeflater::*;
+use miniz_oxide::inflate::core::{decompress, inflate_flags::*, DecompressorOxide};
 
 use crate::{atomicmin::AtomicMin, PngError, PngResult};
 
 pub fn deflate(data: &[u8], level: u8, max_size: &AtomicMin) -> PngResult<Vec<u8>> {
-    let mut compressor = Compressor::new(CompressionLvl::new(level.into()).unwrap());
+    let level =
+        CompressionLvl::new(level.into()).map_err(|_| PngError::InvalidCompressionLevel(level))?;
+    let mut compressor = Compressor::new(level);
     let capacity = max_size
         .get()
         .unwrap_or_else(|| compressor.zlib_compress_bound(data.len()));
@@ -17,6 +20,36 @@ pub fn deflate(data: &[u8], level: u8, max_size: &AtomicMin) -> PngResult<Vec<u8
     Ok(dest)
 }
 
+/// Maximum length of a single stored deflate block
+const MAX_STORED_BLOCK: usize = 65535;
+
+/// Calculate the size of a zlib stream containing the data in stored (uncompressed) blocks
+pub fn stored_size(len: usize) -> usize {
+    // Zlib header and checksum, plus a 5-byte header for each block (at least one)
+    2 + 4 + len + 5 * len.div_ceil(MAX_STORED_BLOCK).max(1)
+}
+
+/// Create a zlib stream containing the data in stored (uncompressed) blocks
+pub fn store(data: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(stored_size(data.len()));
+    // Zlib header for the fastest compression level
+    output.extend_from_slice(&[0x78, 0x01]);
+    if data.is_empty() {
+        // An empty stream still requires a final block
+        output.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    let mut blocks = data.chunks(MAX_STORED_BLOCK).peekable();
+    while let Some(block) = blocks.next() {
+        // Each block is byte-aligned: BFINAL, BTYPE 00 and padding, followed by LEN and NLEN
+        output.push(blocks.peek().is_none() as u8);
+        output.extend_from_slice(&(block.len() as u16).to_le_bytes());
+        output.extend_from_slice(&(!(block.len() as u16)).to_le_bytes());
+        output.extend_from_slice(block);
+    }
+    output.extend_from_slice(&adler32(data).to_be_bytes());
+    output
+}
+
 pub fn inflate(data: &[u8], out_size: usize) -> PngResult<Vec<u8>> {
     let mut decompressor = Decompressor::new();
     let mut dest = vec![0; out_size];
@@ -30,6 +63,22 @@ pub fn inflate(data: &[u8], out_size: usize) -> PngResult<Vec<u8>> {
     Ok(dest)
 }
 
+/// Inflate as much of a zlib stream as possible, ignoring any errors in the stream
+///
+/// The output holds everything that was decoded before the error, up to `out_size` bytes. A
+/// mismatched Adler-32 checksum doesn't prevent the full data from being returned.
+pub fn inflate_partial(data: &[u8], out_size: usize) -> Vec<u8> {
+    let mut decompressor = Box::<DecompressorOxide>::default();
+    let mut dest = vec![0; out_size];
+    let flags = TINFL_FLAG_PARSE_ZLIB_HEADER
+        | TINFL_FLAG_USING_NON_WRAPPING_OUTPUT_BUF
+        | TINFL_FLAG_IGNORE_ADLER32;
+    // Without more input to wait for, this stops at the end of the data or the first error
+    let (_, _, len) = decompress(&mut decompressor, data, &mut dest, 0, flags);
+    dest.truncate(len);
+    dest
+}
+
 pub fn crc32(data: &[u8]) -> u32 {
     let mut crc = Crc::new();
     crc.update(data);
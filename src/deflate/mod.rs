@@ -3,20 +3,39 @@ mod deflater;
 use std::num::NonZeroU8;
 use std::{fmt, fmt::Display};
 
-pub use deflater::{crc32, deflate, inflate};
+pub use deflater::{crc32, deflate, inflate, inflate_partial, store, stored_size};
 
-use crate::{AtomicMin, PngError, PngResult};
+use crate::{AtomicMin, Deadline, PngError, PngResult};
 #[cfg(feature = "zopfli")]
 mod zopfli_oxipng;
 #[cfg(feature = "zopfli")]
-pub use zopfli_oxipng::deflate as zopfli_deflate;
+pub use zopfli_oxipng::{deflate as zopfli_deflate, deflate_split as zopfli_deflate_split};
+#[cfg(feature = "zlib-ng")]
+mod zlibng;
+#[cfg(feature = "zlib-ng")]
+pub use zlibng::deflate as zlibng_deflate;
+
+/// The lowest compression level supported by libdeflater
+///
+/// Level 0 stores the data without compression.
+pub const LIBDEFLATER_MIN_LEVEL: u8 = 0;
+/// The highest compression level supported by libdeflater
+pub const LIBDEFLATER_MAX_LEVEL: u8 = 12;
+/// The lowest compression level supported by zlib-ng
+#[cfg(feature = "zlib-ng")]
+pub const ZLIBNG_MIN_LEVEL: u8 = 1;
+/// The highest compression level supported by zlib-ng
+#[cfg(feature = "zlib-ng")]
+pub const ZLIBNG_MAX_LEVEL: u8 = 9;
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 /// DEFLATE algorithms supported by oxipng
 pub enum Deflaters {
     /// Use libdeflater.
     Libdeflater {
-        /// Which compression level to use on the file (1-12)
+        /// Which compression level to use on the file (0-12)
+        ///
+        /// Use `Deflaters::libdeflater` to construct this with a validated level.
         compression: u8,
     },
     #[cfg(feature = "zopfli")]
@@ -27,22 +46,119 @@ pub enum Deflaters {
         /// less iterations, or else they will be too slow.
         iterations: NonZeroU8,
     },
+    #[cfg(feature = "zlib-ng")]
+    /// Use zlib-ng
+    Zlibng {
+        /// Which compression level to use on the file (1-9)
+        level: u8,
+    },
 }
 
 impl Deflaters {
+    /// Use libdeflater with the given compression level, returning an error if the level is
+    /// outside the supported range of `LIBDEFLATER_MIN_LEVEL` to `LIBDEFLATER_MAX_LEVEL`
+    pub fn libdeflater(compression: u8) -> PngResult<Self> {
+        let deflater = Self::Libdeflater { compression };
+        deflater.validate()?;
+        Ok(deflater)
+    }
+
+    /// Check that the deflater's settings are supported
+    pub(crate) fn validate(self) -> PngResult<()> {
+        match self {
+            Self::Libdeflater { compression }
+                if !(LIBDEFLATER_MIN_LEVEL..=LIBDEFLATER_MAX_LEVEL).contains(&compression) =>
+            {
+                Err(PngError::InvalidCompressionLevel(compression))
+            }
+            #[cfg(feature = "zlib-ng")]
+            Self::Zlibng { level } if !(ZLIBNG_MIN_LEVEL..=ZLIBNG_MAX_LEVEL).contains(&level) => {
+                Err(PngError::InvalidCompressionLevel(level))
+            }
+            _ => Ok(()),
+        }
+    }
+
     pub(crate) fn deflate(self, data: &[u8], max_size: &AtomicMin) -> PngResult<Vec<u8>> {
         let compressed = match self {
             Self::Libdeflater { compression } => deflate(data, compression, max_size)?,
             #[cfg(feature = "zopfli")]
-            Self::Zopfli { iterations } => zopfli_deflate(data, iterations)?,
+            Self::Zopfli { iterations } => zopfli_deflate(data, iterations, false, None)?,
+            #[cfg(feature = "zlib-ng")]
+            Self::Zlibng { level } => zlibng_deflate(data, level, max_size)?,
         };
-        if let Some(max) = max_size.get() {
-            if compressed.len() > max {
-                return Err(PngError::DeflatedDataTooLong(max));
+        check_size(compressed, data, max_size)
+    }
+
+    /// Compress the data as independent parts, split at the given offsets, where supported
+    ///
+    /// Only Zopfli compresses the parts separately (and in parallel). Other algorithms ignore
+    /// the split points. Zopfli also stops with `PngError::TimedOut` if the deadline passes
+    /// during compression, while other algorithms are fast enough to run to completion.
+    ///
+    /// If `single_block_trial` is set, Zopfli also compresses each part as a single block with
+    /// one Huffman table, keeping whichever result is smaller. If the deadline passes during this
+    /// second compression, the first result is kept.
+    #[cfg_attr(not(feature = "zopfli"), allow(unused_variables))]
+    pub(crate) fn deflate_split(
+        self,
+        data: &[u8],
+        split_points: &[usize],
+        single_block_trial: bool,
+        max_size: &AtomicMin,
+        deadline: &Deadline,
+    ) -> PngResult<Vec<u8>> {
+        match self {
+            #[cfg(feature = "zopfli")]
+            Self::Zopfli { iterations } => {
+                let compress = |single_block| {
+                    if split_points.is_empty() {
+                        zopfli_deflate(data, iterations, single_block, Some(deadline))
+                    } else {
+                        zopfli_deflate_split(
+                            data,
+                            iterations,
+                            single_block,
+                            split_points,
+                            Some(deadline),
+                        )
+                    }
+                };
+                let mut compressed = compress(false)?;
+                if single_block_trial {
+                    if let Ok(single) = compress(true) {
+                        if single.len() < compressed.len() {
+                            compressed = single;
+                        }
+                    }
+                }
+                check_size(compressed, data, max_size)
             }
+            _ => self.deflate(data, max_size),
+        }
+    }
+}
+
+/// Use stored (uncompressed) blocks for the data instead if they are smaller
+///
+/// Tiny or incompressible data can be smaller without the overhead of Huffman coding.
+pub(crate) fn store_if_smaller(compressed: Vec<u8>, data: &[u8]) -> Vec<u8> {
+    if stored_size(data.len()) < compressed.len() {
+        store(data)
+    } else {
+        compressed
+    }
+}
+
+/// Fall back to stored blocks if they are smaller, then check against the maximum size
+fn check_size(compressed: Vec<u8>, data: &[u8], max_size: &AtomicMin) -> PngResult<Vec<u8>> {
+    let compressed = store_if_smaller(compressed, data);
+    if let Some(max) = max_size.get() {
+        if compressed.len() > max {
+            return Err(PngError::DeflatedDataTooLong(max));
         }
-        Ok(compressed)
     }
+    Ok(compressed)
 }
 
 impl Display for Deflaters {
@@ -52,6 +168,8 @@ impl Display for Deflaters {
             Self::Libdeflater { compression } => Display::fmt(compression, f),
             #[cfg(feature = "zopfli")]
             Self::Zopfli { .. } => Display::fmt("zopfli", f),
+            #[cfg(feature = "zlib-ng")]
+            Self::Zlibng { level } => write!(f, "zng {level}"),
         }
     }
 }
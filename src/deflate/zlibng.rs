@@ -0,0 +1,33 @@
+use std::ffi::c_ulong;
+
+use libz_sys::{compress2, compressBound, Z_BUF_ERROR, Z_OK};
+
+use crate::{atomicmin::AtomicMin, PngError, PngResult};
+
+/// Compress the data as a zlib stream with zlib-ng at the given level
+pub fn deflate(data: &[u8], level: u8, max_size: &AtomicMin) -> PngResult<Vec<u8>> {
+    // SAFETY: compressBound only performs a calculation on the length
+    let bound = unsafe { compressBound(data.len() as c_ulong) } as usize;
+    let capacity = max_size.get().unwrap_or(bound);
+    let mut dest = vec![0; capacity];
+    let mut dest_len = capacity as c_ulong;
+    // SAFETY: the pointers and lengths describe valid buffers, and zlib-ng writes no more than
+    // dest_len bytes, updating it with the number written
+    let result = unsafe {
+        compress2(
+            dest.as_mut_ptr(),
+            &mut dest_len,
+            data.as_ptr(),
+            data.len() as c_ulong,
+            level.into(),
+        )
+    };
+    match result {
+        Z_OK => {
+            dest.truncate(dest_len as usize);
+            Ok(dest)
+        }
+        Z_BUF_ERROR => Err(PngError::DeflatedDataTooLong(capacity)),
+        _ => Err(PngError::new("Failed to compress in zlib-ng")),
+    }
+}
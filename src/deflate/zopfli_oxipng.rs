@@ -1,17 +1,152 @@
-use std::num::NonZeroU8;
+use std::{io::Write, iter::once, num::NonZeroU8};
 
-use crate::{PngError, PngResult};
+use rayon::prelude::*;
 
-pub fn deflate(data: &[u8], iterations: NonZeroU8) -> PngResult<Vec<u8>> {
+#[cfg(not(feature = "parallel"))]
+use crate::rayon;
+use crate::{Deadline, PngError, PngResult};
+
+/// Amount of data that Zopfli compresses in one go, matching `zopfli::compress`
+const MASTER_BLOCK_SIZE: usize = 1_000_000;
+/// An empty fixed Huffman block with BFINAL set: BFINAL (1), BTYPE (01) and the end code (0000000)
+const EMPTY_FINAL_BLOCK: [u8; 2] = [0b011, 0];
+
+/// Compress the data as a zlib stream
+///
+/// If `single_block` is set, each master block is compressed as a single deflate block with one
+/// Huffman table, rather than being split into blocks with their own tables.
+///
+/// If a deadline is given, it is checked between each master block, returning
+/// `PngError::TimedOut` if it has passed.
+pub fn deflate(
+    data: &[u8],
+    iterations: NonZeroU8,
+    single_block: bool,
+    deadline: Option<&Deadline>,
+) -> PngResult<Vec<u8>> {
     let mut output = Vec::with_capacity(data.len());
-    let options = zopfli::Options {
-        iteration_count: iterations.into(),
-        ..Default::default()
-    };
-    match zopfli::compress(options, zopfli::Format::Zlib, data, &mut output) {
-        Ok(_) => (),
-        Err(_) => return Err(PngError::new("Failed to compress in zopfli")),
-    };
+    let options = zopfli_options(iterations, single_block);
+    let mut encoder = zopfli::ZlibEncoder::new(options, zopfli::BlockType::Dynamic, &mut output)
+        .map_err(|_| PngError::new("Failed to compress in zopfli"))?;
+    for chunk in data.chunks(MASTER_BLOCK_SIZE) {
+        check_deadline(deadline)?;
+        encoder
+            .write_all(chunk)
+            .map_err(|_| PngError::new("Failed to compress in zopfli"))?;
+    }
+    encoder
+        .finish()
+        .map_err(|_| PngError::new("Failed to compress in zopfli"))?;
     output.shrink_to_fit();
     Ok(output)
 }
+
+/// Compress independent parts of the data in parallel, joining them into a single zlib stream
+///
+/// `split_points` are the ascending offsets in the data at which each new part begins.
+/// Matches cannot reach back across a split point, so each split costs a little compression.
+/// If `single_block` is set, each part is compressed as a single deflate block as in `deflate`.
+///
+/// If a deadline is given, it is checked between each master block, returning
+/// `PngError::TimedOut` if it has passed.
+pub fn deflate_split(
+    data: &[u8],
+    iterations: NonZeroU8,
+    single_block: bool,
+    split_points: &[usize],
+    deadline: Option<&Deadline>,
+) -> PngResult<Vec<u8>> {
+    let options = zopfli_options(iterations, single_block);
+    let starts = once(0).chain(split_points.iter().copied());
+    let ends = split_points.iter().copied().chain(once(data.len()));
+    let ranges: Vec<_> = starts.zip(ends).collect();
+    let parts = ranges
+        .par_iter()
+        .with_max_len(1)
+        .map(|&(start, end)| deflate_part(&data[start..end], options, deadline))
+        .collect::<Result<Vec<_>, _>>()?;
+
+    // Zlib header for the maximum compression level, the same as written by zopfli
+    let mut output = vec![0x78, 0xDA];
+    let mut output_bits = output.len() * 8;
+    for (part, part_bits) in parts {
+        append_bits(&mut output, &mut output_bits, &part, part_bits);
+    }
+    // End the stream with an empty final block
+    append_bits(&mut output, &mut output_bits, &EMPTY_FINAL_BLOCK, 10);
+    output.extend_from_slice(&libdeflater::adler32(data).to_be_bytes());
+    Ok(output)
+}
+
+fn zopfli_options(iterations: NonZeroU8, single_block: bool) -> zopfli::Options {
+    let defaults = zopfli::Options::default();
+    zopfli::Options {
+        iteration_count: iterations.into(),
+        // A limit of one block disables block splitting
+        maximum_block_splits: if single_block {
+            1
+        } else {
+            defaults.maximum_block_splits
+        },
+        ..defaults
+    }
+}
+
+/// Compress a part of the data as raw deflate, returning the stream and its length in bits
+///
+/// The blocks are written without the BFINAL flag so the stream can be continued by the next part.
+fn deflate_part(
+    data: &[u8],
+    options: zopfli::Options,
+    deadline: Option<&Deadline>,
+) -> PngResult<(Vec<u8>, usize)> {
+    let error = |_| PngError::new("Failed to compress in zopfli");
+    let mut encoder = zopfli::DeflateEncoder::new(options, zopfli::BlockType::Dynamic, Vec::new());
+    for chunk in data.chunks(MASTER_BLOCK_SIZE) {
+        check_deadline(deadline)?;
+        encoder.write_all(chunk).map_err(error)?;
+    }
+    // An empty write forces all pending data to be compressed as non-final blocks
+    let written = encoder.write(&[]).map_err(error)?;
+    debug_assert_eq!(written, 0);
+    // Finishing then appends an empty final block, identical to `EMPTY_FINAL_BLOCK`, followed
+    // by zero padding. The last set bit is therefore the low BTYPE bit, directly preceded by
+    // BFINAL, and everything before BFINAL is the non-final stream.
+    let output = encoder.finish().map_err(error)?;
+    let (index, &byte) = output
+        .iter()
+        .enumerate()
+        .rfind(|(_, &b)| b != 0)
+        .ok_or_else(|| PngError::new("Failed to compress in zopfli"))?;
+    let btype_bit = index * 8 + (7 - byte.leading_zeros() as usize);
+    Ok((output, btype_bit - 1))
+}
+
+/// Stop compressing if the deadline has passed
+fn check_deadline(deadline: Option<&Deadline>) -> PngResult<()> {
+    match deadline {
+        Some(deadline) if deadline.passed() => Err(PngError::TimedOut),
+        _ => Ok(()),
+    }
+}
+
+/// Append the first `src_bits` bits of `src` to a deflate bitstream of `dest_bits` bits
+fn append_bits(dest: &mut Vec<u8>, dest_bits: &mut usize, src: &[u8], src_bits: usize) {
+    let shift = *dest_bits % 8;
+    dest.truncate(dest_bits.div_ceil(8));
+    for &byte in &src[..src_bits.div_ceil(8)] {
+        if shift == 0 {
+            dest.push(byte);
+        } else {
+            *dest.last_mut().unwrap() |= byte << shift;
+            dest.push(byte >> (8 - shift));
+        }
+    }
+    *dest_bits += src_bits;
+    dest.truncate(dest_bits.div_ceil(8));
+    // Clear any bits beyond the end of the stream
+    let extra = *dest_bits % 8;
+    if extra != 0 {
+        *dest.last_mut().unwrap() &= (1 << extra) - 1;
+    }
+}
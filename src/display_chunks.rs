@@ -1,4 +1,9 @@
 /// List of chunks that affect image display and will be kept when using the `Safe` chunk strip option
-pub const DISPLAY_CHUNKS: [[u8; 4]; 7] = [
-    *b"cICP", *b"iCCP", *b"sRGB", *b"pHYs", *b"acTL", *b"fcTL", *b"fdAT",
+///
+/// The eXIf chunk is included as viewers may apply the image orientation it contains. The oFFs,
+/// sCAL and pCAL chunks are included as they describe the physical geometry of the image, as
+/// pHYs does.
+pub const DISPLAY_CHUNKS: [[u8; 4]; 12] = [
+    *b"cICP", *b"iCCP", *b"sRGB", *b"pHYs", *b"oFFs", *b"sCAL", *b"pCAL", *b"eXIf", *b"sTER",
+    *b"acTL", *b"fcTL", *b"fdAT",
 ];
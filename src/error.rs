@@ -1,12 +1,16 @@
 use std::{error::Error, fmt};
 
-use crate::colors::{BitDepth, ColorType};
+use crate::{
+    colors::{BitDepth, ColorType},
+    deflate::{LIBDEFLATER_MAX_LEVEL, LIBDEFLATER_MIN_LEVEL},
+};
 
 #[derive(Debug, Clone)]
 #[non_exhaustive]
 pub enum PngError {
     DeflatedDataTooLong(usize),
     TimedOut,
+    Cancelled,
     NotPNG,
     APNGNotSupported,
     InvalidData,
@@ -14,6 +18,10 @@ pub enum PngError {
     ChunkMissing(&'static str),
     InvalidDepthForType(BitDepth, ColorType),
     IncorrectDataLength(usize, usize),
+    IncorrectFilterCount(usize, usize),
+    InvalidFilterType(u8),
+    InvalidCompressionLevel(u8),
+    VerificationFailed,
     Other(Box<str>),
 }
 
@@ -26,6 +34,7 @@ impl fmt::Display for PngError {
         match *self {
             PngError::DeflatedDataTooLong(_) => f.write_str("deflated data too long"),
             PngError::TimedOut => f.write_str("timed out"),
+            PngError::Cancelled => f.write_str("cancelled"),
             PngError::NotPNG => f.write_str("Invalid header detected; Not a PNG file"),
             PngError::InvalidData => f.write_str("Invalid data found; unable to read PNG file"),
             PngError::TruncatedData => {
@@ -41,6 +50,20 @@ impl fmt::Display for PngError {
                 "Data length {} does not match the expected length {}",
                 l1, l2
             ),
+            PngError::IncorrectFilterCount(l1, l2) => write!(
+                f,
+                "Filter count {} does not match the number of rows {}",
+                l1, l2
+            ),
+            PngError::InvalidFilterType(t) => write!(f, "Invalid filter type {}", t),
+            PngError::InvalidCompressionLevel(l) => write!(
+                f,
+                "Invalid compression level {}; libdeflater supports levels {}-{}",
+                l, LIBDEFLATER_MIN_LEVEL, LIBDEFLATER_MAX_LEVEL
+            ),
+            PngError::VerificationFailed => {
+                f.write_str("The optimized output does not decode to the expected image")
+            }
             PngError::Other(ref s) => f.write_str(s),
         }
     }
@@ -16,13 +16,21 @@ use rayon::prelude::*;
 
 #[cfg(not(feature = "parallel"))]
 use crate::rayon;
-use crate::{atomicmin::AtomicMin, deflate, filters::RowFilter, png::PngImage, Deadline, PngError};
+use crate::{
+    atomicmin::AtomicMin,
+    deflate,
+    filters::RowFilter,
+    png::{PngImage, BRUTE_DEFLATER},
+    CandidateSelection, Deadline, PngError, Progress,
+};
 
 pub struct Candidate {
     pub image: Arc<PngImage>,
     pub idat_data: Vec<u8>,
     pub filtered: Vec<u8>,
     pub filter: RowFilter,
+    // size adjusted for the preferred color type, if any
+    size: usize,
     // first wins tie-breaker
     nth: usize,
 }
@@ -30,7 +38,7 @@ pub struct Candidate {
 impl Candidate {
     fn cmp_key(&self) -> impl Ord {
         (
-            self.idat_data.len() + self.image.key_chunks_size(),
+            self.size,
             self.image.data.len(),
             self.image.ihdr.bit_depth,
             self.filter,
@@ -45,6 +53,7 @@ pub(crate) struct Evaluator {
     filters: IndexSet<RowFilter>,
     compression: u8,
     optimize_alpha: bool,
+    selection: CandidateSelection,
     nth: AtomicUsize,
     executed: Arc<AtomicUsize>,
     best_candidate_size: Arc<AtomicMin>,
@@ -62,6 +71,7 @@ impl Evaluator {
         filters: IndexSet<RowFilter>,
         compression: u8,
         optimize_alpha: bool,
+        selection: CandidateSelection,
     ) -> Self {
         #[cfg(feature = "parallel")]
         let eval_channel = unbounded();
@@ -70,6 +80,7 @@ impl Evaluator {
             filters,
             compression,
             optimize_alpha,
+            selection,
             nth: AtomicUsize::new(0),
             executed: Arc::new(AtomicUsize::new(0)),
             best_candidate_size: Arc::new(AtomicMin::new(None)),
@@ -120,6 +131,8 @@ impl Evaluator {
         let filters = self.filters.clone();
         let compression = self.compression;
         let optimize_alpha = self.optimize_alpha;
+        // A preferred image is compared as if it were smaller by its bias
+        let bias = self.selection.size_bias(&image.ihdr.color_type);
         let executed = self.executed.clone();
         let best_candidate_size = self.best_candidate_size.clone();
         let description = description.to_string();
@@ -139,11 +152,24 @@ impl Evaluator {
                 if deadline.passed() {
                     return;
                 }
-                let filtered = image.filter_image(filter, optimize_alpha);
-                let idat_data = deflate::deflate(&filtered, compression, &best_candidate_size);
+                let Some(filtered) = image.filter_image_until(
+                    filter,
+                    optimize_alpha,
+                    BRUTE_DEFLATER,
+                    Some(&deadline),
+                ) else {
+                    return;
+                };
+                let max_size = AtomicMin::new(
+                    best_candidate_size
+                        .get()
+                        .map(|s| (s as f64 * bias) as usize),
+                );
+                let idat_data = deflate::deflate(&filtered, compression, &max_size);
+                deadline.report(Progress::Evaluated(filter));
                 if let Ok(idat_data) = idat_data {
                     let size = idat_data.len() + image.key_chunks_size();
-                    best_candidate_size.set_min(size);
+                    best_candidate_size.set_min((size as f64 / bias) as usize);
                     trace!(
                         "Eval: {}-bit {:23} {:8}   {} bytes",
                         image.ihdr.bit_depth,
@@ -156,6 +182,7 @@ impl Evaluator {
                         idat_data,
                         filtered,
                         filter,
+                        size: (size as f64 / bias) as usize,
                         nth,
                     };
 
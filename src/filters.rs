@@ -2,6 +2,12 @@ use std::{fmt, fmt::Display, mem::transmute};
 
 use crate::error::PngError;
 
+#[cfg(all(
+    any(target_arch = "x86", target_arch = "x86_64"),
+    target_feature = "sse2"
+))]
+mod sse2;
+
 #[repr(u8)]
 #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
 pub enum RowFilter {
@@ -17,6 +23,7 @@ pub enum RowFilter {
     Bigrams,
     BigEnt,
     Brute,
+    WeightedSum,
 }
 
 impl TryFrom<u8> for RowFilter {
@@ -44,6 +51,7 @@ impl Display for RowFilter {
                 Self::Bigrams => "Bigrams",
                 Self::BigEnt => "BigEnt",
                 Self::Brute => "Brute",
+                Self::WeightedSum => "WeightedSum",
             },
             f,
         )
@@ -51,7 +59,7 @@ impl Display for RowFilter {
 }
 
 impl RowFilter {
-    pub const LAST: u8 = Self::Brute as u8;
+    pub const LAST: u8 = Self::WeightedSum as u8;
     pub(crate) const STANDARD: [Self; 5] =
         [Self::None, Self::Sub, Self::Up, Self::Average, Self::Paeth];
     pub(crate) const SINGLE_LINE: [Self; 2] = [Self::None, Self::Sub];
@@ -95,30 +103,19 @@ impl RowFilter {
                 );
             }
             Self::Average => {
-                for (i, byte) in data.iter().enumerate() {
-                    buf.push(match i.checked_sub(bpp) {
-                        Some(x) => byte.wrapping_sub(
-                            ((u16::from(data[x]) + u16::from(prev_line[i])) >> 1) as u8,
-                        ),
-                        None => byte.wrapping_sub(prev_line[i] >> 1),
-                    });
-                }
+                buf.resize(data.len() + 1, 0);
+                average_filter(bpp, data, prev_line, &mut buf[1..]);
             }
             Self::Paeth => {
-                for (i, byte) in data.iter().enumerate() {
-                    buf.push(match i.checked_sub(bpp) {
-                        Some(x) => {
-                            byte.wrapping_sub(paeth_predictor(data[x], prev_line[i], prev_line[x]))
-                        }
-                        None => byte.wrapping_sub(prev_line[i]),
-                    });
-                }
+                buf.resize(data.len() + 1, 0);
+                paeth_filter(bpp, data, prev_line, &mut buf[1..]);
             }
             _ => unreachable!(),
         }
     }
 
     // Optimize fully transparent pixels of a scanline such that they will be zeroed when filtered
+    #[allow(clippy::needless_range_loop)]
     fn optimize_alpha(self, bpp: usize, data: &mut [u8], prev_line: &[u8], color_bytes: usize) {
         if self == Self::None {
             // Assume transparent pixels already set to 0
@@ -237,6 +234,95 @@ impl RowFilter {
     }
 }
 
+/// Apply the Average filter to a line, writing the filtered bytes to `out`
+///
+/// Panics if `prev_line` or `out` is shorter than `data`.
+pub fn average_filter(bpp: usize, data: &[u8], prev_line: &[u8], out: &mut [u8]) {
+    assert!(prev_line.len() >= data.len() && out.len() >= data.len());
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse2"
+    ))]
+    let end = sse2::average_filter(bpp, data, prev_line, out);
+    #[cfg(not(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse2"
+    )))]
+    let end = bpp;
+    average_filter_range(bpp, data, prev_line, out, end);
+}
+
+/// Apply the Average filter to a line without SIMD, writing the filtered bytes to `out`
+pub fn average_filter_scalar(bpp: usize, data: &[u8], prev_line: &[u8], out: &mut [u8]) {
+    average_filter_range(bpp, data, prev_line, out, bpp);
+}
+
+/// Apply the Average filter to the first pixel and to the bytes from `start` onwards
+fn average_filter_range(bpp: usize, data: &[u8], prev_line: &[u8], out: &mut [u8], start: usize) {
+    for i in (0..bpp.min(data.len())).chain(start..data.len()) {
+        out[i] = match i.checked_sub(bpp) {
+            Some(x) => {
+                data[i].wrapping_sub(((u16::from(data[x]) + u16::from(prev_line[i])) >> 1) as u8)
+            }
+            None => data[i].wrapping_sub(prev_line[i] >> 1),
+        };
+    }
+}
+
+/// Sum the absolute values of filtered bytes, interpreted as signed, for the MinSum heuristic
+pub fn sum_abs(data: &[u8]) -> usize {
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse2"
+    ))]
+    let (sum, end) = sse2::sum_abs(data);
+    #[cfg(not(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse2"
+    )))]
+    let (sum, end) = (0, 0);
+    sum + sum_abs_scalar(&data[end..])
+}
+
+/// Sum the absolute values of filtered bytes, interpreted as signed, without SIMD
+pub fn sum_abs_scalar(data: &[u8]) -> usize {
+    data.iter()
+        .fold(0, |acc, &x| acc + (x as i8).unsigned_abs() as usize)
+}
+
+/// Apply the Paeth filter to a line, writing the filtered bytes to `out`
+///
+/// Panics if `prev_line` or `out` is shorter than `data`.
+pub fn paeth_filter(bpp: usize, data: &[u8], prev_line: &[u8], out: &mut [u8]) {
+    assert!(prev_line.len() >= data.len() && out.len() >= data.len());
+    #[cfg(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse2"
+    ))]
+    let end = sse2::paeth_filter(bpp, data, prev_line, out);
+    #[cfg(not(all(
+        any(target_arch = "x86", target_arch = "x86_64"),
+        target_feature = "sse2"
+    )))]
+    let end = bpp;
+    paeth_filter_range(bpp, data, prev_line, out, end);
+}
+
+/// Apply the Paeth filter to a line without SIMD, writing the filtered bytes to `out`
+pub fn paeth_filter_scalar(bpp: usize, data: &[u8], prev_line: &[u8], out: &mut [u8]) {
+    paeth_filter_range(bpp, data, prev_line, out, bpp);
+}
+
+/// Apply the Paeth filter to the first pixel and to the bytes from `start` onwards
+fn paeth_filter_range(bpp: usize, data: &[u8], prev_line: &[u8], out: &mut [u8], start: usize) {
+    for i in (0..bpp.min(data.len())).chain(start..data.len()) {
+        out[i] = match i.checked_sub(bpp) {
+            Some(x) => data[i].wrapping_sub(paeth_predictor(data[x], prev_line[i], prev_line[x])),
+            None => data[i].wrapping_sub(prev_line[i]),
+        };
+    }
+}
+
 fn paeth_predictor(a: u8, b: u8, c: u8) -> u8 {
     let p = i32::from(a) + i32::from(b) - i32::from(c);
     let pa = (p - i32::from(a)).abs();
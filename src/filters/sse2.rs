@@ -0,0 +1,117 @@
+#[cfg(target_arch = "x86")]
+use std::arch::x86::*;
+#[cfg(target_arch = "x86_64")]
+use std::arch::x86_64::*;
+
+/// Apply the Average filter to as many bytes after the first pixel as fit in whole vectors
+///
+/// Returns the index of the first byte that was not filtered.
+pub(crate) fn average_filter(bpp: usize, data: &[u8], prev_line: &[u8], out: &mut [u8]) -> usize {
+    assert!(prev_line.len() >= data.len() && out.len() >= data.len());
+    let mut i = bpp;
+    while i + 16 <= data.len() {
+        // SAFETY: SSE2 is enabled for this target and all accesses are within bounds
+        unsafe {
+            let x = load(&data[i..]);
+            let a = load(&data[i - bpp..]);
+            let b = load(&prev_line[i..]);
+            // `_mm_avg_epu8` rounds up, so subtract the carry to get the floor
+            let avg = _mm_sub_epi8(
+                _mm_avg_epu8(a, b),
+                _mm_and_si128(_mm_xor_si128(a, b), _mm_set1_epi8(1)),
+            );
+            store(&mut out[i..], _mm_sub_epi8(x, avg));
+        }
+        i += 16;
+    }
+    i
+}
+
+/// Apply the Paeth filter to as many bytes after the first pixel as fit in whole vectors
+///
+/// Returns the index of the first byte that was not filtered.
+pub(crate) fn paeth_filter(bpp: usize, data: &[u8], prev_line: &[u8], out: &mut [u8]) -> usize {
+    assert!(prev_line.len() >= data.len() && out.len() >= data.len());
+    let mut i = bpp;
+    while i + 16 <= data.len() {
+        // SAFETY: SSE2 is enabled for this target and all accesses are within bounds
+        unsafe {
+            let x = load(&data[i..]);
+            let a = load(&data[i - bpp..]);
+            let b = load(&prev_line[i..]);
+            let c = load(&prev_line[i - bpp..]);
+            let zero = _mm_setzero_si128();
+            let lo = paeth_predictor(
+                _mm_unpacklo_epi8(a, zero),
+                _mm_unpacklo_epi8(b, zero),
+                _mm_unpacklo_epi8(c, zero),
+            );
+            let hi = paeth_predictor(
+                _mm_unpackhi_epi8(a, zero),
+                _mm_unpackhi_epi8(b, zero),
+                _mm_unpackhi_epi8(c, zero),
+            );
+            store(&mut out[i..], _mm_sub_epi8(x, _mm_packus_epi16(lo, hi)));
+        }
+        i += 16;
+    }
+    i
+}
+
+/// Sum the absolute values of filtered bytes, interpreted as signed, in as many whole vectors as fit
+///
+/// Returns the sum and the index of the first byte that was not summed.
+pub(crate) fn sum_abs(data: &[u8]) -> (usize, usize) {
+    let mut i = 0;
+    let mut sum = 0;
+    // SAFETY: SSE2 is enabled for this target and all accesses are within bounds
+    unsafe {
+        let zero = _mm_setzero_si128();
+        let mut acc = zero;
+        while i + 16 <= data.len() {
+            let x = load(&data[i..]);
+            // As unsigned bytes, the smaller of `x` and `-x` is the absolute value of `x as i8`
+            let abs = _mm_min_epu8(x, _mm_sub_epi8(zero, x));
+            acc = _mm_add_epi64(acc, _mm_sad_epu8(abs, zero));
+            i += 16;
+        }
+        let mut lanes = [0u64; 2];
+        _mm_storeu_si128(lanes.as_mut_ptr() as *mut __m128i, acc);
+        sum += (lanes[0] + lanes[1]) as usize;
+    }
+    (sum, i)
+}
+
+/// The Paeth predictor for eight 16-bit lanes, with the same tie-breaking as the scalar version
+#[target_feature(enable = "sse2")]
+unsafe fn paeth_predictor(a: __m128i, b: __m128i, c: __m128i) -> __m128i {
+    let pa = abs(_mm_sub_epi16(b, c));
+    let pb = abs(_mm_sub_epi16(a, c));
+    let pc = abs(_mm_add_epi16(_mm_sub_epi16(b, c), _mm_sub_epi16(a, c)));
+    let not_a = _mm_or_si128(_mm_cmpgt_epi16(pa, pb), _mm_cmpgt_epi16(pa, pc));
+    let use_c = _mm_cmpgt_epi16(pb, pc);
+    let b_or_c = select(use_c, c, b);
+    select(not_a, b_or_c, a)
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn abs(x: __m128i) -> __m128i {
+    _mm_max_epi16(x, _mm_sub_epi16(_mm_setzero_si128(), x))
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn select(mask: __m128i, a: __m128i, b: __m128i) -> __m128i {
+    _mm_or_si128(_mm_and_si128(mask, a), _mm_andnot_si128(mask, b))
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn load(data: &[u8]) -> __m128i {
+    assert!(data.len() >= 16);
+    _mm_loadu_si128(data.as_ptr() as *const __m128i)
+}
+
+#[target_feature(enable = "sse2")]
+unsafe fn store(out: &mut [u8], value: __m128i) {
+    assert!(out.len() >= 16);
+    _mm_storeu_si128(out.as_mut_ptr() as *mut __m128i, value)
+}
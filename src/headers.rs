@@ -1,6 +1,12 @@
+use std::{
+    io::{ErrorKind, Read},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use indexmap::IndexSet;
+use libdeflater::Crc;
 use log::warn;
-use rgb::{RGB16, RGBA8};
+use rgb::{RGB16, RGBA16, RGBA8};
 
 use crate::{
     colors::{BitDepth, ColorType},
@@ -11,6 +17,9 @@ use crate::{
     AtomicMin, Deflaters, PngResult,
 };
 
+/// Maximum size of decompressed text that will be considered for recompression
+const MAX_TEXT_SIZE: usize = 64 * 1024 * 1024;
+
 #[derive(Debug, Clone)]
 /// Headers from the IHDR chunk of the image
 pub struct IhdrData {
@@ -42,7 +51,7 @@ impl IhdrData {
         let bpp = self.bpp();
 
         fn bitmap_size(bpp: usize, w: usize, h: usize) -> usize {
-            ((w * bpp + 7) / 8) * h
+            (w * bpp).div_ceil(8) * h
         }
 
         if self.interlaced == Interlacing::None {
@@ -66,8 +75,11 @@ impl IhdrData {
 }
 
 #[derive(Debug, Clone)]
+/// A chunk of a PNG file
 pub struct Chunk {
+    /// The four-byte name of the chunk, such as `tEXt`
     pub name: [u8; 4],
+    /// The data of the chunk, excluding the length, name and CRC
     pub data: Vec<u8>,
 }
 
@@ -80,6 +92,10 @@ pub enum StripChunks {
     Strip(IndexSet<[u8; 4]>),
     /// Remove all chunks that won't affect image display
     Safe,
+    /// Remove all metadata, keeping chunks that affect color rendering, stereo layout or animation
+    ///
+    /// Unknown chunks are treated as metadata.
+    Metadata,
     /// Remove all non-critical chunks except these
     Keep(IndexSet<[u8; 4]>),
     /// All non-critical chunks
@@ -87,17 +103,61 @@ pub enum StripChunks {
 }
 
 impl StripChunks {
+    /// Whether the chunk with the given name should be kept
+    ///
+    /// Names are matched case-sensitively. Critical chunks are always kept.
     pub(crate) fn keep(&self, name: &[u8; 4]) -> bool {
+        if is_critical(name) {
+            return true;
+        }
         match &self {
             StripChunks::None => true,
             StripChunks::Keep(names) => names.contains(name),
             StripChunks::Strip(names) => !names.contains(name),
             StripChunks::Safe => DISPLAY_CHUNKS.contains(name),
+            StripChunks::Metadata => !matches!(
+                chunk_category(name),
+                ChunkCategory::Metadata | ChunkCategory::Physical
+            ),
             StripChunks::All => false,
         }
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// The role of an ancillary chunk
+pub(crate) enum ChunkCategory {
+    /// Affects how the colors of the image are rendered
+    Color,
+    /// Describes how the image is laid out for display, such as a stereo pair
+    Layout,
+    /// Part of an APNG animation
+    Animation,
+    /// Describes the physical geometry of the image, such as its scale or offset
+    Physical,
+    /// Describes the image without affecting its colors
+    Metadata,
+}
+
+/// Classify an ancillary chunk by its role, treating unknown chunks as metadata
+pub(crate) fn chunk_category(name: &[u8; 4]) -> ChunkCategory {
+    match name {
+        b"tRNS" | b"gAMA" | b"cHRM" | b"sRGB" | b"iCCP" | b"cICP" | b"mDCV" | b"cLLI" | b"sBIT" => {
+            ChunkCategory::Color
+        }
+        b"sTER" => ChunkCategory::Layout,
+        b"acTL" | b"fcTL" | b"fdAT" => ChunkCategory::Animation,
+        b"pHYs" | b"oFFs" | b"sCAL" | b"pCAL" => ChunkCategory::Physical,
+        _ => ChunkCategory::Metadata,
+    }
+}
+
+/// Whether the chunk is critical, indicated by an uppercase first letter in its name
+#[inline]
+pub fn is_critical(name: &[u8; 4]) -> bool {
+    name[0] & 0x20 == 0
+}
+
 #[inline]
 pub fn file_header_is_valid(bytes: &[u8]) -> bool {
     let expected_header: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
@@ -105,10 +165,51 @@ pub fn file_header_is_valid(bytes: &[u8]) -> bool {
     *bytes == expected_header
 }
 
+/// Name of the private chunk that marks a file as already optimized
+///
+/// The chunk is ancillary, private and unsafe to copy, so editors that don't recognize it should
+/// drop it when modifying the image. It holds the CRC of the combined IDAT data.
+pub const MARKER_CHUNK: [u8; 4] = *b"oxIP";
+
+/// Create a marker chunk for the given IDAT data
+pub(crate) fn optimized_marker(idat_data: &[u8]) -> Chunk {
+    Chunk {
+        name: MARKER_CHUNK,
+        data: crc32(idat_data).to_be_bytes().to_vec(),
+    }
+}
+
+/// Check whether a PNG file has a marker chunk that matches its image data
+///
+/// Only the chunk checksums are computed, without decompressing anything. Any invalid chunk,
+/// including one with an incorrect CRC, means the file is not considered marked.
+pub fn has_optimized_marker(data: &[u8]) -> bool {
+    if !data.get(0..8).is_some_and(file_header_is_valid) {
+        return false;
+    }
+    let mut offset = 8;
+    let mut idat_crc = Crc::new();
+    let mut marker = None;
+    loop {
+        match parse_next_chunk(data, &mut offset, false) {
+            Ok(Some(chunk)) => match &chunk.name {
+                b"IDAT" => idat_crc.update(chunk.data),
+                &MARKER_CHUNK => marker = Some(chunk.data),
+                _ => (),
+            },
+            Ok(None) => break,
+            Err(_) => return false,
+        }
+    }
+    marker == Some(&idat_crc.sum().to_be_bytes()[..])
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct RawChunk<'a> {
     pub name: [u8; 4],
     pub data: &'a [u8],
+    /// Whether the stored CRC matches the chunk contents
+    pub crc_valid: bool,
 }
 
 pub fn parse_next_chunk<'a>(
@@ -147,7 +248,8 @@ pub fn parse_next_chunk<'a>(
     let chunk_bytes = byte_data
         .get(chunk_start..chunk_start + 4 + length as usize)
         .ok_or(PngError::TruncatedData)?;
-    if !fix_errors && crc32(chunk_bytes) != crc {
+    let crc_valid = crc32(chunk_bytes) == crc;
+    if !fix_errors && !crc_valid {
         return Err(PngError::new(&format!(
             "CRC Mismatch in {} chunk; May be recoverable by using --fix",
             String::from_utf8_lossy(chunk_name)
@@ -155,7 +257,69 @@ pub fn parse_next_chunk<'a>(
     }
 
     let name: [u8; 4] = chunk_name.try_into().unwrap();
-    Ok(Some(RawChunk { name, data }))
+    Ok(Some(RawChunk {
+        name,
+        data,
+        crc_valid,
+    }))
+}
+
+/// Read the next chunk from a stream into `data`, returning its name and whether its CRC is
+/// valid, or `None` at IEND
+///
+/// The data is read as it arrives rather than allocated up front, so a corrupt length can't cause
+/// an allocation larger than the stream itself.
+pub fn read_next_chunk<R: Read>(
+    reader: &mut R,
+    data: &mut Vec<u8>,
+    fix_errors: bool,
+) -> PngResult<Option<([u8; 4], bool)>> {
+    let mut header = [0; 8];
+    read_exact(reader, &mut header)?;
+    let length = read_be_u32(&header[0..4]);
+    let name: [u8; 4] = header[4..8].try_into().unwrap();
+    if &name == b"IEND" {
+        // End of data
+        return Ok(None);
+    }
+    // The PNG spec limits chunk lengths to 2^31-1 bytes
+    if length > i32::MAX as u32 {
+        return Err(PngError::new(&format!(
+            "Invalid length of {} chunk",
+            String::from_utf8_lossy(&name)
+        )));
+    }
+
+    data.clear();
+    reader
+        .take(length.into())
+        .read_to_end(data)
+        .map_err(|e| PngError::new(&format!("Failed to read PNG data: {}", e)))?;
+    if data.len() != length as usize {
+        return Err(PngError::TruncatedData);
+    }
+    let mut crc = [0; 4];
+    read_exact(reader, &mut crc)?;
+
+    let mut hasher = Crc::new();
+    hasher.update(&name);
+    hasher.update(data);
+    let crc_valid = hasher.sum() == u32::from_be_bytes(crc);
+    if !fix_errors && !crc_valid {
+        return Err(PngError::new(&format!(
+            "CRC Mismatch in {} chunk; May be recoverable by using --fix",
+            String::from_utf8_lossy(&name)
+        )));
+    }
+    Ok(Some((name, crc_valid)))
+}
+
+/// Fill the buffer from a stream, treating an early end of the stream as truncated data
+pub fn read_exact<R: Read>(reader: &mut R, buf: &mut [u8]) -> PngResult<()> {
+    reader.read_exact(buf).map_err(|e| match e.kind() {
+        ErrorKind::UnexpectedEof => PngError::TruncatedData,
+        _ => PngError::new(&format!("Failed to read PNG data: {}", e)),
+    })
 }
 
 pub fn parse_ihdr_chunk(
@@ -180,7 +344,7 @@ pub fn parse_ihdr_chunk(
                 }),
             },
             3 => ColorType::Indexed {
-                palette: palette_to_rgba(palette_data, trns_data).unwrap_or_default(),
+                palette: palette_to_rgba(palette_data, trns_data)?,
             },
             4 => ColorType::GrayscaleAlpha,
             6 => ColorType::RGBA,
@@ -193,14 +357,351 @@ pub fn parse_ihdr_chunk(
     })
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Coding-independent code points from the cICP chunk, as defined in ITU-T H.273
+pub struct CicpData {
+    /// The color primaries
+    pub color_primaries: u8,
+    /// The transfer characteristics
+    pub transfer_function: u8,
+    /// The matrix coefficients, which must be 0 (RGB) in PNG
+    pub matrix_coefficients: u8,
+    /// Whether the image uses the full range of values rather than a narrow range
+    pub video_full_range: bool,
+}
+
+/// Parse the data of a cICP chunk
+///
+/// PNG only supports RGB, so any other matrix coefficients are rejected, as is a range flag other
+/// than 0 or 1.
+pub fn parse_cicp_chunk(byte_data: &[u8]) -> PngResult<CicpData> {
+    let &[color_primaries, transfer_function, matrix_coefficients, range] = byte_data else {
+        return Err(PngError::new("Invalid length of cICP chunk"));
+    };
+    if matrix_coefficients != 0 {
+        return Err(PngError::new(
+            "Unsupported matrix coefficients in cICP chunk",
+        ));
+    }
+    let video_full_range = match range {
+        0 => false,
+        1 => true,
+        _ => return Err(PngError::new("Invalid range flag in cICP chunk")),
+    };
+    Ok(CicpData {
+        color_primaries,
+        transfer_function,
+        matrix_coefficients,
+        video_full_range,
+    })
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The layout of a stereo pair from the sTER chunk, where the image holds the left and right
+/// views side by side
+pub enum StereoLayout {
+    /// The right-eye view is on the left, for cross-fused viewing
+    CrossFuse,
+    /// The left-eye view is on the left, for divergent fused viewing
+    DivergingFuse,
+}
+
+pub fn parse_ster_chunk(byte_data: &[u8]) -> PngResult<StereoLayout> {
+    match byte_data {
+        [0] => Ok(StereoLayout::CrossFuse),
+        [1] => Ok(StereoLayout::DivergingFuse),
+        [_] => Err(PngError::new("Invalid mode in sTER chunk")),
+        _ => Err(PngError::new("Invalid length of sTER chunk")),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The time of the last modification of the image from the tIME chunk, in UTC
+pub struct ModificationTime {
+    /// The complete year, such as 2024
+    pub year: u16,
+    /// The month, from 1 to 12
+    pub month: u8,
+    /// The day of the month, from 1 to 31
+    pub day: u8,
+    /// The hour, from 0 to 23
+    pub hour: u8,
+    /// The minute, from 0 to 59
+    pub minute: u8,
+    /// The second, from 0 to 60 to allow for leap seconds
+    pub second: u8,
+}
+
+impl ModificationTime {
+    /// Convert a system time to UTC, returning `None` if it is before 1970 or after the year 65535
+    pub fn from_system_time(time: SystemTime) -> Option<Self> {
+        let secs = time.duration_since(UNIX_EPOCH).ok()?.as_secs();
+        let (days, secs) = (secs / 86400, secs % 86400);
+        // Convert the days since the epoch to a date in the proleptic Gregorian calendar
+        // This uses an era of 400 years, with the year starting in March so leap days come last
+        let z = days + 719_468;
+        let era = z / 146_097;
+        let doe = z % 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = doy - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = yoe + era * 400 + u64::from(month <= 2);
+        Some(Self {
+            year: year.try_into().ok()?,
+            month: month as u8,
+            day: day as u8,
+            hour: (secs / 3600) as u8,
+            minute: (secs / 60 % 60) as u8,
+            second: (secs % 60) as u8,
+        })
+    }
+
+    /// The data of a tIME chunk holding this time
+    pub fn to_bytes(self) -> [u8; 7] {
+        let [y1, y2] = self.year.to_be_bytes();
+        [
+            y1,
+            y2,
+            self.month,
+            self.day,
+            self.hour,
+            self.minute,
+            self.second,
+        ]
+    }
+}
+
+pub fn parse_time_chunk(byte_data: &[u8]) -> PngResult<ModificationTime> {
+    let &[y1, y2, month, day, hour, minute, second] = byte_data else {
+        return Err(PngError::new("Invalid length of tIME chunk"));
+    };
+    if !(1..=12).contains(&month)
+        || !(1..=31).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return Err(PngError::new("Invalid date or time in tIME chunk"));
+    }
+    Ok(ModificationTime {
+        year: u16::from_be_bytes([y1, y2]),
+        month,
+        day,
+        hour,
+        minute,
+        second,
+    })
+}
+
+/// Check the structure of a pHYs, oFFs, sCAL or pCAL chunk, without interpreting its values
+pub fn validate_physical_chunk(name: &[u8; 4], data: &[u8]) -> PngResult<()> {
+    let valid = match name {
+        // Two 4-byte values and a unit of 0 or 1
+        b"pHYs" | b"oFFs" => data.len() == 9 && data[8] <= 1,
+        // A unit of 1 or 2, followed by the width and height as null-separated strings
+        b"sCAL" => match data.split_first() {
+            Some((1 | 2, values)) => {
+                let values: Vec<_> = values.split(|&b| b == 0).collect();
+                values.len() == 2 && values.iter().all(|v| !v.is_empty())
+            }
+            _ => false,
+        },
+        // A keyword, the original zero and maximum, the equation type, the number of parameters,
+        // then the unit name and each parameter as null-separated strings
+        b"pCAL" => match data.iter().position(|&b| b == 0) {
+            Some(len @ 1..=79) if data.len() >= len + 11 => {
+                let equation_type = data[len + 9];
+                let params = data[len + 10] as usize;
+                let strings = data[len + 11..].split(|&b| b == 0).count();
+                let expected = match equation_type {
+                    0 => 2,
+                    1 | 2 => 3,
+                    3 => 4,
+                    _ => 0,
+                };
+                params == expected && strings == params + 1
+            }
+            _ => false,
+        },
+        _ => true,
+    };
+    if valid {
+        Ok(())
+    } else {
+        Err(PngError::new(&format!(
+            "Invalid {} chunk",
+            String::from_utf8_lossy(name)
+        )))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A suggested palette from an sPLT chunk
+pub struct SuggestedPalette {
+    /// The name of the palette
+    pub name: String,
+    /// The sample depth of the entries, which is either 8 or 16
+    pub sample_depth: u8,
+    /// The entries of the palette, in the order they appear in the chunk
+    pub entries: Vec<SuggestedPaletteEntry>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// An entry in a suggested palette
+pub struct SuggestedPaletteEntry {
+    /// The color of the entry, with samples at the sample depth of the palette
+    pub color: RGBA16,
+    /// The relative frequency of the color in the image, or 0 if unknown
+    pub frequency: u16,
+}
+
+impl SuggestedPalette {
+    /// The colors of the palette, scaled to 8 bits per sample if necessary
+    pub fn colors_8bit(&self) -> Vec<RGBA8> {
+        let scale = |v: u16| match self.sample_depth {
+            16 => ((u32::from(v) * 255 + 32767) / 65535) as u8,
+            _ => v as u8,
+        };
+        self.entries
+            .iter()
+            .map(|e| {
+                RGBA8::new(
+                    scale(e.color.r),
+                    scale(e.color.g),
+                    scale(e.color.b),
+                    scale(e.color.a),
+                )
+            })
+            .collect()
+    }
+}
+
+pub fn parse_splt_chunk(byte_data: &[u8]) -> PngResult<SuggestedPalette> {
+    let name_len = byte_data
+        .iter()
+        .position(|&b| b == 0)
+        .ok_or_else(|| PngError::new("Missing palette name in sPLT chunk"))?;
+    if !(1..=79).contains(&name_len) {
+        return Err(PngError::new("Invalid palette name in sPLT chunk"));
+    }
+    // The name is Latin-1, which maps directly to the first 256 code points
+    let name = byte_data[..name_len].iter().map(|&b| b as char).collect();
+    let (&sample_depth, data) = byte_data[name_len + 1..]
+        .split_first()
+        .ok_or_else(|| PngError::new("Missing sample depth in sPLT chunk"))?;
+    let entry_size = match sample_depth {
+        8 => 6,
+        16 => 10,
+        _ => return Err(PngError::new("Invalid sample depth in sPLT chunk")),
+    };
+    if data.len() % entry_size != 0 {
+        return Err(PngError::new("Invalid length of sPLT chunk"));
+    }
+    let entries = data
+        .chunks(entry_size)
+        .map(|entry| {
+            let (samples, frequency) = entry.split_at(entry_size - 2);
+            let sample = |i: usize| match sample_depth {
+                16 => u16::from_be_bytes([samples[i * 2], samples[i * 2 + 1]]),
+                _ => u16::from(samples[i]),
+            };
+            SuggestedPaletteEntry {
+                color: RGBA16::new(sample(0), sample(1), sample(2), sample(3)),
+                frequency: u16::from_be_bytes([frequency[0], frequency[1]]),
+            }
+        })
+        .collect();
+    Ok(SuggestedPalette {
+        name,
+        sample_depth,
+        entries,
+    })
+}
+
+#[derive(Debug, Clone)]
+/// Basic information about a PNG file, read without decoding the image data
+pub struct PngInfo {
+    /// The width of the image in pixels
+    pub width: u32,
+    /// The height of the image in pixels
+    pub height: u32,
+    /// The color type of the image
+    pub color_type: ColorType,
+    /// The bit depth of the image
+    pub bit_depth: BitDepth,
+    /// The interlacing mode of the image
+    pub interlaced: Interlacing,
+    /// The name and data length of each chunk following IHDR, in file order, excluding IEND
+    pub chunks: Vec<([u8; 4], usize)>,
+}
+
+impl PngInfo {
+    /// The length of the EXIF data in the eXIf chunk, if there is one
+    pub fn exif_size(&self) -> Option<usize> {
+        self.chunks
+            .iter()
+            .find(|(name, _)| name == b"eXIf")
+            .map(|&(_, size)| size)
+    }
+}
+
+/// Read the IHDR and list the chunks of a PNG file, without inflating the image data
+///
+/// Chunk CRCs are not checked.
+pub fn parse_png_info(byte_data: &[u8]) -> PngResult<PngInfo> {
+    let header = byte_data.get(0..8).ok_or(PngError::TruncatedData)?;
+    if !file_header_is_valid(header) {
+        return Err(PngError::NotPNG);
+    }
+    let mut byte_offset = 8;
+    let ihdr = match parse_next_chunk(byte_data, &mut byte_offset, true)? {
+        Some(chunk) if &chunk.name == b"IHDR" => chunk.data,
+        _ => return Err(PngError::ChunkMissing("IHDR")),
+    };
+
+    let mut chunks = Vec::new();
+    let mut palette = None;
+    let mut trns = None;
+    while let Some(chunk) = parse_next_chunk(byte_data, &mut byte_offset, true)? {
+        match &chunk.name {
+            b"PLTE" => palette = Some(chunk.data.to_owned()),
+            b"tRNS" => trns = Some(chunk.data.to_owned()),
+            _ => (),
+        }
+        chunks.push((chunk.name, chunk.data.len()));
+    }
+    let ihdr = parse_ihdr_chunk(ihdr, palette, trns)?;
+    Ok(PngInfo {
+        width: ihdr.width,
+        height: ihdr.height,
+        color_type: ihdr.color_type,
+        bit_depth: ihdr.bit_depth,
+        interlaced: ihdr.interlaced,
+        chunks,
+    })
+}
+
 /// Construct an RGBA palette from the raw palette and transparency data
+///
+/// A missing palette results in an empty one. Malformed palettes with more than 256 entries or an incomplete entry are rejected, as decoders
+/// don't agree on how to read them. The bit depth limits pixel indices to 255 in any case.
 fn palette_to_rgba(
     palette_data: Option<Vec<u8>>,
     trns_data: Option<Vec<u8>>,
 ) -> Result<Vec<RGBA8>, PngError> {
-    let palette_data = palette_data.ok_or_else(|| PngError::new("no palette in indexed image"))?;
+    let Some(palette_data) = palette_data else {
+        return Ok(Vec::new());
+    };
+    if palette_data.len() > 256 * 3 || palette_data.len() % 3 != 0 {
+        return Err(PngError::new(&format!(
+            "Invalid palette length of {} bytes",
+            palette_data.len()
+        )));
+    }
     let mut palette: Vec<_> = palette_data
-        .chunks(3)
+        .chunks_exact(3)
         .map(|color| RGBA8::new(color[0], color[1], color[2], 255))
         .collect();
 
@@ -213,7 +714,7 @@ fn palette_to_rgba(
 }
 
 #[inline]
-fn read_be_u32(bytes: &[u8]) -> u32 {
+pub(crate) fn read_be_u32(bytes: &[u8]) -> u32 {
     u32::from_be_bytes(bytes.try_into().unwrap())
 }
 
@@ -246,10 +747,10 @@ pub fn extract_icc(iccp: &Chunk) -> Option<Vec<u8>> {
 }
 
 /// Construct an iCCP chunk by compressing the ICC profile
-pub fn construct_iccp(icc: &[u8], deflater: Deflaters) -> PngResult<Chunk> {
+pub fn construct_iccp(name: &[u8], icc: &[u8], deflater: Deflaters) -> PngResult<Chunk> {
     let mut compressed = deflater.deflate(icc, &AtomicMin::new(None))?;
-    let mut data = Vec::with_capacity(compressed.len() + 5);
-    data.extend(b"icc"); // Profile name - generally unused, can be anything
+    let mut data = Vec::with_capacity(compressed.len() + name.len() + 2);
+    data.extend(name); // Profile name - generally unused, can be anything
     data.extend([0, 0]); // Null separator, zlib compression method
     data.append(&mut compressed);
     Ok(Chunk {
@@ -258,6 +759,242 @@ pub fn construct_iccp(icc: &[u8], deflater: Deflaters) -> PngResult<Chunk> {
     })
 }
 
+/// Convert a keyword, such as an ICC profile name, to Latin-1 bytes if it is valid for PNG
+///
+/// Keywords must be 1-79 bytes long and may not contain control characters.
+pub fn keyword_bytes(keyword: &str) -> Option<Vec<u8>> {
+    let bytes: Vec<u8> = keyword
+        .chars()
+        .map(|c| {
+            u8::try_from(c)
+                .ok()
+                .filter(|&b| matches!(b, 32..=126 | 161..=255))
+        })
+        .collect::<Option<_>>()?;
+    (1..=79).contains(&bytes.len()).then_some(bytes)
+}
+
+/// Reduce the pixel aspect ratio of a pHYs chunk with unspecified unit to lowest terms
+///
+/// Returns `None` if the chunk is invalid or specifies a unit.
+pub fn normalize_phys(phys: &Chunk) -> Option<[u8; 9]> {
+    let data: &[u8; 9] = phys.data.as_slice().try_into().ok()?;
+    if data[8] != 0 {
+        return None;
+    }
+    let x = read_be_u32(&data[0..4]);
+    let y = read_be_u32(&data[4..8]);
+    if x == 0 || y == 0 {
+        return None;
+    }
+    let (mut a, mut b) = (x, y);
+    while b != 0 {
+        (a, b) = (b, a % b);
+    }
+    let mut normalized = [0; 9];
+    normalized[0..4].copy_from_slice(&(x / a).to_be_bytes());
+    normalized[4..8].copy_from_slice(&(y / a).to_be_bytes());
+    Some(normalized)
+}
+
+/// Reduce the significant bits declared by the sBIT chunk of an indexed image for each channel
+/// that has the same value in every palette entry
+///
+/// A constant value only needs as many bits as it takes to reproduce it by bit replication.
+/// Returns `None` if the chunk is invalid or unchanged.
+pub fn reduced_palette_sbit(data: &[u8], palette: &[RGBA8]) -> Option<Vec<u8>> {
+    if data.len() != 3 || data.iter().any(|&bits| bits == 0 || bits > 8) || palette.is_empty() {
+        return None;
+    }
+    let replicate = |value: u8, bits: u8| {
+        let top = u16::from(value >> (8 - bits));
+        let mut out = 0;
+        let mut filled = 0;
+        while filled < 8 {
+            out = (out << bits) | top;
+            filled += bits;
+        }
+        (out >> (filled - 8)) as u8
+    };
+    let channels: [fn(&RGBA8) -> u8; 3] = [|c| c.r, |c| c.g, |c| c.b];
+    let mut reduced = data.to_vec();
+    for (bits, channel) in reduced.iter_mut().zip(channels) {
+        let value = channel(&palette[0]);
+        if palette.iter().all(|c| channel(c) == value) {
+            let needed = (1..=8).find(|&n| replicate(value, n) == value).unwrap();
+            *bits = (*bits).min(needed);
+        }
+    }
+    (reduced != data).then_some(reduced)
+}
+
+/// Convert the data of a bKGD chunk to a new color type and bit depth
+///
+/// Returns `None` if the chunk is invalid or the color cannot be represented exactly.
+pub fn convert_bkgd(data: &[u8], from: &IhdrData, to: &IhdrData) -> Option<Vec<u8>> {
+    let sample = |i: usize| u32::from(u16::from_be_bytes([data[i * 2], data[i * 2 + 1]]));
+    // Get the color and its bit depth, looking it up in the palette if necessary
+    let (color, depth) = match &from.color_type {
+        ColorType::Indexed { palette } if data.len() == 1 => {
+            let c = palette.get(data[0] as usize)?;
+            ([c.r, c.g, c.b].map(u32::from), 8)
+        }
+        color_type if color_type.is_gray() && data.len() == 2 => {
+            let v = sample(0);
+            ([v, v, v], from.bit_depth as u32)
+        }
+        color_type if color_type.is_rgb() && data.len() == 6 => {
+            ([sample(0), sample(1), sample(2)], from.bit_depth as u32)
+        }
+        _ => return None,
+    };
+    // Palette colors are always 8-bit, regardless of the bit depth of the image
+    let to_depth = match &to.color_type {
+        ColorType::Indexed { .. } => 8,
+        _ => to.bit_depth as u32,
+    };
+    let from_max = (1 << depth) - 1;
+    let to_max = (1 << to_depth) - 1;
+    let mut scaled = [0; 3];
+    for (s, v) in scaled.iter_mut().zip(color) {
+        *s = v * to_max / from_max;
+        if *s * from_max != v * to_max {
+            return None;
+        }
+    }
+    match &to.color_type {
+        ColorType::Indexed { palette } => {
+            // Prefer an opaque entry, as the background should be a solid color
+            let is_match = |c: &RGBA8| [c.r, c.g, c.b].map(u32::from) == scaled;
+            let index = palette
+                .iter()
+                .position(|c| c.a == 255 && is_match(c))
+                .or_else(|| palette.iter().position(is_match))?;
+            Some(vec![index as u8])
+        }
+        color_type if color_type.is_gray() => (scaled[0] == scaled[1] && scaled[1] == scaled[2])
+            .then(|| (scaled[0] as u16).to_be_bytes().to_vec()),
+        _ => Some(
+            scaled
+                .iter()
+                .flat_map(|&s| (s as u16).to_be_bytes())
+                .collect(),
+        ),
+    }
+}
+
+/// Recompress the ICC profile of an iCCP chunk, returning the new chunk if it is smaller
+///
+/// The profile name and compression method are kept as they are.
+pub fn recompress_iccp(iccp: &Chunk, icc: &[u8], deflater: Deflaters) -> Option<Chunk> {
+    let header_len = iccp.data.iter().position(|&b| b == 0)? + 2;
+    let compressed_len = iccp.data.len().checked_sub(header_len)?;
+    let max_size = AtomicMin::new(compressed_len.checked_sub(1));
+    let mut compressed = deflater.deflate(icc, &max_size).ok()?;
+    let mut data = Vec::with_capacity(header_len + compressed.len());
+    data.extend_from_slice(&iccp.data[..header_len]);
+    data.append(&mut compressed);
+    Some(Chunk {
+        name: *b"iCCP",
+        data,
+    })
+}
+
+/// Recompress the text of a zTXt or compressed iTXt chunk, returning the new chunk if it is smaller
+///
+/// Only the compressed text is replaced; the keyword and any other fields are kept as they are.
+pub fn recompress_text(chunk: &Chunk, deflater: Deflaters) -> Option<Chunk> {
+    let keyword_end = chunk.data.iter().position(|&b| b == 0)?;
+    let header_len = match &chunk.name {
+        b"zTXt" => {
+            // Compression method
+            if *chunk.data.get(keyword_end + 1)? != 0 {
+                return None;
+            }
+            keyword_end + 2
+        }
+        b"iTXt" => {
+            // Compression flag and compression method
+            if chunk.data.get(keyword_end + 1..keyword_end + 3)? != [1, 0] {
+                return None;
+            }
+            // Language tag and translated keyword
+            let mut pos = keyword_end + 3;
+            for _ in 0..2 {
+                pos += chunk.data.get(pos..)?.iter().position(|&b| b == 0)? + 1;
+            }
+            pos
+        }
+        _ => return None,
+    };
+    let compressed_data = &chunk.data[header_len..];
+    let text = inflate_text(&chunk.name, compressed_data)?;
+
+    let max_size = AtomicMin::new(Some(compressed_data.len() - 1));
+    let mut compressed = deflater.deflate(&text, &max_size).ok()?;
+    let mut data = Vec::with_capacity(header_len + compressed.len());
+    data.extend_from_slice(&chunk.data[..header_len]);
+    data.append(&mut compressed);
+    Some(Chunk {
+        name: chunk.name,
+        data,
+    })
+}
+
+/// Convert a tEXt chunk to zTXt or vice versa, returning the new chunk if it is smaller
+pub fn convert_text(chunk: &Chunk, deflater: Deflaters) -> Option<Chunk> {
+    let keyword_end = chunk.data.iter().position(|&b| b == 0)?;
+    let (name, mut data) = match &chunk.name {
+        b"tEXt" => {
+            let text = &chunk.data[keyword_end + 1..];
+            // The compressed chunk also needs the compression method byte
+            let max_size = AtomicMin::new(text.len().checked_sub(2));
+            let mut compressed = deflater.deflate(text, &max_size).ok()?;
+            let mut data = Vec::with_capacity(keyword_end + 2 + compressed.len());
+            data.extend_from_slice(&chunk.data[..=keyword_end]);
+            data.push(0); // Compression method
+            data.append(&mut compressed);
+            (*b"zTXt", data)
+        }
+        b"zTXt" => {
+            if *chunk.data.get(keyword_end + 1)? != 0 {
+                return None;
+            }
+            let mut text = inflate_text(&chunk.name, &chunk.data[keyword_end + 2..])?;
+            let mut data = Vec::with_capacity(keyword_end + 1 + text.len());
+            data.extend_from_slice(&chunk.data[..=keyword_end]);
+            data.append(&mut text);
+            (*b"tEXt", data)
+        }
+        _ => return None,
+    };
+    if data.len() >= chunk.data.len() {
+        return None;
+    }
+    data.shrink_to_fit();
+    Some(Chunk { name, data })
+}
+
+/// Decompress the text of a zTXt or iTXt chunk, logging any failure
+fn inflate_text(name: &[u8; 4], compressed_data: &[u8]) -> Option<Vec<u8>> {
+    // The decompressed size is unknown so keep growing the buffer until it fits
+    let mut max_size = compressed_data.len() * 4 + 1000;
+    loop {
+        match inflate(compressed_data, max_size) {
+            Ok(text) => return Some(text),
+            Err(PngError::Other(_)) if max_size < MAX_TEXT_SIZE => max_size *= 4,
+            Err(e) => {
+                warn!(
+                    "Failed to decompress {} chunk: {}",
+                    String::from_utf8_lossy(name),
+                    e
+                );
+                return None;
+            }
+        }
+    }
+}
+
 /// If the profile is sRGB, extracts the rendering intent value from it
 pub fn srgb_rendering_intent(icc_data: &[u8]) -> Option<u8> {
     let rendering_intent = *icc_data.get(67)?;
@@ -1,8 +1,15 @@
-use std::{fmt, fmt::Display};
+use std::{fmt, fmt::Display, ops::Range};
 
 use bitvec::prelude::*;
+use indexmap::IndexSet;
+use rayon::prelude::*;
 
-use crate::{headers::IhdrData, png::PngImage, PngError};
+#[cfg(not(feature = "parallel"))]
+use crate::rayon;
+use crate::{
+    atomicmin::AtomicMin, deflate::deflate, filters::RowFilter, headers::IhdrData, png::PngImage,
+    PngError,
+};
 
 #[repr(u8)]
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
@@ -11,6 +18,18 @@ pub enum Interlacing {
     Adam7,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// Ways to choose the interlacing of the output automatically
+pub enum AutoInterlacing {
+    /// Try both with and without interlacing, keeping whichever is smaller
+    Trial,
+    /// Predict from the image content whether interlacing is likely to be smaller, and only try
+    /// that one
+    ///
+    /// This is much faster than `Trial` but may not always pick the smaller option.
+    Heuristic,
+}
+
 impl TryFrom<u8> for Interlacing {
     type Error = PngError;
 
@@ -118,10 +137,8 @@ fn deinterlace_bits(png: &PngImage) -> Vec<u8> {
     let mut current_y: usize = pass_constants.y_shift as usize;
     for line in png.scan_lines(false) {
         let bit_vec = line.data.view_bits::<Msb0>();
-        let bits_in_line = ((png.ihdr.width - u32::from(pass_constants.x_shift)
-            + u32::from(pass_constants.x_step)
-            - 1)
-            / u32::from(pass_constants.x_step)) as usize
+        let bits_in_line = (png.ihdr.width - u32::from(pass_constants.x_shift))
+            .div_ceil(u32::from(pass_constants.x_step)) as usize
             * bits_per_pixel;
         for (i, bit) in bit_vec.iter().by_vals().enumerate() {
             // Avoid moving padded 0's into new image
@@ -184,6 +201,106 @@ fn deinterlace_bytes(png: &PngImage) -> Vec<u8> {
     lines.concat()
 }
 
+/// Predict whether the image is likely to compress better with or without interlacing
+///
+/// Interlacing places pixels that are 2, 4 or 8 apart next to each other in the passes, so it
+/// tends to help only when pixels are more similar to those 2 apart than to their direct
+/// neighbors, such as in dithered or finely patterned graphics. This compares how often pixels
+/// match at both distances, horizontally and vertically, which is far cheaper than a trial.
+pub fn predict_interlacing(png: &PngImage) -> Interlacing {
+    let progressive;
+    let png = if png.ihdr.interlaced == Interlacing::None {
+        png
+    } else {
+        progressive = deinterlace_image(png);
+        &progressive
+    };
+    let bits = png.ihdr.bpp();
+    let width = png.ihdr.width as usize;
+    // Read a pixel as a single value, which fits in 64 bits for every color type
+    let pixel = |line: &[u8], x: usize| -> u64 {
+        if bits < 8 {
+            let bit = x * bits;
+            let shift = 8 - bits - bit % 8;
+            u64::from((line[bit / 8] >> shift) & ((1 << bits) - 1))
+        } else {
+            let bytes = bits / 8;
+            line[x * bytes..(x + 1) * bytes]
+                .iter()
+                .fold(0, |v, &b| (v << 8) | u64::from(b))
+        }
+    };
+
+    let lines: Vec<_> = png.scan_lines(false).map(|line| line.data).collect();
+    let (mut near, mut far) = (0usize, 0usize);
+    for (y, line) in lines.iter().enumerate() {
+        for x in 0..width {
+            let current = pixel(line, x);
+            near += usize::from(x >= 1 && pixel(line, x - 1) == current);
+            far += usize::from(x >= 2 && pixel(line, x - 2) == current);
+            near += usize::from(y >= 1 && pixel(lines[y - 1], x) == current);
+            far += usize::from(y >= 2 && pixel(lines[y - 2], x) == current);
+        }
+    }
+    // Require a clear margin, as interlacing usually adds to the size when the two are close
+    if far * 10 > near * 11 {
+        Interlacing::Adam7
+    } else {
+        Interlacing::None
+    }
+}
+
+/// Filter each pass of an interlaced image separately, keeping whichever of the given filters
+/// compresses best at the given libdeflate level for each pass
+///
+/// The first row of each pass has no previous row to predict from, so the filtering of one pass
+/// never depends on another and passes filtered differently can be joined together.
+/// Returns the filtered data along with the filter chosen for each pass.
+pub fn filter_passes(
+    png: &PngImage,
+    filters: &IndexSet<RowFilter>,
+    optimize_alpha: bool,
+    compression: u8,
+) -> (Vec<u8>, Vec<RowFilter>) {
+    let candidates: Vec<(RowFilter, Vec<u8>)> = filters
+        .par_iter()
+        .with_max_len(1)
+        .map(|&filter| (filter, png.filter_image(filter, optimize_alpha)))
+        .collect();
+
+    // Find the range of each pass in the filtered data, where each row has an extra filter byte
+    let mut ranges: Vec<Range<usize>> = Vec::new();
+    let mut prev_pass = None;
+    let mut pos = 0;
+    for line in png.scan_lines(false) {
+        let len = line.data.len() + 1;
+        match ranges.last_mut() {
+            Some(range) if prev_pass == line.pass => range.end += len,
+            _ => ranges.push(pos..pos + len),
+        }
+        pos += len;
+        prev_pass = line.pass;
+    }
+
+    let chosen: Vec<&(RowFilter, Vec<u8>)> = ranges
+        .par_iter()
+        .map(|range| {
+            candidates
+                .iter()
+                .min_by_key(|(_, data)| {
+                    deflate(&data[range.clone()], compression, &AtomicMin::new(None))
+                        .map_or(usize::MAX, |d| d.len())
+                })
+                .unwrap()
+        })
+        .collect();
+    let mut filtered = Vec::with_capacity(pos);
+    for (range, (_, data)) in ranges.iter().zip(&chosen) {
+        filtered.extend_from_slice(&data[range.clone()]);
+    }
+    (filtered, chosen.iter().map(|(filter, _)| *filter).collect())
+}
+
 fn increment_pass(current_pass: &mut u8, ihdr: &IhdrData) -> bool {
     if *current_pass == 7 {
         return false;
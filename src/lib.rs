@@ -27,38 +27,54 @@ mod rayon;
 
 use std::{
     borrow::Cow,
-    fs::{File, Metadata},
-    io::{stdin, stdout, BufWriter, Read, Write},
-    path::Path,
+    fs::{File, Metadata, OpenOptions},
+    io::{stdin, stdout, BufWriter, ErrorKind, Read, Write},
+    iter::once,
+    path::{Path, PathBuf},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
-    time::{Duration, Instant},
+    time::{Duration, Instant, SystemTime},
 };
 
 pub use indexmap::{indexset, IndexSet};
 use log::{debug, info, trace, warn};
 use rayon::prelude::*;
-pub use rgb::{RGB16, RGBA8};
+pub use rgb::{RGB16, RGB8, RGBA16, RGBA8};
+use rustc_hash::FxHashSet;
 
 use crate::{
     atomicmin::AtomicMin,
-    evaluate::Evaluator,
+    evaluate::{Candidate, Evaluator},
     headers::*,
-    png::{PngData, PngImage},
+    interlace::{filter_passes, predict_interlacing},
+    png::{is_position_marker, verify_output, PngData, PngImage, BRUTE_DEFLATER},
     reduction::*,
 };
 pub use crate::{
     colors::{BitDepth, ColorType},
-    deflate::Deflaters,
+    deflate::{Deflaters, LIBDEFLATER_MAX_LEVEL, LIBDEFLATER_MIN_LEVEL},
     error::PngError,
     filters::RowFilter,
-    headers::StripChunks,
-    interlace::Interlacing,
-    options::{InFile, Options, OutFile},
+    headers::{
+        has_optimized_marker, is_critical, Chunk, CicpData, IhdrData, ModificationTime, PngInfo,
+        StereoLayout, StripChunks, SuggestedPalette, SuggestedPaletteEntry, MARKER_CHUNK,
+    },
+    interlace::{AutoInterlacing, Interlacing},
+    options::{
+        CandidateSelection, ChunkFilter, InFile, Options, OutFile, Progress, ProgressCallback,
+    },
+    png::{DecodedPng, RecoveredData},
+    reduction::quantize::Dithering,
+    reduction::{
+        palette::{PalettePin, PaletteReductionStats, PaletteSort, PaletteSortWeights},
+        Rejection,
+    },
+    report::{OptimizationOutcome, OptimizationReport, StrategyTrial},
 };
 
+mod apng;
 mod atomicmin;
 mod colors;
 mod deflate;
@@ -71,6 +87,7 @@ mod interlace;
 mod options;
 mod png;
 mod reduction;
+mod report;
 #[cfg(feature = "sanity-checks")]
 mod sanity_checks;
 
@@ -79,7 +96,18 @@ mod sanity_checks;
 pub mod internal_tests {
     #[cfg(feature = "sanity-checks")]
     pub use crate::sanity_checks::*;
-    pub use crate::{atomicmin::*, deflate::*, png::*, reduction::*};
+    pub use crate::{
+        atomicmin::*,
+        deflate::*,
+        filters::{
+            average_filter, average_filter_scalar, paeth_filter, paeth_filter_scalar, sum_abs,
+            sum_abs_scalar,
+        },
+        headers::{parse_splt_chunk, IhdrData},
+        interlace::predict_interlacing,
+        png::*,
+        reduction::*,
+    };
 }
 
 pub type PngResult<T> = Result<T, PngError>;
@@ -115,10 +143,14 @@ impl RawImage {
         if !valid_depth {
             return Err(PngError::InvalidDepthForType(bit_depth, color_type));
         }
+        // Pixel indices can't exceed 255, so the palette reductions assume at most 256 entries
+        if matches!(&color_type, ColorType::Indexed { palette } if palette.len() > 256) {
+            return Err(PngError::new("Palette must have no more than 256 entries"));
+        }
 
         // Validate data length
         let bpp = bit_depth as usize * color_type.channels_per_pixel() as usize;
-        let row_bytes = (bpp * width as usize + 7) / 8;
+        let row_bytes = (bpp * width as usize).div_ceil(8);
         let expected_len = row_bytes * height as usize;
         if data.len() != expected_len {
             return Err(PngError::IncorrectDataLength(data.len(), expected_len));
@@ -148,27 +180,154 @@ impl RawImage {
     pub fn add_icc_profile(&mut self, data: &[u8]) {
         // Compress with fastest compression level - will be recompressed during optimization
         let deflater = Deflaters::Libdeflater { compression: 1 };
-        if let Ok(iccp) = construct_iccp(data, deflater) {
+        if let Ok(iccp) = construct_iccp(b"icc", data, deflater) {
             self.aux_chunks.push(iccp);
         }
     }
 
+    /// Set the ICC profile for the image with the given profile name, replacing any existing one
+    ///
+    /// The name must be 1-79 Latin-1 characters with no control characters. The profile is
+    /// recompressed with the chosen deflater during optimization. If an sRGB chunk is also
+    /// added, the usual rules for conflicting iCCP and sRGB chunks apply.
+    pub fn set_icc_profile(&mut self, name: &str, data: &[u8]) -> PngResult<()> {
+        let name = keyword_bytes(name).ok_or_else(|| {
+            PngError::new("ICC profile name must be 1-79 characters with no control characters")
+        })?;
+        let deflater = Deflaters::Libdeflater { compression: 1 };
+        let iccp = construct_iccp(&name, data, deflater)?;
+        self.aux_chunks.retain(|c| &c.name != b"iCCP");
+        self.aux_chunks.push(iccp);
+        Ok(())
+    }
+
+    /// Filter the raw image data without compressing it, returning the filtered scan lines
+    ///
+    /// Each row is prepended with its filter type, exactly as it would be passed to the deflater.
+    /// If `filter` is `None`, each filter in `opts.filter` is evaluated with a fast compression
+    /// and the data from the one that compresses smallest is returned. No reductions are
+    /// performed.
+    pub fn filter_image(&self, filter: Option<RowFilter>, opts: &Options) -> PngResult<Vec<u8>> {
+        if let Some(filter) = filter {
+            return Ok(self.png.filter_image(filter, opts.optimize_alpha));
+        }
+        with_thread_limit(opts, || {
+            let deadline = Arc::new(Deadline::new(opts));
+            let eval = Evaluator::new(
+                deadline,
+                opts.filter.clone(),
+                eval_compression(opts),
+                opts.optimize_alpha,
+                CandidateSelection::Smallest,
+            );
+            eval.try_image(self.png.clone());
+            eval.get_best_candidate()
+                .map(|candidate| candidate.filtered)
+                .ok_or_else(|| PngError::new("Failed to evaluate filters"))
+        })
+    }
+
+    /// Filter and compress the raw image data, returning the zlib stream that would form the
+    /// IDAT payload, without any chunk framing
+    ///
+    /// The filters and deflater are chosen as in `create_optimized_png`, but no reductions are
+    /// performed, so the data always matches the dimensions, color type and bit depth the image
+    /// was created with. The data is not interlaced.
+    pub fn compress_image_data(&self, opts: &Options) -> PngResult<Vec<u8>> {
+        let opts = Options {
+            interlace: None,
+            auto_interlace: None,
+            bit_depth_reduction: false,
+            color_type_reduction: false,
+            palette_reduction: false,
+            grayscale_reduction: false,
+            force_color_type: None,
+            alpha_levels: None,
+            palette_pin: None,
+            idat_recoding: true,
+            ..opts.clone()
+        };
+        opts.validate()?;
+        with_thread_limit(&opts, || {
+            let deadline = Arc::new(Deadline::new(&opts));
+            let png = optimize_raw(self.png.clone(), &opts, deadline.clone(), None, &[], None);
+            if deadline.cancelled() {
+                return Err(PngError::Cancelled);
+            }
+            let (png, ..) = png.ok_or_else(|| PngError::new("Failed to compress image data"))?;
+            Ok(png.idat_data)
+        })
+    }
+
     /// Create an optimized png from the raw image data using the options provided
     pub fn create_optimized_png(&self, opts: &Options) -> PngResult<Vec<u8>> {
-        let deadline = Arc::new(Deadline::new(opts.timeout));
-        let mut png = optimize_raw(self.png.clone(), opts, deadline.clone(), None)
-            .ok_or_else(|| PngError::new("Failed to optimize input data"))?;
+        let png = self.optimize(opts)?;
+        let output = png.output(opts);
+        if opts.verify {
+            if opts.is_lossy() {
+                verify_output(&output, &png.raw, None, opts)?;
+            } else {
+                let sbit = self.aux_chunks.iter().find(|c| &c.name == b"sBIT");
+                verify_output(&output, &self.png, sbit.map(|c| &c.data[..]), opts)?;
+            }
+        }
+        Ok(output)
+    }
 
-        // Process aux chunks
-        png.aux_chunks = self
-            .aux_chunks
-            .iter()
-            .filter(|c| opts.strip.keep(&c.name))
-            .cloned()
-            .collect();
-        postprocess_chunks(&mut png, opts, deadline, &self.png.ihdr);
+    /// Calculate the exact size of the png that `create_optimized_png` would produce
+    ///
+    /// The full optimization is performed, including filtering and compression, but the output
+    /// is not serialized. The size includes the PNG signature, all chunk overhead and IEND.
+    pub fn serialized_size(&self, opts: &Options) -> PngResult<usize> {
+        Ok(self.optimize(opts)?.output_size(opts))
+    }
 
-        Ok(png.output())
+    /// Optimize the raw image data, returning the `PngData` ready to be serialized
+    fn optimize(&self, opts: &Options) -> PngResult<PngData> {
+        opts.validate()?;
+        with_thread_limit(opts, || {
+            let deadline = Arc::new(Deadline::new(opts));
+            let png = optimize_raw(
+                self.png.clone(),
+                opts,
+                deadline.clone(),
+                None,
+                &self.aux_chunks,
+                None,
+            );
+            if deadline.cancelled() {
+                return Err(PngError::Cancelled);
+            }
+            let (mut png, ..) =
+                png.ok_or_else(|| PngError::new("Failed to optimize input data"))?;
+
+            // Process aux chunks
+            png.aux_chunks = self
+                .aux_chunks
+                .iter()
+                .filter(|c| opts.keep_chunk(&c.name, &c.data))
+                .cloned()
+                .collect();
+            postprocess_chunks(&mut png, opts, deadline, &self.png.ihdr);
+
+            Ok(png)
+        })
+    }
+}
+
+/// The libdeflate compression level used to evaluate reductions and filters
+fn eval_compression(opts: &Options) -> u8 {
+    // Libdeflate has four algorithms: 1-4 = 'greedy', 5-7 = 'lazy', 8-9 = 'lazy2', 10-12 = 'near-optimal'
+    // 5 is the minimumm required for a decent evaluation result
+    // 7 is not noticeably slower than 5 and improves evaluation of filters in 'fast' mode (o2 and lower)
+    // 8 is a little slower but not noticeably when used only for reductions (o3 and higher)
+    // 9 is not appreciably better than 8
+    // 10 and higher are quite slow - good for filters but only good for reductions if matching the main zc level
+    match opts.deflate {
+        Deflaters::Libdeflater { compression } => {
+            if opts.fast_evaluation { 7 } else { 8 }.min(compression)
+        }
+        _ => 8,
     }
 }
 
@@ -177,7 +336,7 @@ pub fn optimize(input: &InFile, output: &OutFile, opts: &Options) -> PngResult<(
     // Read in the file and try to decode as PNG.
     info!("Processing: {}", input);
 
-    let deadline = Arc::new(Deadline::new(opts.timeout));
+    let deadline = Arc::new(Deadline::new(opts));
 
     // grab metadata before even opening input file to preserve atime
     let opt_metadata_preserved;
@@ -216,14 +375,38 @@ pub fn optimize(input: &InFile, output: &OutFile, opts: &Options) -> PngResult<(
         }
     };
 
-    let mut png = PngData::from_slice(&in_data, opts)?;
-
-    // Run the optimizer on the decoded PNG.
-    let mut optimized_output = optimize_png(&mut png, &in_data, opts, deadline)?;
+    let marked = opts.mark_optimized && has_optimized_marker(&in_data);
+    let mut optimized_output = if marked {
+        info!("{}: Already marked as optimized", input);
+        in_data.clone()
+    } else {
+        let mut png = PngData::from_slice(&in_data, opts)?;
+
+        // Run the optimizer on the decoded PNG.
+        match with_thread_limit(opts, || {
+            optimize_png(&mut png, in_data.len(), opts, deadline)
+        }) {
+            Ok((optimized_output, _)) => {
+                #[cfg(feature = "sanity-checks")]
+                assert!(
+                    opts.is_lossy() || sanity_checks::validate_output(&optimized_output, &in_data)
+                );
+                optimized_output
+            }
+            Err(PngError::VerificationFailed) => {
+                warn!(
+                    "{}: Output verification failed, keeping the original",
+                    input
+                );
+                in_data.clone()
+            }
+            Err(e) => return Err(e),
+        }
+    };
 
     let in_length = in_data.len();
 
-    if is_fully_optimized(in_data.len(), optimized_output.len(), opts) {
+    if marked || is_fully_optimized(in_data.len(), optimized_output.len(), opts) {
         match (output, input) {
             // if p is None, it also means same as the input path
             (OutFile::Path { path, .. }, InFile::Path(ref input_path))
@@ -267,110 +450,589 @@ pub fn optimize(input: &InFile, output: &OutFile, opts: &Options) -> PngResult<(
                 .as_ref()
                 .map(|p| p.as_path())
                 .unwrap_or_else(|| input.path().unwrap());
-            let out_file = File::create(output_path).map_err(|err| {
-                PngError::new(&format!(
-                    "Unable to write to file {}: {}",
-                    output_path.display(),
-                    err
-                ))
-            })?;
-            if let Some(metadata_input) = &opt_metadata_preserved {
-                copy_permissions(metadata_input, &out_file)?;
-            }
-
-            let mut buffer = BufWriter::new(out_file);
-            buffer
-                .write_all(&optimized_output)
-                // flush BufWriter so IO errors don't get swallowed silently on close() by drop!
-                .and_then(|()| buffer.flush())
-                .map_err(|e| {
+            write_file_atomically(
+                output_path,
+                &optimized_output,
+                opt_metadata_preserved.as_ref(),
+            )?;
+            info!("{}: {}", savings, output_path.display());
+        }
+    }
+    Ok(())
+}
+
+/// The result of optimizing one file in a batch, along with the path of the file
+pub type BatchResult = Result<(PathBuf, OptimizationReport), (PathBuf, PngError)>;
+
+/// A file found for a batch, with its output path relative to the output directory
+type CollectedFile = Result<(PathBuf, PathBuf), (PathBuf, PngError)>;
+
+/// A file to optimize in a batch, with the path to write its output to if it differs from the input
+type BatchFile = Result<(PathBuf, Option<PathBuf>), (PathBuf, PngError)>;
+
+/// Perform optimization on each of the given files, or the PNG files within the given directories
+///
+/// Directories are searched recursively for files with a `png` or `apng` extension. Each file is
+/// optimized in place, or written to `out_dir` if provided. Files found in a directory keep their
+/// path relative to that directory within `out_dir`, and other files keep only their file name.
+/// Files that would be written to the same output path as an earlier file fail with an error.
+/// Files are processed in parallel within the thread limit of the options, and a failure affects
+/// only the file it occurred on. A file that could not be optimized further is left unchanged
+/// unless `force` is set.
+///
+/// The reports don't hold the output, so their `outcome` is always `None`. If `dry_run` is set,
+/// no files are written. If `batch_memory_limit` is set, the files are processed in groups whose
+/// estimated memory fits within the limit, one group at a time.
+pub fn optimize_files(
+    inputs: &[PathBuf],
+    out_dir: Option<&Path>,
+    opts: &Options,
+) -> Vec<BatchResult> {
+    let mut files = Vec::new();
+    collect_png_files(inputs, None, &mut files);
+    let files = batch_output_paths(files, out_dir);
+    // The whole batch shares a single thread pool
+    let file_opts = Options {
+        threads: None,
+        ..opts.clone()
+    };
+    let optimize_group = |group: Vec<BatchFile>| -> Vec<BatchResult> {
+        group
+            .into_par_iter()
+            .map(|file| {
+                let (path, output_path) = file?;
+                match optimize_batch_file(&path, output_path.as_deref(), &file_opts) {
+                    Ok(report) => Ok((path, report)),
+                    Err(e) => Err((path, e)),
+                }
+            })
+            .collect()
+    };
+    with_thread_limit(opts, || match opts.batch_memory_limit {
+        Some(limit) => group_by_memory(files, limit)
+            .into_iter()
+            .flat_map(optimize_group)
+            .collect(),
+        None => optimize_group(files),
+    })
+}
+
+/// Resolve the output path of each file within the output directory, rejecting any file whose
+/// output path was already taken by an earlier file
+fn batch_output_paths(files: Vec<CollectedFile>, out_dir: Option<&Path>) -> Vec<BatchFile> {
+    let mut seen = FxHashSet::default();
+    files
+        .into_iter()
+        .map(|file| {
+            let (path, relative) = file?;
+            let Some(dir) = out_dir else {
+                return Ok((path, None));
+            };
+            let output_path = dir.join(relative);
+            if !seen.insert(output_path.clone()) {
+                let err = PngError::new(&format!(
+                    "Output path {} is the same as for another file",
+                    output_path.display()
+                ));
+                return Err((path, err));
+            }
+            Ok((path, Some(output_path)))
+        })
+        .collect()
+}
+
+/// Split the files into consecutive groups whose total estimated memory fits within the limit
+///
+/// A file that exceeds the limit on its own is placed in a group by itself, so that it is
+/// processed alone.
+fn group_by_memory(files: Vec<BatchFile>, limit: usize) -> Vec<Vec<BatchFile>> {
+    let mut groups = Vec::new();
+    let mut group = Vec::new();
+    let mut group_size: usize = 0;
+    for file in files {
+        let size = match &file {
+            Ok((path, _)) => estimate_image_memory(path).unwrap_or(0),
+            Err(_) => 0,
+        };
+        if !group.is_empty() && group_size.saturating_add(size) > limit {
+            groups.push(std::mem::take(&mut group));
+            group_size = 0;
+        }
+        group.push(file);
+        group_size = group_size.saturating_add(size);
+    }
+    if !group.is_empty() {
+        groups.push(group);
+    }
+    groups
+}
+
+/// Estimate the size of the raw image data of a PNG file from its IHDR, without reading the rest
+/// of the file
+fn estimate_image_memory(path: &Path) -> Option<usize> {
+    // Signature, chunk length and name, width, height, bit depth and color type
+    let mut header = [0; 26];
+    File::open(path).ok()?.read_exact(&mut header).ok()?;
+    if !file_header_is_valid(&header[0..8]) || &header[12..16] != b"IHDR" {
+        return None;
+    }
+    let width = read_be_u32(&header[16..20]) as usize;
+    let height = read_be_u32(&header[20..24]) as usize;
+    let bytes_per_channel = if header[24] == 16 { 2 } else { 1 };
+    let channels = match header[25] {
+        2 => 3,
+        4 => 2,
+        6 => 4,
+        _ => 1,
+    };
+    Some(
+        width
+            .saturating_mul(height)
+            .saturating_mul(channels * bytes_per_channel),
+    )
+}
+
+/// Collect the paths of files to optimize, searching any directories for PNG files
+///
+/// Each file is paired with its path relative to the input directory it was found in, or just its
+/// file name if it was given directly.
+fn collect_png_files(inputs: &[PathBuf], root: Option<&Path>, files: &mut Vec<CollectedFile>) {
+    for input in inputs {
+        if input.is_dir() {
+            match input.read_dir() {
+                Ok(dir) => {
+                    let entries: Vec<_> = dir.filter_map(|x| x.ok().map(|x| x.path())).collect();
+                    collect_png_files(&entries, Some(root.unwrap_or(input)), files);
+                }
+                Err(e) => files.push(Err((
+                    input.clone(),
+                    PngError::new(&format!("Unable to read directory: {}", e)),
+                ))),
+            }
+        } else if root.is_none()
+            || input.extension().is_some_and(|ext| {
+                ext.eq_ignore_ascii_case("png") || ext.eq_ignore_ascii_case("apng")
+            })
+        {
+            let relative = match root.and_then(|root| input.strip_prefix(root).ok()) {
+                Some(relative) => relative.to_path_buf(),
+                None => PathBuf::from(input.file_name().unwrap_or_default()),
+            };
+            files.push(Ok((input.clone(), relative)));
+        }
+    }
+}
+
+/// Optimize a single file of a batch, writing it to `output_path` if given, or otherwise in place
+/// only if the output has changed
+fn optimize_batch_file(
+    path: &Path,
+    output_path: Option<&Path>,
+    opts: &Options,
+) -> PngResult<OptimizationReport> {
+    info!("Processing: {}", path.display());
+    let in_data = PngData::read_file(path)?;
+    let mut report = optimize_from_memory_with_report(&in_data, opts)?;
+    // Only the report is kept, so the output isn't held for the rest of the batch
+    let Some(output) = report.outcome.take().map(OptimizationOutcome::into_output) else {
+        return Ok(report);
+    };
+    let output_path = match output_path {
+        Some(output_path) => {
+            if let Some(parent) = output_path.parent() {
+                std::fs::create_dir_all(parent).map_err(|e| {
                     PngError::new(&format!(
-                        "Unable to write to {}: {}",
-                        output_path.display(),
+                        "Unable to create directory {}: {}",
+                        parent.display(),
                         e
                     ))
                 })?;
-            // force drop and thereby closing of file handle before modifying any timestamp
-            std::mem::drop(buffer);
-            if let Some(metadata_input) = &opt_metadata_preserved {
-                copy_times(metadata_input, output_path)?;
             }
-            info!("{}: {}", savings, output_path.display());
+            output_path
         }
-    }
-    Ok(())
+        None if output == in_data => return Ok(report),
+        None => path,
+    };
+    write_file_atomically(output_path, &output, None)?;
+    info!("{}: {} bytes", output_path.display(), output.len());
+    Ok(report)
 }
 
 /// Perform optimization on the input file using the options provided, where the file is already
 /// loaded in-memory
 pub fn optimize_from_memory(data: &[u8], opts: &Options) -> PngResult<Vec<u8>> {
+    optimize_from_memory_inner(data, opts).map(|result| result.output)
+}
+
+/// Perform optimization on the input file using the options provided, where the file is already
+/// loaded in-memory, returning a report of the changes made
+///
+/// The output is held in the `outcome` of the report. If the file could not be improved, this is
+/// the input bytes untouched, or the re-serialized output if `force` is set. If `dry_run` is set,
+/// the output is not serialized and there is no outcome, but the `output_size` of the report is
+/// still the exact size the output would have.
+pub fn optimize_from_memory_with_report(
+    data: &[u8],
+    opts: &Options,
+) -> PngResult<OptimizationReport> {
+    if opts.dry_run {
+        return estimate_report(data, opts);
+    }
+    let result = optimize_from_memory_inner(data, opts)?;
+    let report = OptimizationReport::new(
+        data,
+        &result.output,
+        &result.original,
+        result.optimized.as_deref().unwrap_or(&result.original),
+        result.trial,
+        result.pass_filters,
+        result.recovered,
+    );
+    Ok(OptimizationReport {
+        palette_stats: result.palette_stats,
+        outcome: Some(if result.output.len() < data.len() {
+            OptimizationOutcome::Improved(result.output)
+        } else {
+            OptimizationOutcome::Unchanged(result.output)
+        }),
+        ..report
+    })
+}
+
+/// Create the report of optimizing the input file from memory, without serializing the output
+fn estimate_report(data: &[u8], opts: &Options) -> PngResult<OptimizationReport> {
+    info!("Estimating savings from memory");
+
+    let deadline = Arc::new(Deadline::new(opts));
+
+    let mut png = PngData::from_slice(data, opts)?;
+    let original = png.raw.clone();
+    let trial = with_thread_limit(opts, || {
+        optimize_png_data(&mut png, data.len(), opts, deadline)
+    })?;
+    let report = OptimizationReport::estimated(data, &png, &original, trial, opts);
+    let palette_stats = png
+        .palette_stats
+        .filter(|_| !is_fully_optimized(data.len(), report.output_size, opts))
+        .and_then(|stats| stats.with_lengths(&original, &png.raw));
+    Ok(OptimizationReport {
+        palette_stats,
+        ..report
+    })
+}
+
+/// Compress the image with every filter strategy and each of the given deflaters, returning the
+/// size and duration of every trial, where the file is already loaded in-memory
+///
+/// This is intended for choosing options that suit a particular kind of image, so no output is
+/// produced. Reductions are performed once, and all trials share the reduced image, which is
+/// filtered once per strategy. The trials run in parallel and are returned in order of filter,
+/// then deflater. `opts.filter` and `opts.deflate` are ignored, but the other options apply as in
+/// a normal optimization, except that `AutoInterlacing::Trial` keeps the interlacing of the input.
+/// Trials stopped by the timeout are omitted.
+pub fn benchmark_strategies(
+    data: &[u8],
+    deflaters: &[Deflaters],
+    opts: &Options,
+) -> PngResult<Vec<StrategyTrial>> {
+    info!("Benchmarking strategies from memory");
+    opts.validate()?;
+    for deflater in deflaters {
+        deflater.validate()?;
+    }
+
+    let deadline = Arc::new(Deadline::new(opts));
+    let png = PngData::from_slice(data, opts)?;
+    let interlace = match opts.auto_interlace {
+        Some(AutoInterlacing::Heuristic) => Some(predict_interlacing(&png.raw)),
+        Some(AutoInterlacing::Trial) => None,
+        None => opts.interlace,
+    };
+    let opts = Options {
+        interlace,
+        auto_interlace: None,
+        ..opts.clone()
+    };
+    let eval_filters = indexset! {RowFilter::None, RowFilter::Bigrams};
+
+    with_thread_limit(&opts, || {
+        let (png, ..) = reduce_image(png.raw, &opts, &deadline, eval_filters, &png.aux_chunks);
+        if deadline.cancelled() {
+            return Err(PngError::Cancelled);
+        }
+
+        let filtered: Vec<_> = (0..=RowFilter::LAST)
+            .into_par_iter()
+            .map(|f| {
+                let filter = RowFilter::try_from(f).unwrap();
+                let start = Instant::now();
+                let filtered = png.filter_image(filter, opts.optimize_alpha);
+                (filter, filtered, start.elapsed())
+            })
+            .collect();
+        let trials: Vec<_> = filtered
+            .iter()
+            .flat_map(|filtered| deflaters.iter().map(move |&deflater| (filtered, deflater)))
+            .collect();
+        Ok(trials
+            .into_par_iter()
+            .with_max_len(1)
+            .filter_map(|((filter, filtered, filter_time), deflater)| {
+                if deadline.passed() {
+                    return None;
+                }
+                let split_points = split_points(
+                    &png,
+                    &Options {
+                        deflate: deflater,
+                        ..opts.clone()
+                    },
+                );
+                let start = Instant::now();
+                let idat_data = deflater
+                    .deflate_split(
+                        filtered,
+                        &split_points,
+                        opts.single_block_trial,
+                        &AtomicMin::new(None),
+                        &deadline,
+                    )
+                    .ok()?;
+                trace!(
+                    "    zc = {}  f = {:8}  {} bytes",
+                    deflater,
+                    filter,
+                    idat_data.len()
+                );
+                Some(StrategyTrial {
+                    filter: *filter,
+                    deflater,
+                    size: idat_data.len(),
+                    duration: *filter_time + start.elapsed(),
+                })
+            })
+            .collect())
+    })
+}
+
+/// Read the header and list the chunks of a PNG file, without optimizing or decoding the image
+///
+/// This is much faster than a full optimization and can be used to decide which files to
+/// optimize. Chunk CRCs are not checked.
+pub fn parse_header(data: &[u8]) -> PngResult<PngInfo> {
+    parse_png_info(data)
+}
+
+/// The result of optimizing a file from memory
+struct MemoryResult {
+    output: Vec<u8>,
+    original: Arc<PngImage>,
+    /// The optimized image, if the output differs from the input
+    optimized: Option<Arc<PngImage>>,
+    /// The filter and deflater used, if the image data was recompressed
+    trial: Option<Compression>,
+    /// How much of the input image data was recovered, if it was corrupt
+    recovered: Option<RecoveredData>,
+    /// Statistics of the palette reduction, if the output differs from the input
+    palette_stats: Option<PaletteReductionStats>,
+    /// The filter chosen for each interlacing pass, if they were chosen separately
+    pass_filters: Vec<RowFilter>,
+}
+
+impl MemoryResult {
+    /// The result when the input is kept as it is
+    fn unchanged(data: &[u8], original: Arc<PngImage>, recovered: Option<RecoveredData>) -> Self {
+        Self {
+            output: data.to_vec(),
+            original,
+            optimized: None,
+            trial: None,
+            recovered,
+            palette_stats: None,
+            pass_filters: Vec::new(),
+        }
+    }
+}
+
+/// Optimize the input file from memory, keeping the details needed for statistics and reports
+fn optimize_from_memory_inner(data: &[u8], opts: &Options) -> PngResult<MemoryResult> {
     // Read in the file and try to decode as PNG.
     info!("Processing from memory");
 
-    let deadline = Arc::new(Deadline::new(opts.timeout));
+    let deadline = Arc::new(Deadline::new(opts));
 
     let original_size = data.len();
     let mut png = PngData::from_slice(data, opts)?;
+    let original = png.raw.clone();
+
+    if opts.mark_optimized && has_optimized_marker(data) {
+        info!("Image already marked as optimized");
+        return Ok(MemoryResult::unchanged(data, original, png.recovered));
+    }
 
     // Run the optimizer on the decoded PNG.
-    let optimized_output = optimize_png(&mut png, data, opts, deadline)?;
+    let (optimized_output, trial) = match with_thread_limit(opts, || {
+        optimize_png(&mut png, original_size, opts, deadline)
+    }) {
+        Ok(result) => result,
+        Err(PngError::VerificationFailed) => {
+            warn!("Output verification failed, keeping the original");
+            return Ok(MemoryResult::unchanged(data, original, png.recovered));
+        }
+        Err(e) => return Err(e),
+    };
+    #[cfg(feature = "sanity-checks")]
+    assert!(opts.is_lossy() || sanity_checks::validate_output(&optimized_output, data));
 
     if is_fully_optimized(original_size, optimized_output.len(), opts) {
         info!("Image already optimized");
-        Ok(data.to_vec())
+        Ok(MemoryResult::unchanged(data, original, png.recovered))
     } else {
-        Ok(optimized_output)
+        Ok(MemoryResult {
+            output: optimized_output,
+            palette_stats: png
+                .palette_stats
+                .and_then(|stats| stats.with_lengths(&original, &png.raw)),
+            original,
+            optimized: Some(png.raw.clone()),
+            trial,
+            recovered: png.recovered,
+            pass_filters: png.pass_filters,
+        })
     }
 }
 
+/// Perform optimization on a PNG file read from a stream, using the options provided
+///
+/// The input is read one chunk at a time, so only the chunks being kept are held in memory
+/// rather than the entire file. As the input is not kept, the output is returned even if it is
+/// not smaller than the input. Sizes are compared with the number of bytes read, including any
+/// chunks that are not kept.
+pub fn optimize_from_reader<R: Read>(reader: R, opts: &Options) -> PngResult<Vec<u8>> {
+    info!("Processing from reader");
+
+    let deadline = Arc::new(Deadline::new(opts));
+
+    let (mut png, original_size) = PngData::from_reader(reader, opts)?;
+    // The input is not kept, so the sanity checks compare with the decoded image instead
+    #[cfg(feature = "sanity-checks")]
+    let original = png.raw.clone();
+
+    // Run the optimizer on the decoded PNG.
+    let (optimized_output, _) = with_thread_limit(opts, || {
+        optimize_png(&mut png, original_size, opts, deadline)
+    })?;
+    #[cfg(feature = "sanity-checks")]
+    assert!(opts.is_lossy() || verify_output(&optimized_output, &original, None, opts).is_ok());
+
+    if is_fully_optimized(original_size, optimized_output.len(), opts) {
+        info!(
+            "Output is not smaller than the {} bytes read",
+            original_size
+        );
+    }
+    Ok(optimized_output)
+}
+
+/// Recompress the input file using an explicit sequence of filters, where the file is already
+/// loaded in-memory
+///
+/// `filters` must contain one standard filter type (0-4) for each row of the image. For
+/// interlaced images, this includes the rows of every interlacing pass. No reductions or
+/// filter heuristics are performed; the image data is only refiltered and recompressed.
+pub fn recompress_from_memory(data: &[u8], filters: &[u8], opts: &Options) -> PngResult<Vec<u8>> {
+    info!("Recompressing from memory");
+    opts.validate()?;
+
+    let deadline = Arc::new(Deadline::new(opts));
+
+    let mut png = PngData::from_slice(data, opts)?;
+    let filtered = png
+        .raw
+        .filter_image_with_sequence(filters, opts.optimize_alpha)?;
+    with_thread_limit(opts, || {
+        png.idat_data = opts.deflate.deflate(&filtered, &AtomicMin::new(None))?;
+        debug!("    IDAT size = {} bytes", png.idat_data.len());
+
+        let ihdr = png.raw.ihdr.clone();
+        postprocess_chunks(&mut png, opts, deadline, &ihdr);
+
+        Ok(png.output(opts))
+    })
+}
+
+/// Extract each frame of an APNG file as a standalone optimized PNG, where the file is already
+/// loaded in-memory
+///
+/// Each frame is composited onto the canvas as it would be displayed, applying the frame offsets,
+/// blending and disposal, so every output image has the full dimensions of the animation. The
+/// default image is only included if it is part of the animation. Chunks that aren't specific to
+/// animation or to the color type are carried over to each frame, subject to `opts.strip`.
+pub fn extract_apng_frames(data: &[u8], opts: &Options) -> PngResult<Vec<Vec<u8>>> {
+    info!("Extracting APNG frames from memory");
+
+    // The animation chunks must be kept to read the frames
+    let read_opts = Options {
+        strip: StripChunks::None,
+        ..opts.clone()
+    };
+    let png = PngData::from_slice(data, &read_opts)?;
+    let aux_chunks: Vec<_> = png
+        .aux_chunks
+        .iter()
+        .filter(|c| {
+            !is_position_marker(c)
+                && !matches!(
+                    &c.name,
+                    b"acTL" | b"fcTL" | b"fdAT" | b"bKGD" | b"hIST" | b"sBIT"
+                )
+        })
+        .cloned()
+        .collect();
+    apng::composite_frames(&png)?
+        .into_iter()
+        .map(|frame| {
+            RawImage {
+                png: Arc::new(frame),
+                aux_chunks: aux_chunks.clone(),
+            }
+            .create_optimized_png(opts)
+        })
+        .collect()
+}
+
 type TrialResult = (RowFilter, Vec<u8>);
+/// The filter and deflater used to compress the image data
+type Compression = (RowFilter, Deflaters);
+/// Optimized image data along with the filter and deflater that produced it
+type RawResult = (PngData, RowFilter, Deflaters);
 
 /// Perform optimization on the input PNG object using the options provided
 fn optimize_png(
     png: &mut PngData,
-    original_data: &[u8],
+    file_original_size: usize,
     opts: &Options,
     deadline: Arc<Deadline>,
-) -> PngResult<Vec<u8>> {
-    // Print png info
-    let file_original_size = original_data.len();
+) -> PngResult<(Vec<u8>, Option<Compression>)> {
     let idat_original_size = png.idat_data.len();
-    let raw = png.raw.clone();
-    debug!(
-        "    {}x{} pixels, PNG format",
-        raw.ihdr.width, raw.ihdr.height
-    );
-    report_format("    ", &raw);
-    debug!("    IDAT size = {} bytes", idat_original_size);
-    debug!("    File size = {} bytes", file_original_size);
-
-    // Check for APNG by presence of acTL chunk
-    let opts = if png.aux_chunks.iter().any(|c| &c.name == b"acTL") {
-        warn!("APNG detected, disabling all reductions");
-        let mut opts = opts.to_owned();
-        opts.interlace = None;
-        opts.bit_depth_reduction = false;
-        opts.color_type_reduction = false;
-        opts.palette_reduction = false;
-        opts.grayscale_reduction = false;
-        Cow::Owned(opts)
-    } else {
-        Cow::Borrowed(opts)
-    };
-    let max_size = if opts.force {
-        None
-    } else {
-        Some(png.estimated_output_size())
-    };
-    if let Some(new_png) = optimize_raw(raw.clone(), &opts, deadline.clone(), max_size) {
-        png.raw = new_png.raw;
-        png.idat_data = new_png.idat_data;
+    let original = png.raw.clone();
+    let original_sbit = png
+        .aux_chunks
+        .iter()
+        .find(|c| &c.name == b"sBIT")
+        .map(|c| c.data.clone());
+    let trial = optimize_png_data(png, file_original_size, opts, deadline)?;
+
+    let output = png.output(opts);
+    if opts.verify {
+        if opts.is_lossy() {
+            // Lossy options change the pixels, so only the encoding of the reduced image is checked
+            verify_output(&output, &png.raw, None, opts)?;
+        } else {
+            verify_output(&output, &original, original_sbit.as_deref(), opts)?;
+        }
     }
 
-    postprocess_chunks(png, &opts, deadline, &raw.ihdr);
-
-    let output = png.output();
-
     if idat_original_size >= png.idat_data.len() {
         debug!(
             "    IDAT size = {} bytes ({} bytes decrease)",
@@ -400,10 +1062,95 @@ fn optimize_png(
         );
     }
 
-    #[cfg(feature = "sanity-checks")]
-    assert!(sanity_checks::validate_output(&output, original_data));
+    Ok((output, trial))
+}
+
+/// Perform optimization on the input PNG object, without serializing the output
+fn optimize_png_data(
+    png: &mut PngData,
+    file_original_size: usize,
+    opts: &Options,
+    deadline: Arc<Deadline>,
+) -> PngResult<Option<Compression>> {
+    opts.validate()?;
+
+    // Print png info
+    let idat_original_size = png.idat_data.len();
+    let raw = png.raw.clone();
+    debug!(
+        "    {}x{} pixels, PNG format",
+        raw.ihdr.width, raw.ihdr.height
+    );
+    report_format("    ", &raw);
+    debug!("    IDAT size = {} bytes", idat_original_size);
+    debug!("    File size = {} bytes", file_original_size);
+
+    // Check for APNG by presence of acTL chunk
+    let is_apng = png.aux_chunks.iter().any(|c| &c.name == b"acTL");
+    let opts = if is_apng || opts.recompress_only {
+        if is_apng && !opts.recompress_only {
+            warn!("APNG detected, disabling all reductions");
+        }
+        let mut opts = opts.to_owned();
+        opts.interlace = None;
+        opts.auto_interlace = None;
+        opts.bit_depth_reduction = false;
+        opts.color_type_reduction = false;
+        opts.palette_reduction = false;
+        opts.grayscale_reduction = false;
+        opts.force_color_type = None;
+        opts.lossy_quantize = None;
+        opts.alpha_levels = None;
+        opts.palette_pin = None;
+        if opts.recompress_only {
+            opts.optimize_alpha = false;
+            opts.composite_background = false;
+        }
+        Cow::Owned(opts)
+    } else {
+        Cow::Borrowed(opts)
+    };
+    // Corrupt image data must always be replaced
+    let opts = if png.recovered.is_some() && !opts.idat_recoding {
+        Cow::Owned(Options {
+            idat_recoding: true,
+            ..opts.into_owned()
+        })
+    } else {
+        opts
+    };
+    let max_size = if opts.force || png.recovered.is_some() {
+        None
+    } else {
+        Some(png.estimated_output_size())
+    };
+    let mut trial = None;
+    if let Some((new_png, filter, deflater)) = optimize_raw(
+        raw.clone(),
+        &opts,
+        deadline.clone(),
+        max_size,
+        &png.aux_chunks,
+        png.original_filters.as_deref(),
+    ) {
+        png.raw = new_png.raw;
+        png.idat_data = new_png.idat_data;
+        png.palette_stats = new_png.palette_stats;
+        png.pass_filters = new_png.pass_filters;
+        trial = Some((filter, deflater));
+    }
+    if deadline.cancelled() {
+        return Err(PngError::Cancelled);
+    }
 
-    Ok(output)
+    if opts.recompress_only {
+        return Ok(trial);
+    }
+    postprocess_chunks(png, &opts, deadline, &raw.ihdr);
+    if opts.mark_optimized {
+        png.aux_chunks.push(optimized_marker(&png.idat_data));
+    }
+    Ok(trial)
 }
 
 /// Perform optimization on the input image data using the options provided
@@ -412,19 +1159,54 @@ fn optimize_raw(
     opts: &Options,
     deadline: Arc<Deadline>,
     max_size: Option<usize>,
-) -> Option<PngData> {
-    // Libdeflate has four algorithms: 1-4 = 'greedy', 5-7 = 'lazy', 8-9 = 'lazy2', 10-12 = 'near-optimal'
-    // 5 is the minimumm required for a decent evaluation result
-    // 7 is not noticeably slower than 5 and improves evaluation of filters in 'fast' mode (o2 and lower)
-    // 8 is a little slower but not noticeably when used only for reductions (o3 and higher)
-    // 9 is not appreciably better than 8
-    // 10 and higher are quite slow - good for filters but only good for reductions if matching the main zc level
-    let eval_compression = match opts.deflate {
-        Deflaters::Libdeflater { compression } => {
-            if opts.fast_evaluation { 7 } else { 8 }.min(compression)
+    aux_chunks: &[Chunk],
+    original_filters: Option<&[u8]>,
+) -> Option<RawResult> {
+    // A forced filter replaces every other filter strategy
+    if let Some(filter) = opts.force_filter {
+        let forced = indexset! {filter};
+        if opts.filter != forced || opts.per_pass_filters {
+            let opts = Options {
+                filter: forced,
+                per_pass_filters: false,
+                ..opts.clone()
+            };
+            return optimize_raw(image, &opts, deadline, max_size, aux_chunks, None);
         }
-        _ => 8,
-    };
+    }
+    let original_filters = original_filters.filter(|_| opts.force_filter.is_none());
+    match opts.auto_interlace {
+        Some(AutoInterlacing::Trial) => {
+            return optimize_raw_auto_interlacing(
+                image,
+                opts,
+                deadline,
+                max_size,
+                aux_chunks,
+                original_filters,
+            );
+        }
+        Some(AutoInterlacing::Heuristic) => {
+            let interlace = predict_interlacing(&image);
+            info!("Heuristic interlacing: predicted {} is smaller", interlace);
+            let opts = Options {
+                interlace: Some(interlace),
+                auto_interlace: None,
+                ..opts.clone()
+            };
+            return optimize_raw(
+                image,
+                &opts,
+                deadline,
+                max_size,
+                aux_chunks,
+                original_filters,
+            );
+        }
+        None => (),
+    }
+
+    let eval_compression = eval_compression(opts);
     // If only one filter is selected, use this for evaluations
     let eval_filters = if opts.filter.len() == 1 {
         opts.filter.clone()
@@ -432,18 +1214,13 @@ fn optimize_raw(
         // None and Bigrams work well together, especially for alpha reductions
         indexset! {RowFilter::None, RowFilter::Bigrams}
     };
-    // This will collect all versions of images and pick one that compresses best
-    let eval = Evaluator::new(
-        deadline.clone(),
+    let (png, mut eval_result, palette_stats) = reduce_image(
+        image.clone(),
+        opts,
+        &deadline,
         eval_filters.clone(),
-        eval_compression,
-        false,
+        aux_chunks,
     );
-    let mut png = perform_reductions(image.clone(), opts, &deadline, &eval);
-    let mut eval_result = eval.get_best_candidate();
-    if let Some(ref result) = eval_result {
-        png = result.image.clone();
-    }
     let reduction_occurred = png.ihdr.color_type != image.ihdr.color_type
         || png.ihdr.bit_depth != image.ihdr.bit_depth
         || png.ihdr.interlaced != image.ihdr.interlaced;
@@ -454,6 +1231,16 @@ fn optimize_raw(
 
     if opts.idat_recoding || reduction_occurred {
         let mut filters = opts.filter.clone();
+        // Photographic images generally compress best with Paeth, so skip the filter search
+        let photographic = opts.filter.len() > 1
+            && opts
+                .photo_filter_threshold
+                .is_some_and(|threshold| png.is_photographic(threshold));
+        if photographic {
+            debug!("Image appears photographic, using Paeth filter");
+            filters = indexset! {RowFilter::Paeth};
+        }
+        let mut deflater = opts.deflate;
         let fast_eval = opts.fast_evaluation && (filters.len() > 1 || eval_result.is_some());
         let best: Option<TrialResult> = if fast_eval {
             // Perform a fast evaluation of selected filters followed by a single main compression trial
@@ -465,7 +1252,13 @@ fn optimize_raw(
 
             if !filters.is_empty() {
                 trace!("Evaluating: {} filters", filters.len());
-                let eval = Evaluator::new(deadline, filters, eval_compression, opts.optimize_alpha);
+                let eval = Evaluator::new(
+                    deadline.clone(),
+                    filters,
+                    eval_compression,
+                    opts.optimize_alpha,
+                    CandidateSelection::Smallest,
+                );
                 if let Some(ref result) = eval_result {
                     eval.set_best_size(result.idat_data.len());
                 }
@@ -480,12 +1273,38 @@ fn optimize_raw(
             match opts.deflate {
                 Deflaters::Libdeflater { compression } if compression <= eval_compression => {
                     // No further compression required
-                    Some((result.filter, result.idat_data))
+                    let idat_data = deflate::store_if_smaller(result.idat_data, &result.filtered);
+                    Some((result.filter, idat_data))
                 }
                 _ => {
                     debug!("Trying: {}", result.filter);
                     let best_size = AtomicMin::new(max_size);
-                    perform_trial(&result.filtered, opts, result.filter, &best_size)
+                    let split_points = split_points(&png, opts);
+                    let refiltered = if result.filter == RowFilter::Brute && opts.exact_brute {
+                        // The evaluation scored the rows with a fast level, so filter them again
+                        trial_filter_image(&result.image, result.filter, opts, &deadline)
+                    } else {
+                        None
+                    };
+                    perform_trial(
+                        refiltered.as_ref().unwrap_or(&result.filtered),
+                        opts,
+                        result.filter,
+                        &split_points,
+                        &best_size,
+                        &deadline,
+                    )
+                    .or_else(|| {
+                        // If the trial was stopped by the timeout, fall back to the evaluation
+                        deadline.passed().then(|| {
+                            deflater = Deflaters::Libdeflater {
+                                compression: eval_compression,
+                            };
+                            let idat_data =
+                                deflate::store_if_smaller(result.idat_data, &result.filtered);
+                            (result.filter, idat_data)
+                        })
+                    })
                 }
             }
         } else {
@@ -504,14 +1323,30 @@ fn optimize_raw(
 
             debug!("Trying: {} filters", filters.len());
 
+            // The evaluation may have already filtered this image with one of the filters
+            let prefiltered = eval_result
+                .as_ref()
+                .filter(|result| !opts.optimize_alpha && Arc::ptr_eq(&result.image, &png));
+
             let best_size = AtomicMin::new(max_size);
+            let split_points = split_points(&png, opts);
             let results_iter = filters.into_par_iter().with_max_len(1);
             let best = results_iter.filter_map(|filter| {
                 if deadline.passed() {
                     return None;
                 }
-                let filtered = &png.filter_image(filter, opts.optimize_alpha);
-                perform_trial(filtered, opts, filter, &best_size)
+                let filtered = match prefiltered {
+                    Some(result) if result.filter == filter => Cow::Borrowed(&result.filtered),
+                    _ => Cow::Owned(trial_filter_image(&png, filter, opts, &deadline)?),
+                };
+                perform_trial(
+                    &filtered,
+                    opts,
+                    filter,
+                    &split_points,
+                    &best_size,
+                    &deadline,
+                )
             });
             best.reduce_with(|i, j| {
                 if i.1.len() < j.1.len() || (i.1.len() == j.1.len() && i.0 < j.0) {
@@ -522,21 +1357,93 @@ fn optimize_raw(
             })
         };
 
+        let mut pass_filters = Vec::new();
+        let best = if opts.per_pass_filters
+            && png.ihdr.interlaced == Interlacing::Adam7
+            && opts.filter.len() > 1
+            && !photographic
+            && !deadline.passed()
+        {
+            // Try choosing the filter separately for each interlacing pass
+            let (filtered, chosen) =
+                filter_passes(&png, &opts.filter, opts.optimize_alpha, eval_compression);
+            debug!("Trying: per-pass filters {:?}", chosen);
+            // Only keep the result if it's strictly smaller
+            let best_size = AtomicMin::new(best.as_ref().map(|b| b.1.len() - 1).or(max_size));
+            let split_points = split_points(&png, opts);
+            // The last pass contains half of the image, so its filter stands for the result
+            let filter = *chosen.last().unwrap();
+            match perform_trial(
+                &filtered,
+                opts,
+                filter,
+                &split_points,
+                &best_size,
+                &deadline,
+            ) {
+                Some(result) => {
+                    pass_filters = chosen;
+                    Some(result)
+                }
+                None => best,
+            }
+        } else {
+            best
+        };
+
+        let best = match original_filters {
+            Some(original_filters)
+                if png.ihdr.interlaced == Interlacing::None && !deadline.passed() =>
+            {
+                // Try reapplying the filter type of each row in the input
+                png.filter_image_with_sequence(original_filters, opts.optimize_alpha)
+                    .ok()
+                    .and_then(|filtered| {
+                        // The most common filter stands for the result
+                        let mut counts = [0; 5];
+                        for &f in original_filters {
+                            counts[f as usize] += 1;
+                        }
+                        let common = (0..5).max_by_key(|&f| counts[f as usize]).unwrap();
+                        let filter = RowFilter::try_from(common).unwrap();
+                        debug!("Trying: original filters");
+                        // Only keep the result if it's strictly smaller
+                        let best_size =
+                            AtomicMin::new(best.as_ref().map(|b| b.1.len() - 1).or(max_size));
+                        let split_points = split_points(&png, opts);
+                        perform_trial(
+                            &filtered,
+                            opts,
+                            filter,
+                            &split_points,
+                            &best_size,
+                            &deadline,
+                        )
+                    })
+                    .or(best)
+            }
+            _ => best,
+        };
+
         if let Some((filter, idat_data)) = best {
             let image = PngData {
                 raw: png,
                 idat_data,
                 aux_chunks: Vec::new(),
+                recovered: None,
+                original_filters: None,
+                palette_stats,
+                pass_filters,
             };
             if image.estimated_output_size() < max_size.unwrap_or(usize::MAX) {
                 debug!("Found better combination:");
                 debug!(
                     "    zc = {}  f = {:8}  {} bytes",
-                    opts.deflate,
+                    deflater,
                     filter,
                     image.idat_data.len()
                 );
-                return Some(image);
+                return Some((image, filter, deflater));
             }
         }
     } else if let Some(result) = eval_result {
@@ -546,6 +1453,10 @@ fn optimize_raw(
             raw: result.image,
             idat_data: result.idat_data,
             aux_chunks: Vec::new(),
+            recovered: None,
+            original_filters: None,
+            palette_stats,
+            pass_filters: Vec::new(),
         };
         if image.estimated_output_size() < max_size.unwrap_or(usize::MAX) {
             debug!("Found better combination:");
@@ -555,21 +1466,198 @@ fn optimize_raw(
                 result.filter,
                 image.idat_data.len()
             );
-            return Some(image);
+            let deflater = Deflaters::Libdeflater {
+                compression: eval_compression,
+            };
+            return Some((image, result.filter, deflater));
         }
     }
 
     None
 }
 
+/// Perform the reductions on the image, returning the reduced image along with the evaluation of
+/// it, if the evaluation still applies, and the statistics of the palette reduction
+fn reduce_image(
+    image: Arc<PngImage>,
+    opts: &Options,
+    deadline: &Arc<Deadline>,
+    eval_filters: IndexSet<RowFilter>,
+    aux_chunks: &[Chunk],
+) -> (
+    Arc<PngImage>,
+    Option<Candidate>,
+    Option<PaletteReductionStats>,
+) {
+    // This will collect all versions of images and pick one that compresses best
+    let eval = Evaluator::new(
+        deadline.clone(),
+        eval_filters,
+        eval_compression(opts),
+        false,
+        opts.candidate_selection.clone(),
+    );
+    let (mut png, palette_reduction) = perform_reductions(image, opts, deadline, &eval, aux_chunks);
+    let mut eval_result = eval.get_best_candidate();
+    deadline.report(Progress::Reduced);
+    if let Some(ref result) = eval_result {
+        png = result.image.clone();
+    }
+    let palette_stats = palette_reduction.and_then(|(stats, unreduced)| match unreduced {
+        Some(unreduced) if Arc::ptr_eq(&unreduced, &png) => None,
+        _ => Some(stats),
+    });
+    if let Some(pinned) = pin_palette(&png, opts) {
+        // The evaluated image no longer applies
+        png = Arc::new(pinned);
+        eval_result = None;
+    }
+    (png, eval_result, palette_stats)
+}
+
+/// Optimize the image both with and without interlacing, keeping whichever is smaller
+///
+/// Reductions are performed only once, on the non-interlaced image. The reduced image is then
+/// interlaced and only the filtering and compression are repeated.
+fn optimize_raw_auto_interlacing(
+    image: Arc<PngImage>,
+    opts: &Options,
+    deadline: Arc<Deadline>,
+    max_size: Option<usize>,
+    aux_chunks: &[Chunk],
+    original_filters: Option<&[u8]>,
+) -> Option<RawResult> {
+    let progressive_opts = Options {
+        interlace: Some(Interlacing::None),
+        auto_interlace: None,
+        ..opts.clone()
+    };
+    let progressive = optimize_raw(
+        image.clone(),
+        &progressive_opts,
+        deadline.clone(),
+        max_size,
+        aux_chunks,
+        original_filters,
+    );
+    if deadline.passed() {
+        return progressive;
+    }
+
+    let reduced = progressive.as_ref().map_or(image, |p| p.0.raw.clone());
+    let interlaced_image = match reduced.change_interlacing(Interlacing::Adam7) {
+        Some(interlaced) => Arc::new(interlaced),
+        None => reduced,
+    };
+    // The image has already been reduced, including any lossy transformations
+    let interlaced_opts = Options {
+        interlace: Some(Interlacing::Adam7),
+        auto_interlace: None,
+        bit_depth_reduction: false,
+        color_type_reduction: false,
+        palette_reduction: false,
+        grayscale_reduction: false,
+        force_color_type: None,
+        lossy_quantize: None,
+        alpha_levels: None,
+        palette_pin: None,
+        idat_recoding: true,
+        ..opts.clone()
+    };
+    // The interlaced image must beat the non-interlaced one to be kept
+    let max_size = progressive
+        .as_ref()
+        .map_or(max_size, |p| Some(p.0.estimated_output_size()));
+    debug!("Trying: interlaced");
+    let interlaced = optimize_raw(
+        interlaced_image,
+        &interlaced_opts,
+        deadline,
+        max_size,
+        &[],
+        None,
+    );
+
+    // The palette was only reduced in the first pass
+    let interlaced = interlaced.map(|(mut png, filter, deflater)| {
+        png.palette_stats = progressive.as_ref().and_then(|p| p.0.palette_stats);
+        (png, filter, deflater)
+    });
+    let best = interlaced.or(progressive);
+    if let Some((png, ..)) = &best {
+        info!("Auto interlacing: {} is smaller", png.raw.ihdr.interlaced);
+    }
+    best
+}
+
+/// Determine where to split the filtered image data for compression, if requested
+///
+/// Split points are placed on row boundaries, as close as possible to equally sized parts.
+fn split_points(png: &PngImage, opts: &Options) -> Vec<usize> {
+    let zopfli = match opts.deflate {
+        #[cfg(feature = "zopfli")]
+        Deflaters::Zopfli { .. } => true,
+        _ => false,
+    };
+    if opts.zopfli_splits <= 1 || !zopfli {
+        return Vec::new();
+    }
+    // Each filtered row includes a leading filter byte
+    let row_ends: Vec<usize> = png
+        .scan_lines(false)
+        .scan(0, |pos, line| {
+            *pos += line.data.len() + 1;
+            Some(*pos)
+        })
+        .collect();
+    let total = row_ends.last().copied().unwrap_or(0);
+    let mut points: Vec<usize> = Vec::new();
+    for i in 1..opts.zopfli_splits {
+        let target = total * i / opts.zopfli_splits;
+        if let Some(&point) = row_ends.iter().find(|&&end| end >= target) {
+            if point < total && points.last().map_or(true, |&last| point > last) {
+                points.push(point);
+            }
+        }
+    }
+    points
+}
+
+/// Filter the image for a main compression trial, scoring Brute with the main deflater if requested
+///
+/// Returns `None` if the deadline passes during filtering.
+fn trial_filter_image(
+    png: &PngImage,
+    filter: RowFilter,
+    opts: &Options,
+    deadline: &Deadline,
+) -> Option<Vec<u8>> {
+    let brute_deflater = if opts.exact_brute {
+        opts.deflate
+    } else {
+        BRUTE_DEFLATER
+    };
+    png.filter_image_until(filter, opts.optimize_alpha, brute_deflater, Some(deadline))
+}
+
 /// Execute a compression trial
 fn perform_trial(
     filtered: &[u8],
     opts: &Options,
     filter: RowFilter,
+    split_points: &[usize],
     best_size: &AtomicMin,
+    deadline: &Deadline,
 ) -> Option<TrialResult> {
-    match opts.deflate.deflate(filtered, best_size) {
+    let result = opts.deflate.deflate_split(
+        filtered,
+        split_points,
+        opts.single_block_trial,
+        best_size,
+        deadline,
+    );
+    deadline.report(Progress::Compressed(filter));
+    match result {
         Ok(new_idat) => {
             let bytes = new_idat.len();
             best_size.set_min(bytes);
@@ -601,28 +1689,50 @@ struct DeadlineImp {
     print_message: AtomicBool,
 }
 
-/// Keep track of processing timeout
+/// Keep track of processing timeout, progress and cancellation
 #[doc(hidden)]
 #[derive(Debug)]
 pub struct Deadline {
     imp: Option<DeadlineImp>,
+    progress: Option<ProgressCallback>,
+    cancelled: AtomicBool,
 }
 
 impl Deadline {
-    pub fn new(timeout: Option<Duration>) -> Self {
+    pub fn new(opts: &Options) -> Self {
         Self {
-            imp: timeout.map(|timeout| DeadlineImp {
+            imp: opts.timeout.map(|timeout| DeadlineImp {
                 start: Instant::now(),
                 timeout,
                 print_message: AtomicBool::new(true),
             }),
+            progress: opts.progress.clone(),
+            cancelled: AtomicBool::new(false),
         }
     }
 
-    /// True if the timeout has passed, and no new work should be done.
+    /// Report progress to the callback, if any, cancelling if requested
+    pub fn report(&self, progress: Progress) {
+        if let Some(callback) = &self.progress {
+            if callback.call(progress).is_break() && !self.cancelled.swap(true, Ordering::SeqCst) {
+                info!("Optimization cancelled");
+            }
+        }
+    }
+
+    /// True if the optimization was cancelled by the progress callback
+    pub fn cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+
+    /// True if the timeout has passed or the optimization was cancelled, and no new work
+    /// should be done.
     ///
     /// If the verbose option is on, it also prints a timeout message once.
     pub fn passed(&self) -> bool {
+        if self.cancelled() {
+            return true;
+        }
         if let Some(imp) = &self.imp {
             let elapsed = imp.start.elapsed();
             if elapsed > imp.timeout {
@@ -680,20 +1790,137 @@ fn postprocess_chunks(
                 };
             } else if opts.idat_recoding {
                 // Try recompressing the profile
-                if let Ok(iccp) = construct_iccp(&icc, opts.deflate) {
+                if let Some(iccp) =
+                    recompress_iccp(&png.aux_chunks[iccp_idx], &icc, opts.aux_deflater())
+                {
                     let cur_len = png.aux_chunks[iccp_idx].data.len();
                     let new_len = iccp.data.len();
-                    if new_len < cur_len {
+                    debug!(
+                        "Recompressed iCCP chunk: {} ({} bytes decrease)",
+                        new_len,
+                        cur_len - new_len
+                    );
+                    png.aux_chunks[iccp_idx] = iccp;
+                }
+            }
+        }
+    }
+
+    // Remove exact duplicates of chunks that may legally appear more than once
+    if opts.strip != StripChunks::None {
+        let mut seen = FxHashSet::default();
+        png.aux_chunks.retain(|c| {
+            if !matches!(&c.name, b"tEXt" | b"zTXt" | b"iTXt" | b"sPLT") {
+                return true;
+            }
+            let unique = seen.insert((c.name, c.data.clone()));
+            if !unique {
+                debug!(
+                    "Removing duplicate {} chunk",
+                    std::str::from_utf8(&c.name).unwrap()
+                );
+            }
+            unique
+        });
+    }
+
+    // With an sRGB chunk present, gAMA and cHRM are redundant
+    if opts.strip_srgb_redundant && png.aux_chunks.iter().any(|c| &c.name == b"sRGB") {
+        png.aux_chunks.retain(|c| {
+            let redundant = &c.name == b"gAMA" || &c.name == b"cHRM";
+            if redundant {
+                debug!(
+                    "Removing {} chunk as it is redundant with sRGB",
+                    std::str::from_utf8(&c.name).unwrap()
+                );
+            }
+            !redundant
+        });
+    }
+
+    if opts.strip_exif && png.aux_chunks.iter().any(|c| &c.name == b"eXIf") {
+        debug!("Removing eXIf chunk");
+        png.aux_chunks.retain(|c| &c.name != b"eXIf");
+    }
+
+    if opts.strip_physical {
+        png.aux_chunks.retain(|c| {
+            let physical = chunk_category(&c.name) == ChunkCategory::Physical;
+            if physical {
+                debug!("Removing {} chunk", std::str::from_utf8(&c.name).unwrap());
+            }
+            !physical
+        });
+    }
+
+    if opts.strip_time || opts.update_time {
+        png.aux_chunks.retain(|c| &c.name != b"tIME");
+    }
+    if opts.update_time && !opts.strip_time {
+        match ModificationTime::from_system_time(SystemTime::now()) {
+            Some(time) => {
+                let data = time.to_bytes().to_vec();
+                if opts.keep_chunk(b"tIME", &data) {
+                    png.aux_chunks.push(Chunk {
+                        name: *b"tIME",
+                        data,
+                    });
+                }
+            }
+            None => warn!("Unable to set tIME chunk: the system time is out of range"),
+        }
+    }
+
+    // A pHYs chunk with unspecified unit only describes the aspect ratio
+    if opts.normalize_phys {
+        if let Some(idx) = png.aux_chunks.iter().position(|c| &c.name == b"pHYs") {
+            match normalize_phys(&png.aux_chunks[idx]) {
+                Some([0, 0, 0, 1, 0, 0, 0, 1, 0]) => {
+                    debug!("Removing pHYs chunk as it specifies square pixels");
+                    png.aux_chunks.remove(idx);
+                }
+                Some(phys) => png.aux_chunks[idx].data = phys.to_vec(),
+                None => (),
+            }
+        }
+    }
+
+    // Try recompressing any compressed text chunks, and converting between tEXt and zTXt
+    let to_ztxt = opts.convert_text && opts.strip.keep(b"zTXt");
+    let to_text = opts.convert_text && opts.strip.keep(b"tEXt");
+    if opts.idat_recoding || to_ztxt || to_text {
+        png.aux_chunks
+            .par_iter_mut()
+            .with_max_len(1)
+            .filter(|c| matches!(&c.name, b"tEXt" | b"zTXt" | b"iTXt"))
+            .for_each(|c| {
+                if deadline.passed() {
+                    return;
+                }
+                if opts.idat_recoding && &c.name != b"tEXt" {
+                    if let Some(chunk) = recompress_text(c, opts.aux_deflater()) {
                         debug!(
-                            "Recompressed iCCP chunk: {} ({} bytes decrease)",
-                            new_len,
-                            cur_len - new_len
+                            "Recompressed {} chunk: {} ({} bytes decrease)",
+                            std::str::from_utf8(&c.name).unwrap(),
+                            chunk.data.len(),
+                            c.data.len() - chunk.data.len()
                         );
-                        png.aux_chunks[iccp_idx] = iccp;
+                        *c = chunk;
                     }
                 }
-            }
-        }
+                if (&c.name == b"tEXt" && to_ztxt) || (&c.name == b"zTXt" && to_text) {
+                    if let Some(chunk) = convert_text(c, opts.aux_deflater()) {
+                        debug!(
+                            "Converted {} chunk to {}: {} ({} bytes decrease)",
+                            std::str::from_utf8(&c.name).unwrap(),
+                            std::str::from_utf8(&chunk.name).unwrap(),
+                            chunk.data.len(),
+                            c.data.len() - chunk.data.len()
+                        );
+                        *c = chunk;
+                    }
+                }
+            });
     }
 
     // If the depth/color type has changed, some chunks may be invalid and should be dropped
@@ -701,8 +1928,29 @@ fn postprocess_chunks(
     // generally more trouble than they're worth
     let ihdr = &png.raw.ihdr;
     if orig_ihdr.bit_depth != ihdr.bit_depth || orig_ihdr.color_type != ihdr.color_type {
+        // The bKGD chunk is the exception, as it can be converted if the color is representable
+        let mut bkgd_converted = false;
+        if let Some(bkgd) = png.aux_chunks.iter_mut().find(|c| &c.name == b"bKGD") {
+            if let Some(data) = convert_bkgd(&bkgd.data, orig_ihdr, ihdr) {
+                bkgd.data = data;
+                bkgd_converted = true;
+            }
+        }
+        // The sBIT chunk of an indexed image refers to the palette, so it remains valid if the image is still indexed
+        // Channels that have become constant may need fewer significant bits
+        let both_indexed = matches!(orig_ihdr.color_type, ColorType::Indexed { .. })
+            && matches!(ihdr.color_type, ColorType::Indexed { .. });
+        if let (true, ColorType::Indexed { palette }) = (both_indexed, &ihdr.color_type) {
+            if let Some(sbit) = png.aux_chunks.iter_mut().find(|c| &c.name == b"sBIT") {
+                if let Some(data) = reduced_palette_sbit(&sbit.data, palette) {
+                    sbit.data = data;
+                }
+            }
+        }
         png.aux_chunks.retain(|c| {
-            let invalid = &c.name == b"bKGD" || &c.name == b"sBIT" || &c.name == b"hIST";
+            let invalid = (&c.name == b"bKGD" && !bkgd_converted)
+                || (&c.name == b"sBIT" && !both_indexed)
+                || &c.name == b"hIST";
             if invalid {
                 warn!(
                     "Removing {} chunk as it no longer matches the image data",
@@ -713,26 +1961,53 @@ fn postprocess_chunks(
         });
     }
 
-    // Find fdAT chunks and attempt to recompress them
+    // Crop APNG frames to the region that changes
+    let cropped = opts.crop_frames
+        && png.aux_chunks.iter().any(|c| &c.name == b"acTL")
+        && apng::crop_frames(png);
+
+    // Find fdAT chunks and attempt to refilter and recompress them, using the frame size from the
+    // preceding fcTL chunk
     // Note if there are multiple fdATs per frame then decompression will fail and nothing will change
-    let mut fdat: Vec<_> = png
-        .aux_chunks
-        .iter_mut()
-        .filter(|c| &c.name == b"fdAT")
-        .collect();
-    if opts.idat_recoding && !fdat.is_empty() {
-        let buffer_size = orig_ihdr.raw_data_size();
+    let mut frame_ihdr = None;
+    let mut fdat = Vec::new();
+    for c in &mut png.aux_chunks {
+        if &c.name == b"fcTL" && c.data.len() >= 20 {
+            let width = read_be_u32(&c.data[4..8]);
+            let height = read_be_u32(&c.data[8..12]);
+            let x = read_be_u32(&c.data[12..16]);
+            let y = read_be_u32(&c.data[16..20]);
+            // The frame must lie within the canvas, as with frame cropping
+            // The data of a malformed frame is left as it is
+            frame_ihdr = if width == 0
+                || height == 0
+                || u64::from(x) + u64::from(width) > u64::from(orig_ihdr.width)
+                || u64::from(y) + u64::from(height) > u64::from(orig_ihdr.height)
+            {
+                warn!("Skipping APNG frame that is out of bounds");
+                None
+            } else {
+                Some(IhdrData {
+                    width,
+                    height,
+                    ..orig_ihdr.clone()
+                })
+            };
+        } else if &c.name == b"fdAT" && c.data.len() > 4 {
+            if let Some(ihdr) = &frame_ihdr {
+                fdat.push((c, ihdr.clone()));
+            }
+        }
+    }
+    if (opts.idat_recoding || cropped) && !fdat.is_empty() {
         fdat.par_iter_mut()
             .with_max_len(1)
             .enumerate()
-            .for_each(|(i, c)| {
-                if deadline.passed() || c.data.len() <= 4 {
+            .for_each(|(i, (c, ihdr))| {
+                if deadline.passed() {
                     return;
                 }
-                if let Ok(mut data) = deflate::inflate(&c.data[4..], buffer_size).and_then(|data| {
-                    let max_size = AtomicMin::new(Some(c.data.len() - 5));
-                    opts.deflate.deflate(&data, &max_size)
-                }) {
+                if let Some(mut data) = optimize_frame(&c.data[4..], ihdr.clone(), opts) {
                     debug!(
                         "Recompressed fdAT #{:<2}: {} ({} bytes decrease)",
                         i,
@@ -746,11 +2021,148 @@ fn postprocess_chunks(
     }
 }
 
+/// Refilter and recompress the data of an APNG frame, returning the new data if it is smaller
+///
+/// The frame keeps the format of the main image, so only filtering and compression can change.
+fn optimize_frame(compressed: &[u8], ihdr: IhdrData, opts: &Options) -> Option<Vec<u8>> {
+    let size = ihdr.raw_data_size();
+    let mut frame = PngImage {
+        ihdr,
+        data: deflate::inflate(compressed, size).ok()?,
+    };
+    if frame.data.len() != size {
+        return None;
+    }
+    let original = frame.data.clone();
+    frame.data = frame.unfilter_image().ok()?;
+
+    // Choose between the original filtering and each of the filters, as in the main trials
+    let eval_compression = eval_compression(opts);
+    let candidates: Vec<Vec<u8>> = match opts.force_filter {
+        Some(filter) => vec![frame.filter_image(filter, false)],
+        None => once(original)
+            .chain(opts.filter.iter().map(|&f| frame.filter_image(f, false)))
+            .collect(),
+    };
+    let best = candidates.iter().min_by_key(|data| {
+        deflate::deflate(data, eval_compression, &AtomicMin::new(None))
+            .map_or(usize::MAX, |d| d.len())
+    })?;
+    let max_size = AtomicMin::new(Some(compressed.len() - 1));
+    opts.deflate.deflate(best, &max_size).ok()
+}
+
+/// Run the given function within a thread pool limited to the number of threads in the options
+#[cfg_attr(not(feature = "parallel"), allow(unused_variables))]
+fn with_thread_limit<T: Send>(opts: &Options, f: impl FnOnce() -> T + Send) -> T {
+    #[cfg(feature = "parallel")]
+    if let Some(threads) = opts.threads {
+        match rayon::ThreadPoolBuilder::new().num_threads(threads).build() {
+            Ok(pool) => return pool.install(f),
+            Err(e) => warn!("Unable to create thread pool: {}", e),
+        }
+    }
+    f()
+}
+
 /// Check if an image was already optimized prior to oxipng's operations
 fn is_fully_optimized(original_size: usize, optimized_size: usize, opts: &Options) -> bool {
-    original_size <= optimized_size && !opts.force
+    if opts.mark_optimized {
+        // Output that is only larger by the 16-byte marker chunk is still written, so that it
+        // gains the marker
+        original_size + 16 < optimized_size && !opts.force
+    } else {
+        original_size <= optimized_size && !opts.force
+    }
 }
 
+/// Write the output to a temporary file in the same directory, then rename it over the output path
+///
+/// If anything fails, the temporary file is removed and any existing output file is untouched.
+/// The permissions (and ownership on Unix) of an existing output file are kept, unless the
+/// attributes of the input file are being preserved. Symlinks are followed so the link remains
+/// in place.
+fn write_file_atomically(
+    output_path: &Path,
+    data: &[u8],
+    preserved: Option<&Metadata>,
+) -> PngResult<()> {
+    let write_err = |e: std::io::Error| {
+        PngError::new(&format!(
+            "Unable to write to {}: {}",
+            output_path.display(),
+            e
+        ))
+    };
+    let target = output_path
+        .canonicalize()
+        .unwrap_or_else(|_| output_path.to_path_buf());
+    let existing = target.metadata().ok();
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    let (temp_path, temp_file) = (0..)
+        .find_map(|i| {
+            let temp_path = dir.join(format!(".{}.{}-{}.tmp", name, std::process::id(), i));
+            match OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(&temp_path)
+            {
+                Ok(file) => Some(Ok((temp_path, file))),
+                Err(e) if e.kind() == ErrorKind::AlreadyExists => None,
+                Err(e) => Some(Err(e)),
+            }
+        })
+        .unwrap()
+        .map_err(write_err)?;
+    trace!("writing to temporary file {:?}", temp_path);
+
+    let result = (|| {
+        let mut buffer = BufWriter::new(temp_file);
+        buffer
+            .write_all(data)
+            // flush BufWriter so IO errors don't get swallowed silently on close() by drop!
+            .and_then(|()| buffer.flush())
+            .map_err(write_err)?;
+        let temp_file = buffer.into_inner().map_err(|e| write_err(e.into_error()))?;
+        // Make sure the data is on disk before it replaces the original
+        temp_file.sync_all().map_err(write_err)?;
+        match (preserved, &existing) {
+            (Some(metadata), _) => copy_permissions(metadata, &temp_file)?,
+            (None, Some(metadata)) => {
+                copy_permissions(metadata, &temp_file)?;
+                copy_ownership(metadata, &temp_file);
+            }
+            (None, None) => {}
+        }
+        // force drop and thereby closing of file handle before modifying any timestamp
+        std::mem::drop(temp_file);
+        if let Some(metadata) = preserved {
+            copy_times(metadata, &temp_path)?;
+        }
+        std::fs::rename(&temp_path, &target).map_err(write_err)
+    })();
+    if result.is_err() {
+        std::fs::remove_file(&temp_path).ok();
+    }
+    result
+}
+
+#[cfg(unix)]
+fn copy_ownership(metadata: &Metadata, out_file: &File) {
+    use std::os::unix::fs::{fchown, MetadataExt};
+
+    if let Err(err_io) = fchown(out_file, Some(metadata.uid()), Some(metadata.gid())) {
+        warn!("Unable to keep the owner of the output file: {}", err_io);
+    }
+}
+
+#[cfg(not(unix))]
+fn copy_ownership(_: &Metadata, _: &File) {}
+
 fn copy_permissions(metadata_input: &Metadata, out_file: &File) -> PngResult<()> {
     out_file
         .set_permissions(metadata_input.permissions())
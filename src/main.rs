@@ -18,13 +18,19 @@ mod rayon;
 
 #[cfg(feature = "zopfli")]
 use std::num::NonZeroU8;
-use std::{ffi::OsString, fs::DirBuilder, io::Write, path::PathBuf, process::exit, time::Duration};
+use std::{
+    ffi::OsString, fs::DirBuilder, io::Write, num::NonZeroU32, path::PathBuf, process::exit,
+    time::Duration,
+};
 
-use clap::ArgMatches;
+use clap::{parser::ValueSource, ArgMatches};
 mod cli;
 use indexmap::IndexSet;
 use log::{error, warn, Level, LevelFilter};
-use oxipng::{Deflaters, InFile, Options, OutFile, RowFilter, StripChunks};
+use oxipng::{
+    is_critical, AutoInterlacing, Deflaters, InFile, Options, OutFile, PaletteSort, RowFilter,
+    StripChunks,
+};
 use rayon::prelude::*;
 
 use crate::cli::DISPLAY_CHUNKS;
@@ -107,7 +113,7 @@ fn collect_files(
     let mut in_out_pairs = Vec::new();
     let allow_stdin = top_level && files.len() == 1;
     for input in files {
-        let using_stdin = allow_stdin && input.to_str().map_or(false, |p| p == "-");
+        let using_stdin = allow_stdin && input.to_str() == Some("-");
         if !using_stdin && input.is_dir() {
             if recursive {
                 match input.read_dir() {
@@ -189,10 +195,12 @@ fn parse_opts_into_struct(
         })
         .init();
 
-    let mut opts = match matches.get_one::<String>("optimization") {
-        None => Options::default(),
-        Some(x) if x == "max" => Options::max_compression(),
-        Some(level) => Options::from_preset(level.parse::<u8>().unwrap()),
+    let effort = matches.get_one::<i64>("effort");
+    let mut opts = match (effort, matches.get_one::<String>("optimization")) {
+        (Some(&effort), _) => Options::from_effort(effort as u8),
+        (None, None) => Options::default(),
+        (None, Some(x)) if x == "max" => Options::max_compression(),
+        (None, Some(level)) => Options::from_preset(level.parse::<u8>().unwrap()),
     };
 
     if let Some(x) = matches.get_one::<IndexSet<u8>>("filters") {
@@ -206,6 +214,10 @@ fn parse_opts_into_struct(
         opts.timeout = Some(Duration::from_secs(num));
     }
 
+    if let Some(&size) = matches.get_one::<u32>("idat-size") {
+        opts.max_idat_size = NonZeroU32::new(size);
+    }
+
     let out_dir = if let Some(path) = matches.get_one::<PathBuf>("output_dir") {
         if !path.exists() {
             match DirBuilder::new().recursive(true).create(path) {
@@ -243,16 +255,35 @@ fn parse_opts_into_struct(
         opts.fast_evaluation = matches.get_flag("fast");
     }
 
+    opts.original_filters = matches.get_flag("keep-filters");
+
+    opts.exact_brute = matches.get_flag("exact-brute");
+
     opts.force = matches.get_flag("force");
 
+    opts.mark_optimized = matches.get_flag("mark");
+
     opts.fix_errors = matches.get_flag("fix");
 
+    opts.recover_image_data = matches.get_flag("recover");
+
+    opts.verify = matches.get_flag("verify");
+
     opts.bit_depth_reduction = !matches.get_flag("no-bit-reduction");
 
     opts.color_type_reduction = !matches.get_flag("no-color-reduction");
 
     opts.palette_reduction = !matches.get_flag("no-palette-reduction");
 
+    if let Some(x) = matches.get_one::<String>("palette-sort") {
+        opts.palette_sort = match x.as_str() {
+            "alpha" => PaletteSort::Alpha,
+            "frequency" => PaletteSort::Frequency,
+            "nearest" => PaletteSort::Nearest,
+            _ => PaletteSort::Luma,
+        };
+    }
+
     opts.grayscale_reduction = !matches.get_flag("no-grayscale-reduction");
 
     if matches.get_flag("no-reductions") {
@@ -261,15 +292,26 @@ fn parse_opts_into_struct(
         opts.palette_reduction = false;
         opts.grayscale_reduction = false;
         opts.interlace = None;
+        opts.auto_interlace = None;
     }
 
     opts.idat_recoding = !matches.get_flag("no-recoding");
 
-    if let Some(x) = matches.get_one::<String>("interlace") {
+    // The default interlacing doesn't override the effort
+    let default_interlace = matches.value_source("interlace") == Some(ValueSource::DefaultValue);
+    if let Some(x) = matches
+        .get_one::<String>("interlace")
+        .filter(|_| !(default_interlace && effort.is_some()))
+    {
+        opts.auto_interlace = match x.as_str() {
+            "auto" => Some(AutoInterlacing::Trial),
+            "heuristic" => Some(AutoInterlacing::Heuristic),
+            _ => None,
+        };
         opts.interlace = if x == "keep" {
             None
         } else {
-            x.parse::<u8>().unwrap().try_into().ok()
+            x.parse::<u8>().ok().and_then(|x| x.try_into().ok())
         };
     }
 
@@ -296,20 +338,21 @@ fn parse_opts_into_struct(
             opts.strip = StripChunks::Safe;
         } else if strip == "all" {
             opts.strip = StripChunks::All;
+        } else if strip == "metadata" {
+            opts.strip = StripChunks::Metadata;
         } else {
-            const FORBIDDEN_CHUNKS: [[u8; 4]; 5] =
-                [*b"IHDR", *b"IDAT", *b"tRNS", *b"PLTE", *b"IEND"];
             let names = strip
                 .split(',')
                 .map(|x| {
-                    if x == "safe" || x == "all" {
+                    if x == "safe" || x == "all" || x == "metadata" {
                         return Err(
-                            "'safe' or 'all' presets for --strip should be used by themselves"
+                            "'safe', 'all' or 'metadata' presets for --strip should be used by themselves"
                                 .to_owned(),
                         );
                     }
                     let name = parse_chunk_name(x)?;
-                    if FORBIDDEN_CHUNKS.contains(&name) {
+                    // Critical chunks and tRNS are required
+                    if is_critical(&name) || &name == b"tRNS" {
                         return Err(format!("{} chunk is not allowed to be stripped", x));
                     }
                     Ok(name)
@@ -325,9 +368,26 @@ fn parse_opts_into_struct(
 
     if matches.get_flag("zopfli") {
         #[cfg(feature = "zopfli")]
-        if let Some(iterations) = NonZeroU8::new(15) {
-            opts.deflate = Deflaters::Zopfli { iterations };
+        {
+            let iterations = matches
+                .get_one::<i64>("iterations")
+                .map_or(15, |x| *x as u8);
+            if let Some(iterations) = NonZeroU8::new(iterations) {
+                opts.deflate = Deflaters::Zopfli { iterations };
+            }
+            if let Some(x) = matches.get_one::<i64>("splits") {
+                opts.zopfli_splits = *x as usize;
+            }
+        }
+    } else if let Some(&level) = matches.get_one::<i64>("zlib-ng") {
+        #[cfg(feature = "zlib-ng")]
+        {
+            opts.deflate = Deflaters::Zlibng { level: level as u8 };
         }
+        #[cfg(not(feature = "zlib-ng"))]
+        return Err(format!(
+            "zlib-ng level {level} requested, but oxipng was built without the zlib-ng feature"
+        ));
     } else if let Deflaters::Libdeflater { compression } = &mut opts.deflate {
         if let Some(x) = matches.get_one::<i64>("compression") {
             *compression = *x as u8;
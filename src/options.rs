@@ -1,13 +1,28 @@
+#[cfg(feature = "zopfli")]
+use std::num::NonZeroU8;
 use std::{
     fmt,
+    num::NonZeroU32,
+    ops::ControlFlow,
     path::{Path, PathBuf},
+    sync::Arc,
     time::Duration,
 };
 
 use indexmap::{indexset, IndexSet};
 use log::warn;
+use rgb::RGB8;
 
-use crate::{deflate::Deflaters, filters::RowFilter, headers::StripChunks, interlace::Interlacing};
+use crate::{
+    colors::ColorType,
+    deflate::Deflaters,
+    filters::RowFilter,
+    headers::{is_critical, StripChunks},
+    interlace::{AutoInterlacing, Interlacing},
+    reduction::palette::{PalettePin, PaletteSort, PaletteSortWeights},
+    reduction::quantize::Dithering,
+    PngError, PngResult,
+};
 
 #[derive(Clone, Debug)]
 pub enum OutFile {
@@ -15,6 +30,9 @@ pub enum OutFile {
     None,
     /// Write output to a file.
     ///
+    /// The output is written to a temporary file in the same directory, which is then renamed
+    /// over the destination, so an existing file is never left partially written.
+    ///
     /// * `path`: Path to write the output file. `None` means same as input.
     /// * `preserve_attrs`: Ensure the output file has the same permissions & timestamps as the input file.
     Path {
@@ -77,6 +95,106 @@ impl<T: Into<PathBuf>> From<T> for InFile {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+#[non_exhaustive]
+/// A milestone reached during optimization
+///
+/// More milestones may be added in future, so callbacks should ignore any they don't recognize.
+pub enum Progress {
+    /// An image was filtered and compressed with fast settings to evaluate it
+    Evaluated(RowFilter),
+    /// Reductions are complete and the format of the output image has been chosen
+    Reduced,
+    /// A full compression trial finished with the given filter
+    Compressed(RowFilter),
+}
+
+#[derive(Debug, PartialEq, Clone)]
+/// How to choose between the candidate reductions of an image
+pub enum CandidateSelection {
+    /// Choose the smallest candidate
+    Smallest,
+    /// Choose a candidate of the given color type if it is no more than `tolerance` percent larger
+    /// than the smallest
+    ///
+    /// Only the kind of color type is compared, not its transparency or palette.
+    PreferColorType {
+        color_type: ColorType,
+        tolerance: f32,
+    },
+}
+
+impl CandidateSelection {
+    /// The factor by which a candidate of the given color type may exceed the smallest size
+    pub(crate) fn size_bias(&self, color_type: &ColorType) -> f64 {
+        match self {
+            Self::PreferColorType {
+                color_type: preferred,
+                tolerance,
+            } if preferred.png_header_code() == color_type.png_header_code() => {
+                1.0 + f64::from(tolerance.max(0.0)) / 100.0
+            }
+            _ => 1.0,
+        }
+    }
+}
+
+#[derive(Clone)]
+/// A callback to receive progress updates during optimization
+///
+/// The callback may be called from multiple threads at once. Returning `ControlFlow::Break`
+/// cancels the optimization, which then fails with `PngError::Cancelled` and writes no output.
+pub struct ProgressCallback(Arc<dyn Fn(Progress) -> ControlFlow<()> + Send + Sync>);
+
+impl ProgressCallback {
+    pub fn new<F>(callback: F) -> Self
+    where
+        F: Fn(Progress) -> ControlFlow<()> + Send + Sync + 'static,
+    {
+        Self(Arc::new(callback))
+    }
+
+    #[inline]
+    pub(crate) fn call(&self, progress: Progress) -> ControlFlow<()> {
+        (self.0)(progress)
+    }
+}
+
+impl fmt::Debug for ProgressCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ProgressCallback")
+    }
+}
+
+#[derive(Clone)]
+/// A predicate deciding whether to keep each ancillary chunk, given its name and data
+///
+/// The predicate may be called from multiple threads at once. It is never called for critical
+/// chunks, which are always kept.
+pub struct ChunkFilter(Arc<ChunkPredicate>);
+
+type ChunkPredicate = dyn Fn(&[u8; 4], &[u8]) -> bool + Send + Sync;
+
+impl ChunkFilter {
+    pub fn new<F>(filter: F) -> Self
+    where
+        F: Fn(&[u8; 4], &[u8]) -> bool + Send + Sync + 'static,
+    {
+        Self(Arc::new(filter))
+    }
+
+    #[inline]
+    pub(crate) fn call(&self, name: &[u8; 4], data: &[u8]) -> bool {
+        (self.0)(name, data)
+    }
+}
+
+impl fmt::Debug for ChunkFilter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("ChunkFilter")
+    }
+}
+
 #[derive(Clone, Debug)]
 /// Options controlling the output of the `optimize` function
 pub struct Options {
@@ -84,6 +202,16 @@ pub struct Options {
     ///
     /// Default: `false`
     pub fix_errors: bool,
+    /// Recover as much as possible of image data that is truncated or corrupt, rather than
+    /// returning an `Err`.
+    ///
+    /// The image data is inflated up to the point of the error, and any missing rows are filled
+    /// with zeros. A mismatched checksum at the end of the data is ignored. The output is always
+    /// recompressed, but it is only written if it is smaller than the input unless `force` is
+    /// also set.
+    ///
+    /// Default: `false`
+    pub recover_image_data: bool,
     /// Write to output even if there was no improvement in compression.
     ///
     /// Default: `false`
@@ -92,6 +220,23 @@ pub struct Options {
     ///
     /// Default: `None,Sub,Entropy,Bigrams`
     pub filter: IndexSet<RowFilter>,
+    /// Score the filters of each row in the Brute strategy by compressing them with the main
+    /// deflater, rather than a fast libdeflater level
+    ///
+    /// This is exact but much slower, particularly with Zopfli. It only applies to the main
+    /// compression trials, not the fast evaluation.
+    ///
+    /// Default: `false`
+    pub exact_brute: bool,
+    /// Use only the Paeth filter for images that appear to be photographic, rather than trying
+    /// each of the selected filters
+    ///
+    /// An image is considered photographic if every row of its unfiltered data has at least this
+    /// much entropy, in bits per byte (0-8). Around 6 works well for typical photos. Only images
+    /// of at least 8 bits per channel that are not indexed are considered.
+    ///
+    /// Default: `None`
+    pub photo_filter_threshold: Option<f32>,
     /// Whether to change the interlacing type of the file.
     ///
     /// `None` will not change the current interlacing type.
@@ -100,8 +245,53 @@ pub struct Options {
     ///
     /// Default: `Some(Interlacing::None)`
     pub interlace: Option<Interlacing>,
+    /// Choose the interlacing of the output automatically, instead of using `interlace`
+    ///
+    /// Default: `None`
+    pub auto_interlace: Option<AutoInterlacing>,
+    /// Whether to choose filters separately for each pass of interlaced images
+    ///
+    /// Each pass is filtered with every strategy in `filter`, and the one which compresses best
+    /// is kept for that pass. This is in addition to the normal trials of whole-image filters.
+    ///
+    /// Default: `false`
+    pub per_pass_filters: bool,
+    /// Whether to also try the filter types used by the input image
+    ///
+    /// The filter type of each row is read from the input and reapplied in an additional trial,
+    /// which can beat the heuristics on files that were well filtered by another tool. This only
+    /// applies to non-interlaced input.
+    ///
+    /// Default: `false`
+    pub original_filters: bool,
+    /// A standard filter type to apply to every row, in place of the filter strategies
+    ///
+    /// This overrides `filter`, `per_pass_filters` and `original_filters`, which can suit decoders
+    /// that are fastest with a uniform filter. The image data is still compressed as usual. Only
+    /// `None`, `Sub`, `Up`, `Average` and `Paeth` are allowed.
+    ///
+    /// Default: `None`
+    pub force_filter: Option<RowFilter>,
     /// Whether to allow transparent pixels to be altered to improve compression.
+    ///
+    /// Default: `false`
     pub optimize_alpha: bool,
+    /// The color to use for fully transparent palette entries
+    ///
+    /// When `optimize_alpha` is enabled, all fully transparent entries in the palette of an
+    /// indexed image are merged into a single entry of this color.
+    ///
+    /// Default: `RGB8::new(0, 0, 0)`
+    pub transparent_palette_color: RGB8,
+    /// Whether to composite transparent pixels over the background color of a bKGD chunk
+    ///
+    /// This applies only to images where every pixel is either fully opaque or fully
+    /// transparent. The transparent pixels are replaced with the background color and the alpha
+    /// channel is removed, which is identical to the original when rendered over the background
+    /// color, but not over any other background. The bKGD chunk must not be stripped.
+    ///
+    /// Default: `false`
+    pub composite_background: bool,
     /// Whether to attempt bit depth reduction
     ///
     /// Default: `true`
@@ -114,10 +304,113 @@ pub struct Options {
     ///
     /// Default: `true`
     pub palette_reduction: bool,
+    /// Maximum distance between palette colors for them to be merged together
+    ///
+    /// Merging is a lossy transformation that only applies to images which are already indexed.
+    /// The distance is a weighted RGBA difference on the same scale as a single channel value.
+    /// A value of 0 disables merging.
+    ///
+    /// Default: `0`
+    pub palette_merge_threshold: u8,
+    /// Which ordering to use when sorting the palette
+    ///
+    /// Default: `Luma`
+    pub palette_sort: PaletteSort,
+    /// Weights of the objectives combined by the `Luma` palette sort
+    ///
+    /// The defaults sort by alpha and then luma. Increasing the frequency or background weights
+    /// moves the most common colors or the bKGD color towards the start of the palette.
+    ///
+    /// Default: alpha 1, luma 1, frequency 0, background 0
+    pub palette_sort_weights: PaletteSortWeights,
+    /// A color to keep at index 0 of the palette of indexed output
+    ///
+    /// Some software requires a particular color, such as the transparent color, to be first in
+    /// the palette. The color is moved there after the palette is sorted, or added to the palette
+    /// if the image doesn't use it. The rest of the palette keeps its order.
+    ///
+    /// Default: `None`
+    pub palette_pin: Option<PalettePin>,
+    /// Whether to keep the existing palette order of indexed images
+    ///
+    /// Unused and duplicate colors may still be removed, but the remaining colors will not be
+    /// reordered. Palettes created from non-indexed images are not affected.
+    ///
+    /// Default: `false`
+    pub preserve_palette_order: bool,
+    /// Whether to order a palette created from a non-indexed image to follow its suggested palette
+    ///
+    /// If the image has an sPLT chunk, the colors it lists are placed first in the new palette in
+    /// the same order, followed by any other colors. The palette is then not sorted further.
+    ///
+    /// Default: `false`
+    pub splt_palette: bool,
+    /// How to choose between the candidate reductions of an image
+    ///
+    /// This applies to the reductions that are evaluated against each other. Reductions that are
+    /// only a removal of bytes, such as dropping an unused alpha channel, are always performed.
+    ///
+    /// Default: `Smallest`
+    pub candidate_selection: CandidateSelection,
+    /// Whether to also try compressing with a single Huffman table for the whole stream
+    ///
+    /// Zopfli normally splits the data into blocks that each have their own Huffman table. When
+    /// this is set, it also compresses the data as a single block and keeps the smaller result,
+    /// which roughly doubles the compression time. This has no effect with libdeflater.
+    ///
+    /// Default: `false`
+    pub single_block_trial: bool,
+    /// Whether to mark the output as optimized, and skip inputs that are already marked
+    ///
+    /// The marker is a small private chunk holding a checksum of the image data, so it becomes
+    /// invalid if the image data is changed. A marked input is returned unchanged without being
+    /// optimized, except by `optimize_from_reader`. An input without the marker is written with
+    /// it even if the output is otherwise the same size. When this is not set, any existing
+    /// marker is removed from the output.
+    ///
+    /// Default: `false`
+    pub mark_optimized: bool,
+    /// Whether to decode the output and check that it matches the input image
+    ///
+    /// This guards against bugs in the reductions and filters, at the cost of decoding each
+    /// output. The pixels are compared as RGBA, so a change of color type, bit depth or
+    /// interlacing is allowed. The color of fully transparent pixels is only ignored if
+    /// `optimize_alpha` is set. When a lossy option is set, the output is instead compared with
+    /// the reduced image. If the decoded pixels differ, a warning is logged and the original file
+    /// is kept as the output. `RawImage::create_optimized_png` has no original to fall back to, so it
+    /// fails with `PngError::VerificationFailed` instead.
+    ///
+    /// Default: `false`
+    pub verify: bool,
+    /// Whether to skip producing the output, so that only the report of the changes is created
+    ///
+    /// The full optimization is performed, but the output is not serialized or written.
+    /// `optimize_from_memory_with_report` still gives the exact size the output would have, and
+    /// `optimize_files` leaves every file unchanged. Other functions ignore this.
+    ///
+    /// Default: `false`
+    pub dry_run: bool,
     /// Whether to attempt grayscale reduction
     ///
     /// Default: `true`
     pub grayscale_reduction: bool,
+    /// Expand the image to this color type before optimizing
+    ///
+    /// Indexed images can be expanded to RGB or RGBA, grayscale images to RGB, and an alpha
+    /// channel can be added to any image. Transparency is carried over, and the transparency
+    /// fields of the given color type are ignored. Color type reductions are disabled when this
+    /// is set, but bit depth reductions are still performed unless disabled separately.
+    ///
+    /// Default: `None`
+    pub force_color_type: Option<ColorType>,
+    /// Whether to attempt conversion to or from an indexed color type
+    ///
+    /// This only has an effect if `color_type_reduction` is enabled. Disabling it allows other
+    /// color type reductions, such as to grayscale or removal of the alpha channel, while keeping
+    /// the image indexed or non-indexed as it was.
+    ///
+    /// Default: `true`
+    pub indexed_reduction: bool,
     /// Whether to perform recoding of IDAT and other compressed chunks
     ///
     /// If any type of reduction is performed, IDAT recoding will be performed
@@ -125,29 +418,194 @@ pub struct Options {
     ///
     /// Default: `true`
     pub idat_recoding: bool,
+    /// Whether to only refilter and recompress the image data, leaving everything else intact
+    ///
+    /// All reductions and chunk transformations are disabled, regardless of the other options,
+    /// and every chunk is kept in its original order. The pixels are unchanged, including the
+    /// color of transparent pixels. The IDAT data is written as a single chunk unless
+    /// `max_idat_size` is set, and the tRNS chunk of an indexed image omits any entries beyond the last transparent one.
+    ///
+    /// Default: `false`
+    pub recompress_only: bool,
     /// Whether to forcibly reduce 16-bit to 8-bit by scaling
     ///
     /// Default: `false`
     pub scale_16: bool,
+    /// Whether to forcibly reduce 16-bit to 8-bit using error diffusion dithering
+    ///
+    /// This takes precedence over `scale_16`. Images that can be reduced losslessly are unaffected.
+    ///
+    /// Default: `false`
+    pub allow_lossy_bit_depth: bool,
+    /// Lossy: quantize RGB and RGBA images to a palette of at most this many colors (2-256)
+    ///
+    /// The palette is chosen by median cut and each pixel is mapped to the nearest color. Images
+    /// that already have few enough colors are converted to indexed losslessly as usual.
+    ///
+    /// Default: `None`
+    pub lossy_quantize: Option<u16>,
+    /// The dithering to use when quantizing with `lossy_quantize`
+    ///
+    /// Default: `None`
+    pub quantize_dither: Dithering,
+    /// Lossy: snap alpha values to this many evenly spaced levels (2-256)
+    ///
+    /// Each alpha value is rounded to the nearest level, with fully transparent and fully opaque
+    /// always being levels. This is done before other reductions and can allow more colors to be
+    /// merged. Pixels that become fully transparent have their color set to black.
+    ///
+    /// Default: `None`
+    pub alpha_levels: Option<u16>,
     /// Which chunks to strip from the PNG file, if any
     ///
+    /// When stripping is enabled, exact duplicates of repeatable chunks (tEXt, zTXt, iTXt and
+    /// sPLT) are also removed, keeping the first occurrence.
+    ///
     /// Default: `None`
     pub strip: StripChunks,
+    /// Whether to crop the frames of an APNG to the region that changes in each frame
+    ///
+    /// The composited animation remains identical. Frames which are disposed to the background
+    /// are left as they are.
+    ///
+    /// Default: `false`
+    pub crop_frames: bool,
+    /// Whether to remove gAMA and cHRM chunks when an sRGB chunk is present
+    ///
+    /// The sRGB chunk fully specifies gamma and chromaticities, so these chunks are redundant.
+    /// Note that some decoders ignore sRGB and rely on an explicit gAMA chunk.
+    ///
+    /// Default: `false`
+    pub strip_srgb_redundant: bool,
+    /// Whether to remove the eXIf chunk, regardless of the `strip` setting
+    ///
+    /// The eXIf chunk is otherwise treated as metadata that affects display, so it is kept by
+    /// `StripChunks::Safe` and removed by `StripChunks::All`.
+    ///
+    /// Default: `false`
+    pub strip_exif: bool,
+    /// Whether to remove the pHYs, oFFs, sCAL and pCAL chunks, regardless of the `strip` setting
+    ///
+    /// These chunks describe the physical geometry of the image, such as its scale or offset.
+    /// They are otherwise kept by `StripChunks::Safe` and removed by `StripChunks::Metadata`.
+    ///
+    /// Default: `false`
+    pub strip_physical: bool,
+    /// Whether to remove the tIME chunk, regardless of the `strip` setting
+    ///
+    /// Default: `false`
+    pub strip_time: bool,
+    /// Whether to set the tIME chunk to the current UTC time, adding one if there is none
+    ///
+    /// This records when the file was optimized. It has no effect if tIME chunks are being
+    /// stripped. Note that the output then differs on each run.
+    ///
+    /// Default: `false`
+    pub update_time: bool,
+    /// Whether to normalize a pHYs chunk that specifies only the pixel aspect ratio
+    ///
+    /// When the unit is unspecified, the ratio is reduced to lowest terms, and the chunk is
+    /// removed entirely if it declares square pixels. A pHYs chunk in meters is never changed.
+    ///
+    /// Default: `false`
+    pub normalize_phys: bool,
+    /// Whether to convert text chunks between tEXt and zTXt, whichever is smaller
+    ///
+    /// The keyword and text are preserved exactly. Conversion only happens if the other chunk
+    /// type is not being stripped.
+    ///
+    /// Default: `false`
+    pub convert_text: bool,
     /// Which DEFLATE algorithm to use
     ///
     /// Default: `Libdeflater`
     pub deflate: Deflaters,
+    /// Which DEFLATE algorithm to use for compressed ancillary chunks, such as iCCP, zTXt and
+    /// iTXt, or `None` to use the same as `deflate`
+    ///
+    /// The gains from slower algorithms on these small chunks are usually negligible.
+    ///
+    /// Default: `None`
+    pub aux_deflate: Option<Deflaters>,
+    /// Number of parts to split the image data into for compression with Zopfli
+    ///
+    /// Each part is compressed independently and in parallel, which is much faster on multicore
+    /// machines at the cost of slightly larger output. Parts are split on row boundaries. A value
+    /// of 0 or 1 compresses the data as a whole.
+    ///
+    /// Default: `1`
+    pub zopfli_splits: usize,
+    /// Maximum length of each IDAT chunk, in bytes
+    ///
+    /// The compressed image data is split into consecutive IDAT chunks of at most this length,
+    /// which some decoders and streaming consumers prefer. Splitting adds 12 bytes for each
+    /// additional chunk. `None` writes the data as a single chunk.
+    ///
+    /// Default: `None`
+    pub max_idat_size: Option<NonZeroU32>,
     /// Whether to use fast evaluation to pick the best filter
     ///
     /// Default: `true`
     pub fast_evaluation: bool,
 
+    /// Maximum number of threads to use for the optimization
+    ///
+    /// This creates a separate thread pool for each optimization. Use `None` to run in the
+    /// current (or global) rayon thread pool, such as when optimizing many files in parallel.
+    ///
+    /// Default: `None`
+    pub threads: Option<usize>,
+    /// Maximum estimated memory, in bytes, of the images optimized at once by `optimize_files`
+    ///
+    /// The memory of each image is estimated from its dimensions, channels and bit depth. Files
+    /// are processed in groups that fit within the limit, and an image that exceeds the limit
+    /// on its own is processed alone. Use `None` to bound the batch by the thread count only.
+    ///
+    /// Default: `None`
+    pub batch_memory_limit: Option<usize>,
+
     /// Maximum amount of time to spend on optimizations.
     /// Further potential optimizations are skipped if the timeout is exceeded.
+    ///
+    /// Zopfli compression is also stopped between blocks, in which case the best result found
+    /// so far is used, falling back to the result of the filter evaluation or the original image
+    /// data.
+    ///
+    /// Default: `None`
     pub timeout: Option<Duration>,
+    /// Callback to receive progress updates and optionally cancel the optimization
+    ///
+    /// Default: `None`
+    pub progress: Option<ProgressCallback>,
+    /// Predicate to decide which chunks to keep, in place of `strip`
+    ///
+    /// Each ancillary chunk of the input is passed to the predicate with its data, and is
+    /// stripped if it returns `false`. This allows decisions based on the chunk contents, such as
+    /// keeping only the text chunks with a certain keyword.
+    ///
+    /// Default: `None`
+    pub chunk_filter: Option<ChunkFilter>,
 }
 
 impl Options {
+    /// Whether any of the lossy transformations are enabled, so the pixels may not be preserved
+    pub(crate) fn is_lossy(&self) -> bool {
+        self.composite_background
+            || self.palette_merge_threshold > 0
+            || self.scale_16
+            || self.allow_lossy_bit_depth
+            || self.lossy_quantize.is_some()
+            || self.alpha_levels.is_some()
+    }
+
+    /// Whether the chunk should be kept, according to `chunk_filter` if set or `strip` otherwise
+    pub(crate) fn keep_chunk(&self, name: &[u8; 4], data: &[u8]) -> bool {
+        match &self.chunk_filter {
+            Some(filter) => is_critical(name) || filter.call(name, data),
+            None => self.strip.keep(name),
+        }
+    }
+
     pub fn from_preset(level: u8) -> Options {
         let opts = Options::default();
         match level {
@@ -169,6 +627,53 @@ impl Options {
         Options::from_preset(6)
     }
 
+    /// Construct options from a single compression effort, from 0 (fastest) to 10 (smallest)
+    ///
+    /// Efforts 0-6 are identical to the optimization presets. Higher efforts add Zopfli with
+    /// increasing iterations on top of the maximum preset, along with a trial of a single Huffman
+    /// table, and effort 10 also chooses interlacing automatically. Without the `zopfli` feature,
+    /// efforts 7-9 are identical to preset 6. Any field can be changed afterwards to override the
+    /// effort.
+    pub fn from_effort(effort: u8) -> Options {
+        if effort > 10 {
+            warn!("Effort above 10 doesn't exist and is identical to effort 10");
+        }
+        let mut opts = Options::from_preset(effort.min(6));
+        #[cfg(feature = "zopfli")]
+        if let Some(iterations) = match effort {
+            7 => NonZeroU8::new(15),
+            8 => NonZeroU8::new(30),
+            9 => NonZeroU8::new(60),
+            10.. => NonZeroU8::new(100),
+            _ => None,
+        } {
+            opts.deflate = Deflaters::Zopfli { iterations };
+            opts.single_block_trial = effort >= 8;
+        }
+        if effort >= 10 {
+            opts.auto_interlace = Some(AutoInterlacing::Trial);
+        }
+        opts
+    }
+
+    /// The deflater to use for compressed ancillary chunks
+    pub(crate) fn aux_deflater(&self) -> Deflaters {
+        self.aux_deflate.unwrap_or(self.deflate)
+    }
+
+    /// Check that the deflater and forced filter settings are supported
+    pub(crate) fn validate(&self) -> PngResult<()> {
+        self.deflate.validate()?;
+        self.aux_deflater().validate()?;
+        match self.force_filter {
+            Some(filter) if !RowFilter::STANDARD.contains(&filter) => Err(PngError::new(&format!(
+                "Cannot force the {} filter strategy; only standard filter types are allowed",
+                filter
+            ))),
+            _ => Ok(()),
+        }
+    }
+
     // The following methods make assumptions that they are operating
     // on an `Options` struct generated by the `default` method.
     fn apply_preset_0(mut self) -> Self {
@@ -233,20 +738,62 @@ impl Default for Options {
         // Default settings based on -o 2 from the CLI interface
         Options {
             fix_errors: false,
+            recover_image_data: false,
             force: false,
             filter: indexset! {RowFilter::None, RowFilter::Sub, RowFilter::Entropy, RowFilter::Bigrams},
+            exact_brute: false,
+            photo_filter_threshold: None,
             interlace: Some(Interlacing::None),
+            auto_interlace: None,
+            per_pass_filters: false,
+            original_filters: false,
+            force_filter: None,
             optimize_alpha: false,
+            transparent_palette_color: RGB8::new(0, 0, 0),
+            composite_background: false,
             bit_depth_reduction: true,
             color_type_reduction: true,
             palette_reduction: true,
+            palette_merge_threshold: 0,
+            palette_sort: PaletteSort::Luma,
+            palette_sort_weights: PaletteSortWeights::default(),
+            palette_pin: None,
+            preserve_palette_order: false,
+            splt_palette: false,
+            candidate_selection: CandidateSelection::Smallest,
+            single_block_trial: false,
+            mark_optimized: false,
+            verify: false,
+            dry_run: false,
             grayscale_reduction: true,
+            force_color_type: None,
+            indexed_reduction: true,
             idat_recoding: true,
+            recompress_only: false,
             scale_16: false,
+            allow_lossy_bit_depth: false,
+            lossy_quantize: None,
+            quantize_dither: Dithering::None,
+            alpha_levels: None,
             strip: StripChunks::None,
+            crop_frames: false,
+            strip_srgb_redundant: false,
+            strip_exif: false,
+            strip_physical: false,
+            strip_time: false,
+            update_time: false,
+            normalize_phys: false,
+            convert_text: false,
             deflate: Deflaters::Libdeflater { compression: 11 },
+            aux_deflate: None,
+            zopfli_splits: 1,
+            max_idat_size: None,
             fast_evaluation: true,
+            threads: None,
+            batch_memory_limit: None,
             timeout: None,
+            progress: None,
+            chunk_filter: None,
         }
     }
 }
@@ -1,4 +1,6 @@
 use std::{
+    cell::RefCell,
+    cmp::Ordering,
     fs::File,
     io::{BufReader, Read, Write},
     path::Path,
@@ -8,9 +10,12 @@ use std::{
 use bitvec::bitarr;
 use libdeflater::{CompressionLvl, Compressor};
 use log::warn;
-use rgb::ComponentSlice;
+use rayon::prelude::*;
+use rgb::{ComponentSlice, RGBA16};
 use rustc_hash::FxHashMap;
 
+#[cfg(not(feature = "parallel"))]
+use crate::rayon;
 use crate::{
     colors::{BitDepth, ColorType},
     deflate,
@@ -18,17 +23,24 @@ use crate::{
     filters::*,
     headers::*,
     interlace::{deinterlace_image, interlace_image, Interlacing},
-    Options,
+    reduction::{color::expanded_to_color_type, palette::PaletteReductionStats},
+    Deadline, Deflaters, Options,
 };
 
 pub(crate) mod scan_lines;
 
 use self::scan_lines::ScanLines;
 
-/// Compression level to use for the Brute filter strategy
-const BRUTE_LEVEL: i32 = 1; // 1 is fastest, 2-4 are not useful, 5 is slower but more effective
+/// Compression level to use for the Brute filter strategy, unless scoring with the main deflater
+const BRUTE_LEVEL: u8 = 1; // 1 is fastest, 2-4 are not useful, 5 is slower but more effective
+/// Deflater to use for the Brute filter strategy, unless scoring with the main deflater
+pub(crate) const BRUTE_DEFLATER: Deflaters = Deflaters::Libdeflater {
+    compression: BRUTE_LEVEL,
+};
 /// Number of lines to compress with the Brute filter strategy
 const BRUTE_LINES: usize = 4; // Values over 8 are generally not useful
+/// Penalty for each change in byte value with the WeightedSum filter strategy
+const WEIGHTED_SUM_TRANSITION: usize = 48;
 
 #[derive(Debug, Clone)]
 pub struct PngImage {
@@ -38,6 +50,19 @@ pub struct PngImage {
     pub data: Vec<u8>,
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// How much of a corrupt image data stream was recovered, when recovery is enabled
+pub struct RecoveredData {
+    /// The number of bytes that were inflated from the stream, including filter bytes
+    pub bytes: usize,
+    /// The number of complete rows that were recovered
+    ///
+    /// For interlaced images, this counts the rows of every pass.
+    pub rows: usize,
+    /// The number of rows in the image, which the missing rows were padded up to with zeros
+    pub total_rows: usize,
+}
+
 /// Contains all data relevant to a PNG image
 #[derive(Debug, Clone)]
 pub struct PngData {
@@ -46,7 +71,18 @@ pub struct PngData {
     /// The filtered and compressed data of the IDAT chunk
     pub idat_data: Vec<u8>,
     /// All non-critical chunks from the PNG are stored here
+    ///
+    /// Empty IDAT and PLTE entries mark where those chunks were positioned in the input.
     pub aux_chunks: Vec<Chunk>,
+    /// How much of the image data was recovered, if it was corrupt and `recover_image_data` is set
+    pub recovered: Option<RecoveredData>,
+    /// The filter type of each row in the input, if `original_filters` is set and the input
+    /// is not interlaced
+    pub original_filters: Option<Vec<u8>>,
+    /// Statistics of the palette reduction, if the image has been optimized
+    pub palette_stats: Option<PaletteReductionStats>,
+    /// The filter chosen for each interlacing pass, if they were chosen separately
+    pub pass_filters: Vec<RowFilter>,
 }
 
 impl PngData {
@@ -94,70 +130,71 @@ impl PngData {
         byte_offset += 8;
 
         // Read the data chunks
-        let mut idat_data: Vec<u8> = Vec::new();
-        let mut key_chunks: FxHashMap<[u8; 4], Vec<u8>> = FxHashMap::default();
-        let mut aux_chunks: Vec<Chunk> = Vec::new();
+        let mut chunks = ChunkCollector::default();
         while let Some(chunk) = parse_next_chunk(byte_data, &mut byte_offset, opts.fix_errors)? {
-            match &chunk.name {
-                b"IDAT" => {
-                    if idat_data.is_empty() {
-                        // Keep track of where the first IDAT sits relative to other chunks
-                        aux_chunks.push(Chunk {
-                            name: chunk.name,
-                            data: Vec::new(),
-                        })
-                    }
-                    idat_data.extend_from_slice(chunk.data);
-                }
-                b"IHDR" | b"PLTE" | b"tRNS" => {
-                    key_chunks.insert(chunk.name, chunk.data.to_owned());
-                }
-                _ => {
-                    if opts.strip.keep(&chunk.name) {
-                        aux_chunks.push(Chunk {
-                            name: chunk.name,
-                            data: chunk.data.to_owned(),
-                        })
-                    } else if chunk.name == *b"acTL" {
-                        warn!(
-                            "Stripping animation data from APNG - image will become standard PNG"
-                        );
-                    }
-                }
-            }
+            chunks.add(chunk, opts);
         }
+        chunks.finish(opts)
+    }
 
-        // Parse the chunks into our PngData
-        if idat_data.is_empty() {
-            return Err(PngError::ChunkMissing("IDAT"));
+    /// Create a new `PngData` struct by reading from a stream, one chunk at a time
+    ///
+    /// Only the chunks that are kept are held in memory, rather than the entire file.
+    /// Returns the number of bytes read along with the `PngData`.
+    pub fn from_reader<R: Read>(mut reader: R, opts: &Options) -> Result<(Self, usize), PngError> {
+        let mut header = [0; 8];
+        read_exact(&mut reader, &mut header)?;
+        if !file_header_is_valid(&header) {
+            return Err(PngError::NotPNG);
         }
-        let ihdr_chunk = match key_chunks.remove(b"IHDR") {
-            Some(ihdr) => ihdr,
-            None => return Err(PngError::ChunkMissing("IHDR")),
-        };
-        let ihdr = parse_ihdr_chunk(
-            &ihdr_chunk,
-            key_chunks.remove(b"PLTE"),
-            key_chunks.remove(b"tRNS"),
-        )?;
-        let raw_data = deflate::inflate(idat_data.as_ref(), ihdr.raw_data_size())?;
-
-        // Reject files with incorrect width/height or truncated data
-        if raw_data.len() != ihdr.raw_data_size() {
-            return Err(PngError::TruncatedData);
+        let mut bytes_read = header.len();
+
+        // Read the data chunks
+        let mut chunks = ChunkCollector::default();
+        let mut data = Vec::new();
+        while let Some((name, crc_valid)) =
+            read_next_chunk(&mut reader, &mut data, opts.fix_errors)?
+        {
+            bytes_read += data.len() + 12;
+            chunks.add(
+                RawChunk {
+                    name,
+                    data: &data,
+                    crc_valid,
+                },
+                opts,
+            );
         }
+        // The IEND chunk
+        bytes_read += 12;
+        Ok((chunks.finish(opts)?, bytes_read))
+    }
 
-        let mut raw = PngImage {
-            ihdr,
-            data: raw_data,
-        };
-        raw.data = raw.unfilter_image()?;
-        // Return the PngData
-        Ok(Self {
-            idat_data,
-            raw: Arc::new(raw),
-            aux_chunks,
-        })
+    /// Return the coding-independent code points of the image, if it has a cICP chunk
+    pub fn cicp(&self) -> Option<CicpData> {
+        let chunk = self.aux_chunks.iter().find(|c| &c.name == b"cICP")?;
+        parse_cicp_chunk(&chunk.data).ok()
+    }
+
+    /// Return the stereo layout of the image, if it has a valid sTER chunk
+    pub fn stereo_layout(&self) -> Option<StereoLayout> {
+        let chunk = self.aux_chunks.iter().find(|c| &c.name == b"sTER")?;
+        parse_ster_chunk(&chunk.data).ok()
+    }
+
+    /// Return the last modification time of the image, if it has a valid tIME chunk
+    pub fn modification_time(&self) -> Option<ModificationTime> {
+        let chunk = self.aux_chunks.iter().find(|c| &c.name == b"tIME")?;
+        parse_time_chunk(&chunk.data).ok()
+    }
+
+    /// Return the valid suggested palettes of the image, from its sPLT chunks
+    pub fn suggested_palettes(&self) -> Vec<SuggestedPalette> {
+        self.aux_chunks
+            .iter()
+            .filter(|c| &c.name == b"sPLT")
+            .filter_map(|c| parse_splt_chunk(&c.data).ok())
+            .collect()
     }
 
     /// Return an estimate of the output size which can help with evaluation of very small data
@@ -165,8 +202,33 @@ impl PngData {
         self.idat_data.len() + self.raw.key_chunks_size()
     }
 
+    /// Calculate the exact size of the PNG bytestream that `output` would produce
+    pub fn output_size(&self, opts: &Options) -> usize {
+        // Signature, IHDR, IDAT and IEND
+        let mut size = 8 + (12 + 13) + self.idat_data.len() + 12;
+        size += 12 * self.idat_chunks(opts).len();
+        size += self.raw.key_chunks_size();
+        size += self
+            .aux_chunks
+            .iter()
+            .filter(|c| !is_position_marker(c))
+            .map(|c| 12 + c.data.len())
+            .sum::<usize>();
+        size
+    }
+
+    /// Split the IDAT data into the chunks that will be written, according to `max_idat_size`
+    fn idat_chunks(&self, opts: &Options) -> Vec<&[u8]> {
+        match opts.max_idat_size {
+            Some(max_size) if !self.idat_data.is_empty() => {
+                self.idat_data.chunks(max_size.get() as usize).collect()
+            }
+            _ => vec![&self.idat_data],
+        }
+    }
+
     /// Format the `PngData` struct into a valid PNG bytestream
-    pub fn output(&self) -> Vec<u8> {
+    pub fn output(&self, opts: &Options) -> Vec<u8> {
         // PNG header
         let mut output = vec![0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
         // IHDR
@@ -185,11 +247,19 @@ impl PngData {
         write_png_block(b"IHDR", &ihdr_data, &mut output);
         // Ancillary chunks - split into those that come before IDAT and those that come after
         let mut aux_split = self.aux_chunks.split(|c| &c.name == b"IDAT");
-        let aux_pre = aux_split.next().unwrap();
-        for chunk in aux_pre
-            .iter()
-            .filter(|c| !(&c.name == b"bKGD" || &c.name == b"hIST" || &c.name == b"tRNS"))
-        {
+        let mut aux_pre = aux_split.next().unwrap();
+        // If the palette is kept, the chunks that followed it in the input should still do so
+        let mut aux_post_plte: &[Chunk] = &[];
+        if let (ColorType::Indexed { .. }, Some(idx)) = (
+            &self.raw.ihdr.color_type,
+            aux_pre.iter().position(|c| &c.name == b"PLTE"),
+        ) {
+            aux_post_plte = &aux_pre[idx + 1..];
+            aux_pre = &aux_pre[..idx];
+        }
+        for chunk in aux_pre.iter().filter(|c| {
+            !(&c.name == b"bKGD" || &c.name == b"hIST" || &c.name == b"tRNS" || &c.name == b"PLTE")
+        }) {
             write_png_block(&chunk.name, &chunk.data, &mut output);
         }
         // Palette and transparency
@@ -227,11 +297,17 @@ impl PngData {
         {
             write_png_block(&chunk.name, &chunk.data, &mut output);
         }
+        // Chunks that followed PLTE in the input, in their original order
+        for chunk in aux_post_plte {
+            write_png_block(&chunk.name, &chunk.data, &mut output);
+        }
         // IDAT data
-        write_png_block(b"IDAT", &self.idat_data, &mut output);
+        for idat in self.idat_chunks(opts) {
+            write_png_block(b"IDAT", idat, &mut output);
+        }
         // Ancillary chunks that come after IDAT
         for aux_post in aux_split {
-            for chunk in aux_post {
+            for chunk in aux_post.iter().filter(|c| !is_position_marker(c)) {
                 write_png_block(&chunk.name, &chunk.data, &mut output);
             }
         }
@@ -242,6 +318,242 @@ impl PngData {
     }
 }
 
+/// Collects the chunks of a PNG file as they are read
+#[derive(Default)]
+struct ChunkCollector {
+    idat_data: Vec<u8>,
+    key_chunks: FxHashMap<[u8; 4], Vec<u8>>,
+    aux_chunks: Vec<Chunk>,
+}
+
+impl ChunkCollector {
+    fn add(&mut self, chunk: RawChunk<'_>, opts: &Options) {
+        if !chunk.crc_valid {
+            warn!(
+                "Ignoring CRC mismatch in {} chunk",
+                String::from_utf8_lossy(&chunk.name)
+            );
+        }
+        match &chunk.name {
+            b"IDAT" => {
+                if self.idat_data.is_empty() {
+                    // Keep track of where the first IDAT sits relative to other chunks
+                    self.aux_chunks.push(Chunk {
+                        name: chunk.name,
+                        data: Vec::new(),
+                    })
+                }
+                self.idat_data.extend_from_slice(chunk.data);
+            }
+            b"PLTE" => {
+                if !self.key_chunks.contains_key(&chunk.name) {
+                    // Keep track of where PLTE sits relative to other chunks
+                    self.aux_chunks.push(Chunk {
+                        name: chunk.name,
+                        data: Vec::new(),
+                    })
+                }
+                self.key_chunks.insert(chunk.name, chunk.data.to_owned());
+            }
+            b"IHDR" | b"tRNS" => {
+                self.key_chunks.insert(chunk.name, chunk.data.to_owned());
+            }
+            // A marker is only valid for the image data it was created with, so a new one is
+            // added to the output if required
+            &MARKER_CHUNK if !opts.recompress_only => {}
+            b"cICP" if opts.keep_chunk(&chunk.name, chunk.data) && !opts.recompress_only => {
+                // Drop invalid cICP chunks rather than have them misinterpreted
+                match parse_cicp_chunk(chunk.data) {
+                    Ok(_) => self.aux_chunks.push(Chunk {
+                        name: chunk.name,
+                        data: chunk.data.to_owned(),
+                    }),
+                    Err(e) => warn!("Removing cICP chunk: {}", e),
+                }
+            }
+            b"sTER" if opts.keep_chunk(&chunk.name, chunk.data) && !opts.recompress_only => {
+                // Drop invalid sTER chunks rather than have them misinterpreted
+                match parse_ster_chunk(chunk.data) {
+                    Ok(_) => self.aux_chunks.push(Chunk {
+                        name: chunk.name,
+                        data: chunk.data.to_owned(),
+                    }),
+                    Err(e) => warn!("Removing sTER chunk: {}", e),
+                }
+            }
+            b"tIME" if opts.keep_chunk(&chunk.name, chunk.data) && !opts.recompress_only => {
+                // Drop invalid tIME chunks rather than have them misinterpreted
+                match parse_time_chunk(chunk.data) {
+                    Ok(_) => self.aux_chunks.push(Chunk {
+                        name: chunk.name,
+                        data: chunk.data.to_owned(),
+                    }),
+                    Err(e) => warn!("Removing tIME chunk: {}", e),
+                }
+            }
+            b"pHYs" | b"oFFs" | b"sCAL" | b"pCAL"
+                if opts.keep_chunk(&chunk.name, chunk.data) && !opts.recompress_only =>
+            {
+                // Drop malformed physical chunks rather than have them misinterpreted
+                match validate_physical_chunk(&chunk.name, chunk.data) {
+                    Ok(_) => self.aux_chunks.push(Chunk {
+                        name: chunk.name,
+                        data: chunk.data.to_owned(),
+                    }),
+                    Err(e) => warn!(
+                        "Removing {} chunk: {}",
+                        String::from_utf8_lossy(&chunk.name),
+                        e
+                    ),
+                }
+            }
+            _ => {
+                if opts.recompress_only || opts.keep_chunk(&chunk.name, chunk.data) {
+                    self.aux_chunks.push(Chunk {
+                        name: chunk.name,
+                        data: chunk.data.to_owned(),
+                    })
+                } else if chunk.name == *b"acTL" {
+                    warn!("Stripping animation data from APNG - image will become standard PNG");
+                }
+            }
+        }
+    }
+
+    /// Parse the chunks into our PngData
+    fn finish(mut self, opts: &Options) -> Result<PngData, PngError> {
+        if self.idat_data.is_empty() {
+            return Err(PngError::ChunkMissing("IDAT"));
+        }
+        let ihdr_chunk = match self.key_chunks.remove(b"IHDR") {
+            Some(ihdr) => ihdr,
+            None => return Err(PngError::ChunkMissing("IHDR")),
+        };
+        let palette = self.key_chunks.remove(b"PLTE");
+        let mut trns = self.key_chunks.remove(b"tRNS");
+        if let (Some(palette), Some(trns_data)) = (&palette, &trns) {
+            // A tRNS longer than the palette is invalid and decoders ignore it entirely
+            if ihdr_chunk.get(9) == Some(&3) && trns_data.len() > palette.len() / 3 {
+                warn!("Repairing tRNS chunk: ignoring it as it is longer than the palette");
+                trns = None;
+            }
+        }
+        let ihdr = parse_ihdr_chunk(&ihdr_chunk, palette, trns)?;
+        let raw_size = ihdr.raw_data_size();
+        let mut recovered = None;
+        let raw_data = match deflate::inflate(self.idat_data.as_ref(), raw_size) {
+            // Reject files with incorrect width/height or truncated data
+            Ok(data) if data.len() == raw_size => data,
+            Ok(_) if !opts.recover_image_data => return Err(PngError::TruncatedData),
+            Err(err) if !opts.recover_image_data => return Err(err),
+            _ => {
+                let mut data = deflate::inflate_partial(&self.idat_data, raw_size);
+                let bytes = data.len();
+                data.resize(raw_size, 0);
+                recovered = Some(bytes);
+                data
+            }
+        };
+
+        let mut raw = PngImage {
+            ihdr,
+            data: raw_data,
+        };
+        let recovered = recovered.map(|bytes| {
+            let (mut rows, mut total_rows, mut end) = (0, 0, 0);
+            for line in raw.scan_lines(true) {
+                end += line.data.len() + 1;
+                total_rows += 1;
+                rows += usize::from(end <= bytes);
+            }
+            warn!(
+                "Recovered {} of {} rows ({} bytes) from corrupt image data",
+                rows, total_rows, bytes
+            );
+            RecoveredData {
+                bytes,
+                rows,
+                total_rows,
+            }
+        });
+        let original_filters = (opts.original_filters && raw.ihdr.interlaced == Interlacing::None)
+            .then(|| raw.scan_lines(true).map(|line| line.filter).collect());
+        raw.data = raw.unfilter_image()?;
+        // Return the PngData
+        Ok(PngData {
+            idat_data: self.idat_data,
+            raw: Arc::new(raw),
+            aux_chunks: self.aux_chunks,
+            recovered,
+            original_filters,
+            palette_stats: None,
+            pass_filters: Vec::new(),
+        })
+    }
+}
+
+/// A decoded PNG image, giving read-only access to its headers and ancillary chunks
+///
+/// This can be used to inspect an image before and after optimization.
+#[derive(Debug, Clone)]
+pub struct DecodedPng(PngData);
+
+impl DecodedPng {
+    /// Decode a PNG image from a slice
+    pub fn from_slice(data: &[u8], opts: &Options) -> Result<Self, PngError> {
+        PngData::from_slice(data, opts).map(Self)
+    }
+
+    /// The headers of the image from the IHDR chunk
+    #[inline]
+    pub fn ihdr(&self) -> &IhdrData {
+        &self.0.raw.ihdr
+    }
+
+    /// The color type of the image
+    #[inline]
+    pub fn color_type(&self) -> &ColorType {
+        &self.ihdr().color_type
+    }
+
+    /// The bit depth of the image
+    #[inline]
+    pub fn bit_depth(&self) -> BitDepth {
+        self.ihdr().bit_depth
+    }
+
+    /// The width and height of the image in pixels
+    #[inline]
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.ihdr().width, self.ihdr().height)
+    }
+
+    /// The ancillary chunks of the image, in file order
+    pub fn ancillary_chunks(&self) -> impl Iterator<Item = &Chunk> {
+        self.0.aux_chunks.iter().filter(|c| !is_position_marker(c))
+    }
+
+    /// The coding-independent code points of the image, if it has a cICP chunk
+    pub fn cicp(&self) -> Option<CicpData> {
+        self.0.cicp()
+    }
+
+    /// The stereo layout of the image, if it has a valid sTER chunk
+    pub fn stereo_layout(&self) -> Option<StereoLayout> {
+        self.0.stereo_layout()
+    }
+
+    /// The last modification time of the image, if it has a valid tIME chunk
+    pub fn modification_time(&self) -> Option<ModificationTime> {
+        self.0.modification_time()
+    }
+
+    /// The valid suggested palettes of the image, from its sPLT chunks
+    pub fn suggested_palettes(&self) -> Vec<SuggestedPalette> {
+        self.0.suggested_palettes()
+    }
+}
+
 impl PngImage {
     /// Convert the image to the specified interlacing type
     /// Returns true if the interlacing was changed, false otherwise
@@ -279,6 +591,55 @@ impl PngImage {
         }
     }
 
+    /// Whether the other image has the same dimensions and pixels as this one
+    ///
+    /// The pixels are compared as 16-bit RGBA, so the images may differ in color type, bit depth
+    /// and interlacing. If `ignore_transparent` is set, the color of pixels that are fully
+    /// transparent in both images is not compared, as alpha optimization may have altered it.
+    pub fn same_pixels(&self, other: &PngImage, ignore_transparent: bool) -> bool {
+        self.same_masked_pixels(other, ignore_transparent, RGBA16::new(!0, !0, !0, !0))
+    }
+
+    /// Whether the other image has the same pixels as this one, comparing only the bits of each
+    /// 16-bit RGBA sample that are set in `mask`
+    fn same_masked_pixels(&self, other: &PngImage, ignore_transparent: bool, mask: RGBA16) -> bool {
+        if self.ihdr.width != other.ihdr.width || self.ihdr.height != other.ihdr.height {
+            return false;
+        }
+        let masked =
+            |c: &RGBA16| RGBA16::new(c.r & mask.r, c.g & mask.g, c.b & mask.b, c.a & mask.a);
+        let (a, b) = (self.rgba16_pixels(), other.rgba16_pixels());
+        a.len() == b.len()
+            && a.iter().zip(&b).all(|(x, y)| {
+                masked(x) == masked(y) || (ignore_transparent && x.a == 0 && y.a == 0)
+            })
+    }
+
+    /// The pixels of the image in progressive order, expanded to 16-bit RGBA
+    fn rgba16_pixels(&self) -> Vec<RGBA16> {
+        let progressive = self.change_interlacing(Interlacing::None);
+        let png = progressive.as_ref().unwrap_or(self);
+        // This only fails if the image is already RGBA
+        let expanded = expanded_to_color_type(png, &ColorType::RGBA);
+        let png = expanded.as_ref().unwrap_or(png);
+        if png.ihdr.bit_depth == BitDepth::Sixteen {
+            png.data
+                .chunks_exact(8)
+                .map(|px| {
+                    let sample = |i: usize| u16::from_be_bytes([px[i], px[i + 1]]);
+                    RGBA16::new(sample(0), sample(2), sample(4), sample(6))
+                })
+                .collect()
+        } else {
+            // Scale 8-bit samples so that they match 16-bit samples reduced without loss
+            let scale = |v: u8| u16::from(v) * 257;
+            png.data
+                .chunks_exact(4)
+                .map(|px| RGBA16::new(scale(px[0]), scale(px[1]), scale(px[2]), scale(px[3])))
+                .collect()
+        }
+    }
+
     /// Calculate the size of the PLTE and tRNS chunks
     pub fn key_chunks_size(&self) -> usize {
         match &self.ihdr.color_type {
@@ -302,8 +663,37 @@ impl PngImage {
         ScanLines::new(self, has_filter)
     }
 
+    /// Whether every row of the image has at least the given Shannon entropy, in bits per byte
+    ///
+    /// This is a sign of photographic content, where Paeth usually performs best. Images with
+    /// less than 8 bits per channel or a palette are never considered photographic.
+    pub fn is_photographic(&self, min_entropy: f32) -> bool {
+        if self.ihdr.bit_depth < BitDepth::Eight
+            || matches!(self.ihdr.color_type, ColorType::Indexed { .. })
+        {
+            return false;
+        }
+        let mut counts = [0u32; 256];
+        self.scan_lines(false).all(|line| {
+            counts.fill(0);
+            for &b in line.data {
+                counts[b as usize] += 1;
+            }
+            let len = line.data.len() as f32;
+            let entropy: f32 = counts
+                .iter()
+                .filter(|&&c| c > 0)
+                .map(|&c| {
+                    let p = c as f32 / len;
+                    -p * p.log2()
+                })
+                .sum();
+            entropy >= min_entropy
+        })
+    }
+
     /// Reverse all filters applied on the image, returning an unfiltered IDAT bytestream
-    fn unfilter_image(&self) -> Result<Vec<u8>, PngError> {
+    pub(crate) fn unfilter_image(&self) -> Result<Vec<u8>, PngError> {
         let mut unfiltered = Vec::with_capacity(self.data.len());
         let bpp = self.bytes_per_channel() * self.channels_per_pixel();
         let mut last_line: Vec<u8> = Vec::new();
@@ -326,6 +716,33 @@ impl PngImage {
 
     /// Apply the specified filter type to all rows in the image
     pub fn filter_image(&self, filter: RowFilter, optimize_alpha: bool) -> Vec<u8> {
+        self.filter_image_with_deflater(filter, optimize_alpha, BRUTE_DEFLATER)
+    }
+
+    /// Apply the specified filter type to all rows in the image, using the given deflater to
+    /// score the attempts of the Brute filter strategy
+    pub fn filter_image_with_deflater(
+        &self,
+        filter: RowFilter,
+        optimize_alpha: bool,
+        brute_deflater: Deflaters,
+    ) -> Vec<u8> {
+        self.filter_image_until(filter, optimize_alpha, brute_deflater, None)
+            .unwrap()
+    }
+
+    /// Apply the specified filter type to all rows in the image, as in
+    /// `filter_image_with_deflater`, stopping with `None` if the deadline passes
+    ///
+    /// The deadline is checked before each row, as the heuristic strategies can take a long time
+    /// on large images, particularly Brute when scored with Zopfli.
+    pub(crate) fn filter_image_until(
+        &self,
+        filter: RowFilter,
+        optimize_alpha: bool,
+        brute_deflater: Deflaters,
+        deadline: Option<&Deadline>,
+    ) -> Option<Vec<u8>> {
         let mut filtered = Vec::with_capacity(self.data.len());
         let bpp = self.bytes_per_channel() * self.channels_per_pixel();
         // If alpha optimization is enabled, determine how many bytes of alpha there are per pixel
@@ -335,10 +752,28 @@ impl PngImage {
             0
         };
 
+        // The transition penalty of WeightedSum makes RGBA images larger, so use MinSum for them
+        let filter = if filter == RowFilter::WeightedSum && self.ihdr.color_type == ColorType::RGBA
+        {
+            RowFilter::MinSum
+        } else {
+            filter
+        };
+
+        // Ties in MinSum are broken by inverting the samples, which is only done for grayscale
+        // images such as scanned documents
+        let invert_ties = matches!(self.ihdr.color_type, ColorType::Grayscale { .. });
+        let mut prev_inverted = Vec::new();
+        let mut inverted_line = Vec::new();
+        let mut inverted_buf = Vec::new();
+
         let mut prev_line = Vec::new();
         let mut prev_pass: Option<u8> = None;
         let mut f_buf = Vec::new();
         for line in self.scan_lines(false) {
+            if deadline.is_some_and(|deadline| deadline.passed()) {
+                return None;
+            }
             if prev_pass != line.pass || line.data.len() != prev_line.len() {
                 prev_line = vec![0; line.data.len()];
             }
@@ -355,27 +790,90 @@ impl PngImage {
                 filter.filter_line(bpp, &mut line_data, &prev_line, &mut f_buf, alpha_bytes);
                 filtered.extend_from_slice(&f_buf);
                 prev_line = line_data;
+            } else if prev_pass == line.pass && line_data == prev_line {
+                // A row identical to the previous one is all zeros with the Up filter,
+                // which deflate compresses better than anything a heuristic might pick
+                RowFilter::Up.filter_line(bpp, &mut line_data, &prev_line, &mut f_buf, alpha_bytes);
+                filtered.extend_from_slice(&f_buf);
+                prev_line = line_data;
             } else {
                 // Heuristic filter selection strategies
                 let mut best_line = Vec::new();
                 let mut best_line_raw = Vec::new();
                 // Avoid vertical filtering on first line of each interlacing pass
-                let try_filters = if prev_pass == line.pass {
-                    RowFilter::STANDARD.iter()
+                let try_filters: &[RowFilter] = if prev_pass == line.pass {
+                    &RowFilter::STANDARD
                 } else {
-                    RowFilter::SINGLE_LINE.iter()
+                    &RowFilter::SINGLE_LINE
                 };
                 match filter {
                     RowFilter::MinSum => {
                         // MSAD algorithm mentioned in libpng reference docs
                         // http://www.libpng.org/pub/png/book/chapter09.html
                         let mut best_size = usize::MAX;
+                        let mut best_filter = RowFilter::None;
+                        let mut best_inverted = None;
+                        let mut prev_inverted_ready = false;
+                        for &f in try_filters {
+                            f.filter_line(bpp, &mut line_data, &prev_line, &mut f_buf, alpha_bytes);
+                            let size = sum_abs(&f_buf);
+                            let better = match size.cmp(&best_size) {
+                                Ordering::Less => {
+                                    best_inverted = None;
+                                    true
+                                }
+                                Ordering::Equal if invert_ties => {
+                                    // Break ties with the sum for the inverted samples
+                                    if !prev_inverted_ready {
+                                        prev_inverted.clear();
+                                        prev_inverted.extend(prev_line.iter().map(|&b| !b));
+                                        prev_inverted_ready = true;
+                                    }
+                                    let mut inverted_sum = |filter, line: &[u8]| {
+                                        inverted_sum_abs(
+                                            filter,
+                                            bpp,
+                                            line,
+                                            &prev_inverted,
+                                            &mut inverted_line,
+                                            &mut inverted_buf,
+                                        )
+                                    };
+                                    let inverted = inverted_sum(f, &line_data);
+                                    let best = *best_inverted.get_or_insert_with(|| {
+                                        inverted_sum(best_filter, &best_line_raw)
+                                    });
+                                    if inverted < best {
+                                        best_inverted = Some(inverted);
+                                    }
+                                    inverted < best
+                                }
+                                Ordering::Equal | Ordering::Greater => false,
+                            };
+                            if better {
+                                best_size = size;
+                                best_filter = f;
+                                std::mem::swap(&mut best_line, &mut f_buf);
+                                best_line_raw.clone_from(&line_data);
+                            }
+                        }
+                    }
+                    RowFilter::WeightedSum => {
+                        // Sum of absolute differences, plus a penalty for each change in a sample
+                        // from the previous pixel
+                        // Runs of identical pixels are cheap for deflate regardless of their magnitude
+                        let mut best_size = usize::MAX;
                         for f in try_filters {
                             f.filter_line(bpp, &mut line_data, &prev_line, &mut f_buf, alpha_bytes);
-                            let size = f_buf.iter().fold(0, |acc, &x| {
-                                let signed = x as i8;
-                                acc + signed.unsigned_abs() as usize
-                            });
+                            let sum = sum_abs(&f_buf);
+                            let samples = &f_buf[1..];
+                            let transitions = samples
+                                .iter()
+                                .skip(bpp)
+                                .zip(samples)
+                                .filter(|(a, b)| a != b)
+                                .count();
+                            let size = sum + transitions * WEIGHTED_SUM_TRANSITION;
                             if size < best_size {
                                 best_size = size;
                                 std::mem::swap(&mut best_line, &mut f_buf);
@@ -448,28 +946,34 @@ impl PngImage {
                     RowFilter::Brute => {
                         // Brute force by compressing each filter attempt
                         // Similar to that of LodePNG but includes some previous lines for context
-                        let mut best_size = usize::MAX;
-                        let line_start = filtered.len();
-                        filtered.resize(filtered.len() + line.data.len() + 1, 0);
-                        let mut compressor =
-                            Compressor::new(CompressionLvl::new(BRUTE_LEVEL).unwrap());
-                        let limit = filtered.len().min((line.data.len() + 1) * BRUTE_LINES);
-                        let capacity = compressor.zlib_compress_bound(limit);
-                        let mut dest = vec![0; capacity];
-
-                        for f in try_filters {
-                            f.filter_line(bpp, &mut line_data, &prev_line, &mut f_buf, alpha_bytes);
-                            filtered[line_start..].copy_from_slice(&f_buf);
-                            let size = compressor
-                                .zlib_compress(&filtered[filtered.len() - limit..], &mut dest)
-                                .unwrap_or(usize::MAX);
-                            if size < best_size {
-                                best_size = size;
-                                std::mem::swap(&mut best_line, &mut f_buf);
-                                best_line_raw.clone_from(&line_data);
-                            }
-                        }
-                        filtered.resize(line_start, 0);
+                        // The attempts are independent of each other so can be performed in parallel
+                        let context_len = filtered
+                            .len()
+                            .min((line.data.len() + 1) * (BRUTE_LINES - 1));
+                        let context = &filtered[filtered.len() - context_len..];
+                        let line_data = &line_data;
+                        let prev_line = &prev_line;
+                        // Ties go to the earliest filter to keep the result deterministic
+                        (_, best_line, best_line_raw) = try_filters
+                            .par_iter()
+                            .map(|f| {
+                                let mut line_data = line_data.clone();
+                                let mut f_buf = Vec::new();
+                                f.filter_line(
+                                    bpp,
+                                    &mut line_data,
+                                    prev_line,
+                                    &mut f_buf,
+                                    alpha_bytes,
+                                );
+                                let mut input = Vec::with_capacity(context_len + f_buf.len());
+                                input.extend_from_slice(context);
+                                input.extend_from_slice(&f_buf);
+                                let size = brute_size(brute_deflater, &input);
+                                (size, f_buf, line_data)
+                            })
+                            .min_by_key(|(size, ..)| *size)
+                            .unwrap();
                     }
                     _ => unreachable!(),
                 }
@@ -479,8 +983,58 @@ impl PngImage {
 
             prev_pass = line.pass;
         }
-        filtered
+        Some(filtered)
     }
+
+    /// Apply an explicit sequence of filter types, one for each row in the image
+    ///
+    /// Rows are counted across all interlacing passes. Only the standard filter types (0-4)
+    /// are accepted.
+    pub fn filter_image_with_sequence(
+        &self,
+        filters: &[u8],
+        optimize_alpha: bool,
+    ) -> Result<Vec<u8>, PngError> {
+        let line_count = self.scan_lines(false).count();
+        if filters.len() != line_count {
+            return Err(PngError::IncorrectFilterCount(filters.len(), line_count));
+        }
+        let filters = filters
+            .iter()
+            .map(|&f| match RowFilter::try_from(f) {
+                Ok(filter) if filter <= RowFilter::Paeth => Ok(filter),
+                _ => Err(PngError::InvalidFilterType(f)),
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let mut filtered = Vec::with_capacity(self.data.len());
+        let bpp = self.bytes_per_channel() * self.channels_per_pixel();
+        let alpha_bytes = if optimize_alpha && self.ihdr.color_type.has_alpha() {
+            self.bytes_per_channel()
+        } else {
+            0
+        };
+
+        let mut prev_line = Vec::new();
+        let mut prev_pass: Option<u8> = None;
+        let mut f_buf = Vec::new();
+        for (line, filter) in self.scan_lines(false).zip(filters) {
+            if prev_pass != line.pass || line.data.len() != prev_line.len() {
+                prev_line = vec![0; line.data.len()];
+            }
+            let mut line_data = line.data.to_vec();
+            filter.filter_line(bpp, &mut line_data, &prev_line, &mut f_buf, alpha_bytes);
+            filtered.extend_from_slice(&f_buf);
+            prev_line = line_data;
+            prev_pass = line.pass;
+        }
+        Ok(filtered)
+    }
+}
+
+/// Whether the chunk is an empty entry marking the position of IDAT or PLTE in the input
+pub(crate) fn is_position_marker(chunk: &Chunk) -> bool {
+    matches!(&chunk.name, b"IDAT" | b"PLTE")
 }
 
 fn write_png_block(key: &[u8], chunk: &[u8], output: &mut Vec<u8>) {
@@ -494,6 +1048,101 @@ fn write_png_block(key: &[u8], chunk: &[u8], output: &mut Vec<u8>) {
     output.extend_from_slice(&crc.to_be_bytes());
 }
 
+/// Compress the data with the given deflater and return the compressed size, for the Brute strategy
+///
+/// Each thread keeps its libdeflater compressor and output buffer between calls, as they are
+/// expensive to create for every attempt of every row.
+fn brute_size(deflater: Deflaters, data: &[u8]) -> usize {
+    thread_local! {
+        static COMPRESSOR: RefCell<Option<(u8, Compressor, Vec<u8>)>> = const { RefCell::new(None) };
+    }
+    match deflater {
+        Deflaters::Libdeflater { compression } => COMPRESSOR.with_borrow_mut(|cached| {
+            if cached.as_ref().map(|(level, ..)| *level) != Some(compression) {
+                let lvl = CompressionLvl::new(compression.into()).unwrap();
+                *cached = Some((compression, Compressor::new(lvl), Vec::new()));
+            }
+            let (_, compressor, dest) = cached.as_mut().unwrap();
+            dest.resize(compressor.deflate_compress_bound(data.len()), 0);
+            compressor
+                .deflate_compress(data, dest)
+                .unwrap_or(usize::MAX)
+        }),
+        #[cfg(feature = "zopfli")]
+        Deflaters::Zopfli { iterations } => deflate::zopfli_deflate(data, iterations, false, None)
+            .map_or(usize::MAX, |compressed| compressed.len()),
+        #[cfg(feature = "zlib-ng")]
+        Deflaters::Zlibng { level } => {
+            deflate::zlibng_deflate(data, level, &crate::AtomicMin::new(None))
+                .map_or(usize::MAX, |compressed| compressed.len())
+        }
+    }
+}
+
+/// Decode the output and check that it has the same pixels as the expected image
+///
+/// If `sbit` holds the data of an sBIT chunk for the expected image, only the significant bits
+/// of each sample are compared, as colors differing in the other bits may have been merged.
+pub fn verify_output(
+    output: &[u8],
+    expected: &PngImage,
+    sbit: Option<&[u8]>,
+    opts: &Options,
+) -> Result<(), PngError> {
+    let decoded = PngData::from_slice(output, &Options::default())?;
+    let mask = significant_bits_mask(&expected.ihdr, sbit.unwrap_or_default());
+    if decoded
+        .raw
+        .same_masked_pixels(expected, opts.optimize_alpha, mask)
+    {
+        Ok(())
+    } else {
+        Err(PngError::VerificationFailed)
+    }
+}
+
+/// The mask of the significant bits of each 16-bit RGBA sample, according to an sBIT chunk
+///
+/// All bits are significant for channels that the chunk does not describe, or if it is invalid.
+fn significant_bits_mask(ihdr: &IhdrData, sbit: &[u8]) -> RGBA16 {
+    let depth = match ihdr.color_type {
+        ColorType::Indexed { .. } => 8,
+        _ => ihdr.bit_depth as u8,
+    };
+    let mask = |bits: Option<&u8>| match bits {
+        Some(&bits) if (1..=depth).contains(&bits) => u16::MAX << (16 - bits),
+        _ => u16::MAX,
+    };
+    let [r, g, b, a] = match ihdr.color_type {
+        ColorType::Grayscale { .. } => [0, 0, 0, 4],
+        ColorType::GrayscaleAlpha => [0, 0, 0, 1],
+        ColorType::RGB { .. } | ColorType::Indexed { .. } => [0, 1, 2, 4],
+        ColorType::RGBA => [0, 1, 2, 3],
+    }
+    .map(|i| mask(sbit.get(i)));
+    RGBA16::new(r, g, b, a)
+}
+
+/// Sum the absolute values of the line filtered with its samples inverted, for the MinSum heuristic
+///
+/// Inverting the samples changes the sum by a small amount that depends on how the filter rounds
+/// its prediction, so this can break ties between filters without affecting the image data.
+///
+/// The previous line must already be inverted, and the buffers are reused between calls.
+fn inverted_sum_abs(
+    filter: RowFilter,
+    bpp: usize,
+    line: &[u8],
+    prev_inverted: &[u8],
+    inverted: &mut Vec<u8>,
+    buf: &mut Vec<u8>,
+) -> usize {
+    inverted.clear();
+    inverted.extend(line.iter().map(|&b| !b));
+    filter.filter_line(bpp, inverted, prev_inverted, buf, 0);
+    sum_abs(buf)
+}
+
 // Integer approximation for i * log2(i) - much faster than float calculations
 fn ilog2i(i: u32) -> u32 {
     let log = 32 - i.leading_zeros() - 1;
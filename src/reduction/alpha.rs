@@ -1,15 +1,16 @@
-use rgb::RGB16;
+use rgb::{RGB16, RGBA8};
 
 use crate::{
     colors::{BitDepth, ColorType},
     headers::IhdrData,
     png::PngImage,
+    reduction::Rejection,
 };
 
 /// Clean the alpha channel by setting the color of all fully transparent pixels to black
-pub fn cleaned_alpha_channel(png: &PngImage) -> Option<PngImage> {
+pub fn cleaned_alpha_channel(png: &PngImage) -> Result<PngImage, Rejection> {
     if !png.ihdr.color_type.has_alpha() {
-        return None;
+        return Err(Rejection::UnsupportedColorType);
     }
     let byte_depth = png.bytes_per_channel();
     let bpp = png.channels_per_pixel() * byte_depth;
@@ -24,41 +25,213 @@ pub fn cleaned_alpha_channel(png: &PngImage) -> Option<PngImage> {
         }
     }
 
-    Some(PngImage {
+    Ok(PngImage {
         data: reduced,
         ihdr: png.ihdr.clone(),
     })
 }
 
-#[must_use]
-pub fn reduced_alpha_channel(png: &PngImage, optimize_alpha: bool) -> Option<PngImage> {
+/// Snap the alpha values to the given number of evenly spaced levels, returning the quantized
+/// image if any values changed
+///
+/// This is a lossy transformation. Alpha values are rounded to the nearest level, and pixels that
+/// become fully transparent have their color set to black. For indexed images, the alpha of the
+/// palette entries is snapped instead.
+pub fn quantized_alpha(png: &PngImage, levels: u16) -> Result<PngImage, Rejection> {
+    if !(2..=256).contains(&levels) {
+        return Err(Rejection::InvalidParameter);
+    }
+    let steps = u32::from(levels - 1);
+    let snap = |value: u32, max: u32| {
+        let level = (value * steps + max / 2) / max;
+        (level * max + steps / 2) / steps
+    };
+
+    if let ColorType::Indexed { palette: original } = &png.ihdr.color_type {
+        let palette: Vec<_> = original
+            .iter()
+            .map(|&c| match snap(u32::from(c.a), 255) as u8 {
+                0 if c.a != 0 => RGBA8::new(0, 0, 0, 0),
+                a => RGBA8 { a, ..c },
+            })
+            .collect();
+        if &palette == original {
+            return Err(Rejection::Unchanged);
+        }
+        return Ok(PngImage {
+            data: png.data.clone(),
+            ihdr: IhdrData {
+                color_type: ColorType::Indexed { palette },
+                ..png.ihdr
+            },
+        });
+    }
+    if !png.ihdr.color_type.has_alpha() {
+        return Err(Rejection::UnsupportedColorType);
+    }
+    let byte_depth = png.bytes_per_channel();
+    let bpp = png.channels_per_pixel() * byte_depth;
+    let colored_bytes = bpp - byte_depth;
+
+    let mut changed = false;
+    let mut reduced = Vec::with_capacity(png.data.len());
+    for pixel in png.data.chunks(bpp) {
+        let alpha = &pixel[colored_bytes..];
+        let new_alpha = if byte_depth == 2 {
+            let value = u32::from(u16::from_be_bytes([alpha[0], alpha[1]]));
+            (snap(value, 65535) as u16).to_be_bytes().to_vec()
+        } else {
+            vec![snap(u32::from(alpha[0]), 255) as u8]
+        };
+        if new_alpha == alpha {
+            reduced.extend_from_slice(pixel);
+        } else if new_alpha.iter().all(|b| *b == 0) {
+            changed = true;
+            reduced.resize(reduced.len() + bpp, 0);
+        } else {
+            changed = true;
+            reduced.extend_from_slice(&pixel[..colored_bytes]);
+            reduced.extend_from_slice(&new_alpha);
+        }
+    }
+    if !changed {
+        return Err(Rejection::Unchanged);
+    }
+
+    Ok(PngImage {
+        data: reduced,
+        ihdr: png.ihdr.clone(),
+    })
+}
+
+/// Composite the image over a background color, returning an image without an alpha channel
+///
+/// This is only done if there are fully transparent pixels and every other pixel is fully
+/// opaque, so that the result is exactly the original image as rendered over the background.
+/// The background is given as the data of a bKGD chunk.
+pub fn composited_over_background(png: &PngImage, bkgd: &[u8]) -> Result<PngImage, Rejection> {
+    let (color_type, samples) = match png.ihdr.color_type {
+        ColorType::RGBA => (
+            ColorType::RGB {
+                transparent_color: None,
+            },
+            3,
+        ),
+        ColorType::GrayscaleAlpha => (
+            ColorType::Grayscale {
+                transparent_shade: None,
+            },
+            1,
+        ),
+        _ => return Err(Rejection::UnsupportedColorType),
+    };
+    if bkgd.len() != samples * 2 {
+        return Err(Rejection::InvalidChunk);
+    }
+    let byte_depth = png.bytes_per_channel();
+    // The bKGD samples are always 16-bit, but must fit in the bit depth of the image
+    let background: Vec<u8> = if byte_depth == 2 {
+        bkgd.to_vec()
+    } else if bkgd.chunks(2).all(|s| s[0] == 0) {
+        bkgd.chunks(2).map(|s| s[1]).collect()
+    } else {
+        return Err(Rejection::InvalidChunk);
+    };
+    let bpp = png.channels_per_pixel() * byte_depth;
+    let colored_bytes = bpp - byte_depth;
+
+    let mut has_transparency = false;
+    let mut reduced = Vec::with_capacity(png.data.len() / bpp * colored_bytes);
+    for pixel in png.data.chunks(bpp) {
+        let alpha = &pixel[colored_bytes..];
+        if alpha.iter().all(|b| *b == 0) {
+            has_transparency = true;
+            reduced.extend_from_slice(&background);
+        } else if alpha.iter().all(|b| *b == 255) {
+            reduced.extend_from_slice(&pixel[..colored_bytes]);
+        } else {
+            return Err(Rejection::PartialTransparency);
+        }
+    }
+    // Fully opaque images are handled by the regular alpha reduction
+    if !has_transparency {
+        return Err(Rejection::Unchanged);
+    }
+
+    Ok(PngImage {
+        data: reduced,
+        ihdr: IhdrData {
+            color_type,
+            ..png.ihdr
+        },
+    })
+}
+
+/// Attempt to remove the alpha channel, returning the reduced image if successful
+///
+/// If every pixel is fully opaque, RGBA becomes RGB and Grayscale + Alpha becomes Grayscale, at the
+/// same bit depth. If every other pixel is fully transparent and they all share a color that isn't
+/// used by any opaque pixel, that color will be used for a tRNS chunk. Otherwise, if alpha
+/// optimization is enabled, the fully transparent pixels may be changed to an unused color for the
+/// tRNS chunk. Any other transparency will prevent the reduction.
+pub fn reduced_alpha_channel(png: &PngImage, optimize_alpha: bool) -> Result<PngImage, Rejection> {
     if !png.ihdr.color_type.has_alpha() {
-        return None;
+        return Err(Rejection::UnsupportedColorType);
     }
     let byte_depth = png.bytes_per_channel();
     let bpp = png.channels_per_pixel() * byte_depth;
     let colored_bytes = bpp - byte_depth;
+    let is_transparent = |pixel: &[u8]| pixel[colored_bytes..].iter().all(|b| *b == 0);
 
-    // If alpha optimisation is enabled, see if the image contains only fully opaque and fully transparent pixels.
-    // In case this occurs, we want to try and find an unused color we can use for the tRNS chunk.
-    // Rather than an exhaustive search, we will just keep track of 256 shades of gray, which should cover many cases.
+    // See if the image contains only fully opaque and fully transparent pixels.
+    // In case this occurs, we want to find a color we can use for the tRNS chunk. Ideally this is the
+    // color the transparent pixels already have, but if alpha optimisation is enabled we can look for
+    // an unused one. Rather than an exhaustive search, we will just keep track of 256 shades of gray,
+    // which should cover many cases.
     let mut has_transparency = false;
+    let mut transparent_color = None;
     let mut used_colors = vec![false; 256];
 
     for pixel in png.data.chunks(bpp) {
-        if optimize_alpha && pixel.iter().skip(colored_bytes).all(|b| *b == 0) {
+        let color = &pixel[0..colored_bytes];
+        if is_transparent(pixel) {
             // Fully transparent, we may be able to reduce with tRNS
+            // Keep track of the color, as long as all transparent pixels share the same one
+            if !has_transparency {
+                transparent_color = Some(color);
+            } else if transparent_color != Some(color) {
+                transparent_color = None;
+            }
             has_transparency = true;
-        } else if pixel.iter().skip(colored_bytes).any(|b| *b != 255) {
+        } else if pixel[colored_bytes..].iter().any(|b| *b != 255) {
             // Partially transparent, the image is not reducible
-            return None;
-        } else if optimize_alpha && pixel.iter().take(colored_bytes).all(|b| *b == pixel[0]) {
+            return Err(Rejection::PartialTransparency);
+        } else if optimize_alpha && color.iter().all(|b| *b == pixel[0]) {
             // Opaque shade of gray, we can't use this color for tRNS
             used_colors[pixel[0] as usize] = true;
         }
     }
 
-    let transparency_pixel = if has_transparency {
+    // The existing color can be used losslessly if no opaque pixel shares it
+    let transparent_color = transparent_color.filter(|&trns| {
+        !png.data
+            .chunks(bpp)
+            .any(|pixel| !is_transparent(pixel) && &pixel[0..colored_bytes] == trns)
+    });
+
+    // Determine the tRNS channel values, and the byte to fill transparent pixels with if they need changing
+    let (transparency_pixel, transparent) = if !has_transparency {
+        (None, None)
+    } else if let Some(trns) = transparent_color {
+        let channels = match png.ihdr.bit_depth {
+            BitDepth::Sixteen => trns
+                .chunks(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect(),
+            _ => trns.iter().map(|&c| c.into()).collect(),
+        };
+        (None, Some(channels))
+    } else if optimize_alpha {
         // For grayscale, start by checking 4 specific values in the hope that we may reduce depth
         let unused = match png.ihdr.color_type {
             ColorType::GrayscaleAlpha => [0x00, 0xFF, 0x55, 0xAA]
@@ -68,15 +241,20 @@ pub fn reduced_alpha_channel(png: &PngImage, optimize_alpha: bool) -> Option<Png
         }
         .or_else(|| used_colors.iter().position(|&u| !u).map(|v| v as u8));
         // If no unused color was found we will have to fail here
-        Some(unused?)
+        let trns = unused.ok_or(Rejection::NoTransparentColor)?;
+        let channel = match png.ihdr.bit_depth {
+            BitDepth::Sixteen => (trns as u16) << 8 | trns as u16,
+            _ => trns as u16,
+        };
+        (Some(trns), Some(vec![channel; colored_bytes / byte_depth]))
     } else {
-        None
+        return Err(Rejection::NoTransparentColor);
     };
 
     let mut raw_data = Vec::with_capacity(png.data.len());
     for pixel in png.data.chunks(bpp) {
         match transparency_pixel {
-            Some(trns) if pixel.iter().skip(colored_bytes).all(|b| *b == 0) => {
+            Some(trns) if is_transparent(pixel) => {
                 raw_data.resize(raw_data.len() + colored_bytes, trns);
             }
             _ => raw_data.extend_from_slice(&pixel[0..colored_bytes]),
@@ -84,20 +262,16 @@ pub fn reduced_alpha_channel(png: &PngImage, optimize_alpha: bool) -> Option<Png
     }
 
     // Construct the color type with appropriate transparency data
-    let transparent = transparency_pixel.map(|trns| match png.ihdr.bit_depth {
-        BitDepth::Sixteen => (trns as u16) << 8 | trns as u16,
-        _ => trns as u16,
-    });
     let target_color_type = match png.ihdr.color_type {
         ColorType::GrayscaleAlpha => ColorType::Grayscale {
-            transparent_shade: transparent,
+            transparent_shade: transparent.map(|t: Vec<u16>| t[0]),
         },
         _ => ColorType::RGB {
-            transparent_color: transparent.map(|t| RGB16::new(t, t, t)),
+            transparent_color: transparent.map(|t| RGB16::new(t[0], t[1], t[2])),
         },
     };
 
-    Some(PngImage {
+    Ok(PngImage {
         data: raw_data,
         ihdr: IhdrData {
             color_type: target_color_type,
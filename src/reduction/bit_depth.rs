@@ -1,14 +1,16 @@
+use rgb::RGB16;
+
 use crate::{
     colors::{BitDepth, ColorType},
     headers::IhdrData,
     png::PngImage,
+    reduction::Rejection,
 };
 
 /// Attempt to reduce a 16-bit image to 8-bit, returning the reduced image if successful
-#[must_use]
-pub fn reduced_bit_depth_16_to_8(png: &PngImage, force_scale: bool) -> Option<PngImage> {
+pub fn reduced_bit_depth_16_to_8(png: &PngImage, force_scale: bool) -> Result<PngImage, Rejection> {
     if png.ihdr.bit_depth != BitDepth::Sixteen {
-        return None;
+        return Err(Rejection::UnsupportedBitDepth);
     }
 
     if force_scale {
@@ -18,56 +20,221 @@ pub fn reduced_bit_depth_16_to_8(png: &PngImage, force_scale: bool) -> Option<Pn
     // Reduce from 16 to 8 bits per channel per pixel
     if png.data.chunks(2).any(|pair| pair[0] != pair[1]) {
         // Can't reduce
-        return None;
+        return Err(Rejection::LowBytesDiffer);
     }
 
-    Some(PngImage {
-        data: png.data.iter().step_by(2).cloned().collect(),
+    let mut data: Vec<u8> = png.data.iter().step_by(2).cloned().collect();
+    let color_type = color_type_16_to_8(&png.ihdr.color_type, false);
+    Ok(PngImage {
         ihdr: IhdrData {
-            color_type: png.ihdr.color_type.clone(),
+            color_type: matched_transparency(png, &mut data, color_type),
             bit_depth: BitDepth::Eight,
             ..png.ihdr
         },
+        data,
     })
 }
 
 /// Forcibly reduce a 16-bit image to 8-bit by scaling, returning the reduced image if successful
-#[must_use]
-pub fn scaled_bit_depth_16_to_8(png: &PngImage) -> Option<PngImage> {
+pub fn scaled_bit_depth_16_to_8(png: &PngImage) -> Result<PngImage, Rejection> {
     if png.ihdr.bit_depth != BitDepth::Sixteen {
-        return None;
+        return Err(Rejection::UnsupportedBitDepth);
     }
 
     // Reduce from 16 to 8 bits per channel per pixel by scaling when necessary
-    let data = png
+    let mut data: Vec<u8> = png
         .data
         .chunks(2)
         .map(|pair| {
             if pair[0] == pair[1] {
                 return pair[0];
             }
-            // See: http://www.libpng.org/pub/png/spec/1.2/PNG-Decoders.html#D.Sample-depth-rescaling
-            // This allows values such as 0x00FF to be rounded to 0x01 rather than truncated to 0x00
-            let val = u16::from_be_bytes([pair[0], pair[1]]) as f64;
-            (val * 255.0 / 65535.0).round() as u8
+            scaled_sample(u16::from_be_bytes([pair[0], pair[1]]))
         })
         .collect();
 
-    Some(PngImage {
+    let color_type = color_type_16_to_8(&png.ihdr.color_type, true);
+    Ok(PngImage {
+        ihdr: IhdrData {
+            color_type: matched_transparency(png, &mut data, color_type),
+            bit_depth: BitDepth::Eight,
+            ..png.ihdr
+        },
         data,
+    })
+}
+
+/// Reduce a 16-bit image to 8-bit using Floyd-Steinberg error diffusion, returning the reduced image if successful
+pub fn dithered_bit_depth_16_to_8(png: &PngImage) -> Result<PngImage, Rejection> {
+    if png.ihdr.bit_depth != BitDepth::Sixteen {
+        return Err(Rejection::UnsupportedBitDepth);
+    }
+
+    let channels = png.channels_per_pixel();
+    let color_type = color_type_16_to_8(&png.ihdr.color_type, true);
+    // Other pixels must not be dithered onto the reduced transparent color
+    let key = transparency_key(&png.ihdr.color_type);
+    let new_key = transparency_key(&color_type);
+    let mut data = Vec::with_capacity(png.data.len() / 2);
+    // Accumulated error for each sample of the current and next lines
+    let mut errors: Vec<f32> = Vec::new();
+    let mut next_errors: Vec<f32> = Vec::new();
+    let mut pass = None;
+    for line in png.scan_lines(false) {
+        let samples = line.num_pixels * channels;
+        if line.pass == pass && next_errors.len() == samples {
+            std::mem::swap(&mut errors, &mut next_errors);
+        } else {
+            // Start afresh on the first line and on each interlacing pass
+            errors.clear();
+            errors.resize(samples, 0.0);
+            pass = line.pass;
+        }
+        next_errors.clear();
+        next_errors.resize(samples, 0.0);
+
+        for (x, pixel) in line.data.chunks(channels * 2).enumerate() {
+            let mut samples = [0; 4];
+            let mut targets = [0.0; 4];
+            let mut quantized = [0.0; 4];
+            for (c, pair) in pixel.chunks(2).enumerate() {
+                samples[c] = u16::from_be_bytes([pair[0], pair[1]]);
+                targets[c] = f32::from(samples[c]) * 255.0 / 65535.0 + errors[x * channels + c];
+                quantized[c] = targets[c].round().clamp(0.0, 255.0);
+            }
+            let is_key = key.as_deref() == Some(&samples[..channels]);
+            let on_new_key = new_key.as_ref().is_some_and(|new_key| {
+                new_key
+                    .iter()
+                    .zip(&quantized)
+                    .all(|(&k, &q)| f32::from(k) == q)
+            });
+            if on_new_key && !is_key {
+                // Nudge the first sample towards its target, so the error diffusion accounts for it
+                let up = (targets[0] > quantized[0] && quantized[0] < 255.0) || quantized[0] == 0.0;
+                quantized[0] += if up { 1.0 } else { -1.0 };
+            }
+
+            for c in 0..channels {
+                data.push(quantized[c] as u8);
+
+                // Distribute the error to neighbouring samples of the same channel, without wrapping
+                // around the edges of the line
+                let i = x * channels + c;
+                let error = targets[c] - quantized[c];
+                if x + 1 < line.num_pixels {
+                    errors[i + channels] += error * 7.0 / 16.0;
+                    next_errors[i + channels] += error / 16.0;
+                }
+                if x > 0 {
+                    next_errors[i - channels] += error * 3.0 / 16.0;
+                }
+                next_errors[i] += error * 5.0 / 16.0;
+            }
+        }
+    }
+
+    Ok(PngImage {
         ihdr: IhdrData {
-            color_type: png.ihdr.color_type.clone(),
+            color_type: matched_transparency(png, &mut data, color_type),
             bit_depth: BitDepth::Eight,
             ..png.ihdr
         },
+        data,
     })
 }
 
+fn scaled_sample(val: u16) -> u8 {
+    // See: http://www.libpng.org/pub/png/spec/1.2/PNG-Decoders.html#D.Sample-depth-rescaling
+    // This allows values such as 0x00FF to be rounded to 0x01 rather than truncated to 0x00
+    (val as f64 * 255.0 / 65535.0).round() as u8
+}
+
+/// Convert the transparency of a 16-bit color type to 8-bit, matching the reduction of the data
+fn color_type_16_to_8(color_type: &ColorType, scale: bool) -> ColorType {
+    let reduce = |val: u16| {
+        let [high, low] = val.to_be_bytes();
+        if high == low {
+            Some(u16::from(high))
+        } else if scale {
+            Some(u16::from(scaled_sample(val)))
+        } else {
+            // No pixel could have matched this value, so the transparency can be dropped
+            None
+        }
+    };
+    match color_type {
+        ColorType::Grayscale { transparent_shade } => ColorType::Grayscale {
+            transparent_shade: transparent_shade.and_then(reduce),
+        },
+        ColorType::RGB { transparent_color } => ColorType::RGB {
+            transparent_color: transparent_color
+                .and_then(|t| Some(RGB16::new(reduce(t.r)?, reduce(t.g)?, reduce(t.b)?))),
+        },
+        _ => color_type.clone(),
+    }
+}
+
+/// The samples of the transparent color of a color type, if it has one
+fn transparency_key(color_type: &ColorType) -> Option<Vec<u16>> {
+    match color_type {
+        ColorType::Grayscale {
+            transparent_shade: Some(shade),
+        } => Some(vec![*shade]),
+        ColorType::RGB {
+            transparent_color: Some(trns),
+        } => Some(vec![trns.r, trns.g, trns.b]),
+        _ => None,
+    }
+}
+
+/// Make the transparency of a 16-bit image reduced to 8-bit match the reduced pixels exactly
+///
+/// Pixels that had the transparent color are given the reduced transparent color. Any other
+/// pixels that a lossy reduction mapped onto the reduced transparent color are nudged to a
+/// neighbouring value, so they don't become transparent. The transparency is dropped if no pixel
+/// had the transparent color.
+fn matched_transparency(png: &PngImage, data: &mut [u8], color_type: ColorType) -> ColorType {
+    let (Some(key), Some(new_key)) = (
+        transparency_key(&png.ihdr.color_type),
+        transparency_key(&color_type),
+    ) else {
+        return color_type;
+    };
+    let new_key: Vec<u8> = new_key.iter().map(|&v| v as u8).collect();
+    let channels = key.len();
+    let mut used = false;
+    for (pixel, original) in data.chunks_mut(channels).zip(png.data.chunks(channels * 2)) {
+        let is_key = original
+            .chunks(2)
+            .zip(&key)
+            .all(|(sample, &k)| u16::from_be_bytes([sample[0], sample[1]]) == k);
+        if is_key {
+            pixel.copy_from_slice(&new_key);
+            used = true;
+        } else if pixel == new_key.as_slice() {
+            pixel[0] = if pixel[0] == 255 { 254 } else { pixel[0] + 1 };
+        }
+    }
+    match color_type {
+        _ if used => color_type,
+        ColorType::Grayscale { .. } => ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        ColorType::RGB { .. } => ColorType::RGB {
+            transparent_color: None,
+        },
+        _ => color_type,
+    }
+}
+
 /// Attempt to reduce an 8-bit image to a lower bit depth, returning the reduced image if successful
-#[must_use]
-pub fn reduced_bit_depth_8_or_less(png: &PngImage) -> Option<PngImage> {
-    if png.ihdr.bit_depth != BitDepth::Eight || png.channels_per_pixel() != 1 {
-        return None;
+pub fn reduced_bit_depth_8_or_less(png: &PngImage) -> Result<PngImage, Rejection> {
+    if png.ihdr.bit_depth != BitDepth::Eight {
+        return Err(Rejection::UnsupportedBitDepth);
+    }
+    if png.channels_per_pixel() != 1 {
+        return Err(Rejection::UnsupportedColorType);
     }
 
     let mut minimum_bits = 1;
@@ -78,7 +245,7 @@ pub fn reduced_bit_depth_8_or_less(png: &PngImage) -> Option<PngImage> {
             0..=2 => 1,
             3..=4 => 2,
             5..=16 => 4,
-            _ => return None,
+            _ => return Err(Rejection::TooManyColors),
         };
     } else {
         // Finding minimum depth for grayscale is much more complicated
@@ -100,7 +267,7 @@ pub fn reduced_bit_depth_8_or_less(png: &PngImage) -> Option<PngImage> {
                         // This depth is not possible, try the next one up
                         minimum_bits <<= 1;
                         if minimum_bits == 8 {
-                            return None;
+                            return Err(Rejection::FullDepthRequired);
                         }
                         mask = (1 << minimum_bits) - 1;
                         divisions = 1..(8 / minimum_bits);
@@ -142,8 +309,9 @@ pub fn reduced_bit_depth_8_or_less(png: &PngImage) -> Option<PngImage> {
             bits <<= 1;
         }
         // If the transparency doesn't fit the new bit depth it is therefore unused - set it to None
+        // It is also dropped if no pixel has it
         ColorType::Grayscale {
-            transparent_shade: if trans == check {
+            transparent_shade: if trans == check && png.data.contains(&(trans as u8)) {
                 Some(reduced_trans)
             } else {
                 None
@@ -153,7 +321,7 @@ pub fn reduced_bit_depth_8_or_less(png: &PngImage) -> Option<PngImage> {
         png.ihdr.color_type.clone()
     };
 
-    Some(PngImage {
+    Ok(PngImage {
         data: reduced,
         ihdr: IhdrData {
             color_type,
@@ -164,11 +332,10 @@ pub fn reduced_bit_depth_8_or_less(png: &PngImage) -> Option<PngImage> {
 }
 
 /// Expand a 1/2/4-bit image to 8-bit, returning the expanded image if successful
-#[must_use]
-pub fn expanded_bit_depth_to_8(png: &PngImage) -> Option<PngImage> {
+pub fn expanded_bit_depth_to_8(png: &PngImage) -> Result<PngImage, Rejection> {
     let bit_depth = png.ihdr.bit_depth as u32;
     if bit_depth >= 8 {
-        return None;
+        return Err(Rejection::UnsupportedBitDepth);
     }
     // Calculate the current number of pixels per byte
     let ppb = 8 / bit_depth;
@@ -217,7 +384,7 @@ pub fn expanded_bit_depth_to_8(png: &PngImage) -> Option<PngImage> {
         png.ihdr.color_type.clone()
     };
 
-    Some(PngImage {
+    Ok(PngImage {
         data: reduced,
         ihdr: IhdrData {
             color_type,
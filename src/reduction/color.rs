@@ -1,13 +1,14 @@
 use std::hash::{BuildHasherDefault, Hash};
 
 use indexmap::IndexSet;
-use rgb::{alt::Gray, ComponentMap, ComponentSlice, FromSlice, RGB, RGBA};
+use rgb::{alt::Gray, ComponentMap, ComponentSlice, FromSlice, RGB, RGB16, RGBA};
 use rustc_hash::FxHasher;
 
 use crate::{
     colors::{BitDepth, ColorType},
     headers::IhdrData,
     png::PngImage,
+    reduction::{bit_depth::expanded_bit_depth_to_8, Rejection},
 };
 
 type FxIndexSet<V> = IndexSet<V, BuildHasherDefault<FxHasher>>;
@@ -34,22 +35,22 @@ where
     Some(palette)
 }
 
-#[must_use]
-pub fn reduced_to_indexed(png: &PngImage, allow_grayscale: bool) -> Option<PngImage> {
+pub fn reduced_to_indexed(png: &PngImage, allow_grayscale: bool) -> Result<PngImage, Rejection> {
     if png.ihdr.bit_depth != BitDepth::Eight {
-        return None;
+        return Err(Rejection::UnsupportedBitDepth);
     }
     if matches!(png.ihdr.color_type, ColorType::Indexed { .. }) {
-        return None;
+        return Err(Rejection::UnsupportedColorType);
     }
     if !allow_grayscale && png.ihdr.color_type.is_gray() {
-        return None;
+        return Err(Rejection::UnsupportedColorType);
     }
 
     let mut raw_data = Vec::with_capacity(png.data.len() / png.channels_per_pixel());
     let palette: Vec<_> = match png.ihdr.color_type {
         ColorType::Grayscale { transparent_shade } => {
-            let pmap = build_palette(png.data.as_gray().iter().cloned(), &mut raw_data)?;
+            let pmap = build_palette(png.data.as_gray().iter().cloned(), &mut raw_data)
+                .ok_or(Rejection::TooManyColors)?;
             // Convert the Gray16 transparency to Gray8
             let transparency_pixel = transparent_shade.map(|t| Gray::from(t as u8));
             pmap.into_iter()
@@ -63,7 +64,8 @@ pub fn reduced_to_indexed(png: &PngImage, allow_grayscale: bool) -> Option<PngIm
                 .collect()
         }
         ColorType::RGB { transparent_color } => {
-            let pmap = build_palette(png.data.as_rgb().iter().cloned(), &mut raw_data)?;
+            let pmap = build_palette(png.data.as_rgb().iter().cloned(), &mut raw_data)
+                .ok_or(Rejection::TooManyColors)?;
             // Convert the RGB16 transparency to RGB8
             let transparency_pixel = transparent_color.map(|t| t.map(|c| c as u8));
             pmap.into_iter()
@@ -77,17 +79,19 @@ pub fn reduced_to_indexed(png: &PngImage, allow_grayscale: bool) -> Option<PngIm
                 .collect()
         }
         ColorType::GrayscaleAlpha => {
-            let pmap = build_palette(png.data.as_gray_alpha().iter().cloned(), &mut raw_data)?;
+            let pmap = build_palette(png.data.as_gray_alpha().iter().cloned(), &mut raw_data)
+                .ok_or(Rejection::TooManyColors)?;
             pmap.into_iter().map(RGBA::from).collect()
         }
         ColorType::RGBA => {
-            let pmap = build_palette(png.data.as_rgba().iter().cloned(), &mut raw_data)?;
+            let pmap = build_palette(png.data.as_rgba().iter().cloned(), &mut raw_data)
+                .ok_or(Rejection::TooManyColors)?;
             pmap.into_iter().collect()
         }
-        _ => return None,
+        _ => return Err(Rejection::UnsupportedColorType),
     };
 
-    Some(PngImage {
+    Ok(PngImage {
         data: raw_data,
         ihdr: IhdrData {
             color_type: ColorType::Indexed { palette },
@@ -96,10 +100,14 @@ pub fn reduced_to_indexed(png: &PngImage, allow_grayscale: bool) -> Option<PngIm
     })
 }
 
-#[must_use]
-pub fn reduced_rgb_to_grayscale(png: &PngImage) -> Option<PngImage> {
+/// Attempt to reduce RGB to grayscale, returning the reduced image if successful
+///
+/// Every pixel must have equal red, green and blue values (compared as whole samples at 16-bit).
+/// A gray tRNS color is carried over as the transparent shade, while any other tRNS color cannot
+/// match a pixel and is dropped.
+pub fn reduced_rgb_to_grayscale(png: &PngImage) -> Result<PngImage, Rejection> {
     if !png.ihdr.color_type.is_rgb() {
-        return None;
+        return Err(Rejection::UnsupportedColorType);
     }
 
     let mut reduced = Vec::with_capacity(png.data.len());
@@ -109,10 +117,10 @@ pub fn reduced_rgb_to_grayscale(png: &PngImage) -> Option<PngImage> {
     for pixel in png.data.chunks(bpp) {
         if byte_depth == 1 {
             if pixel[0] != pixel[1] || pixel[1] != pixel[2] {
-                return None;
+                return Err(Rejection::NotGray);
             }
         } else if pixel[0..2] != pixel[2..4] || pixel[2..4] != pixel[4..6] {
-            return None;
+            return Err(Rejection::NotGray);
         }
         reduced.extend_from_slice(&pixel[last_color..]);
     }
@@ -127,7 +135,7 @@ pub fn reduced_rgb_to_grayscale(png: &PngImage) -> Option<PngImage> {
         _ => ColorType::GrayscaleAlpha,
     };
 
-    Some(PngImage {
+    Ok(PngImage {
         data: reduced,
         ihdr: IhdrData {
             color_type,
@@ -137,14 +145,13 @@ pub fn reduced_rgb_to_grayscale(png: &PngImage) -> Option<PngImage> {
 }
 
 /// Attempt to convert indexed to a different color type, returning the resulting image if successful
-#[must_use]
-pub fn indexed_to_channels(png: &PngImage, allow_grayscale: bool) -> Option<PngImage> {
+pub fn indexed_to_channels(png: &PngImage, allow_grayscale: bool) -> Result<PngImage, Rejection> {
     if png.ihdr.bit_depth != BitDepth::Eight {
-        return None;
+        return Err(Rejection::UnsupportedBitDepth);
     }
     let palette = match &png.ihdr.color_type {
         ColorType::Indexed { palette } => palette,
-        _ => return None,
+        _ => return Err(Rejection::UnsupportedColorType),
     };
 
     // Determine which channels are required
@@ -168,7 +175,7 @@ pub fn indexed_to_channels(png: &PngImage, allow_grayscale: bool) -> Option<PngI
     // Don't proceed if output would be too much larger
     let out_size = color_type.channels_per_pixel() as usize * png.data.len();
     if out_size - png.data.len() > INDEXED_MAX_DIFF {
-        return None;
+        return Err(Rejection::TooLarge);
     }
 
     // Construct the new data
@@ -181,7 +188,120 @@ pub fn indexed_to_channels(png: &PngImage, allow_grayscale: bool) -> Option<PngI
         data.extend_from_slice(&color.as_slice()[ch_start..=ch_end]);
     }
 
-    Some(PngImage {
+    Ok(PngImage {
+        ihdr: IhdrData {
+            color_type,
+            ..png.ihdr
+        },
+        data,
+    })
+}
+
+/// Attempt to expand the image to a color type with more channels, returning the resulting image if successful
+///
+/// Indexed images may be expanded to RGB or RGBA, or to grayscale if all colors in the palette are gray.
+/// Grayscale images may be expanded to RGB, and an alpha channel may be added to any image. Transparency
+/// from the palette or the transparent color is carried over, and the transparency fields of `target`
+/// are ignored. Conversions that would lose color or transparency information are not performed.
+pub fn expanded_to_color_type(png: &PngImage, target: &ColorType) -> Result<PngImage, Rejection> {
+    if png.ihdr.color_type.png_header_code() == target.png_header_code() {
+        return Err(Rejection::Unchanged);
+    }
+    // Sub-byte images are expanded to 8-bit first
+    let expanded;
+    let png = if png.ihdr.bit_depth < BitDepth::Eight {
+        expanded = expanded_bit_depth_to_8(png)?;
+        &expanded
+    } else {
+        png
+    };
+    let source = &png.ihdr.color_type;
+    let opaque = match source {
+        ColorType::Indexed { palette } => palette.iter().all(|c| c.a == 255),
+        _ => !source.has_alpha() && !source.has_trns(),
+    };
+    let gray = match source {
+        ColorType::Indexed { palette } => palette.iter().all(|c| c.r == c.g && c.g == c.b),
+        _ => source.is_gray(),
+    };
+    let color_type = match target {
+        ColorType::Indexed { .. } => return Err(Rejection::UnsupportedColorType),
+        _ if target.is_gray() && !gray => return Err(Rejection::NotGray),
+        _ if source.has_alpha() && !target.has_alpha() => return Err(Rejection::Lossy),
+        ColorType::Grayscale { .. } if opaque => ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        ColorType::RGB { .. } => match source {
+            ColorType::Grayscale { transparent_shade } => ColorType::RGB {
+                transparent_color: transparent_shade.map(|v| RGB16::new(v, v, v)),
+            },
+            _ if opaque => ColorType::RGB {
+                transparent_color: None,
+            },
+            _ => return Err(Rejection::Lossy),
+        },
+        ColorType::GrayscaleAlpha => ColorType::GrayscaleAlpha,
+        ColorType::RGBA => ColorType::RGBA,
+        _ => return Err(Rejection::UnsupportedColorType),
+    };
+
+    let bytes = png.bytes_per_channel();
+    let max = if bytes == 2 {
+        u16::MAX
+    } else {
+        u16::from(u8::MAX)
+    };
+    let black = RGBA::new(0, 0, 0, 255);
+    let channels: &[usize] = match color_type {
+        ColorType::Grayscale { .. } => &[0],
+        ColorType::GrayscaleAlpha => &[0, 3],
+        ColorType::RGB { .. } => &[0, 1, 2],
+        _ => &[0, 1, 2, 3],
+    };
+    let mut data = Vec::with_capacity(png.data.len() / png.channels_per_pixel() * channels.len());
+    for pixel in png.data.chunks_exact(png.channels_per_pixel() * bytes) {
+        let sample = |i: usize| match bytes {
+            2 => u16::from_be_bytes([pixel[i * 2], pixel[i * 2 + 1]]),
+            _ => u16::from(pixel[i]),
+        };
+        let rgba = match &png.ihdr.color_type {
+            ColorType::Grayscale { transparent_shade } => {
+                let v = sample(0);
+                let a = if *transparent_shade == Some(v) {
+                    0
+                } else {
+                    max
+                };
+                [v, v, v, a]
+            }
+            ColorType::RGB { transparent_color } => {
+                let (r, g, b) = (sample(0), sample(1), sample(2));
+                let a = if *transparent_color == Some(RGB16::new(r, g, b)) {
+                    0
+                } else {
+                    max
+                };
+                [r, g, b, a]
+            }
+            ColorType::Indexed { palette } => {
+                let c = palette.get(pixel[0] as usize).unwrap_or(&black);
+                [c.r, c.g, c.b, c.a].map(u16::from)
+            }
+            ColorType::GrayscaleAlpha => {
+                let v = sample(0);
+                [v, v, v, sample(1)]
+            }
+            ColorType::RGBA => [sample(0), sample(1), sample(2), sample(3)],
+        };
+        for &ch in channels {
+            match bytes {
+                2 => data.extend_from_slice(&rgba[ch].to_be_bytes()),
+                _ => data.push(rgba[ch] as u8),
+            }
+        }
+    }
+
+    Ok(PngImage {
         ihdr: IhdrData {
             color_type,
             ..png.ihdr
@@ -1,6 +1,14 @@
-use std::sync::Arc;
+use std::{fmt, fmt::Display, sync::Arc};
 
-use crate::{evaluate::Evaluator, png::PngImage, ColorType, Deadline, Deflaters, Options};
+use log::{trace, warn};
+use rgb::{RGB8, RGBA8};
+
+use crate::{
+    evaluate::Evaluator,
+    headers::{convert_bkgd, parse_splt_chunk, Chunk, IhdrData},
+    png::PngImage,
+    BitDepth, ColorType, Deadline, Deflaters, Options,
+};
 
 pub mod alpha;
 use crate::alpha::*;
@@ -10,14 +18,160 @@ pub mod color;
 use crate::color::*;
 pub mod palette;
 use crate::palette::*;
+pub mod quantize;
+use crate::quantize::*;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// The reason a reduction was not applied to an image
+pub enum Rejection {
+    /// The reduction does not apply to the color type of the image
+    UnsupportedColorType,
+    /// The reduction does not apply to the bit depth of the image
+    UnsupportedBitDepth,
+    /// The reduction does not apply to interlaced images
+    Interlaced,
+    /// A parameter of the reduction is out of range
+    InvalidParameter,
+    /// An ancillary chunk needed by the reduction is malformed or doesn't fit the image
+    InvalidChunk,
+    /// The image data uses indices beyond the end of the palette
+    InvalidPalette,
+    /// The image would be unchanged by the reduction
+    Unchanged,
+    /// Some pixels are neither fully opaque nor fully transparent
+    PartialTransparency,
+    /// No color is available to represent the transparent pixels in a tRNS chunk
+    NoTransparentColor,
+    /// The low and high bytes of some 16-bit samples differ
+    LowBytesDiffer,
+    /// All of the bits of some samples are significant
+    FullDepthRequired,
+    /// Some colors are not gray
+    NotGray,
+    /// The image has too many colors for the target
+    TooManyColors,
+    /// The output would be too much larger than the input
+    TooLarge,
+    /// The conversion would lose color or transparency information
+    Lossy,
+}
+
+impl Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(
+            match self {
+                Self::UnsupportedColorType => "unsupported color type",
+                Self::UnsupportedBitDepth => "unsupported bit depth",
+                Self::Interlaced => "interlaced",
+                Self::InvalidParameter => "invalid parameter",
+                Self::InvalidChunk => "invalid chunk",
+                Self::InvalidPalette => "invalid palette indices",
+                Self::Unchanged => "no change",
+                Self::PartialTransparency => "partially transparent pixels",
+                Self::NoTransparentColor => "no color available for tRNS",
+                Self::LowBytesDiffer => "low bytes differ",
+                Self::FullDepthRequired => "all bits are significant",
+                Self::NotGray => "colors are not gray",
+                Self::TooManyColors => "too many colors",
+                Self::TooLarge => "output too large",
+                Self::Lossy => "information would be lost",
+            },
+            f,
+        )
+    }
+}
+
+/// Log the outcome of a reduction, returning the reduced image if it was applied
+fn attempt<T>(description: &str, result: Result<T, Rejection>) -> Option<T> {
+    match result {
+        Ok(reduced) => {
+            trace!("{}: applied", description);
+            Some(reduced)
+        }
+        Err(reason) => {
+            trace!("{}: rejected ({})", description, reason);
+            None
+        }
+    }
+}
+
+/// Keep the pinned color at index 0 of the palette of an indexed image, returning the image if
+/// it was changed
+///
+/// The bit depth is increased if the pinned color doesn't fit in the palette, and reduced again
+/// where possible.
+pub(crate) fn pin_palette(png: &PngImage, opts: &Options) -> Option<PngImage> {
+    let ColorType::Indexed { palette } = &png.ihdr.color_type else {
+        return None;
+    };
+    let color = match opts.palette_pin? {
+        PalettePin::Color(color) => color,
+        PalettePin::Transparent => {
+            let RGB8 { r, g, b } = opts.transparent_palette_color;
+            palette
+                .iter()
+                .find(|c| c.a == 0)
+                .copied()
+                .unwrap_or(RGBA8::new(r, g, b, 0))
+        }
+    };
+    let expanded = expanded_bit_depth_to_8(png).ok();
+    let pinned = attempt(
+        "Palette pin",
+        pinned_palette(expanded.as_ref().unwrap_or(png), color),
+    )?;
+    Some(reduced_bit_depth_8_or_less(&pinned).unwrap_or(pinned))
+}
+
+/// Get the 8-bit color of a bKGD chunk for the image, if it can be represented exactly
+fn background_color(bkgd: &[u8], ihdr: &IhdrData) -> Option<RGB8> {
+    let rgb = IhdrData {
+        color_type: ColorType::RGB {
+            transparent_color: None,
+        },
+        bit_depth: BitDepth::Eight,
+        ..ihdr.clone()
+    };
+    let data = convert_bkgd(bkgd, ihdr, &rgb)?;
+    Some(RGB8::new(data[1], data[3], data[5]))
+}
 
+/// The statistics of a palette reduction, along with the image it was applied to if it changed
+///
+/// The statistics don't apply if the evaluator chooses the unreduced image.
+pub(crate) type PaletteReduction = (PaletteReductionStats, Option<Arc<PngImage>>);
+
+/// Perform the reductions on the image, returning the baseline image along with the palette
+/// reduction, if it was attempted
 pub(crate) fn perform_reductions(
     mut png: Arc<PngImage>,
     opts: &Options,
     deadline: &Deadline,
     eval: &Evaluator,
-) -> Arc<PngImage> {
+    aux_chunks: &[Chunk],
+) -> (Arc<PngImage>, Option<PaletteReduction>) {
     let mut evaluation_added = false;
+    let sort_description = format!("Indexed ({} sort)", opts.palette_sort);
+    let find_chunk = |name: &[u8; 4]| {
+        aux_chunks
+            .iter()
+            .find(|c| &c.name == name && opts.strip.keep(name))
+            .map(|c| c.data.as_slice())
+    };
+    // The background color is only needed if it's weighted in the palette sort
+    let background = if opts.palette_sort_weights.background > 0 {
+        find_chunk(b"bKGD").and_then(|bkgd| background_color(bkgd, &png.ihdr))
+    } else {
+        None
+    };
+    let sort_palette = |png: &PngImage| {
+        sorted_palette_weighted(
+            png,
+            opts.palette_sort,
+            &opts.palette_sort_weights,
+            background,
+        )
+    };
 
     // At low compression levels, skip some transformations which are less likely to be effective
     // This currently affects optimization presets 0-2
@@ -33,10 +187,49 @@ pub(crate) fn perform_reductions(
         }
     }
 
+    // Expand to the forced color type, after which color type reductions must not be performed
+    let color_type_reduction = match &opts.force_color_type {
+        Some(color_type) => {
+            match expanded_to_color_type(&png, color_type) {
+                Ok(expanded) => png = Arc::new(expanded),
+                Err(Rejection::Unchanged) => (),
+                Err(reason) => warn!(
+                    "Unable to convert {} to {} ({})",
+                    png.ihdr.color_type, color_type, reason
+                ),
+            }
+            false
+        }
+        None => opts.color_type_reduction,
+    };
+
+    // Snap alpha to the requested number of levels
+    // This is a lossy transformation that does not need to be evaluated
+    if let Some(levels) = opts.alpha_levels {
+        if let Some(reduced) = attempt("Alpha quantization", quantized_alpha(&png, levels)) {
+            png = Arc::new(reduced);
+        }
+    }
+
     // If alpha optimization is enabled, clean the alpha channel before continuing
     // This can allow some color type reductions which may not have been possible otherwise
     if opts.optimize_alpha && !deadline.passed() {
-        if let Some(reduced) = cleaned_alpha_channel(&png) {
+        if let Some(reduced) = attempt("Alpha cleaning", cleaned_alpha_channel(&png)) {
+            png = Arc::new(reduced);
+        }
+    }
+
+    // Composite a binary alpha channel over the background color, so the alpha can be removed
+    // This is just removal of bytes and does not need to be evaluated
+    if let (true, true, Some(bkgd)) = (
+        opts.composite_background,
+        color_type_reduction,
+        find_chunk(b"bKGD"),
+    ) {
+        if let Some(reduced) = attempt(
+            "Background compositing",
+            composited_over_background(&png, bkgd),
+        ) {
             png = Arc::new(reduced);
         }
     }
@@ -44,15 +237,20 @@ pub(crate) fn perform_reductions(
     // Attempt to reduce 16-bit to 8-bit
     // This is just removal of bytes and does not need to be evaluated
     if opts.bit_depth_reduction && !deadline.passed() {
-        if let Some(reduced) = reduced_bit_depth_16_to_8(&png, opts.scale_16) {
+        let reduced = if opts.allow_lossy_bit_depth {
+            reduced_bit_depth_16_to_8(&png, false).or_else(|_| dithered_bit_depth_16_to_8(&png))
+        } else {
+            reduced_bit_depth_16_to_8(&png, opts.scale_16)
+        };
+        if let Some(reduced) = attempt("16 to 8 bit", reduced) {
             png = Arc::new(reduced);
         }
     }
 
     // Attempt to reduce RGB to grayscale
     // This is just removal of bytes and does not need to be evaluated
-    if opts.color_type_reduction && opts.grayscale_reduction && !deadline.passed() {
-        if let Some(reduced) = reduced_rgb_to_grayscale(&png) {
+    if color_type_reduction && opts.grayscale_reduction && !deadline.passed() {
+        if let Some(reduced) = attempt("RGB to grayscale", reduced_rgb_to_grayscale(&png)) {
             png = Arc::new(reduced);
         }
     }
@@ -60,7 +258,31 @@ pub(crate) fn perform_reductions(
     // Attempt to expand the bit depth to 8
     // This does need to be evaluated but will be done so later when it gets reduced again
     if opts.bit_depth_reduction && !deadline.passed() {
-        if let Some(reduced) = expanded_bit_depth_to_8(&png) {
+        if let Some(reduced) = attempt("Expand to 8 bit", expanded_bit_depth_to_8(&png)) {
+            png = Arc::new(reduced);
+        }
+    }
+
+    // Quantize truecolor to a palette of the requested size
+    // This is a lossy transformation that does not need to be evaluated
+    if let (true, Some(max_colors)) = (color_type_reduction, opts.lossy_quantize) {
+        if !deadline.passed() {
+            if let Some(reduced) = attempt(
+                "Quantization",
+                quantized_to_indexed(&png, max_colors, opts.quantize_dither),
+            ) {
+                png = Arc::new(reduced);
+            }
+        }
+    }
+
+    // Attempt to merge similar palette colors
+    // This is a lossy transformation that does not need to be evaluated
+    if opts.palette_reduction && opts.palette_merge_threshold > 0 && !deadline.passed() {
+        if let Some(reduced) = attempt(
+            "Palette merging",
+            merged_similar_palette(&png, opts.palette_merge_threshold),
+        ) {
             png = Arc::new(reduced);
         }
     }
@@ -70,27 +292,64 @@ pub(crate) fn perform_reductions(
     let mut baseline = png.clone();
 
     // Attempt to reduce and sort the palette
+    // Colors that differ only in the bits declared insignificant by sBIT are merged, which is
+    // lossless relative to the declared precision
+    let mut palette_stats = None;
     if opts.palette_reduction && !deadline.passed() {
-        if let Some(reduced) = reduced_palette(&png, opts.optimize_alpha) {
+        let reduced = attempt(
+            "Palette reduction",
+            reduced_palette_with_stats(
+                &png,
+                opts.optimize_alpha
+                    .then_some(opts.transparent_palette_color),
+                find_chunk(b"sBIT"),
+            ),
+        );
+        if let ColorType::Indexed { palette } = &png.ihdr.color_type {
+            palette_stats = Some(match &reduced {
+                Some((_, stats)) => (*stats, Some(png.clone())),
+                None => (
+                    PaletteReductionStats {
+                        original_len: palette.len(),
+                        final_len: palette.len(),
+                        duplicates_merged: 0,
+                        transparent_merged: 0,
+                    },
+                    None,
+                ),
+            });
+        }
+        if let Some((reduced, _)) = reduced {
             png = Arc::new(reduced);
             // If the palette was reduced but the data is unchanged then this should become the baseline
             if png.data == baseline.data {
                 baseline = png.clone();
             }
         }
-        if let Some(reduced) = sorted_palette(&png) {
-            png = Arc::new(reduced);
+        // An existing palette should only be sorted if its order doesn't need to be preserved
+        if !opts.preserve_palette_order {
+            if let Some(reduced) = attempt("Palette sort", sort_palette(&png)) {
+                png = Arc::new(reduced);
+            }
         }
         // If either action changed the data then enter this into the evaluator
         if !Arc::ptr_eq(&png, &baseline) {
-            eval.try_image_with_description(png.clone(), "Indexed (luma sort)");
+            let description = if opts.preserve_palette_order {
+                "Indexed (original order)"
+            } else {
+                &sort_description
+            };
+            eval.try_image_with_description(png.clone(), description);
             evaluation_added = true;
         }
     }
 
     // Attempt alpha removal
-    if opts.color_type_reduction && !deadline.passed() {
-        if let Some(reduced) = reduced_alpha_channel(&png, opts.optimize_alpha) {
+    if color_type_reduction && !deadline.passed() {
+        if let Some(reduced) = attempt(
+            "Alpha removal",
+            reduced_alpha_channel(&png, opts.optimize_alpha),
+        ) {
             png = Arc::new(reduced);
             // For small differences, if a tRNS chunk is required then enter this into the evaluator
             // Otherwise it is mostly just removal of bytes and should become the baseline
@@ -103,10 +362,36 @@ pub(crate) fn perform_reductions(
         }
     }
 
+    // Attempt to convert from indexed to grayscale, if all colors in the palette are gray
+    // This may give a better result due to dropping the PLTE chunk, and can often reduce in depth
+    let mut gray = None;
+    if !cheap
+        && color_type_reduction
+        && opts.grayscale_reduction
+        && opts.indexed_reduction
+        && !deadline.passed()
+    {
+        if let Some(reduced) = attempt("Indexed to grayscale", indexed_to_grayscale(&png)) {
+            // This result should not be passed on to subsequent reductions
+            let reduced = Arc::new(reduced);
+            eval.try_image(reduced.clone());
+            evaluation_added = true;
+            gray = Some(reduced);
+        }
+    }
+
     // Attempt to convert from indexed to channels
     // This may give a better result due to dropping the PLTE chunk
-    if !cheap && opts.color_type_reduction && !deadline.passed() {
-        if let Some(reduced) = indexed_to_channels(&png, opts.grayscale_reduction) {
+    if !cheap
+        && gray.is_none()
+        && color_type_reduction
+        && opts.indexed_reduction
+        && !deadline.passed()
+    {
+        if let Some(reduced) = attempt(
+            "Indexed to channels",
+            indexed_to_channels(&png, opts.grayscale_reduction),
+        ) {
             // This result should not be passed on to subsequent reductions
             eval.try_image(Arc::new(reduced));
             evaluation_added = true;
@@ -116,14 +401,32 @@ pub(crate) fn perform_reductions(
     // Attempt to reduce to indexed
     // Keep the existing `png` var in case it is grayscale - we can test both for depth reduction later
     let mut indexed = None;
-    if opts.color_type_reduction && !deadline.passed() {
-        if let Some(reduced) = reduced_to_indexed(&png, opts.grayscale_reduction) {
+    // A new palette may instead be ordered to follow the suggested palette, if there is one
+    let splt = match find_chunk(b"sPLT") {
+        Some(data) if opts.splt_palette => parse_splt_chunk(data).ok(),
+        _ => None,
+    };
+    if color_type_reduction && opts.indexed_reduction && !deadline.passed() {
+        if let Some(reduced) = attempt(
+            "Reduce to indexed",
+            reduced_to_indexed(&png, opts.grayscale_reduction),
+        ) {
             // Make sure the palette gets sorted (but don't bother evaluating both results)
-            let new = Arc::new(sorted_palette(&reduced).unwrap_or(reduced));
+            let (new, description) = match &splt {
+                Some(splt) => (
+                    seeded_palette(&reduced, &splt.colors_8bit()).unwrap_or(reduced),
+                    "Indexed (sPLT order)",
+                ),
+                None => (
+                    sort_palette(&reduced).unwrap_or(reduced),
+                    sort_description.as_str(),
+                ),
+            };
+            let new = Arc::new(new);
             // For relatively small differences, enter this into the evaluator
             // Otherwise we're confident enough for it to become the baseline
             if png.data.len() - new.data.len() <= INDEXED_MAX_DIFF {
-                eval.try_image_with_description(new.clone(), "Indexed (luma sort)");
+                eval.try_image_with_description(new.clone(), description);
                 evaluation_added = true;
             } else {
                 baseline = new.clone();
@@ -133,7 +436,15 @@ pub(crate) fn perform_reductions(
     }
 
     // Attempt additional palette sorting techniques
-    if !cheap && opts.palette_reduction {
+    // An existing palette is left alone if its order needs to be preserved, but a new one may be sorted
+    // unless it follows the suggested palette
+    if !cheap
+        && opts.palette_reduction
+        && match indexed {
+            Some(_) => splt.is_none(),
+            None => !opts.preserve_palette_order,
+        }
+    {
         // Collect a list of palettes so we can avoid evaluating the same one twice
         let mut palettes = Vec::new();
         if let ColorType::Indexed { palette } = &baseline.ihdr.color_type {
@@ -145,7 +456,7 @@ pub(crate) fn perform_reductions(
 
         // Attempt to sort the palette using the battiato method
         if !deadline.passed() {
-            if let Some(reduced) = sorted_palette_battiato(input) {
+            if let Some(reduced) = attempt("Battiato sort", sorted_palette_battiato(input)) {
                 if let ColorType::Indexed { palette } = &reduced.ihdr.color_type {
                     if !palettes.contains(palette) {
                         palettes.push(palette.clone());
@@ -161,7 +472,7 @@ pub(crate) fn perform_reductions(
 
         // Attempt to sort the palette using the mzeng method
         if !deadline.passed() {
-            if let Some(reduced) = sorted_palette_mzeng(input) {
+            if let Some(reduced) = attempt("Mzeng sort", sorted_palette_mzeng(input)) {
                 if let ColorType::Indexed { palette } = &reduced.ihdr.color_type {
                     if !palettes.contains(palette) {
                         palettes.push(palette.clone());
@@ -176,10 +487,15 @@ pub(crate) fn perform_reductions(
     // Attempt to reduce to a lower bit depth
     if opts.bit_depth_reduction && !deadline.passed() {
         // First try the `png` var
-        let reduced = reduced_bit_depth_8_or_less(&png);
+        let reduced = attempt("Bit depth reduction", reduced_bit_depth_8_or_less(&png));
         // Then try the `indexed` var, unless we're doing cheap evaluations and already have a reduction
         if (!cheap || reduced.is_none()) && !deadline.passed() {
-            if let Some(indexed) = indexed.and_then(|png| reduced_bit_depth_8_or_less(&png)) {
+            if let Some(indexed) = indexed.and_then(|png| {
+                attempt(
+                    "Indexed bit depth reduction",
+                    reduced_bit_depth_8_or_less(&png),
+                )
+            }) {
                 // Only evaluate this if it's different from the first result (which must be grayscale if it exists)
                 if reduced.as_ref().map_or(true, |r| r.data != indexed.data) {
                     eval.try_image(Arc::new(indexed));
@@ -192,10 +508,20 @@ pub(crate) fn perform_reductions(
             eval.try_image(Arc::new(reduced));
             evaluation_added = true;
         }
+        // Also try the grayscale conversion of an indexed image
+        if let Some(reduced) = gray.and_then(|png| {
+            attempt(
+                "Grayscale bit depth reduction",
+                reduced_bit_depth_8_or_less(&png),
+            )
+        }) {
+            eval.try_image(Arc::new(reduced));
+            evaluation_added = true;
+        }
     }
 
     if evaluation_added {
         eval.try_image(baseline.clone());
     }
-    baseline
+    (baseline, palette_stats)
 }
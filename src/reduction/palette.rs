@@ -1,39 +1,177 @@
-use indexmap::IndexSet;
-use rgb::RGBA8;
+use std::{cmp::Reverse, fmt, fmt::Display};
+
+use indexmap::{IndexMap, IndexSet};
+use rgb::{RGB8, RGBA8};
 
 use crate::{
     colors::{BitDepth, ColorType},
     headers::IhdrData,
     png::{scan_lines::ScanLine, PngImage},
+    reduction::Rejection,
     Interlacing,
 };
 
-/// Attempt to reduce the number of colors in the palette, returning the reduced image if successful
-#[must_use]
-pub fn reduced_palette(png: &PngImage, optimize_alpha: bool) -> Option<PngImage> {
-    if png.ihdr.bit_depth != BitDepth::Eight {
-        return None;
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// Ordering to use when sorting the palette
+pub enum PaletteSort {
+    /// Sort by ascending alpha and descending luma, which keeps the tRNS chunk as short as possible
+    Luma,
+    /// Sort by ascending alpha only, keeping the existing order of colors with equal alpha
+    ///
+    /// This minimizes the length of the tRNS chunk, which can matter more than the luma order for
+    /// large palettes with few transparent entries.
+    Alpha,
+    /// Sort by descending frequency of use in the image, with ties resolved by the luma order
+    Frequency,
+    /// Order the colors as a nearest-neighbor tour starting from the darkest opaque color, so that
+    /// consecutive entries are as similar as possible
+    Nearest,
+}
+
+impl Display for PaletteSort {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        Display::fmt(
+            match self {
+                Self::Luma => "luma",
+                Self::Alpha => "alpha",
+                Self::Frequency => "frequency",
+                Self::Nearest => "nearest",
+            },
+            f,
+        )
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// Weights of the objectives combined by the `Luma` palette sort
+///
+/// Each color is scored by every objective, and the palette is sorted by the weighted sum of the
+/// scores. The alpha score is scaled so that it takes precedence over the others at equal
+/// weights, while the other scores share the range of the luma score.
+pub struct PaletteSortWeights {
+    /// Ascending alpha, which puts transparent entries first to keep the tRNS chunk short
+    pub alpha: u32,
+    /// Descending luma
+    pub luma: u32,
+    /// Descending frequency of use, which clusters the most common colors at low indices
+    pub frequency: u32,
+    /// Placing the opaque color of the bKGD chunk before others of the same alpha
+    pub background: u32,
+}
+
+impl Default for PaletteSortWeights {
+    fn default() -> Self {
+        Self {
+            alpha: 1,
+            luma: 1,
+            frequency: 0,
+            background: 0,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// A color to keep at index 0 of the palette
+pub enum PalettePin {
+    /// The given color, which is added to the palette if the image doesn't use it
+    Color(RGBA8),
+    /// The first fully transparent color, or the transparent palette color if there is none
+    Transparent,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// Statistics describing how the palette of an indexed image was reduced
+pub struct PaletteReductionStats {
+    /// The number of entries in the original palette
+    pub original_len: usize,
+    /// The number of entries in the output palette
+    pub final_len: usize,
+    /// The number of used entries that were merged into an identical color, or one that differs
+    /// only in the bits declared insignificant by an sBIT chunk
+    pub duplicates_merged: usize,
+    /// The number of used fully transparent entries that were merged into a single transparent color
+    pub transparent_merged: usize,
+}
+
+impl PaletteReductionStats {
+    /// Set the lengths to those of the original and output palettes, returning the statistics if
+    /// both images are indexed
+    pub(crate) fn with_lengths(self, original: &PngImage, output: &PngImage) -> Option<Self> {
+        match (&original.ihdr.color_type, &output.ihdr.color_type) {
+            (
+                ColorType::Indexed { palette },
+                ColorType::Indexed {
+                    palette: final_palette,
+                },
+            ) => Some(Self {
+                original_len: palette.len(),
+                final_len: final_palette.len(),
+                ..self
+            }),
+            _ => None,
+        }
     }
+}
+
+/// Attempt to reduce the number of colors in the palette, returning the reduced image if successful
+///
+/// If `transparent_color` is given, all fully transparent entries are merged into a single entry
+/// of that color.
+///
+/// Like the other palette functions, this assumes the palette has no more than 256 entries, since
+/// the bit depth caps pixel indices at 255. Longer palettes in malformed input are rejected when
+/// the image is read, and duplicate entries are merged here.
+pub fn reduced_palette(
+    png: &PngImage,
+    transparent_color: Option<RGB8>,
+) -> Result<PngImage, Rejection> {
+    reduced_palette_with_stats(png, transparent_color, None).map(|(reduced, _)| reduced)
+}
+
+/// Attempt to reduce the number of colors in the palette, returning the reduced image along with
+/// statistics of the entries that were merged if successful
+///
+/// If `sbit` is the data of an sBIT chunk, colors that differ only in the bits it declares
+/// insignificant are also merged, keeping the first of them unchanged. An invalid sBIT chunk is
+/// ignored.
+pub fn reduced_palette_with_stats(
+    png: &PngImage,
+    transparent_color: Option<RGB8>,
+    sbit: Option<&[u8]>,
+) -> Result<(PngImage, PaletteReductionStats), Rejection> {
+    // Lower bit depths are also handled, so that unused entries can be dropped without expanding the data
     let ColorType::Indexed { palette } = &png.ihdr.color_type else {
-        return None;
+        return Err(Rejection::UnsupportedColorType);
     };
 
-    let mut used = [false; 256];
-    for &byte in &png.data {
-        used[byte as usize] = true;
-    }
+    let counts = color_counts(png);
+
+    let mask = sbit
+        .and_then(significant_bits_mask)
+        .unwrap_or(RGB8::new(255, 255, 255));
 
     let black = RGBA8::new(0, 0, 0, 255);
-    let mut condensed = IndexSet::with_capacity(palette.len());
+    let mut condensed = IndexMap::with_capacity(palette.len());
     let mut byte_map = [0; 256];
     let mut did_change = false;
-    for (i, used) in used.iter().enumerate() {
-        if !used {
+    let mut duplicates_merged = 0;
+    let mut transparent_merged = 0;
+    for (i, &count) in counts.iter().enumerate() {
+        if count == 0 {
             continue;
         }
         // There are invalid files that use pixel indices beyond palette size
         let color = *palette.get(i).unwrap_or(&black);
-        byte_map[i] = add_color_to_set(color, &mut condensed, optimize_alpha);
+        let len = condensed.len();
+        byte_map[i] = add_color_to_set(color, &mut condensed, transparent_color, mask);
+        if condensed.len() == len {
+            // The color was merged into an earlier entry
+            if transparent_color.is_some() && color.a == 0 {
+                transparent_merged += 1;
+            } else {
+                duplicates_merged += 1;
+            }
+        }
         if byte_map[i] as usize != i {
             did_change = true;
         }
@@ -41,75 +179,320 @@ pub fn reduced_palette(png: &PngImage, optimize_alpha: bool) -> Option<PngImage>
 
     let data = if did_change {
         // Reassign data bytes to new indices
-        png.data.iter().map(|b| byte_map[*b as usize]).collect()
+        remapped_data(png, &byte_map)
     } else if condensed.len() != palette.len() {
         // Data is unchanged but palette is different size
         // Note the new palette could potentially be larger if the original had a missing entry
         png.data.clone()
     } else {
         // Nothing has changed
-        return None;
+        return Err(Rejection::Unchanged);
+    };
+
+    let stats = PaletteReductionStats {
+        original_len: palette.len(),
+        final_len: condensed.len(),
+        duplicates_merged,
+        transparent_merged,
+    };
+    let palette: Vec<_> = condensed.into_values().collect();
+
+    Ok((
+        PngImage {
+            ihdr: IhdrData {
+                color_type: ColorType::Indexed { palette },
+                ..png.ihdr
+            },
+            data,
+        },
+        stats,
+    ))
+}
+
+// Greedily order the colors so that each is followed by the closest remaining one
+fn nearest_neighbor_tour(mut remaining: Vec<(usize, &RGBA8)>) -> Vec<(usize, &RGBA8)> {
+    let mut tour = Vec::with_capacity(remaining.len());
+    // Start from the darkest opaque color, or the darkest color if none are opaque
+    let luma = |c: &RGBA8| u32::from(c.r) * 299 + u32::from(c.g) * 587 + u32::from(c.b) * 114;
+    let start = remaining
+        .iter()
+        .enumerate()
+        .min_by_key(|&(_, &(_, c))| (c.a != 255, luma(c)))
+        .map(|(pos, _)| pos);
+    let Some(start) = start else {
+        return tour;
+    };
+    let mut current = remaining.remove(start);
+    tour.push(current);
+    while !remaining.is_empty() {
+        // Ties are resolved by position, so the first (lowest index) candidate wins
+        let (pos, _) = remaining
+            .iter()
+            .enumerate()
+            .min_by_key(|&(_, &(_, c))| color_distance(*current.1, *c))
+            .unwrap();
+        current = remaining.remove(pos);
+        tour.push(current);
+    }
+    tour
+}
+
+// The largest luma value, which is the range shared by the scores other than alpha
+const MAX_LUMA: i64 = 255 * 1000;
+
+// Sort key for the weighted sum of the palette sort objectives
+fn weighted_sort_key(
+    color: &RGBA8,
+    count: u32,
+    max_count: u32,
+    background: Option<RGB8>,
+    weights: &PaletteSortWeights,
+) -> i64 {
+    let a = i64::from(color.a);
+    // Put 7 high bits of alpha first, then the other scores, then low bit of alpha
+    let alpha = ((a & 0xFE) << 18) + (a & 0x01);
+    let luma = i64::from(color.r) * 299 + i64::from(color.g) * 587 + i64::from(color.b) * 114;
+    let frequency = if max_count == 0 {
+        0
+    } else {
+        i64::from(count) * MAX_LUMA / i64::from(max_count)
+    };
+    let is_background = color.a == 255 && background == Some(color.rgb());
+    let background = if is_background { MAX_LUMA } else { 0 };
+    i64::from(weights.alpha) * alpha
+        - i64::from(weights.luma) * luma
+        - i64::from(weights.frequency) * frequency
+        - i64::from(weights.background) * background
+}
+
+// Sort key for ascending alpha and descending luma
+fn luma_sort_key(color: &RGBA8) -> i32 {
+    let a = i32::from(color.a);
+    // Put 7 high bits of alpha first, then luma, then low bit of alpha
+    // This provides notable improvement in images with a lot of alpha
+    ((a & 0xFE) << 18) + (a & 0x01)
+    // These are coefficients for standard sRGB to luma conversion
+    - i32::from(color.r) * 299
+    - i32::from(color.g) * 587
+    - i32::from(color.b) * 114
+}
+
+/// Merge palette colors that are within the given distance of each other, returning the merged image
+/// if successful
+///
+/// This is a lossy transformation. Each cluster of similar colors is represented by its most used
+/// member and the image data is rewritten to use it. Fully transparent colors are left alone, as
+/// these are handled by the alpha optimization in `reduced_palette`.
+pub fn merged_similar_palette(png: &PngImage, max_delta: u8) -> Result<PngImage, Rejection> {
+    if png.ihdr.bit_depth != BitDepth::Eight {
+        return Err(Rejection::UnsupportedBitDepth);
+    }
+    if max_delta == 0 {
+        return Err(Rejection::InvalidParameter);
+    }
+    let palette = match &png.ihdr.color_type {
+        ColorType::Indexed { palette } if palette.len() > 1 => palette,
+        _ => return Err(Rejection::UnsupportedColorType),
+    };
+
+    // Visit the colors from most to least used so that the most used member of a cluster becomes its representative
+    let counts = color_counts(png);
+    let mut order: Vec<_> = (0..palette.len()).filter(|&i| counts[i] != 0).collect();
+    order.sort_by_key(|&i| Reverse(counts[i]));
+
+    // The distance weights sum to 12, so scale the threshold to match
+    let max_dist = u32::from(max_delta).pow(2) * 12;
+    let mut representatives: Vec<usize> = Vec::new();
+    let mut byte_map: Vec<u8> = (0..=255).collect();
+    let mut did_change = false;
+    for i in order {
+        if palette[i].a == 0 {
+            continue;
+        }
+        let nearest = representatives
+            .iter()
+            .map(|&r| (r, color_distance(palette[i], palette[r])))
+            .filter(|&(_, d)| d <= max_dist)
+            .min_by_key(|&(_, d)| d);
+        if let Some((r, _)) = nearest {
+            byte_map[i] = r as u8;
+            did_change = true;
+        } else {
+            representatives.push(i);
+        }
+    }
+    if !did_change {
+        return Err(Rejection::Unchanged);
+    }
+
+    let merged = PngImage {
+        ihdr: png.ihdr.clone(),
+        data: png.data.iter().map(|&b| byte_map[b as usize]).collect(),
+    };
+    // Remove the entries that are no longer used
+    Ok(reduced_palette(&merged, None).unwrap_or(merged))
+}
+
+// Weighted squared distance between two colors
+pub(crate) fn color_distance(a: RGBA8, b: RGBA8) -> u32 {
+    let diff = |x: u8, y: u8| u32::from(x.abs_diff(y)).pow(2);
+    // Green is weighted highest and blue lowest, approximating their perceptual contribution
+    diff(a.r, b.r) * 3 + diff(a.g, b.g) * 4 + diff(a.b, b.b) * 2 + diff(a.a, b.a) * 3
+}
+
+/// Attempt to convert an indexed image with an all-gray palette to grayscale, returning the converted
+/// image if successful
+///
+/// A single fully transparent shade will be preserved as a tRNS chunk, otherwise any transparency
+/// requires an alpha channel.
+pub fn indexed_to_grayscale(png: &PngImage) -> Result<PngImage, Rejection> {
+    if png.ihdr.bit_depth != BitDepth::Eight {
+        return Err(Rejection::UnsupportedBitDepth);
+    }
+    let palette = match &png.ihdr.color_type {
+        ColorType::Indexed { palette } if !palette.is_empty() => palette,
+        _ => return Err(Rejection::UnsupportedColorType),
     };
+    if palette.iter().any(|c| c.r != c.g || c.g != c.b) {
+        return Err(Rejection::NotGray);
+    }
 
-    let palette: Vec<_> = condensed.into_iter().collect();
+    // Look at the colors that are actually used (there may be invalid indices beyond the palette)
+    let counts = color_counts(png);
+    let mut used = Vec::with_capacity(palette.len());
+    for (i, &count) in counts.iter().enumerate() {
+        if count != 0 {
+            used.push(*palette.get(i).ok_or(Rejection::InvalidPalette)?);
+        }
+    }
 
-    Some(PngImage {
+    let opaque = |shade: u8| used.iter().any(|c| c.a == 255 && c.r == shade);
+    let mut transparent = used.iter().filter(|c| c.a != 255);
+    let color_type = match transparent.next() {
+        None => ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        // A single fully transparent shade which isn't also used for opaque pixels can go in tRNS
+        Some(&trns) if trns.a == 0 && !opaque(trns.r) && transparent.all(|&c| c == trns) => {
+            ColorType::Grayscale {
+                transparent_shade: Some(trns.r.into()),
+            }
+        }
+        _ => ColorType::GrayscaleAlpha,
+    };
+
+    let data = match color_type {
+        ColorType::GrayscaleAlpha => png
+            .data
+            .iter()
+            .flat_map(|&b| [palette[b as usize].r, palette[b as usize].a])
+            .collect(),
+        _ => png.data.iter().map(|&b| palette[b as usize].r).collect(),
+    };
+
+    Ok(PngImage {
         ihdr: IhdrData {
-            color_type: ColorType::Indexed { palette },
+            color_type,
             ..png.ihdr
         },
         data,
     })
 }
 
-fn add_color_to_set(mut color: RGBA8, set: &mut IndexSet<RGBA8>, optimize_alpha: bool) -> u8 {
+// Add the color to the set, keyed by its significant bits, returning its index in the set
+fn add_color_to_set(
+    mut color: RGBA8,
+    set: &mut IndexMap<RGBA8, RGBA8>,
+    transparent_color: Option<RGB8>,
+    mask: RGB8,
+) -> u8 {
     // If there are multiple fully transparent entries, reduce them into one
-    if optimize_alpha && color.a == 0 {
-        color.r = 0;
-        color.g = 0;
-        color.b = 0;
+    if let (Some(transparent), 0) = (transparent_color, color.a) {
+        color = RGBA8::new(transparent.r, transparent.g, transparent.b, 0);
     }
-    let (idx, _) = set.insert_full(color);
+    let key = RGBA8::new(
+        color.r & mask.r,
+        color.g & mask.g,
+        color.b & mask.b,
+        color.a,
+    );
+    let entry = set.entry(key);
+    let idx = entry.index();
+    entry.or_insert(color);
     idx as u8
 }
 
-/// Attempt to sort the colors in the palette by luma, returning the sorted image if successful
-#[must_use]
-pub fn sorted_palette(png: &PngImage) -> Option<PngImage> {
-    if png.ihdr.bit_depth != BitDepth::Eight {
+// Get the mask of the significant bits of each channel declared by the sBIT chunk of an indexed image
+fn significant_bits_mask(sbit: &[u8]) -> Option<RGB8> {
+    let &[r, g, b] = sbit else {
         return None;
+    };
+    if [r, g, b].iter().any(|&bits| bits == 0 || bits > 8) {
+        return None;
+    }
+    let mask = |bits: u8| 0xFFu8 << (8 - bits);
+    Some(RGB8::new(mask(r), mask(g), mask(b)))
+}
+
+/// Attempt to sort the colors in the palette using the given ordering, returning the sorted image if successful
+pub fn sorted_palette(png: &PngImage, sort: PaletteSort) -> Result<PngImage, Rejection> {
+    sorted_palette_weighted(png, sort, &PaletteSortWeights::default(), None)
+}
+
+/// Attempt to sort the colors in the palette using the given ordering, returning the sorted image if successful
+///
+/// The `Luma` sort combines its objectives using the given weights, where `background` is the
+/// color of the bKGD chunk, if any.
+pub fn sorted_palette_weighted(
+    png: &PngImage,
+    sort: PaletteSort,
+    weights: &PaletteSortWeights,
+    background: Option<RGB8>,
+) -> Result<PngImage, Rejection> {
+    if png.ihdr.bit_depth != BitDepth::Eight {
+        return Err(Rejection::UnsupportedBitDepth);
     }
     let palette = match &png.ihdr.color_type {
         ColorType::Indexed { palette } if palette.len() > 1 => palette,
-        _ => return None,
+        _ => return Err(Rejection::UnsupportedColorType),
     };
 
     let mut enumerated: Vec<_> = palette.iter().enumerate().collect();
-    // Put the most popular edge color first, which can help slightly if the filter bytes are 0
-    let keep_first = most_popular_edge_color(palette.len(), png);
-    let first = enumerated.remove(keep_first);
-
-    // Sort the palette
-    enumerated.sort_by(|a, b| {
-        // Sort by ascending alpha and descending luma
-        let color_val = |color: &RGBA8| {
-            let a = i32::from(color.a);
-            // Put 7 high bits of alpha first, then luma, then low bit of alpha
-            // This provides notable improvement in images with a lot of alpha
-            ((a & 0xFE) << 18) + (a & 0x01)
-            // These are coefficients for standard sRGB to luma conversion
-            - i32::from(color.r) * 299
-            - i32::from(color.g) * 587
-            - i32::from(color.b) * 114
-        };
-        color_val(a.1).cmp(&color_val(b.1))
-    });
-    enumerated.insert(0, first);
+    match sort {
+        PaletteSort::Luma => {
+            // Put the most popular edge color first, which can help slightly if the filter bytes are 0
+            let keep_first = most_popular_edge_color(palette.len(), png);
+            let first = enumerated.remove(keep_first);
+            // Only count the colors if the frequency is used
+            let counts = if weights.frequency > 0 {
+                color_counts(png)
+            } else {
+                [0; 256]
+            };
+            let max_count = counts.iter().copied().max().unwrap_or_default();
+            enumerated.sort_by_key(|&(i, color)| {
+                weighted_sort_key(color, counts[i], max_count, background, weights)
+            });
+            enumerated.insert(0, first);
+        }
+        PaletteSort::Alpha => {
+            // A stable sort keeps fully transparent entries together without reordering the rest
+            enumerated.sort_by_key(|&(_, color)| color.a);
+        }
+        PaletteSort::Frequency => {
+            // Most used colors first, falling back to the luma order (and then the original order) on ties
+            let counts = color_counts(png);
+            enumerated.sort_by_key(|&(i, color)| (Reverse(counts[i]), luma_sort_key(color)));
+        }
+        PaletteSort::Nearest => {
+            enumerated = nearest_neighbor_tour(enumerated);
+        }
+    }
 
     // Extract the new palette and determine if anything changed
     let (remapping, palette): (Vec<_>, Vec<RGBA8>) = enumerated.into_iter().unzip();
     if remapping.iter().enumerate().all(|(a, b)| a == *b) {
-        return None;
+        return Err(Rejection::Unchanged);
     }
 
     // Construct the new mapping and convert the data
@@ -119,7 +502,7 @@ pub fn sorted_palette(png: &PngImage) -> Option<PngImage> {
     }
     let data = png.data.iter().map(|&b| byte_map[b as usize]).collect();
 
-    Some(PngImage {
+    Ok(PngImage {
         ihdr: IhdrData {
             color_type: ColorType::Indexed { palette },
             ..png.ihdr
@@ -128,17 +511,54 @@ pub fn sorted_palette(png: &PngImage) -> Option<PngImage> {
     })
 }
 
+/// Move the given color to index 0 of the palette, returning the reordered image if successful
+///
+/// If the color is not in the palette, it is inserted at index 0. The order of the other colors
+/// is kept.
+pub fn pinned_palette(png: &PngImage, color: RGBA8) -> Result<PngImage, Rejection> {
+    if png.ihdr.bit_depth != BitDepth::Eight {
+        return Err(Rejection::UnsupportedBitDepth);
+    }
+    let ColorType::Indexed { palette } = &png.ihdr.color_type else {
+        return Err(Rejection::UnsupportedColorType);
+    };
+
+    match palette.iter().position(|&c| c == color) {
+        Some(0) => Err(Rejection::Unchanged),
+        Some(index) => {
+            let remapping: Vec<_> = [index]
+                .into_iter()
+                .chain((0..palette.len()).filter(|&i| i != index))
+                .collect();
+            apply_palette_reorder(png, &remapping)
+        }
+        None if palette.len() >= 256 => Err(Rejection::TooManyColors),
+        None => {
+            let palette = [color].iter().chain(palette).copied().collect();
+            Ok(PngImage {
+                ihdr: IhdrData {
+                    color_type: ColorType::Indexed { palette },
+                    ..png.ihdr
+                },
+                data: png.data.iter().map(|&b| b + 1).collect(),
+            })
+        }
+    }
+}
+
 /// Sort the colors in the palette using the mzeng technique, returning the sorted image if successful
-#[must_use]
-pub fn sorted_palette_mzeng(png: &PngImage) -> Option<PngImage> {
+pub fn sorted_palette_mzeng(png: &PngImage) -> Result<PngImage, Rejection> {
     // Interlacing not currently supported
-    if png.ihdr.bit_depth != BitDepth::Eight || png.ihdr.interlaced != Interlacing::None {
-        return None;
+    if png.ihdr.bit_depth != BitDepth::Eight {
+        return Err(Rejection::UnsupportedBitDepth);
+    }
+    if png.ihdr.interlaced != Interlacing::None {
+        return Err(Rejection::Interlaced);
     }
     let palette = match &png.ihdr.color_type {
         // Images with only two colors will remain unchanged from previous luma sort
         ColorType::Indexed { palette } if palette.len() > 2 => palette,
-        _ => return None,
+        _ => return Err(Rejection::UnsupportedColorType),
     };
 
     let matrix = co_occurrence_matrix(palette.len(), png);
@@ -151,16 +571,18 @@ pub fn sorted_palette_mzeng(png: &PngImage) -> Option<PngImage> {
 }
 
 /// Sort the colors in the palette using the battiato technique, returning the sorted image if successful
-#[must_use]
-pub fn sorted_palette_battiato(png: &PngImage) -> Option<PngImage> {
+pub fn sorted_palette_battiato(png: &PngImage) -> Result<PngImage, Rejection> {
     // Interlacing not currently supported
-    if png.ihdr.bit_depth != BitDepth::Eight || png.ihdr.interlaced != Interlacing::None {
-        return None;
+    if png.ihdr.bit_depth != BitDepth::Eight {
+        return Err(Rejection::UnsupportedBitDepth);
+    }
+    if png.ihdr.interlaced != Interlacing::None {
+        return Err(Rejection::Interlaced);
     }
     let palette = match &png.ihdr.color_type {
         // Images with only two colors will remain unchanged from previous luma sort
         ColorType::Indexed { palette } if palette.len() > 2 => palette,
-        _ => return None,
+        _ => return Err(Rejection::UnsupportedColorType),
     };
 
     let matrix = co_occurrence_matrix(palette.len(), png);
@@ -172,15 +594,38 @@ pub fn sorted_palette_battiato(png: &PngImage) -> Option<PngImage> {
     apply_palette_reorder(png, &remapping)
 }
 
+/// Reorder the palette so that the given colors come first, in the given order, returning the
+/// reordered image if successful
+///
+/// Colors that are not in the palette are skipped, and the remaining palette colors follow in
+/// their existing order.
+pub fn seeded_palette(png: &PngImage, seed: &[RGBA8]) -> Result<PngImage, Rejection> {
+    if png.ihdr.bit_depth != BitDepth::Eight {
+        return Err(Rejection::UnsupportedBitDepth);
+    }
+    let ColorType::Indexed { palette } = &png.ihdr.color_type else {
+        return Err(Rejection::UnsupportedColorType);
+    };
+
+    let mut remapping: IndexSet<usize> = seed
+        .iter()
+        .filter_map(|color| palette.iter().position(|c| c == color))
+        .collect();
+    remapping.extend(0..palette.len());
+    let remapping: Vec<_> = remapping.into_iter().collect();
+
+    apply_palette_reorder(png, &remapping)
+}
+
 // Apply the palette reordering to the image data
-fn apply_palette_reorder(png: &PngImage, remapping: &[usize]) -> Option<PngImage> {
+fn apply_palette_reorder(png: &PngImage, remapping: &[usize]) -> Result<PngImage, Rejection> {
     let ColorType::Indexed { palette } = &png.ihdr.color_type else {
-        return None;
+        return Err(Rejection::UnsupportedColorType);
     };
 
     // Check if anything changed
     if remapping.iter().enumerate().all(|(a, b)| a == *b) {
-        return None;
+        return Err(Rejection::Unchanged);
     }
 
     // Construct the palette and byte maps and convert the data
@@ -192,7 +637,7 @@ fn apply_palette_reorder(png: &PngImage, remapping: &[usize]) -> Option<PngImage
     }
     let data = png.data.iter().map(|&b| byte_map[b as usize]).collect();
 
-    Some(PngImage {
+    Ok(PngImage {
         ihdr: IhdrData {
             color_type: ColorType::Indexed {
                 palette: new_palette,
@@ -222,13 +667,58 @@ fn most_popular_edge_color(num_colors: usize, png: &PngImage) -> usize {
         .0
 }
 
-// Find the most popular color in the image, along with its count
-fn most_popular_color(num_colors: usize, png: &PngImage) -> (usize, u32) {
+// Count the number of times each palette index is used in the image
+fn color_counts(png: &PngImage) -> [u32; 256] {
     let mut counts = [0u32; 256];
-    for &val in &png.data {
-        counts[val as usize] += 1;
+    let bit_depth = png.ihdr.bit_depth as usize;
+    if bit_depth == 8 {
+        for &val in &png.data {
+            counts[val as usize] += 1;
+        }
+        return counts;
+    }
+    // Unpack the pixels of each line, ignoring any padding at the end
+    let mask = (1 << bit_depth) - 1;
+    for line in png.scan_lines(false) {
+        for i in 0..line.num_pixels {
+            let shift = 8 - bit_depth - (i * bit_depth) % 8;
+            let val = (line.data[i * bit_depth / 8] >> shift) & mask;
+            counts[val as usize] += 1;
+        }
     }
     counts
+}
+
+// Reassign the palette indices of the image data using the byte map, at any bit depth
+fn remapped_data(png: &PngImage, byte_map: &[u8; 256]) -> Vec<u8> {
+    let bit_depth = png.ihdr.bit_depth as usize;
+    if bit_depth == 8 {
+        return png.data.iter().map(|&b| byte_map[b as usize]).collect();
+    }
+    let mask = (1 << bit_depth) - 1;
+    let mut data = Vec::with_capacity(png.data.len());
+    for line in png.scan_lines(false) {
+        // Remap each pixel packed into the byte
+        data.extend(line.data.iter().map(|&byte| {
+            (0..8).step_by(bit_depth).fold(0, |acc, shift| {
+                let val = (byte >> shift) & mask;
+                acc | (byte_map[val as usize] & mask) << shift
+            })
+        }));
+        // Clear the padding bits at the end of the line, which may not have been zero to begin with
+        let padding = line.data.len() * 8 - line.num_pixels * bit_depth;
+        if padding > 0 {
+            if let Some(last) = data.last_mut() {
+                *last &= 0xFF << padding;
+            }
+        }
+    }
+    data
+}
+
+// Find the most popular color in the image, along with its count
+fn most_popular_color(num_colors: usize, png: &PngImage) -> (usize, u32) {
+    color_counts(png)
         .iter()
         .copied()
         .take(num_colors)
@@ -0,0 +1,283 @@
+use rgb::{ComponentMap, FromSlice, RGBA8};
+use rustc_hash::FxHashMap;
+
+use crate::{
+    colors::{BitDepth, ColorType},
+    headers::IhdrData,
+    png::PngImage,
+    reduction::{palette::color_distance, Rejection},
+};
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// Dithering to use when mapping pixels to a quantized palette
+pub enum Dithering {
+    /// Map each pixel to the nearest palette color
+    None,
+    /// Floyd-Steinberg error diffusion
+    FloydSteinberg,
+    /// Ordered dithering with an 8x8 Bayer matrix
+    Ordered,
+}
+
+/// The 8x8 Bayer threshold matrix
+const BAYER_8X8: [[u8; 8]; 8] = [
+    [0, 32, 8, 40, 2, 34, 10, 42],
+    [48, 16, 56, 24, 50, 18, 58, 26],
+    [12, 44, 4, 36, 14, 46, 6, 38],
+    [60, 28, 52, 20, 62, 30, 54, 22],
+    [3, 35, 11, 43, 1, 33, 9, 41],
+    [51, 19, 59, 27, 49, 17, 57, 25],
+    [15, 47, 7, 39, 13, 45, 5, 37],
+    [63, 31, 55, 23, 61, 29, 53, 21],
+];
+
+/// A box of colors in the median cut, with the number of pixels of each color
+struct ColorBox {
+    colors: Vec<(RGBA8, u32)>,
+}
+
+impl ColorBox {
+    /// The channel with the largest range of values, and that range
+    fn widest_channel(&self) -> (usize, u8) {
+        (0..4)
+            .map(|c| {
+                let values = self.colors.iter().map(|(px, _)| channel(px, c));
+                let range = values.clone().max().unwrap() - values.min().unwrap();
+                (c, range)
+            })
+            .max_by_key(|&(_, range)| range)
+            .unwrap()
+    }
+
+    fn pixel_count(&self) -> u64 {
+        self.colors.iter().map(|&(_, n)| u64::from(n)).sum()
+    }
+
+    /// Split the box at the weighted median of its widest channel
+    fn split(mut self) -> (Self, Self) {
+        let (c, _) = self.widest_channel();
+        self.colors.sort_unstable_by_key(|(px, _)| channel(px, c));
+        let half = self.pixel_count() / 2;
+        let mut count = 0;
+        let mut mid = 1;
+        for (i, &(_, n)) in self.colors.iter().enumerate() {
+            count += u64::from(n);
+            if count >= half {
+                mid = i + 1;
+                break;
+            }
+        }
+        // Both halves must have at least one color
+        let mid = mid.clamp(1, self.colors.len() - 1);
+        let rest = self.colors.split_off(mid);
+        (self, Self { colors: rest })
+    }
+
+    /// The average color of the box, weighted by pixel count
+    fn average(&self) -> RGBA8 {
+        let total = self.pixel_count();
+        let mean = |c: usize| {
+            let sum: u64 = self
+                .colors
+                .iter()
+                .map(|(px, n)| u64::from(channel(px, c)) * u64::from(*n))
+                .sum();
+            ((sum + total / 2) / total) as u8
+        };
+        RGBA8::new(mean(0), mean(1), mean(2), mean(3))
+    }
+}
+
+fn channel(px: &RGBA8, c: usize) -> u8 {
+    match c {
+        0 => px.r,
+        1 => px.g,
+        2 => px.b,
+        _ => px.a,
+    }
+}
+
+/// Quantize an 8-bit RGB or RGBA image to a palette of at most `max_colors` colors using median
+/// cut, returning the indexed image if successful
+///
+/// This is a lossy transformation. Pixels are mapped to the palette using the given dithering.
+/// Images that already have few enough colors are left alone, as they can be converted to indexed
+/// losslessly.
+pub fn quantized_to_indexed(
+    png: &PngImage,
+    max_colors: u16,
+    dithering: Dithering,
+) -> Result<PngImage, Rejection> {
+    if png.ihdr.bit_depth != BitDepth::Eight || !(2..=256).contains(&max_colors) {
+        return Err(Rejection::InvalidParameter);
+    }
+    let pixels: Vec<RGBA8> = match png.ihdr.color_type {
+        ColorType::RGB { transparent_color } => {
+            let trns = transparent_color.map(|t| t.map(|c| c as u8));
+            png.data
+                .as_rgb()
+                .iter()
+                .map(|&px| px.alpha(if Some(px) == trns { 0 } else { 255 }))
+                .collect()
+        }
+        ColorType::RGBA => png.data.as_rgba().to_vec(),
+        _ => return Err(Rejection::UnsupportedColorType),
+    };
+
+    let mut counts = FxHashMap::<RGBA8, u32>::default();
+    for px in &pixels {
+        *counts.entry(*px).or_default() += 1;
+    }
+    if counts.len() <= max_colors as usize {
+        return Err(Rejection::Unchanged);
+    }
+
+    // Repeatedly split the box with the widest range, weighted by its number of pixels
+    let mut boxes = vec![ColorBox {
+        colors: counts.into_iter().collect(),
+    }];
+    while boxes.len() < max_colors as usize {
+        let Some((i, _)) = boxes
+            .iter()
+            .enumerate()
+            .filter(|(_, b)| b.colors.len() > 1)
+            .map(|(i, b)| (i, u64::from(b.widest_channel().1) * b.pixel_count()))
+            .max_by_key(|&(_, score)| score)
+        else {
+            break;
+        };
+        let (a, b) = boxes.swap_remove(i).split();
+        boxes.push(a);
+        boxes.push(b);
+    }
+    let palette: Vec<RGBA8> = boxes.iter().map(ColorBox::average).collect();
+
+    let data = match dithering {
+        Dithering::None => {
+            let mut mapper = PaletteMapper::new(&palette);
+            pixels.iter().map(|&px| mapper.nearest(px)).collect()
+        }
+        Dithering::FloydSteinberg => floyd_steinberg(png, &pixels, &palette),
+        Dithering::Ordered => ordered(png, &pixels, &palette),
+    };
+
+    Ok(PngImage {
+        data,
+        ihdr: IhdrData {
+            color_type: ColorType::Indexed { palette },
+            ..png.ihdr
+        },
+    })
+}
+
+/// Finds the nearest palette color, caching the result for each input color
+struct PaletteMapper<'a> {
+    palette: &'a [RGBA8],
+    cache: FxHashMap<RGBA8, u8>,
+}
+
+impl<'a> PaletteMapper<'a> {
+    fn new(palette: &'a [RGBA8]) -> Self {
+        Self {
+            palette,
+            cache: FxHashMap::default(),
+        }
+    }
+
+    fn nearest(&mut self, px: RGBA8) -> u8 {
+        let palette = self.palette;
+        *self.cache.entry(px).or_insert_with(|| {
+            (0..palette.len())
+                .min_by_key(|&i| color_distance(px, palette[i]))
+                .unwrap() as u8
+        })
+    }
+}
+
+/// Map the pixels to the palette using Floyd-Steinberg error diffusion
+fn floyd_steinberg(png: &PngImage, pixels: &[RGBA8], palette: &[RGBA8]) -> Vec<u8> {
+    let mut mapper = PaletteMapper::new(palette);
+    let mut data = Vec::with_capacity(pixels.len());
+    // Accumulated error for each channel of the current and next lines
+    let mut errors: Vec<[f32; 4]> = Vec::new();
+    let mut next_errors: Vec<[f32; 4]> = Vec::new();
+    let mut pass = None;
+    let mut offset = 0;
+    for line in png.scan_lines(false) {
+        let width = line.num_pixels;
+        if line.pass == pass && next_errors.len() == width {
+            std::mem::swap(&mut errors, &mut next_errors);
+        } else {
+            // Start afresh on the first line and on each interlacing pass
+            errors.clear();
+            errors.resize(width, [0.0; 4]);
+            pass = line.pass;
+        }
+        next_errors.clear();
+        next_errors.resize(width, [0.0; 4]);
+
+        for (x, px) in pixels[offset..offset + width].iter().enumerate() {
+            let target: [f32; 4] =
+                std::array::from_fn(|c| f32::from(channel(px, c)) + errors[x][c]);
+            let [r, g, b, a] = target.map(|v| v.round().clamp(0.0, 255.0) as u8);
+            let idx = mapper.nearest(RGBA8::new(r, g, b, a));
+            data.push(idx);
+
+            // Distribute the error to neighbouring pixels, without wrapping around the edges of
+            // the line
+            let chosen = palette[idx as usize];
+            for (c, target) in target.iter().enumerate() {
+                let error = target - f32::from(channel(&chosen, c));
+                if x + 1 < width {
+                    errors[x + 1][c] += error * 7.0 / 16.0;
+                    next_errors[x + 1][c] += error / 16.0;
+                }
+                if x > 0 {
+                    next_errors[x - 1][c] += error * 3.0 / 16.0;
+                }
+                next_errors[x][c] += error * 5.0 / 16.0;
+            }
+        }
+        offset += width;
+    }
+    data
+}
+
+/// Map the pixels to the palette using ordered dithering
+///
+/// The color channels are offset by a threshold from the Bayer matrix, scaled to the approximate
+/// spacing of the palette colors. Alpha is not dithered.
+fn ordered(png: &PngImage, pixels: &[RGBA8], palette: &[RGBA8]) -> Vec<u8> {
+    let mut mapper = PaletteMapper::new(palette);
+    let spread = 255.0 / (palette.len() as f32).cbrt();
+    let mut data = Vec::with_capacity(pixels.len());
+    let mut pass = None;
+    let mut y = 0;
+    let mut offset = 0;
+    for line in png.scan_lines(false) {
+        if line.pass != pass {
+            // Restart the pattern on each interlacing pass
+            pass = line.pass;
+            y = 0;
+        }
+        let width = line.num_pixels;
+        for (x, px) in pixels[offset..offset + width].iter().enumerate() {
+            let threshold = (f32::from(BAYER_8X8[y % 8][x % 8]) + 0.5) / 64.0 - 0.5;
+            let offset_channel = |v: u8| {
+                (f32::from(v) + threshold * spread)
+                    .round()
+                    .clamp(0.0, 255.0) as u8
+            };
+            let target = RGBA8::new(
+                offset_channel(px.r),
+                offset_channel(px.g),
+                offset_channel(px.b),
+                px.a,
+            );
+            data.push(mapper.nearest(target));
+        }
+        offset += width;
+        y += 1;
+    }
+    data
+}
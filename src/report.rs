@@ -0,0 +1,257 @@
+use std::time::Duration;
+
+use indexmap::IndexSet;
+
+use crate::{
+    colors::{BitDepth, ColorType},
+    deflate::{inflate, Deflaters},
+    filters::RowFilter,
+    headers::parse_next_chunk,
+    interlace::Interlacing,
+    png::{is_position_marker, PngData, PngImage, RecoveredData},
+    reduction::palette::PaletteReductionStats,
+    Options,
+};
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+/// The output of optimization, distinguishing whether the file could be made smaller
+pub enum OptimizationOutcome {
+    /// The output is smaller than the input
+    Improved(Vec<u8>),
+    /// The output is not smaller than the input
+    ///
+    /// This holds the re-serialized output if `force` is set, which may differ from the input in
+    /// chunk order or size even when the pixels are identical. Otherwise it holds the input bytes
+    /// exactly.
+    Unchanged(Vec<u8>),
+}
+
+impl OptimizationOutcome {
+    /// Whether the output is smaller than the input
+    pub fn is_improved(&self) -> bool {
+        matches!(self, Self::Improved(_))
+    }
+
+    /// The output bytes, regardless of whether they were improved
+    pub fn into_output(self) -> Vec<u8> {
+        match self {
+            Self::Improved(output) | Self::Unchanged(output) => output,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// The result of a single compression trial from
+/// [`benchmark_strategies`](crate::benchmark_strategies)
+pub struct StrategyTrial {
+    /// The filter strategy applied to the image data
+    pub filter: RowFilter,
+    /// The deflater used to compress the filtered data
+    pub deflater: Deflaters,
+    /// The size of the compressed image data in bytes
+    pub size: usize,
+    /// The time taken to filter and compress the image data
+    pub duration: Duration,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+/// A summary of the changes made by optimization
+pub struct OptimizationReport {
+    /// The size of the input file in bytes
+    pub input_size: usize,
+    /// The size of the output file in bytes
+    pub output_size: usize,
+    /// The filter used for the image data, or `None` if the original image data was kept
+    ///
+    /// If filters were chosen separately for each interlacing pass, this is only set if every
+    /// pass used the same filter; see `pass_filters`. If the input's original filters were kept,
+    /// this is the most common of them.
+    pub chosen_filter: Option<RowFilter>,
+    /// The filter chosen for each interlacing pass, or empty if they were not chosen separately
+    pub pass_filters: Vec<RowFilter>,
+    /// The filter type of each scanline in the output image data
+    ///
+    /// For interlaced images, this is the scanlines of each pass in turn. This can be passed to
+    /// [`recompress_from_memory`](crate::recompress_from_memory) to reproduce the filtering. It is empty if the
+    /// output image data could not be read.
+    pub row_filters: Vec<u8>,
+    /// The deflater used for the image data, or `None` if the original image data was kept
+    pub chosen_deflater: Option<Deflaters>,
+    /// The color type of the input image
+    pub color_type_before: ColorType,
+    /// The color type of the output image
+    pub color_type_after: ColorType,
+    /// The bit depth of the input image
+    pub bit_depth_before: BitDepth,
+    /// The bit depth of the output image
+    pub bit_depth_after: BitDepth,
+    /// The interlacing of the output image
+    pub interlaced: Interlacing,
+    /// The names of chunks in the input file that are not in the output file
+    pub stripped_chunks: Vec<[u8; 4]>,
+    /// The names of chunks in the input file with an incorrect CRC, which are only accepted when
+    /// `fix_errors` is set. The output always has correct CRCs.
+    pub crc_mismatches: Vec<[u8; 4]>,
+    /// How much of the input image data was recovered, if it was corrupt and
+    /// `recover_image_data` is set
+    pub recovered: Option<RecoveredData>,
+    /// Statistics of the palette reduction, if both the input and output images are indexed and
+    /// the output differs from the input
+    pub palette_stats: Option<PaletteReductionStats>,
+    /// The output, distinguishing whether it is smaller than the input, or `None` if `dry_run`
+    /// was set
+    pub outcome: Option<OptimizationOutcome>,
+}
+
+impl OptimizationReport {
+    pub(crate) fn new(
+        input: &[u8],
+        output: &[u8],
+        original: &PngImage,
+        optimized: &PngImage,
+        trial: Option<(RowFilter, Deflaters)>,
+        pass_filters: Vec<RowFilter>,
+        recovered: Option<RecoveredData>,
+    ) -> Self {
+        let mut idat_data = Vec::new();
+        let mut offset = 8;
+        while let Ok(Some(chunk)) = parse_next_chunk(output, &mut offset, true) {
+            if &chunk.name == b"IDAT" {
+                idat_data.extend_from_slice(chunk.data);
+            }
+        }
+        Self {
+            recovered,
+            ..Self::with_output_chunks(
+                input,
+                output.len(),
+                &chunk_names(output),
+                &idat_data,
+                original,
+                optimized,
+                trial,
+            )
+        }
+        .with_pass_filters(pass_filters)
+    }
+
+    /// Create a report for optimized data that has not been serialized
+    pub(crate) fn estimated(
+        input: &[u8],
+        output: &PngData,
+        original: &PngImage,
+        trial: Option<(RowFilter, Deflaters)>,
+        opts: &Options,
+    ) -> Self {
+        let mut output_chunks: IndexSet<_> = [*b"IHDR", *b"IDAT", *b"IEND"].into();
+        output_chunks.extend(
+            output
+                .aux_chunks
+                .iter()
+                .filter(|c| !is_position_marker(c))
+                .map(|c| c.name),
+        );
+        match &output.raw.ihdr.color_type {
+            ColorType::Indexed { palette } => {
+                output_chunks.insert(*b"PLTE");
+                if palette.iter().any(|c| c.a != 255) {
+                    output_chunks.insert(*b"tRNS");
+                }
+            }
+            color_type if color_type.has_trns() => {
+                output_chunks.insert(*b"tRNS");
+            }
+            _ => (),
+        }
+        Self {
+            recovered: output.recovered,
+            ..Self::with_output_chunks(
+                input,
+                output.output_size(opts),
+                &output_chunks,
+                &output.idat_data,
+                original,
+                &output.raw,
+                trial,
+            )
+        }
+        .with_pass_filters(output.pass_filters.clone())
+    }
+
+    fn with_output_chunks(
+        input: &[u8],
+        output_size: usize,
+        output_chunks: &IndexSet<[u8; 4]>,
+        idat_data: &[u8],
+        original: &PngImage,
+        optimized: &PngImage,
+        trial: Option<(RowFilter, Deflaters)>,
+    ) -> Self {
+        let stripped_chunks = chunk_names(input)
+            .into_iter()
+            .filter(|name| !output_chunks.contains(name))
+            .collect();
+        Self {
+            input_size: input.len(),
+            output_size,
+            chosen_filter: trial.map(|(filter, _)| filter),
+            pass_filters: Vec::new(),
+            row_filters: row_filters(idat_data, optimized),
+            chosen_deflater: trial.map(|(_, deflater)| deflater),
+            color_type_before: original.ihdr.color_type.clone(),
+            color_type_after: optimized.ihdr.color_type.clone(),
+            bit_depth_before: original.ihdr.bit_depth,
+            bit_depth_after: optimized.ihdr.bit_depth,
+            interlaced: optimized.ihdr.interlaced,
+            stripped_chunks,
+            crc_mismatches: crc_mismatches(input),
+            recovered: None,
+            palette_stats: None,
+            outcome: None,
+        }
+    }
+
+    /// Record the filters chosen for each interlacing pass, keeping the chosen filter only if
+    /// every pass used it
+    fn with_pass_filters(mut self, pass_filters: Vec<RowFilter>) -> Self {
+        if pass_filters.iter().any(|&f| Some(f) != self.chosen_filter) {
+            self.chosen_filter = None;
+        }
+        self.pass_filters = pass_filters;
+        self
+    }
+}
+
+/// Read the filter type of each scanline from compressed image data
+fn row_filters(idat_data: &[u8], image: &PngImage) -> Vec<u8> {
+    let Ok(data) = inflate(idat_data, image.ihdr.raw_data_size()) else {
+        return Vec::new();
+    };
+    let filtered = PngImage {
+        data,
+        ihdr: image.ihdr.clone(),
+    };
+    filtered.scan_lines(true).map(|line| line.filter).collect()
+}
+
+/// Collect the unique chunk names in a PNG file, in order of first appearance
+fn chunk_names(data: &[u8]) -> IndexSet<[u8; 4]> {
+    let mut names = IndexSet::new();
+    let mut offset = 8;
+    while let Ok(Some(chunk)) = parse_next_chunk(data, &mut offset, true) {
+        names.insert(chunk.name);
+    }
+    names
+}
+
+/// Collect the names of chunks in a PNG file whose CRC doesn't match their contents
+fn crc_mismatches(data: &[u8]) -> Vec<[u8; 4]> {
+    let mut names = Vec::new();
+    let mut offset = 8;
+    while let Ok(Some(chunk)) = parse_next_chunk(data, &mut offset, true) {
+        if !chunk.crc_valid {
+            names.push(chunk.name);
+        }
+    }
+    names
+}
@@ -855,3 +855,133 @@ fn filter_5_for_palette_1() {
         BitDepth::One,
     );
 }
+
+#[test]
+fn filter_10_for_rgba_8() {
+    test_it_converts(
+        "tests/files/filter_5_for_rgba_8.png",
+        RowFilter::WeightedSum,
+        RGBA,
+        BitDepth::Eight,
+        RGBA,
+        BitDepth::Eight,
+    );
+}
+
+#[test]
+fn filter_10_is_minsum_for_rgba() {
+    let png = PngData::new(
+        Path::new("tests/files/filter_5_for_rgba_8.png"),
+        &Options::default(),
+    )
+    .unwrap();
+    assert_eq!(
+        png.raw.filter_image(RowFilter::WeightedSum, false),
+        png.raw.filter_image(RowFilter::MinSum, false)
+    );
+}
+
+#[test]
+fn filter_10_for_grayscale_8() {
+    test_it_converts(
+        "tests/files/filter_5_for_grayscale_8.png",
+        RowFilter::WeightedSum,
+        GRAYSCALE,
+        BitDepth::Eight,
+        GRAYSCALE,
+        BitDepth::Eight,
+    );
+}
+
+#[test]
+fn simd_filters_match_scalar() {
+    // A simple generator that covers all byte values, including the extremes
+    let mut state = 0x2545_f491_u32;
+    let mut next = || {
+        state ^= state << 13;
+        state ^= state >> 17;
+        state ^= state << 5;
+        match state % 8 {
+            0 => 0,
+            1 => 255,
+            _ => (state >> 8) as u8,
+        }
+    };
+    // Bytes per pixel for every combination of bit depth and channel count
+    for bpp in 1..=8 {
+        for len in [bpp, bpp + 1, 16, 17, bpp + 16, 100, 257] {
+            let len = len.max(bpp);
+            let data: Vec<u8> = (0..len).map(|_| next()).collect();
+            let prev_line: Vec<u8> = (0..len).map(|_| next()).collect();
+            let mut expected = vec![0; len];
+            let mut actual = vec![0; len];
+
+            average_filter_scalar(bpp, &data, &prev_line, &mut expected);
+            average_filter(bpp, &data, &prev_line, &mut actual);
+            assert_eq!(actual, expected, "Average, bpp {bpp}, length {len}");
+
+            paeth_filter_scalar(bpp, &data, &prev_line, &mut expected);
+            paeth_filter(bpp, &data, &prev_line, &mut actual);
+            assert_eq!(actual, expected, "Paeth, bpp {bpp}, length {len}");
+
+            assert_eq!(
+                sum_abs(&data),
+                sum_abs_scalar(&data),
+                "Sum, bpp {bpp}, length {len}"
+            );
+        }
+    }
+}
+
+#[test]
+fn heuristics_use_up_for_repeated_rows() {
+    let width = 16;
+    let rows: [&[u8]; 5] = [
+        &[
+            10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 110, 120, 130, 140, 150, 160,
+        ],
+        &[
+            10, 20, 30, 40, 50, 60, 70, 80, 90, 100, 110, 120, 130, 140, 150, 160,
+        ],
+        &[
+            5, 9, 200, 3, 77, 150, 8, 19, 0, 255, 33, 64, 99, 12, 180, 41,
+        ],
+        &[
+            5, 9, 200, 3, 77, 150, 8, 19, 0, 255, 33, 64, 99, 12, 180, 41,
+        ],
+        &[
+            5, 9, 200, 3, 77, 150, 8, 19, 0, 255, 33, 64, 99, 12, 180, 41,
+        ],
+    ];
+    let png = PngImage {
+        ihdr: IhdrData {
+            width,
+            height: rows.len() as u32,
+            color_type: ColorType::Grayscale {
+                transparent_shade: None,
+            },
+            bit_depth: BitDepth::Eight,
+            interlaced: Interlacing::None,
+        },
+        data: rows.concat(),
+    };
+
+    for filter in RowFilter::MinSum as u8..=RowFilter::LAST {
+        let filter = RowFilter::try_from(filter).unwrap();
+        let filtered = png.filter_image(filter, false);
+        for (i, row) in filtered.chunks(width as usize + 1).enumerate() {
+            if i == 1 || i >= 3 {
+                assert_eq!(row[0], RowFilter::Up as u8, "{filter}, row {i}");
+                assert!(row[1..].iter().all(|&b| b == 0), "{filter}, row {i}");
+            }
+        }
+    }
+}
+
+#[test]
+#[should_panic]
+fn simd_filter_short_prev_line() {
+    let data = vec![0; 64];
+    let mut out = vec![0; 64];
+    paeth_filter(4, &data, &data[..20], &mut out);
+}
@@ -245,6 +245,50 @@ fn strip_headers_list() {
     remove_file(output).ok();
 }
 
+#[test]
+fn strip_headers_private() {
+    let mut raw = RawImage::new(
+        1,
+        1,
+        ColorType::RGB {
+            transparent_color: None,
+        },
+        BitDepth::Eight,
+        vec![0, 0, 0],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"prVt", vec![1]);
+    raw.add_png_chunk(*b"prVu", vec![2]);
+    raw.add_png_chunk(*b"tEXt", b"key\0value".to_vec());
+    raw.add_png_chunk(*b"CRIt", vec![3]);
+    let file = raw.create_optimized_png(&Options::default()).unwrap();
+
+    let opts = Options {
+        force: true,
+        // Names are case-sensitive, and critical chunks can never be stripped
+        strip: StripChunks::Strip(indexset![*b"prVt", *b"PRVU", *b"CRIt"]),
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&file, &opts).unwrap();
+    let png = PngData::from_slice(&output, &opts).unwrap();
+
+    assert_eq!(count_chunk(&png, b"prVt"), 0);
+    assert_eq!(count_chunk(&png, b"prVu"), 1);
+    assert_eq!(count_chunk(&png, b"tEXt"), 1);
+    assert_eq!(count_chunk(&png, b"CRIt"), 1);
+
+    let opts = Options {
+        force: true,
+        strip: StripChunks::All,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&file, &opts).unwrap();
+    let png = PngData::from_slice(&output, &opts).unwrap();
+
+    assert_eq!(count_chunk(&png, b"prVu"), 0);
+    assert_eq!(count_chunk(&png, b"CRIt"), 1);
+}
+
 #[test]
 fn strip_headers_safe() {
     let input = PathBuf::from("tests/files/strip_headers_safe.png");
@@ -279,6 +323,41 @@ fn strip_headers_safe() {
     remove_file(output).ok();
 }
 
+#[test]
+fn strip_headers_metadata() {
+    let input = PathBuf::from("tests/files/strip_headers_metadata.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.strip = StripChunks::Metadata;
+
+    let png = PngData::new(&input, &Options::default()).unwrap();
+
+    assert_eq!(count_chunk(&png, b"tEXt"), 3);
+    assert_eq!(count_chunk(&png, b"iTXt"), 1);
+    assert_eq!(count_chunk(&png, b"iCCP"), 1);
+
+    match oxipng::optimize(&InFile::Path(input), &output, &opts) {
+        Ok(_) => (),
+        Err(x) => panic!("{}", x),
+    };
+    let output = output.path().unwrap();
+    assert!(output.exists());
+
+    let png = match PngData::new(output, &opts) {
+        Ok(x) => x,
+        Err(x) => {
+            remove_file(output).ok();
+            panic!("{}", x)
+        }
+    };
+
+    assert_eq!(count_chunk(&png, b"tEXt"), 0);
+    assert_eq!(count_chunk(&png, b"iTXt"), 0);
+    // The color profile is kept, though it may be replaced with an equivalent sRGB chunk
+    assert_eq!(count_chunk(&png, b"iCCP") + count_chunk(&png, b"sRGB"), 1);
+
+    remove_file(output).ok();
+}
+
 #[test]
 fn strip_headers_all() {
     let input = PathBuf::from("tests/files/strip_headers_all.png");
@@ -607,6 +686,23 @@ fn fix_errors() {
     remove_file(output).ok();
 }
 
+#[test]
+fn verify_mode() {
+    let input = PathBuf::from("tests/files/rgba_8_should_be_palette_8.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.verify = true;
+
+    test_it_converts(
+        input,
+        &output,
+        &opts,
+        RGBA,
+        BitDepth::Eight,
+        INDEXED,
+        BitDepth::Eight,
+    );
+}
+
 #[test]
 fn no_color_type_change() {
     let input = PathBuf::from("tests/files/palette_8_should_be_rgb.png");
@@ -641,6 +737,42 @@ fn no_grayscale_change() {
     );
 }
 
+#[test]
+fn no_indexed_change() {
+    let input = PathBuf::from("tests/files/rgba_8_should_be_palette_8.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.indexed_reduction = false;
+
+    test_it_converts(
+        input,
+        &output,
+        &opts,
+        RGBA,
+        BitDepth::Eight,
+        RGB,
+        BitDepth::Eight,
+    );
+}
+
+#[test]
+fn no_format_change() {
+    let input = PathBuf::from("tests/files/rgba_8_should_be_grayscale_8.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.bit_depth_reduction = false;
+    opts.color_type_reduction = false;
+    opts.palette_reduction = false;
+
+    test_it_converts(
+        input,
+        &output,
+        &opts,
+        RGBA,
+        BitDepth::Eight,
+        RGBA,
+        BitDepth::Eight,
+    );
+}
+
 #[test]
 fn no_bit_depth_change() {
     let input = PathBuf::from("tests/files/palette_4_should_be_palette_2.png");
@@ -694,3 +826,174 @@ fn zopfli_mode() {
         BitDepth::Eight,
     );
 }
+
+#[test]
+#[cfg(feature = "zopfli")]
+fn zopfli_mode_single_iteration() {
+    let input = PathBuf::from("tests/files/zopfli_mode.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.deflate = Deflaters::Zopfli {
+        iterations: NonZeroU8::new(1).unwrap(),
+    };
+
+    test_it_converts(
+        input,
+        &output,
+        &opts,
+        INDEXED,
+        BitDepth::Eight,
+        INDEXED,
+        BitDepth::Eight,
+    );
+}
+
+#[test]
+#[cfg(all(feature = "zopfli", feature = "binary"))]
+fn zopfli_iterations_cli() {
+    use std::process::Command;
+
+    let input = "tests/files/zopfli_mode.png";
+    let run = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_oxipng"))
+            .args(args)
+            .args(["--stdout", input])
+            .output()
+            .unwrap()
+    };
+
+    // The iteration count given on the command line reaches the Zopfli deflater
+    let data = std::fs::read(input).unwrap();
+    for iterations in [1, 3] {
+        let output = run(&["-Z", "--zi", &iterations.to_string()]);
+        assert!(output.status.success());
+        let opts = Options {
+            deflate: Deflaters::Zopfli {
+                iterations: NonZeroU8::new(iterations).unwrap(),
+            },
+            ..Options::from_preset(2)
+        };
+        assert_eq!(
+            output.stdout,
+            oxipng::optimize_from_memory(&data, &opts).unwrap()
+        );
+    }
+
+    // The count must be at least 1, and requires Zopfli to be enabled
+    assert!(!run(&["-Z", "--zi", "0"]).status.success());
+    assert!(!run(&["--zi", "3"]).status.success());
+}
+
+#[test]
+#[cfg(feature = "zlib-ng")]
+fn zlibng_mode() {
+    let input = PathBuf::from("tests/files/zopfli_mode.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.deflate = Deflaters::Zlibng { level: 9 };
+
+    test_it_converts(
+        input,
+        &output,
+        &opts,
+        INDEXED,
+        BitDepth::Eight,
+        INDEXED,
+        BitDepth::Eight,
+    );
+}
+
+#[test]
+#[cfg(feature = "zlib-ng")]
+fn zlibng_mode_round_trip() {
+    let input = std::fs::read("tests/files/rgb_8_should_be_rgb_8.png").unwrap();
+    let mut opts = Options::default();
+    for level in [1, 6, 9] {
+        opts.deflate = Deflaters::Zlibng { level };
+        let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+        let original = PngData::from_slice(&input, &opts).unwrap();
+        let png = PngData::from_slice(&output, &opts).unwrap();
+        assert_eq!(png.raw.data, original.raw.data);
+    }
+
+    opts.deflate = Deflaters::Zlibng { level: 10 };
+    assert!(oxipng::optimize_from_memory(&input, &opts).is_err());
+}
+
+#[test]
+#[cfg(feature = "zopfli")]
+fn zopfli_mode_split() {
+    let input = PathBuf::from("tests/files/zopfli_mode.png");
+    let (output, mut opts) = get_opts(&input);
+    opts.deflate = Deflaters::Zopfli {
+        iterations: NonZeroU8::new(15).unwrap(),
+    };
+    opts.zopfli_splits = 4;
+
+    test_it_converts(
+        input,
+        &output,
+        &opts,
+        INDEXED,
+        BitDepth::Eight,
+        INDEXED,
+        BitDepth::Eight,
+    );
+}
+
+#[test]
+#[cfg(feature = "zopfli")]
+fn zopfli_split_round_trip() {
+    let png = PngData::new(
+        Path::new("tests/files/rgb_8_should_be_rgb_8.png"),
+        &Options::default(),
+    )
+    .unwrap();
+    let data = &png.raw.data;
+    let iterations = NonZeroU8::new(1).unwrap();
+    for split_points in [
+        vec![1],
+        vec![data.len() / 3, data.len() / 2],
+        vec![7, 8, 9, 10],
+    ] {
+        for single_block in [false, true] {
+            let compressed =
+                zopfli_deflate_split(data, iterations, single_block, &split_points, None).unwrap();
+            assert_eq!(&inflate(&compressed, data.len()).unwrap(), data);
+        }
+    }
+}
+
+#[test]
+fn preserve_palette_order() {
+    let input = PathBuf::from("tests/files/palette_8_should_be_palette_8.png");
+    let (_, mut opts) = get_opts(&input);
+    opts.color_type_reduction = false;
+    opts.preserve_palette_order = true;
+
+    let original = PngData::read_file(&input).unwrap();
+    let png = PngData::from_slice(&original, &opts).unwrap();
+    let ColorType::Indexed { palette } = &png.raw.ihdr.color_type else {
+        panic!("Expected indexed color type");
+    };
+
+    let output = oxipng::optimize_from_memory(&original, &opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    let ColorType::Indexed {
+        palette: new_palette,
+    } = &new.raw.ihdr.color_type
+    else {
+        panic!("Expected indexed color type");
+    };
+
+    // The used colors should remain in their original order, with duplicates removed
+    let mut used = [false; 256];
+    for &b in &png.raw.data {
+        used[b as usize] = true;
+    }
+    let mut expected = Vec::new();
+    for (i, color) in palette.iter().enumerate() {
+        if used[i] && !expected.contains(color) {
+            expected.push(*color);
+        }
+    }
+    assert_eq!(new_palette, &expected);
+}
@@ -676,3 +676,50 @@ fn interlaced_odd_width() {
         BitDepth::Eight,
     );
 }
+
+#[test]
+fn interlaced_per_pass_filters() {
+    let input = PathBuf::from("tests/files/interlaced_rgb_8_should_be_rgb_8.png");
+    let (_, mut opts) = get_opts(&input);
+    opts.filter = indexset! {
+        RowFilter::None,
+        RowFilter::Sub,
+        RowFilter::Up,
+        RowFilter::Paeth,
+        RowFilter::MinSum
+    };
+    let in_data = PngData::read_file(&input).unwrap();
+    let original = PngData::from_slice(&in_data, &opts).unwrap();
+    let whole = oxipng::optimize_from_memory(&in_data, &opts).unwrap();
+    opts.per_pass_filters = true;
+    let per_pass = oxipng::optimize_from_memory(&in_data, &opts).unwrap();
+    assert!(per_pass.len() < whole.len());
+
+    let png = PngData::from_slice(&per_pass, &opts).unwrap();
+    assert_eq!(png.raw.ihdr.interlaced, Interlacing::Adam7);
+    assert_eq!(png.raw.data, original.raw.data);
+}
+
+#[test]
+fn interlaced_per_pass_filters_report() {
+    let input = PathBuf::from("tests/files/interlaced_rgb_8_should_be_rgb_8.png");
+    let (_, mut opts) = get_opts(&input);
+    opts.filter = indexset! {
+        RowFilter::None,
+        RowFilter::Sub,
+        RowFilter::Up,
+        RowFilter::Paeth,
+        RowFilter::MinSum
+    };
+    opts.per_pass_filters = true;
+    let in_data = PngData::read_file(&input).unwrap();
+    let report = oxipng::optimize_from_memory_with_report(&in_data, &opts).unwrap();
+
+    // Every pass is reported, and there is no single filter as they differ
+    assert_eq!(report.pass_filters.len(), 7);
+    assert!(report
+        .pass_filters
+        .iter()
+        .any(|&f| f != report.pass_filters[0]));
+    assert_eq!(report.chosen_filter, None);
+}
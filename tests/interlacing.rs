@@ -207,3 +207,103 @@ fn interlace_palette_1() {
         BitDepth::One,
     );
 }
+
+fn test_it_picks_smaller(input: &str) {
+    let input = PathBuf::from(input);
+    let (_, mut opts) = get_opts(&input);
+    let in_data = PngData::read_file(&input).unwrap();
+
+    opts.interlace = Some(Interlacing::None);
+    let progressive = oxipng::optimize_from_memory(&in_data, &opts).unwrap();
+    opts.auto_interlace = Some(AutoInterlacing::Trial);
+    let auto = oxipng::optimize_from_memory(&in_data, &opts).unwrap();
+    assert!(auto.len() <= progressive.len());
+
+    let png = PngData::from_slice(&auto, &opts).unwrap();
+    let expected = if auto.len() < progressive.len() {
+        Interlacing::Adam7
+    } else {
+        Interlacing::None
+    };
+    assert_eq!(png.raw.ihdr.interlaced, expected);
+}
+
+#[test]
+fn auto_interlace_rgb_8() {
+    test_it_picks_smaller("tests/files/interlaced_rgb_8_should_be_rgb_8.png");
+}
+
+#[test]
+fn auto_interlace_palette_1() {
+    test_it_picks_smaller("tests/files/interlaced_palette_1_should_be_palette_1.png");
+}
+
+#[test]
+fn auto_interlace_lossy_once() {
+    let input = PathBuf::from("tests/files/interlaced_rgb_8_should_be_rgb_8.png");
+    let (_, mut opts) = get_opts(&input);
+    let in_data = PngData::read_file(&input).unwrap();
+    opts.lossy_quantize = Some(16);
+    opts.alpha_levels = Some(4);
+
+    opts.interlace = Some(Interlacing::None);
+    let progressive = oxipng::optimize_from_memory(&in_data, &opts).unwrap();
+    opts.auto_interlace = Some(AutoInterlacing::Trial);
+    let auto = oxipng::optimize_from_memory(&in_data, &opts).unwrap();
+
+    // The lossy transformations are applied once, so both have the same pixels
+    let expected = PngData::from_slice(&progressive, &opts).unwrap().raw;
+    let png = PngData::from_slice(&auto, &opts).unwrap().raw;
+    let png = png
+        .change_interlacing(Interlacing::None)
+        .unwrap_or(png.as_ref().clone());
+    assert_eq!(png.ihdr.color_type, expected.ihdr.color_type);
+    assert_eq!(png.data, expected.data);
+}
+
+fn test_it_predicts(data: Vec<u8>, expected: Interlacing) {
+    let color_type = ColorType::RGB {
+        transparent_color: None,
+    };
+    let raw = RawImage::new(32, 32, color_type, BitDepth::Eight, data).unwrap();
+    let opts = Options::default();
+    let progressive = raw.create_optimized_png(&opts).unwrap();
+    let png = PngData::from_slice(&progressive, &opts).unwrap();
+    assert_eq!(predict_interlacing(&png.raw), expected);
+
+    // The prediction doesn't depend on the interlacing of the input
+    let interlaced = png.raw.change_interlacing(Interlacing::Adam7).unwrap();
+    assert_eq!(predict_interlacing(&interlaced), expected);
+
+    let opts = Options {
+        auto_interlace: Some(AutoInterlacing::Heuristic),
+        ..opts
+    };
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let png = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(png.raw.ihdr.interlaced, expected);
+}
+
+#[test]
+fn heuristic_interlace_pattern() {
+    // A pattern that repeats every 2 pixels in each direction
+    let data = (0..32 * 32)
+        .flat_map(|i| {
+            let (x, y) = (i % 32, i / 32);
+            [(x % 2) as u8 * 200, (y % 2) as u8 * 200, 50]
+        })
+        .collect();
+    test_it_predicts(data, Interlacing::Adam7);
+}
+
+#[test]
+fn heuristic_interlace_blocks() {
+    // Flat blocks of 8x8 pixels
+    let data = (0..32 * 32)
+        .flat_map(|i| {
+            let (x, y) = (i % 32, i / 32);
+            [(x / 8) as u8 * 60, (y / 8) as u8 * 60, 50]
+        })
+        .collect();
+    test_it_predicts(data, Interlacing::None);
+}
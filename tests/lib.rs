@@ -1,7 +1,21 @@
-use std::{fs, fs::File, io::prelude::*};
+use std::{
+    fs,
+    fs::File,
+    io::prelude::*,
+    num::NonZeroU32,
+    ops::ControlFlow,
+    sync::{Arc, Mutex},
+};
 
 use oxipng::*;
 
+/// Optimize from memory with a report, taking the output out of the report
+fn optimize_with_report(data: &[u8], opts: &Options) -> (Vec<u8>, OptimizationReport) {
+    let mut report = oxipng::optimize_from_memory_with_report(data, opts).unwrap();
+    let output = report.outcome.take().unwrap().into_output();
+    (output, report)
+}
+
 #[test]
 fn optimize_from_memory() {
     let mut in_file = File::open("tests/files/fully_optimized.png").unwrap();
@@ -32,6 +46,95 @@ fn optimize_from_memory_apng() {
     assert!(result.is_ok());
 }
 
+#[test]
+fn optimize_apng_frames() {
+    let file = fs::read("tests/files/apng_file.png").unwrap();
+    let opts = Options {
+        force: true,
+        ..Options::default()
+    };
+    let input = internal_tests::PngData::from_slice(&file, &opts).unwrap();
+    let output = oxipng::optimize_from_memory(&file, &opts).unwrap();
+    let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+
+    let names = |png: &internal_tests::PngData| -> Vec<[u8; 4]> {
+        png.aux_chunks.iter().map(|c| c.name).collect()
+    };
+    assert_eq!(names(&png), names(&input));
+
+    // Every frame still decompresses to the size given by its fcTL
+    let mut ihdr = png.raw.ihdr.clone();
+    let mut frames = 0;
+    for c in &png.aux_chunks {
+        if &c.name == b"fcTL" {
+            ihdr.width = u32::from_be_bytes(c.data[4..8].try_into().unwrap());
+            ihdr.height = u32::from_be_bytes(c.data[8..12].try_into().unwrap());
+        } else if &c.name == b"fdAT" {
+            let size = ihdr.raw_data_size();
+            assert_eq!(
+                internal_tests::inflate(&c.data[4..], size).unwrap().len(),
+                size
+            );
+            frames += 1;
+        }
+    }
+    assert!(frames > 0);
+
+    // The data of a frame that extends beyond the canvas or is empty is left as it is
+    let last_fdat = |png: &internal_tests::PngData| -> Vec<u8> {
+        let c = png.aux_chunks.iter().rfind(|c| &c.name == b"fdAT");
+        c.unwrap().data.clone()
+    };
+    for height in [0x4000_0000, 0] {
+        let mut file = file.clone();
+        let fctl = file.windows(4).rposition(|w| w == b"fcTL").unwrap();
+        file[fctl + 8..fctl + 12].copy_from_slice(&u32::to_be_bytes(height));
+        for idat_recoding in [true, false] {
+            let opts = Options {
+                fix_errors: true,
+                idat_recoding,
+                ..opts.clone()
+            };
+            let input = internal_tests::PngData::from_slice(&file, &opts).unwrap();
+            let output = oxipng::optimize_from_memory(&file, &opts).unwrap();
+            let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+            assert_eq!(last_fdat(&png), last_fdat(&input));
+        }
+    }
+}
+
+#[test]
+fn parse_header() {
+    let file = fs::read("tests/files/apng_file.png").unwrap();
+    let info = oxipng::parse_header(&file).unwrap();
+    let png = internal_tests::PngData::from_slice(&file, &Options::default()).unwrap();
+    assert_eq!(info.width, png.raw.ihdr.width);
+    assert_eq!(info.height, png.raw.ihdr.height);
+    assert_eq!(info.color_type, png.raw.ihdr.color_type);
+    assert_eq!(info.bit_depth, png.raw.ihdr.bit_depth);
+    assert_eq!(info.interlaced, png.raw.ihdr.interlaced);
+    let names: Vec<_> = info.chunks.iter().map(|(name, _)| name).collect();
+    assert!(names.contains(&b"acTL"));
+    assert!(names.contains(&b"fdAT"));
+    assert!(!names.contains(&b"IHDR"));
+    let idat_size: usize = info
+        .chunks
+        .iter()
+        .filter(|(name, _)| name == b"IDAT")
+        .map(|(_, size)| size)
+        .sum();
+    assert_eq!(idat_size, png.idat_data.len());
+
+    assert!(matches!(
+        oxipng::parse_header(b"not a png file"),
+        Err(PngError::NotPNG)
+    ));
+    assert!(matches!(
+        oxipng::parse_header(&file[..40]),
+        Err(PngError::TruncatedData)
+    ));
+}
+
 #[test]
 fn optimize() {
     let result = oxipng::optimize(
@@ -74,3 +177,2285 @@ fn optimize_srgb_icc() {
     let result = oxipng::optimize_from_memory(&file, &opts);
     assert!(result.unwrap().len() < 1000);
 }
+
+#[test]
+fn optimize_from_memory_palette_stats() {
+    // Create an image with a duplicate color, two transparent colors and an unused color
+    let palette = vec![
+        RGBA8::new(255, 0, 0, 255),
+        RGBA8::new(0, 0, 255, 255),
+        RGBA8::new(255, 0, 0, 255),
+        RGBA8::new(0, 0, 0, 0),
+        RGBA8::new(255, 255, 255, 0),
+    ];
+    let data = vec![0, 2, 3, 4, 0, 2, 3, 4, 0, 2, 3, 4, 0, 2, 3, 4];
+    let raw = RawImage::new(4, 4, ColorType::Indexed { palette }, BitDepth::Eight, data).unwrap();
+    let file = raw
+        .create_optimized_png(&Options {
+            palette_reduction: false,
+            ..Options::default()
+        })
+        .unwrap();
+
+    let opts = Options {
+        force: true,
+        optimize_alpha: true,
+        color_type_reduction: false,
+        ..Options::default()
+    };
+    let report = oxipng::optimize_from_memory_with_report(&file, &opts).unwrap();
+    assert_eq!(
+        report.palette_stats,
+        Some(PaletteReductionStats {
+            original_len: 5,
+            final_len: 2,
+            duplicates_merged: 1,
+            transparent_merged: 1,
+        })
+    );
+}
+
+fn recompress_with_filter_pattern(path: &str) {
+    let in_file_buf = fs::read(path).unwrap();
+    let opts = Options::default();
+    let png = internal_tests::PngData::from_slice(&in_file_buf, &opts).unwrap();
+    let line_count = png.raw.scan_lines(false).count();
+    let filters: Vec<u8> = (0..line_count).map(|i| (i % 5) as u8).collect();
+
+    let result = oxipng::recompress_from_memory(&in_file_buf, &filters, &opts).unwrap();
+    let new_png = internal_tests::PngData::from_slice(&result, &opts).unwrap();
+    assert_eq!(new_png.raw.ihdr.interlaced, png.raw.ihdr.interlaced);
+    assert_eq!(new_png.raw.data, png.raw.data);
+
+    // Check that the exact filters were written
+    let data =
+        internal_tests::inflate(&new_png.idat_data, png.raw.data.len() + line_count).unwrap();
+    let filtered = internal_tests::PngImage {
+        ihdr: new_png.raw.ihdr.clone(),
+        data,
+    };
+    let used: Vec<u8> = filtered.scan_lines(true).map(|l| l.filter).collect();
+    assert_eq!(used, filters);
+}
+
+#[test]
+fn recompress_from_memory() {
+    recompress_with_filter_pattern("tests/files/rgb_8_should_be_rgb_8.png");
+}
+
+#[test]
+fn recompress_from_memory_interlaced() {
+    recompress_with_filter_pattern("tests/files/interlaced_rgb_8_should_be_rgb_8.png");
+}
+
+#[test]
+fn recompress_from_memory_invalid_filters() {
+    let in_file_buf = fs::read("tests/files/rgb_8_should_be_rgb_8.png").unwrap();
+    let opts = Options::default();
+    let png = internal_tests::PngData::from_slice(&in_file_buf, &opts).unwrap();
+    let line_count = png.raw.scan_lines(false).count();
+
+    let filters = vec![0; line_count - 1];
+    let result = oxipng::recompress_from_memory(&in_file_buf, &filters, &opts);
+    assert!(
+        matches!(result, Err(PngError::IncorrectFilterCount(l1, l2)) if l1 == line_count - 1 && l2 == line_count)
+    );
+
+    let mut filters = vec![0; line_count];
+    filters[1] = 5;
+    let result = oxipng::recompress_from_memory(&in_file_buf, &filters, &opts);
+    assert!(matches!(result, Err(PngError::InvalidFilterType(5))));
+}
+
+#[test]
+fn original_filters() {
+    let in_file_buf = fs::read("tests/files/rgb_8_should_be_rgb_8.png").unwrap();
+    let mut opts = Options::default();
+    let png = internal_tests::PngData::from_slice(&in_file_buf, &opts).unwrap();
+    assert_eq!(png.original_filters, None);
+    let line_count = png.raw.scan_lines(false).count();
+
+    // Filter the input entirely with Paeth, which None alone can't match
+    let paeth = vec![4; line_count];
+    let input = oxipng::recompress_from_memory(&in_file_buf, &paeth, &opts).unwrap();
+    opts.original_filters = true;
+    let png = internal_tests::PngData::from_slice(&input, &opts).unwrap();
+    assert_eq!(png.original_filters.as_deref(), Some(&paeth[..]));
+
+    opts.filter = [RowFilter::None].into_iter().collect();
+    opts.force = true;
+    let (output, report) = optimize_with_report(&input, &opts);
+    assert_eq!(report.chosen_filter, Some(RowFilter::Paeth));
+    assert_eq!(report.row_filters, paeth);
+    let new_png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(new_png.raw.data, png.raw.data);
+}
+
+#[test]
+fn min_sum_tie_breaker() {
+    let gray = ColorType::Grayscale {
+        transparent_shade: None,
+    };
+    let raw = RawImage::new(2, 2, gray, BitDepth::Eight, vec![3, 1, 7, 0]).unwrap();
+    let filtered = raw
+        .filter_image(Some(RowFilter::MinSum), &Options::default())
+        .unwrap();
+    // None and Up have the same sum for the second row, but Up is smaller with inverted samples
+    assert_eq!(filtered[3..], [2, 4, 255]);
+}
+
+#[test]
+fn force_filter() {
+    let input = fs::read("tests/files/rgb_8_should_be_rgb_8.png").unwrap();
+    for filter in [
+        RowFilter::None,
+        RowFilter::Sub,
+        RowFilter::Up,
+        RowFilter::Average,
+        RowFilter::Paeth,
+    ] {
+        let opts = Options {
+            force_filter: Some(filter),
+            original_filters: true,
+            force: true,
+            ..Options::default()
+        };
+        let (output, report) = optimize_with_report(&input, &opts);
+        assert_eq!(report.chosen_filter, Some(filter));
+        assert!(report.row_filters.iter().all(|&f| f == filter as u8));
+        let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+        let original = internal_tests::PngData::from_slice(&input, &opts).unwrap();
+        assert_eq!(png.raw.data, original.raw.data);
+    }
+
+    // Heuristic strategies can't be forced
+    let opts = Options {
+        force_filter: Some(RowFilter::MinSum),
+        ..Options::default()
+    };
+    assert!(oxipng::optimize_from_memory(&input, &opts).is_err());
+}
+
+#[test]
+fn benchmark_strategies() {
+    let input = fs::read("tests/files/rgb_8_should_be_rgb_8.png").unwrap();
+    let deflaters = [
+        Deflaters::Libdeflater { compression: 1 },
+        Deflaters::Libdeflater { compression: 12 },
+    ];
+    let trials = oxipng::benchmark_strategies(&input, &deflaters, &Options::default()).unwrap();
+    assert_eq!(
+        trials.len(),
+        (RowFilter::LAST as usize + 1) * deflaters.len()
+    );
+    for (i, trial) in trials.iter().enumerate() {
+        assert_eq!(trial.filter as usize, i / deflaters.len());
+        assert_eq!(trial.deflater, deflaters[i % deflaters.len()]);
+        assert!(trial.size > 0);
+    }
+
+    // The best trial matches a normal optimization limited to its combination
+    let best = trials.iter().min_by_key(|t| t.size).unwrap();
+    let opts = Options {
+        filter: indexset! {best.filter},
+        deflate: best.deflater,
+        fast_evaluation: false,
+        force: true,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(png.idat_data.len(), best.size);
+
+    let invalid = [Deflaters::Libdeflater { compression: 13 }];
+    assert!(oxipng::benchmark_strategies(&input, &invalid, &Options::default()).is_err());
+}
+
+#[test]
+fn stored_blocks_round_trip() {
+    // Sizes around the 65535 byte limit of a single stored block
+    for len in [0, 1, 65535, 65536, 200_000] {
+        let data: Vec<u8> = (0..len).map(|i| (i * 7919 % 251) as u8).collect();
+        let stored = internal_tests::store(&data);
+        assert_eq!(stored.len(), internal_tests::stored_size(len));
+        assert_eq!(internal_tests::inflate(&stored, len).unwrap(), data);
+    }
+}
+
+#[test]
+fn optimize_incompressible_data() {
+    // Pseudo-random noise that no compression method can improve on
+    let mut state = 0x12345678u32;
+    let data: Vec<u8> = (0..4 * 4 * 4)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    let raw = RawImage::new(4, 4, ColorType::RGBA, BitDepth::Eight, data.clone()).unwrap();
+    let opts = Options {
+        filter: indexset! {RowFilter::None},
+        ..Options::default()
+    };
+    let output = raw.create_optimized_png(&opts).unwrap();
+
+    let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(png.raw.data, data);
+    // The filtered data is stored with a single block
+    assert_eq!(
+        png.idat_data.len(),
+        internal_tests::stored_size(data.len() + 4)
+    );
+}
+
+#[test]
+fn recompress_text_chunks() {
+    let text = b"The quick brown fox jumps over the lazy dog. ".repeat(20);
+    let stored = internal_tests::store(&text);
+
+    let mut ztxt = b"Comment\0\0".to_vec();
+    ztxt.extend_from_slice(&stored);
+    let mut itxt = b"Comment\0\x01\0en\0Kommentar\0".to_vec();
+    itxt.extend_from_slice(&stored);
+    let mut corrupt = b"Broken\0\0".to_vec();
+    corrupt.extend_from_slice(&stored[..stored.len() - 10]);
+
+    let mut raw = RawImage::new(
+        1,
+        1,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        vec![0],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"zTXt", ztxt.clone());
+    raw.add_png_chunk(*b"iTXt", itxt.clone());
+    raw.add_png_chunk(*b"zTXt", corrupt.clone());
+    let output = raw.create_optimized_png(&Options::default()).unwrap();
+
+    let png = internal_tests::PngData::from_slice(&output, &Options::default()).unwrap();
+    let chunks: Vec<_> = png
+        .aux_chunks
+        .iter()
+        .filter(|c| &c.name == b"zTXt" || &c.name == b"iTXt")
+        .collect();
+    assert_eq!(chunks.len(), 3);
+
+    assert!(chunks[0].data.len() < ztxt.len());
+    assert_eq!(&chunks[0].data[..9], b"Comment\0\0");
+    assert_eq!(
+        internal_tests::inflate(&chunks[0].data[9..], text.len()).unwrap(),
+        text
+    );
+
+    assert!(chunks[1].data.len() < itxt.len());
+    assert_eq!(&chunks[1].data[..23], b"Comment\0\x01\0en\0Kommentar\0");
+    assert_eq!(
+        internal_tests::inflate(&chunks[1].data[23..], text.len()).unwrap(),
+        text
+    );
+
+    // Chunks that fail to decompress are left untouched
+    assert_eq!(chunks[2].data, corrupt);
+}
+
+#[test]
+#[cfg(feature = "zopfli")]
+fn aux_deflate() {
+    use std::num::NonZeroU8;
+
+    let text = b"The quick brown fox jumps over the lazy dog. ".repeat(20);
+    let mut ztxt = b"Comment\0\0".to_vec();
+    ztxt.extend_from_slice(&internal_tests::store(&text));
+    let mut raw = RawImage::new(
+        1,
+        1,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        vec![0],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"zTXt", ztxt);
+    let ztxt_data = |opts: &Options| {
+        let output = raw.create_optimized_png(opts).unwrap();
+        let png = internal_tests::PngData::from_slice(&output, opts).unwrap();
+        let chunk = png.aux_chunks.iter().find(|c| &c.name == b"zTXt").unwrap();
+        chunk.data.clone()
+    };
+
+    // The text is compressed with the ancillary deflater, not the one for the image data
+    let libdeflater = Deflaters::Libdeflater { compression: 12 };
+    let opts = Options {
+        deflate: Deflaters::Zopfli {
+            iterations: NonZeroU8::new(15).unwrap(),
+        },
+        aux_deflate: Some(libdeflater),
+        ..Options::default()
+    };
+    let expected = ztxt_data(&Options {
+        deflate: libdeflater,
+        ..Options::default()
+    });
+    assert_eq!(ztxt_data(&opts), expected);
+}
+
+#[test]
+fn convert_text_chunks() {
+    let text = b"The quick brown fox jumps over the lazy dog. ".repeat(20);
+    let mut long_text = b"Comment\0".to_vec();
+    long_text.extend_from_slice(&text);
+    let short_text = b"Title\0Fox".to_vec();
+    let mut short_ztxt = b"Author\0\0".to_vec();
+    short_ztxt.extend_from_slice(
+        &internal_tests::deflate(b"Dog", 9, &internal_tests::AtomicMin::new(None)).unwrap(),
+    );
+
+    let mut raw = RawImage::new(
+        1,
+        1,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        vec![0],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"tEXt", long_text.clone());
+    raw.add_png_chunk(*b"tEXt", short_text.clone());
+    raw.add_png_chunk(*b"zTXt", short_ztxt);
+    let opts = Options {
+        convert_text: true,
+        ..Options::default()
+    };
+    let output = raw.create_optimized_png(&opts).unwrap();
+
+    let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+    let chunks: Vec<_> = png
+        .aux_chunks
+        .iter()
+        .filter(|c| &c.name == b"tEXt" || &c.name == b"zTXt")
+        .collect();
+    assert_eq!(chunks.len(), 3);
+
+    // Large text is compressed
+    assert_eq!(&chunks[0].name, b"zTXt");
+    assert_eq!(&chunks[0].data[..9], b"Comment\0\0");
+    assert_eq!(
+        internal_tests::inflate(&chunks[0].data[9..], text.len()).unwrap(),
+        text
+    );
+
+    // Short text would grow if compressed
+    assert_eq!(&chunks[1].name, b"tEXt");
+    assert_eq!(chunks[1].data, short_text);
+
+    // Short compressed text is smaller uncompressed
+    assert_eq!(&chunks[2].name, b"tEXt");
+    assert_eq!(chunks[2].data, b"Author\0Dog");
+}
+
+#[test]
+fn recompress_iccp_chunk() {
+    let icc = b"Not a real ICC profile. ".repeat(40);
+    let mut iccp = b"Custom profile\0\0".to_vec();
+    iccp.extend_from_slice(&internal_tests::store(&icc));
+
+    let mut raw = RawImage::new(
+        1,
+        1,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        vec![0],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"iCCP", iccp.clone());
+    let output = raw.create_optimized_png(&Options::default()).unwrap();
+
+    let png = internal_tests::PngData::from_slice(&output, &Options::default()).unwrap();
+    let chunk = png.aux_chunks.iter().find(|c| &c.name == b"iCCP").unwrap();
+    assert!(chunk.data.len() < iccp.len());
+    // The profile name is preserved
+    assert_eq!(&chunk.data[..16], b"Custom profile\0\0");
+    assert_eq!(
+        internal_tests::inflate(&chunk.data[16..], icc.len()).unwrap(),
+        icc
+    );
+
+    // Malformed profiles are left untouched
+    iccp.truncate(iccp.len() - 10);
+    let mut raw = RawImage::new(
+        1,
+        1,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        vec![0],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"iCCP", iccp.clone());
+    let output = raw.create_optimized_png(&Options::default()).unwrap();
+
+    let png = internal_tests::PngData::from_slice(&output, &Options::default()).unwrap();
+    let chunk = png.aux_chunks.iter().find(|c| &c.name == b"iCCP").unwrap();
+    assert_eq!(chunk.data, iccp);
+}
+
+#[test]
+fn strip_srgb_redundant_chunks() {
+    let create = |with_srgb: bool| {
+        let mut raw = RawImage::new(
+            1,
+            1,
+            ColorType::Grayscale {
+                transparent_shade: None,
+            },
+            BitDepth::Eight,
+            vec![0],
+        )
+        .unwrap();
+        raw.add_png_chunk(*b"gAMA", 45455u32.to_be_bytes().to_vec());
+        if with_srgb {
+            raw.add_png_chunk(*b"sRGB", vec![0]);
+        }
+        raw.add_png_chunk(*b"cHRM", vec![0; 32]);
+        raw.create_optimized_png(&Options::default()).unwrap()
+    };
+    let names = |output: &[u8]| -> Vec<[u8; 4]> {
+        let png = internal_tests::PngData::from_slice(output, &Options::default()).unwrap();
+        png.aux_chunks
+            .iter()
+            .map(|c| c.name)
+            .filter(|name| name != b"IDAT")
+            .collect()
+    };
+    let opts = Options {
+        force: true,
+        strip_srgb_redundant: true,
+        ..Options::default()
+    };
+
+    let output = oxipng::optimize_from_memory(&create(true), &opts).unwrap();
+    assert_eq!(names(&output), [*b"sRGB"]);
+    // Running again changes nothing
+    let output = oxipng::optimize_from_memory(&output, &opts).unwrap();
+    assert_eq!(names(&output), [*b"sRGB"]);
+
+    // Without sRGB the chunks are kept
+    let output = oxipng::optimize_from_memory(&create(false), &opts).unwrap();
+    assert_eq!(names(&output), [*b"gAMA", *b"cHRM"]);
+}
+
+#[test]
+fn exif_chunk() {
+    let mut raw = RawImage::new(
+        1,
+        1,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        vec![0],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"eXIf", b"MM\0\x2a\0\0\0\x08\0\0".to_vec());
+    let input = raw.create_optimized_png(&Options::default()).unwrap();
+    assert_eq!(oxipng::parse_header(&input).unwrap().exif_size(), Some(10));
+    let optimize = |opts: Options| {
+        let opts = Options {
+            force: true,
+            ..opts
+        };
+        let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+        oxipng::parse_header(&output).unwrap().exif_size()
+    };
+
+    assert_eq!(
+        optimize(Options {
+            strip: StripChunks::Safe,
+            ..Options::default()
+        }),
+        Some(10)
+    );
+    assert_eq!(
+        optimize(Options {
+            strip: StripChunks::All,
+            ..Options::default()
+        }),
+        None
+    );
+    assert_eq!(
+        optimize(Options {
+            strip_exif: true,
+            ..Options::default()
+        }),
+        None
+    );
+}
+
+#[test]
+fn strip_metadata_keeps_color_chunks() {
+    let mut raw = RawImage::new(
+        1,
+        1,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        vec![0],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"gAMA", 45455u32.to_be_bytes().to_vec());
+    raw.add_png_chunk(*b"cHRM", vec![0; 32]);
+    raw.add_png_chunk(*b"pHYs", vec![0, 0, 0, 2, 0, 0, 0, 1, 0]);
+    raw.add_png_chunk(*b"tIME", vec![7, 232, 1, 1, 0, 0, 0]);
+    raw.add_png_chunk(*b"eXIf", b"MM\0\x2a\0\0\0\x08\0\0".to_vec());
+    raw.add_png_chunk(*b"prVt", vec![1, 2, 3]);
+    let opts = Options {
+        strip: StripChunks::Metadata,
+        ..Options::default()
+    };
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let info = oxipng::parse_header(&output).unwrap();
+    let names: Vec<_> = info
+        .chunks
+        .into_iter()
+        .map(|(name, _)| name)
+        .filter(|name| name != b"IDAT")
+        .collect();
+    assert_eq!(names, [*b"gAMA", *b"cHRM"]);
+}
+
+#[test]
+fn cicp_chunk() {
+    let create = |cicp: Vec<u8>| {
+        let mut raw = RawImage::new(
+            1,
+            1,
+            ColorType::Grayscale {
+                transparent_shade: None,
+            },
+            BitDepth::Eight,
+            vec![0],
+        )
+        .unwrap();
+        raw.add_png_chunk(*b"cICP", cicp);
+        raw.create_optimized_png(&Options::default()).unwrap()
+    };
+    let opts = Options {
+        force: true,
+        strip: StripChunks::Safe,
+        ..Options::default()
+    };
+
+    // BT.2100 PQ, full range
+    let output = oxipng::optimize_from_memory(&create(vec![9, 16, 0, 1]), &opts).unwrap();
+    let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(
+        png.cicp(),
+        Some(CicpData {
+            color_primaries: 9,
+            transfer_function: 16,
+            matrix_coefficients: 0,
+            video_full_range: true,
+        })
+    );
+
+    // Invalid chunks are removed
+    for cicp in [vec![9, 16, 0], vec![9, 16, 1, 1], vec![9, 16, 0, 2]] {
+        let output = oxipng::optimize_from_memory(&create(cicp), &opts).unwrap();
+        let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+        assert!(png.aux_chunks.iter().all(|c| &c.name != b"cICP"));
+    }
+}
+
+#[test]
+fn chunk_filter() {
+    let mut raw = RawImage::new(
+        1,
+        1,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        vec![0],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"tEXt", b"Copyright\0Someone".to_vec());
+    raw.add_png_chunk(*b"tEXt", b"Comment\0Something".to_vec());
+    raw.add_png_chunk(*b"pHYs", vec![0, 0, 0, 2, 0, 0, 0, 1, 0]);
+    let opts = Options {
+        normalize_phys: false,
+        chunk_filter: Some(ChunkFilter::new(|name, data| {
+            name != b"tEXt" || data.starts_with(b"Copyright\0")
+        })),
+        ..Options::default()
+    };
+    let names = |png: &[u8]| {
+        let png = internal_tests::PngData::from_slice(png, &Options::default()).unwrap();
+        png.aux_chunks
+            .into_iter()
+            .filter(|c| c.name != *b"IDAT")
+            .map(|c| (c.name, c.data))
+            .collect::<Vec<_>>()
+    };
+    let expected = vec![
+        (*b"tEXt", b"Copyright\0Someone".to_vec()),
+        (*b"pHYs", vec![0, 0, 0, 2, 0, 0, 0, 1, 0]),
+    ];
+
+    // The predicate applies to chunks of a raw image
+    let output = raw.create_optimized_png(&opts).unwrap();
+    assert_eq!(names(&output), expected);
+
+    // It also applies to the chunks of an input file, overriding `strip`
+    let input = raw
+        .create_optimized_png(&Options {
+            normalize_phys: false,
+            ..Options::default()
+        })
+        .unwrap();
+    assert_eq!(names(&input).len(), 3);
+    let opts = Options {
+        strip: StripChunks::All,
+        force: true,
+        ..opts
+    };
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    assert_eq!(names(&output), expected);
+}
+
+#[test]
+fn ster_chunk() {
+    let create = |ster: Vec<u8>| {
+        let mut raw = RawImage::new(
+            2,
+            1,
+            ColorType::Grayscale {
+                transparent_shade: None,
+            },
+            BitDepth::Eight,
+            vec![0, 255],
+        )
+        .unwrap();
+        raw.add_png_chunk(*b"sTER", ster);
+        raw.create_optimized_png(&Options::default()).unwrap()
+    };
+
+    // Kept by the safe and metadata presets
+    for strip in [StripChunks::Safe, StripChunks::Metadata] {
+        let opts = Options {
+            force: true,
+            strip,
+            ..Options::default()
+        };
+        let output = oxipng::optimize_from_memory(&create(vec![1]), &opts).unwrap();
+        let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+        assert_eq!(png.stereo_layout(), Some(StereoLayout::DivergingFuse));
+    }
+
+    // Invalid chunks are removed
+    let opts = Options {
+        force: true,
+        ..Options::default()
+    };
+    for ster in [vec![], vec![2], vec![0, 0]] {
+        let output = oxipng::optimize_from_memory(&create(ster), &opts).unwrap();
+        let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+        assert!(png.aux_chunks.iter().all(|c| &c.name != b"sTER"));
+    }
+}
+
+#[test]
+fn time_chunk() {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    let leap_day = UNIX_EPOCH + Duration::from_secs(951_782_400 + 3723);
+    let time = ModificationTime::from_system_time(leap_day).unwrap();
+    assert_eq!(time.to_bytes(), [7, 208, 2, 29, 1, 2, 3]);
+    assert_eq!(
+        ModificationTime::from_system_time(UNIX_EPOCH - Duration::from_secs(1)),
+        None
+    );
+
+    let create = |time: Vec<u8>| {
+        let mut raw = RawImage::new(
+            2,
+            1,
+            ColorType::Grayscale {
+                transparent_shade: None,
+            },
+            BitDepth::Eight,
+            vec![0, 255],
+        )
+        .unwrap();
+        raw.add_png_chunk(*b"tIME", time);
+        raw.create_optimized_png(&Options::default()).unwrap()
+    };
+    let read_time = |output: &[u8], opts: &Options| {
+        let png = internal_tests::PngData::from_slice(output, opts).unwrap();
+        png.modification_time()
+    };
+    let input = create(time.to_bytes().to_vec());
+
+    // Kept as-is by default, or removed on request
+    let opts = Options {
+        force: true,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    assert_eq!(read_time(&output, &opts), Some(time));
+    let strip_opts = Options {
+        strip_time: true,
+        ..opts.clone()
+    };
+    let output = oxipng::optimize_from_memory(&input, &strip_opts).unwrap();
+    assert_eq!(read_time(&output, &strip_opts), None);
+
+    // Updated to the current time
+    let update_opts = Options {
+        update_time: true,
+        ..opts.clone()
+    };
+    let output = oxipng::optimize_from_memory(&input, &update_opts).unwrap();
+    let updated = read_time(&output, &update_opts).unwrap();
+    assert!(updated.year >= 2024);
+    let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(
+        png.aux_chunks.iter().filter(|c| &c.name == b"tIME").count(),
+        1
+    );
+
+    // Invalid chunks are removed
+    for time in [
+        vec![7, 208, 2, 29, 1, 2],
+        vec![7, 208, 13, 1, 0, 0, 0],
+        vec![7, 208, 1, 1, 24, 0, 0],
+    ] {
+        let output = oxipng::optimize_from_memory(&create(time), &opts).unwrap();
+        let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+        assert!(png.aux_chunks.iter().all(|c| &c.name != b"tIME"));
+    }
+}
+
+#[test]
+fn physical_chunks() {
+    let offs = vec![0, 0, 0, 10, 0, 0, 0, 20, 0];
+    let scal = b"\x011.5\x002.5".to_vec();
+    let pcal = b"temp\0\0\0\0\0\0\0\0\xFF\0\x02K\x000\x001".to_vec();
+    let create = |chunks: &[(&[u8; 4], &Vec<u8>)]| {
+        let mut raw = RawImage::new(
+            2,
+            1,
+            ColorType::Grayscale {
+                transparent_shade: None,
+            },
+            BitDepth::Eight,
+            vec![0, 255],
+        )
+        .unwrap();
+        for &(name, data) in chunks {
+            raw.add_png_chunk(*name, data.clone());
+        }
+        raw.create_optimized_png(&Options::default()).unwrap()
+    };
+    let names = |output: &[u8], opts: &Options| {
+        let png = internal_tests::PngData::from_slice(output, opts).unwrap();
+        png.aux_chunks
+            .iter()
+            .map(|c| c.name)
+            .filter(|n| n != b"IDAT")
+            .collect::<Vec<_>>()
+    };
+    let input = create(&[(b"oFFs", &offs), (b"sCAL", &scal), (b"pCAL", &pcal)]);
+
+    // Kept by the safe preset, unless stripped explicitly
+    let opts = Options {
+        force: true,
+        strip: StripChunks::Safe,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    assert_eq!(names(&output, &opts), vec![*b"oFFs", *b"sCAL", *b"pCAL"]);
+    let opts = Options {
+        strip_physical: true,
+        ..opts
+    };
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    assert!(names(&output, &opts).is_empty());
+
+    // Malformed chunks are removed
+    let opts = Options {
+        force: true,
+        ..Options::default()
+    };
+    let invalid = [
+        (b"oFFs", vec![0; 8]),
+        (b"oFFs", vec![0, 0, 0, 0, 0, 0, 0, 0, 2]),
+        (b"sCAL", b"\x031\x001".to_vec()),
+        (b"sCAL", b"\x011".to_vec()),
+        (
+            b"pCAL",
+            b"temp\0\0\0\0\0\0\0\0\xFF\0\x03K\x000\x001".to_vec(),
+        ),
+        (b"pCAL", b"temp".to_vec()),
+    ];
+    for (name, data) in &invalid {
+        let output = oxipng::optimize_from_memory(&create(&[(name, data)]), &opts).unwrap();
+        assert!(names(&output, &opts).is_empty());
+    }
+}
+
+#[test]
+fn normalize_phys_chunk() {
+    let phys = |x: u32, y: u32, unit: u8| {
+        let mut data = x.to_be_bytes().to_vec();
+        data.extend_from_slice(&y.to_be_bytes());
+        data.push(unit);
+        data
+    };
+    let optimize = |data: Vec<u8>| {
+        let mut raw = RawImage::new(
+            1,
+            1,
+            ColorType::Grayscale {
+                transparent_shade: None,
+            },
+            BitDepth::Eight,
+            vec![0],
+        )
+        .unwrap();
+        raw.add_png_chunk(*b"pHYs", data);
+        let opts = Options {
+            normalize_phys: true,
+            ..Options::default()
+        };
+        let output = raw.create_optimized_png(&opts).unwrap();
+        let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+        png.aux_chunks
+            .into_iter()
+            .find(|c| &c.name == b"pHYs")
+            .map(|c| c.data)
+    };
+
+    // Square pixels carry no information
+    assert_eq!(optimize(phys(72, 72, 0)), None);
+    // Aspect ratio is reduced to lowest terms
+    assert_eq!(optimize(phys(300, 200, 0)), Some(phys(3, 2, 0)));
+    // Physical sizes are untouched
+    assert_eq!(optimize(phys(2835, 2835, 1)), Some(phys(2835, 2835, 1)));
+}
+
+#[test]
+fn remove_duplicate_chunks() {
+    let mut raw = RawImage::new(
+        1,
+        1,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        vec![0],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"tEXt", b"Title\0One".to_vec());
+    raw.add_png_chunk(*b"tEXt", b"Title\0Two".to_vec());
+    raw.add_png_chunk(*b"tEXt", b"Title\0One".to_vec());
+    raw.add_png_chunk(*b"prVt", vec![1]);
+    raw.add_png_chunk(*b"prVt", vec![1]);
+    let file = raw.create_optimized_png(&Options::default()).unwrap();
+    let chunks = |output: &[u8]| -> Vec<Vec<u8>> {
+        let png = internal_tests::PngData::from_slice(output, &Options::default()).unwrap();
+        png.aux_chunks
+            .into_iter()
+            .filter(|c| &c.name != b"IDAT")
+            .map(|c| c.data)
+            .collect()
+    };
+
+    // Nothing is removed without stripping
+    assert_eq!(chunks(&file).len(), 5);
+
+    let opts = Options {
+        force: true,
+        strip: StripChunks::Strip(indexset![*b"zTXt"]),
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&file, &opts).unwrap();
+    // Only repeatable chunks are deduplicated
+    assert_eq!(
+        chunks(&output),
+        [
+            b"Title\0One".to_vec(),
+            b"Title\0Two".to_vec(),
+            vec![1],
+            vec![1]
+        ]
+    );
+}
+
+fn png_chunk(out: &mut Vec<u8>, name: &[u8; 4], data: &[u8]) {
+    out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    let start = out.len();
+    out.extend_from_slice(name);
+    out.extend_from_slice(data);
+    let crc = internal_tests::crc32(&out[start..]);
+    out.extend_from_slice(&crc.to_be_bytes());
+}
+
+#[test]
+fn palette_longer_than_256() {
+    let file = |plte: &[u8]| {
+        let mut file = b"\x89PNG\r\n\x1a\n".to_vec();
+        png_chunk(&mut file, b"IHDR", &[0, 0, 0, 4, 0, 0, 0, 1, 8, 3, 0, 0, 0]);
+        png_chunk(&mut file, b"PLTE", plte);
+        let idat = internal_tests::deflate(
+            &[0, 0, 1, 254, 255],
+            1,
+            &internal_tests::AtomicMin::new(None),
+        )
+        .unwrap();
+        png_chunk(&mut file, b"IDAT", &idat);
+        png_chunk(&mut file, b"IEND", &[]);
+        file
+    };
+
+    // 256 entries of only two distinct colors is valid, and the duplicates are merged
+    let plte: Vec<u8> = (0..256).flat_map(|i| [(i % 2) as u8 * 255, 0, 0]).collect();
+    let opts = Options {
+        bit_depth_reduction: false,
+        color_type_reduction: false,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&file(&plte), &opts).unwrap();
+    let new = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+    let ColorType::Indexed { palette } = &new.raw.ihdr.color_type else {
+        panic!("Expected indexed color type");
+    };
+    assert_eq!(palette.len(), 2);
+
+    // Decoders disagree on longer palettes or incomplete entries, so they are rejected
+    let long: Vec<u8> = (0..300).flat_map(|i| [(i % 2) as u8 * 255, 0, 0]).collect();
+    assert!(internal_tests::PngData::from_slice(&file(&long), &Options::default()).is_err());
+    let incomplete = [&plte[..255 * 3], &[1, 2]].concat();
+    assert!(internal_tests::PngData::from_slice(&file(&incomplete), &Options::default()).is_err());
+
+    // A raw image can't have more than 256 palette entries
+    let raw = RawImage::new(
+        1,
+        1,
+        ColorType::Indexed {
+            palette: vec![RGBA8::new(0, 0, 0, 255); 257],
+        },
+        BitDepth::Eight,
+        vec![0],
+    );
+    assert!(raw.is_err());
+}
+
+#[test]
+fn trns_longer_than_palette() {
+    let mut file = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut file, b"IHDR", &[0, 0, 0, 2, 0, 0, 0, 1, 8, 3, 0, 0, 0]);
+    png_chunk(&mut file, b"PLTE", &[255, 0, 0, 0, 0, 255]);
+    // Two of the entries don't correspond to any palette color
+    png_chunk(&mut file, b"tRNS", &[128, 255, 0, 0]);
+    let idat =
+        internal_tests::deflate(&[0, 0, 1], 1, &internal_tests::AtomicMin::new(None)).unwrap();
+    png_chunk(&mut file, b"IDAT", &idat);
+    png_chunk(&mut file, b"IEND", &[]);
+
+    // Decoders ignore a tRNS chunk that is longer than the palette, so all colors are opaque
+    let png = internal_tests::PngData::from_slice(&file, &Options::default()).unwrap();
+    assert_eq!(
+        png.raw.ihdr.color_type,
+        ColorType::Indexed {
+            palette: vec![RGBA8::new(255, 0, 0, 255), RGBA8::new(0, 0, 255, 255)]
+        }
+    );
+
+    let opts = Options {
+        force: true,
+        bit_depth_reduction: false,
+        color_type_reduction: false,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&file, &opts).unwrap();
+    let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+    let ColorType::Indexed { palette } = &png.raw.ihdr.color_type else {
+        panic!("Expected indexed output, got {:?}", png.raw.ihdr.color_type);
+    };
+    let pixels: Vec<_> = png.raw.data.iter().map(|&i| palette[i as usize]).collect();
+    assert_eq!(palette.len(), 2);
+    assert_eq!(
+        pixels,
+        [RGBA8::new(255, 0, 0, 255), RGBA8::new(0, 0, 255, 255)]
+    );
+}
+
+#[test]
+fn trns_follows_palette_order() {
+    let mut file = b"\x89PNG\r\n\x1a\n".to_vec();
+    png_chunk(&mut file, b"IHDR", &[0, 0, 0, 3, 0, 0, 0, 1, 8, 3, 0, 0, 0]);
+    png_chunk(&mut file, b"PLTE", &[255, 0, 0, 0, 0, 255, 0, 255, 0]);
+    png_chunk(&mut file, b"tRNS", &[255, 64]);
+    let idat =
+        internal_tests::deflate(&[0, 0, 1, 2], 1, &internal_tests::AtomicMin::new(None)).unwrap();
+    png_chunk(&mut file, b"IDAT", &idat);
+    png_chunk(&mut file, b"IEND", &[]);
+    let expected = [
+        RGBA8::new(255, 0, 0, 255),
+        RGBA8::new(0, 0, 255, 64),
+        RGBA8::new(0, 255, 0, 255),
+    ];
+
+    let opts = Options {
+        force: true,
+        bit_depth_reduction: false,
+        color_type_reduction: false,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&file, &opts).unwrap();
+    let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+    let ColorType::Indexed { palette } = &png.raw.ihdr.color_type else {
+        panic!("Expected indexed output, got {:?}", png.raw.ihdr.color_type);
+    };
+    assert_ne!(palette, &expected);
+    let pixels: Vec<_> = png.raw.data.iter().map(|&i| palette[i as usize]).collect();
+    assert_eq!(pixels, expected);
+
+    // The tRNS chunk is rebuilt in the new order, without trailing opaque entries
+    let mut alphas: Vec<_> = palette.iter().map(|c| c.a).collect();
+    while alphas.last() == Some(&255) {
+        alphas.pop();
+    }
+    let trns_pos = output.windows(4).position(|w| w == b"tRNS").unwrap();
+    let trns_len = u32::from_be_bytes(output[trns_pos - 4..trns_pos].try_into().unwrap());
+    assert_eq!(
+        &output[trns_pos + 4..trns_pos + 4 + trns_len as usize],
+        alphas.as_slice()
+    );
+}
+
+fn rgba_png(width: u32, height: u32, idat: &[u8]) -> Vec<u8> {
+    let mut out = b"\x89PNG\r\n\x1a\n".to_vec();
+    let mut ihdr = width.to_be_bytes().to_vec();
+    ihdr.extend_from_slice(&height.to_be_bytes());
+    ihdr.extend_from_slice(&[8, 6, 0, 0, 0]);
+    png_chunk(&mut out, b"IHDR", &ihdr);
+    png_chunk(&mut out, b"IDAT", idat);
+    png_chunk(&mut out, b"IEND", &[]);
+    out
+}
+
+#[test]
+fn extract_apng_frames() {
+    let compress = |width: usize, data: &[u8]| {
+        let filtered: Vec<u8> = data
+            .chunks(width * 4)
+            .flat_map(|row| std::iter::once(0).chain(row.iter().copied()))
+            .collect();
+        internal_tests::deflate(&filtered, 1, &internal_tests::AtomicMin::new(None)).unwrap()
+    };
+    let fctl = |seq: u32, size: u32, offset: u32, dispose: u8, blend: u8| {
+        let mut data = seq.to_be_bytes().to_vec();
+        for field in [size, size, offset, offset] {
+            data.extend_from_slice(&field.to_be_bytes());
+        }
+        data.extend_from_slice(&[0, 1, 0, 10, dispose, blend]);
+        data
+    };
+    let fdat = |seq: u32, data: &[u8]| {
+        let mut chunk = seq.to_be_bytes().to_vec();
+        chunk.extend_from_slice(data);
+        chunk
+    };
+    let red = [255, 0, 0, 255];
+    let green = [0, 255, 0, 255];
+
+    let mut file = rgba_png(2, 2, &[]);
+    file.truncate(33);
+    png_chunk(&mut file, b"acTL", &[0, 0, 0, 3, 0, 0, 0, 0]);
+    // A full red frame, replacing the canvas
+    png_chunk(&mut file, b"fcTL", &fctl(0, 2, 0, 0, 0));
+    png_chunk(&mut file, b"IDAT", &compress(2, &[red; 4].concat()));
+    // Half-transparent blue blended over the bottom right, then disposed to the background
+    png_chunk(&mut file, b"fcTL", &fctl(1, 1, 1, 1, 1));
+    png_chunk(
+        &mut file,
+        b"fdAT",
+        &fdat(2, &compress(1, &[0, 0, 255, 128])),
+    );
+    // Green replacing the top left
+    png_chunk(&mut file, b"fcTL", &fctl(3, 1, 0, 0, 0));
+    png_chunk(&mut file, b"fdAT", &fdat(4, &compress(1, &green)));
+    png_chunk(&mut file, b"IEND", &[]);
+
+    let opts = Options::default();
+    let frames = oxipng::extract_apng_frames(&file, &opts).unwrap();
+    let pixels: Vec<Vec<u8>> = frames
+        .iter()
+        .map(|frame| {
+            let png = internal_tests::PngData::from_slice(frame, &opts).unwrap();
+            assert!(!png.aux_chunks.iter().any(|c| &c.name == b"fcTL"));
+            internal_tests::color::expanded_to_color_type(&png.raw, &ColorType::RGBA)
+                .map_or(png.raw.data.clone(), |rgba| rgba.data)
+        })
+        .collect();
+    assert_eq!(
+        pixels,
+        [
+            [red, red, red, red].concat(),
+            [red, red, red, [127, 0, 128, 255]].concat(),
+            [green, red, red, [0; 4]].concat(),
+        ]
+    );
+
+    // A static PNG has no frames to extract
+    let file = fs::read("tests/files/rgb_8_should_be_rgb_8.png").unwrap();
+    assert!(oxipng::extract_apng_frames(&file, &opts).is_err());
+}
+
+/// Composite the frames of an opaque RGBA APNG with no disposal and source blending
+fn composite_frames(file: &[u8]) -> Vec<Vec<u8>> {
+    let png = internal_tests::PngData::from_slice(file, &Options::default()).unwrap();
+    let width = png.raw.ihdr.width as usize;
+    let mut canvas = vec![0; png.raw.data.len()];
+    let mut frames = Vec::new();
+    let mut region = (0, 0, 0, 0);
+    let mut blend_over = false;
+    for c in &png.aux_chunks {
+        let data = match &c.name {
+            b"fcTL" => {
+                let field = |i: usize| u32::from_be_bytes(c.data[i..i + 4].try_into().unwrap());
+                region = (field(4), field(8), field(12) as usize, field(16) as usize);
+                blend_over = c.data[25] == 1;
+                continue;
+            }
+            b"IDAT" if region.0 > 0 => png.raw.data.clone(),
+            b"fdAT" => {
+                let frame = rgba_png(region.0, region.1, &c.data[4..]);
+                let frame = internal_tests::PngData::from_slice(&frame, &Options::default());
+                frame.unwrap().raw.data.clone()
+            }
+            _ => continue,
+        };
+        let (w, h, x, y) = (region.0 as usize, region.1 as usize, region.2, region.3);
+        for row in 0..h {
+            let dest = ((y + row) * width + x) * 4;
+            let dest = canvas[dest..dest + w * 4].chunks_exact_mut(4);
+            // Only fully transparent and opaque pixels are used, so blending either skips or copies
+            for (dest, src) in dest.zip(data[row * w * 4..(row + 1) * w * 4].chunks_exact(4)) {
+                if !blend_over || src[3] != 0 {
+                    dest.copy_from_slice(src);
+                }
+            }
+        }
+        frames.push(canvas.clone());
+    }
+    frames
+}
+
+#[test]
+fn crop_apng_frames() {
+    let (width, height) = (8u32, 8u32);
+    let base: Vec<u8> = (0..width * height)
+        .flat_map(|i| [i as u8 * 3, 100, 200 - i as u8, 255])
+        .collect();
+    let mut changed = base.clone();
+    for (x, y) in [(3, 4), (4, 4), (3, 5)] {
+        changed[(y * 8 + x) * 4] = 0;
+    }
+    let filter_rows = |data: &[u8]| -> Vec<u8> {
+        data.chunks(width as usize * 4)
+            .flat_map(|row| std::iter::once(0).chain(row.iter().copied()))
+            .collect()
+    };
+    let compress = |data: &[u8]| {
+        internal_tests::deflate(&filter_rows(data), 1, &internal_tests::AtomicMin::new(None))
+            .unwrap()
+    };
+    let fctl = |seq: u32, blend: u8| {
+        let mut data = seq.to_be_bytes().to_vec();
+        for field in [width, height, 0, 0] {
+            data.extend_from_slice(&field.to_be_bytes());
+        }
+        data.extend_from_slice(&[0, 1, 0, 10, 0, blend]);
+        data
+    };
+    let fdat = |seq: u32, data: &[u8]| {
+        let mut chunk = seq.to_be_bytes().to_vec();
+        chunk.extend_from_slice(&compress(data));
+        chunk
+    };
+
+    let mut file = rgba_png(width, height, &[]);
+    file.truncate(33);
+    // Blended over the canvas, unchanged pixels may be left transparent
+    let mut over = vec![0; changed.len()];
+    over[(8 + 6) * 4..][..4].copy_from_slice(&[1, 2, 3, 255]);
+
+    png_chunk(&mut file, b"acTL", &[0, 0, 0, 4, 0, 0, 0, 0]);
+    png_chunk(&mut file, b"fcTL", &fctl(0, 0));
+    png_chunk(&mut file, b"IDAT", &compress(&base));
+    png_chunk(&mut file, b"fcTL", &fctl(1, 0));
+    png_chunk(&mut file, b"fdAT", &fdat(2, &changed));
+    png_chunk(&mut file, b"fcTL", &fctl(3, 0));
+    png_chunk(&mut file, b"fdAT", &fdat(4, &changed));
+    png_chunk(&mut file, b"fcTL", &fctl(5, 1));
+    png_chunk(&mut file, b"fdAT", &fdat(6, &over));
+    png_chunk(&mut file, b"IEND", &[]);
+
+    let opts = Options {
+        force: true,
+        crop_frames: true,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&file, &opts).unwrap();
+    assert_eq!(composite_frames(&output), composite_frames(&file));
+
+    // The changed pixels are in a 2x2 region, the third frame has no changes and the last frame
+    // changes a single pixel
+    let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+    let sizes: Vec<_> = png
+        .aux_chunks
+        .iter()
+        .filter(|c| &c.name == b"fcTL")
+        .map(|c| c.data[4..20].to_vec())
+        .collect();
+    assert_eq!(sizes[0], fctl(0, 0)[4..20]);
+    assert_eq!(sizes[1], [0, 0, 0, 2, 0, 0, 0, 2, 0, 0, 0, 3, 0, 0, 0, 4]);
+    assert_eq!(sizes[2], [0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 0]);
+    assert_eq!(sizes[3], [0, 0, 0, 1, 0, 0, 0, 1, 0, 0, 0, 6, 0, 0, 0, 1]);
+}
+
+#[test]
+fn progress_callback() {
+    let events = Arc::new(Mutex::new(Vec::new()));
+    let opts = Options {
+        force: true,
+        progress: Some(ProgressCallback::new({
+            let events = events.clone();
+            move |progress| {
+                events.lock().unwrap().push(progress);
+                ControlFlow::Continue(())
+            }
+        })),
+        ..Options::from_preset(4)
+    };
+    let file = fs::read("tests/files/fully_optimized.png").unwrap();
+    oxipng::optimize_from_memory(&file, &opts).unwrap();
+
+    let events = events.lock().unwrap();
+    assert!(events.contains(&Progress::Reduced));
+    assert!(events
+        .iter()
+        .any(|e| matches!(e, Progress::Evaluated(RowFilter::None))));
+    assert!(events.iter().any(|e| matches!(e, Progress::Compressed(_))));
+}
+
+#[test]
+fn progress_callback_cancel() {
+    let opts = Options {
+        force: true,
+        progress: Some(ProgressCallback::new(|_| ControlFlow::Break(()))),
+        ..Options::default()
+    };
+    let file = fs::read("tests/files/fully_optimized.png").unwrap();
+    let result = oxipng::optimize_from_memory(&file, &opts);
+    assert!(matches!(result, Err(PngError::Cancelled)));
+
+    // The input file is left untouched
+    let path = std::env::temp_dir().join("oxipng_progress_callback_cancel.png");
+    fs::write(&path, &file).unwrap();
+    let output = OutFile::Path {
+        path: None,
+        preserve_attrs: false,
+    };
+    let result = oxipng::optimize(&InFile::Path(path.clone()), &output, &opts);
+    assert!(matches!(result, Err(PngError::Cancelled)));
+    assert_eq!(fs::read(&path).unwrap(), file);
+    fs::remove_file(&path).ok();
+}
+
+#[test]
+fn optimize_from_memory_report() {
+    let file = fs::read("tests/files/strip_headers_none.png").unwrap();
+    let opts = Options {
+        strip: StripChunks::Safe,
+        ..Options::default()
+    };
+    let (output, report) = optimize_with_report(&file, &opts);
+    assert_eq!(report.input_size, file.len());
+    assert_eq!(report.output_size, output.len());
+    assert!(report.chosen_filter.is_some());
+    assert_eq!(report.chosen_deflater, Some(opts.deflate));
+    assert!(report.stripped_chunks.contains(b"tEXt"));
+    assert!(report.stripped_chunks.contains(b"iTXt"));
+    assert!(!report.stripped_chunks.contains(b"IHDR"));
+    assert!(report.crc_mismatches.is_empty());
+
+    let file = fs::read("tests/files/rgba_16_should_be_rgb_8.png").unwrap();
+    let report = oxipng::optimize_from_memory_with_report(&file, &Options::default()).unwrap();
+    assert_eq!(report.color_type_before, ColorType::RGBA);
+    assert_eq!(report.bit_depth_before, BitDepth::Sixteen);
+    assert!(matches!(report.color_type_after, ColorType::RGB { .. }));
+    assert_eq!(report.bit_depth_after, BitDepth::Eight);
+    assert_eq!(report.interlaced, Interlacing::None);
+
+    // Nothing changes if the file is already optimized
+    let file = fs::read("tests/files/fully_optimized.png").unwrap();
+    let (output, report) = optimize_with_report(&file, &Options::default());
+    assert_eq!(output, file);
+    assert_eq!(report.output_size, file.len());
+    assert_eq!(report.chosen_filter, None);
+    assert_eq!(report.color_type_after, report.color_type_before);
+    assert!(report.stripped_chunks.is_empty());
+}
+
+#[test]
+fn report_row_filters() {
+    for path in [
+        "tests/files/rgb_8_should_be_rgb_8.png",
+        "tests/files/interlaced_rgb_8_should_be_rgb_8.png",
+    ] {
+        let file = fs::read(path).unwrap();
+        let opts = Options {
+            filter: indexset! {RowFilter::Brute},
+            ..Options::default()
+        };
+        let (output, report) = optimize_with_report(&file, &opts);
+        let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+        assert_eq!(report.row_filters.len(), png.raw.scan_lines(false).count());
+
+        // Recompressing with the reported filters reproduces the same image data
+        let recompressed =
+            oxipng::recompress_from_memory(&output, &report.row_filters, &opts).unwrap();
+        let new_png = internal_tests::PngData::from_slice(&recompressed, &opts).unwrap();
+        assert_eq!(new_png.idat_data, png.idat_data);
+    }
+}
+
+#[test]
+fn exact_brute() {
+    let file = fs::read("tests/files/rgb_8_should_be_rgb_8.png").unwrap();
+    let opts = Options {
+        filter: indexset! {RowFilter::Brute},
+        exact_brute: true,
+        deflate: Deflaters::Libdeflater { compression: 11 },
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&file, &opts).unwrap();
+    let original = internal_tests::PngData::from_slice(&file, &opts).unwrap();
+    let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(png.raw.data, original.raw.data);
+
+    // Scoring with the main deflater gives different filters than the fast level
+    let exact = png
+        .raw
+        .filter_image_with_deflater(RowFilter::Brute, false, opts.deflate);
+    assert_ne!(exact, png.raw.filter_image(RowFilter::Brute, false));
+}
+
+#[test]
+fn recompress_only() {
+    let chunks = |data: &[u8]| {
+        let mut chunks = Vec::new();
+        let mut offset = 8;
+        while offset < data.len() {
+            let length = u32::from_be_bytes(data[offset..offset + 4].try_into().unwrap()) as usize;
+            chunks.push(data[offset + 4..offset + 12 + length].to_vec());
+            offset += length + 12;
+        }
+        chunks
+    };
+    let file = fs::read("tests/files/strip_headers_none.png").unwrap();
+    let opts = Options {
+        recompress_only: true,
+        force: true,
+        strip: StripChunks::All,
+        optimize_alpha: true,
+        normalize_phys: true,
+        convert_text: true,
+        mark_optimized: true,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&file, &opts).unwrap();
+
+    // Only the image data differs
+    let before = chunks(&file);
+    let after = chunks(&output);
+    let is_idat = |c: &&Vec<u8>| c.starts_with(b"IDAT");
+    assert!(after.iter().any(|c| is_idat(&c)));
+    assert_eq!(
+        before.iter().filter(|c| !is_idat(c)).collect::<Vec<_>>(),
+        after.iter().filter(|c| !is_idat(c)).collect::<Vec<_>>()
+    );
+    let original = internal_tests::PngData::from_slice(&file, &opts).unwrap();
+    let new = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(new.raw.data, original.raw.data);
+}
+
+#[test]
+fn crc_mismatches() {
+    let mut file = fs::read("tests/files/rgba_16_should_be_rgb_8.png").unwrap();
+    // Corrupt the CRC of the IHDR chunk
+    file[29] ^= 0xFF;
+    assert!(oxipng::optimize_from_memory(&file, &Options::default()).is_err());
+
+    let opts = Options {
+        fix_errors: true,
+        ..Options::default()
+    };
+    let (output, report) = optimize_with_report(&file, &opts);
+    assert_eq!(report.crc_mismatches, vec![*b"IHDR"]);
+    // The output always has correct CRCs
+    let report = oxipng::optimize_from_memory_with_report(&output, &Options::default()).unwrap();
+    assert!(report.crc_mismatches.is_empty());
+}
+
+#[test]
+fn optimize_from_memory_outcome() {
+    let file = fs::read("tests/files/rgba_16_should_be_rgb_8.png").unwrap();
+    let outcome = oxipng::optimize_from_memory_with_report(&file, &Options::default())
+        .unwrap()
+        .outcome
+        .unwrap();
+    assert!(outcome.is_improved());
+    assert!(outcome.into_output().len() < file.len());
+
+    // An unchanged file is returned byte-for-byte
+    let file = fs::read("tests/files/fully_optimized.png").unwrap();
+    let report = oxipng::optimize_from_memory_with_report(&file, &Options::default()).unwrap();
+    assert_eq!(
+        report.outcome,
+        Some(OptimizationOutcome::Unchanged(file.clone()))
+    );
+
+    // Unless the re-serialized output is forced
+    let opts = Options {
+        force: true,
+        ..Options::default()
+    };
+    let forced = oxipng::optimize_from_memory(&file, &opts).unwrap();
+    let report = oxipng::optimize_from_memory_with_report(&file, &opts).unwrap();
+    assert_eq!(report.outcome, Some(OptimizationOutcome::Unchanged(forced)));
+}
+
+#[test]
+fn preserve_chunk_order() {
+    let file = fs::read("tests/files/issue-82.png").unwrap();
+    let names = |data: &[u8]| -> Vec<[u8; 4]> {
+        let info = oxipng::parse_header(data).unwrap();
+        info.chunks.into_iter().map(|(name, _)| name).collect()
+    };
+    assert_eq!(
+        names(&file),
+        [*b"PLTE", *b"tRNS", *b"bKGD", *b"pHYs", *b"tIME", *b"IDAT"]
+    );
+
+    let opts = Options {
+        force: true,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&file, &opts).unwrap();
+    let info = oxipng::parse_header(&output).unwrap();
+    assert!(matches!(info.color_type, ColorType::Indexed { .. }));
+    // Chunks that followed the palette still do so
+    assert_eq!(names(&output), names(&file));
+}
+
+#[test]
+fn photo_filter_threshold() {
+    let create = |data: Vec<u8>| {
+        let color_type = ColorType::RGB {
+            transparent_color: None,
+        };
+        let raw = RawImage::new(64, 64, color_type, BitDepth::Eight, data).unwrap();
+        raw.create_optimized_png(&Options::default()).unwrap()
+    };
+    let opts = Options {
+        force: true,
+        photo_filter_threshold: Some(6.0),
+        ..Options::default()
+    };
+
+    // Noise has high entropy in every row
+    let mut state = 1u32;
+    let noise = (0..64 * 64 * 3)
+        .map(|_| {
+            state = state.wrapping_mul(1103515245).wrapping_add(12345);
+            (state >> 16) as u8
+        })
+        .collect();
+    let report = oxipng::optimize_from_memory_with_report(&create(noise), &opts).unwrap();
+    assert_eq!(report.chosen_filter, Some(RowFilter::Paeth));
+
+    // A gradient does not
+    let gradient = (0..64 * 64 * 3).map(|i| (i / 192) as u8).collect();
+    let report = oxipng::optimize_from_memory_with_report(&create(gradient), &opts).unwrap();
+    assert_ne!(report.chosen_filter, Some(RowFilter::Paeth));
+}
+
+#[test]
+fn optimize_from_reader() {
+    let path = "tests/files/rgba_16_should_be_rgb_8.png";
+    let opts = Options::default();
+    let output = oxipng::optimize_from_reader(File::open(path).unwrap(), &opts).unwrap();
+    let expected = oxipng::optimize_from_memory(&fs::read(path).unwrap(), &opts).unwrap();
+    assert_eq!(output, expected);
+
+    // Chunks that are read but not kept still count towards the size of the input
+    let mut file = fs::read(path).unwrap();
+    let iend = file.len() - 12;
+    let mut text = Vec::new();
+    png_chunk(&mut text, b"tEXt", &[b'a'; 4096]);
+    file.splice(iend..iend, text);
+    let strip = Options {
+        strip: StripChunks::Safe,
+        ..opts.clone()
+    };
+    let output = oxipng::optimize_from_reader(&file[..], &strip).unwrap();
+    assert_eq!(output, oxipng::optimize_from_memory(&file, &strip).unwrap());
+    assert!(output.len() < file.len() - 4096);
+
+    let file = fs::read(path).unwrap();
+    let result = oxipng::optimize_from_reader(&file[..file.len() - 20], &opts);
+    assert!(matches!(result, Err(PngError::TruncatedData)));
+
+    // A corrupt chunk length must not be trusted for the allocation
+    let mut file = file[..33].to_vec();
+    file.extend_from_slice(&0x7FFF_FFFFu32.to_be_bytes());
+    file.extend_from_slice(b"IDAT");
+    let result = oxipng::optimize_from_reader(&file[..], &opts);
+    assert!(matches!(result, Err(PngError::TruncatedData)));
+    file[33] = 0x80;
+    assert!(oxipng::optimize_from_reader(&file[..], &opts).is_err());
+}
+
+#[test]
+fn optimize_with_thread_limit() {
+    let file = fs::read("tests/files/rgba_16_should_be_rgb_8.png").unwrap();
+    let opts = Options {
+        filter: indexset! {RowFilter::None, RowFilter::Sub, RowFilter::Up, RowFilter::Paeth, RowFilter::Bigrams},
+        fast_evaluation: false,
+        ..Options::default()
+    };
+    let expected = oxipng::optimize_from_memory(&file, &opts).unwrap();
+    // The result is the same regardless of the number of threads
+    for threads in [1, 3] {
+        let opts = Options {
+            threads: Some(threads),
+            ..opts.clone()
+        };
+        assert_eq!(
+            oxipng::optimize_from_memory(&file, &opts).unwrap(),
+            expected
+        );
+    }
+}
+
+#[test]
+fn optimize_files_batch() {
+    let dir = std::env::temp_dir().join("oxipng_optimize_files_batch");
+    let out_dir = dir.join("out");
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(dir.join("nested/deeper")).unwrap();
+    fs::create_dir_all(&out_dir).unwrap();
+    let good = fs::read("tests/files/rgba_16_should_be_rgb_8.png").unwrap();
+    fs::write(dir.join("good.png"), &good).unwrap();
+    fs::write(dir.join("nested/also_good.PNG"), &good).unwrap();
+    fs::write(dir.join("nested/deeper/good.png"), &good).unwrap();
+    fs::write(dir.join("nested/notes.txt"), "not a png").unwrap();
+    fs::copy("tests/files/corrupted_header.png", dir.join("corrupt.png")).unwrap();
+
+    let opts = Options {
+        threads: Some(2),
+        ..Options::default()
+    };
+    let inputs = [
+        dir.join("good.png"),
+        dir.join("corrupt.png"),
+        dir.join("nested"),
+    ];
+    let mut results = oxipng::optimize_files(&inputs, Some(&out_dir), &opts);
+    assert_eq!(results.len(), 4);
+    let (path, report) = results[0].as_ref().unwrap();
+    assert_eq!(*path, dir.join("good.png"));
+    assert_eq!(report.input_size, good.len());
+    assert!(report.output_size < good.len());
+    assert!(matches!(&results[1], Err((path, _)) if *path == dir.join("corrupt.png")));
+    // The output is written to the output directory, leaving the input untouched
+    assert_eq!(
+        fs::metadata(out_dir.join("good.png")).unwrap().len() as usize,
+        report.output_size
+    );
+    assert_eq!(fs::read(dir.join("good.png")).unwrap(), good);
+    // Files found in a directory keep their path relative to it
+    results[2..].sort_by_key(|r| r.as_ref().unwrap().0.clone());
+    assert_eq!(
+        results[2].as_ref().unwrap().0,
+        dir.join("nested/also_good.PNG")
+    );
+    assert_eq!(
+        results[3].as_ref().unwrap().0,
+        dir.join("nested/deeper/good.png")
+    );
+    assert!(out_dir.join("also_good.PNG").exists());
+    assert!(out_dir.join("deeper/good.png").exists());
+
+    // Files that would be written to the same output path are rejected
+    let inputs = [dir.join("good.png"), dir.join("nested/deeper/good.png")];
+    let results = oxipng::optimize_files(&inputs, Some(&out_dir), &opts);
+    assert!(results[0].is_ok());
+    assert!(matches!(&results[1], Err((path, _)) if *path == inputs[1]));
+
+    // Nothing is written in a dry run
+    let dry_run = Options {
+        dry_run: true,
+        ..opts.clone()
+    };
+    let results = oxipng::optimize_files(&[dir.join("good.png")], None, &dry_run);
+    assert!(results[0].as_ref().unwrap().1.output_size < good.len());
+    assert_eq!(fs::read(dir.join("good.png")).unwrap(), good);
+
+    // Files are overwritten in place without an output directory
+    let results = oxipng::optimize_files(&[dir.join("good.png")], None, &opts);
+    assert_eq!(
+        fs::metadata(dir.join("good.png")).unwrap().len() as usize,
+        results[0].as_ref().unwrap().1.output_size
+    );
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn optimize_files_memory_limit() {
+    let dir = std::env::temp_dir().join("oxipng_optimize_files_memory_limit");
+    let out_dir = dir.join("out");
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&out_dir).unwrap();
+    let good = fs::read("tests/files/rgba_16_should_be_rgb_8.png").unwrap();
+    let inputs: Vec<_> = (0..4).map(|i| dir.join(format!("{}.png", i))).collect();
+    for input in &inputs {
+        fs::write(input, &good).unwrap();
+    }
+    let expected = oxipng::optimize_from_memory(&good, &Options::default()).unwrap();
+
+    // A limit smaller than any image processes the files one at a time
+    for limit in [1, usize::MAX] {
+        let opts = Options {
+            batch_memory_limit: Some(limit),
+            ..Options::default()
+        };
+        let results = oxipng::optimize_files(&inputs, Some(&out_dir), &opts);
+        assert_eq!(results.len(), inputs.len());
+        for (i, result) in results.iter().enumerate() {
+            assert_eq!(result.as_ref().unwrap().1.output_size, expected.len());
+            assert_eq!(
+                fs::read(out_dir.join(format!("{}.png", i))).unwrap(),
+                expected
+            );
+        }
+    }
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn optimize_in_place_atomically() {
+    let dir = std::env::temp_dir().join("oxipng_optimize_in_place_atomically");
+    fs::remove_dir_all(&dir).ok();
+    fs::create_dir_all(&dir).unwrap();
+    let path = dir.join("image.png");
+    let input = fs::read("tests/files/rgba_16_should_be_rgb_8.png").unwrap();
+    fs::write(&path, &input).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o640)).unwrap();
+    }
+
+    let out_file = OutFile::Path {
+        path: None,
+        preserve_attrs: false,
+    };
+    oxipng::optimize(&InFile::Path(path.clone()), &out_file, &Options::default()).unwrap();
+    assert!(fs::metadata(&path).unwrap().len() < input.len() as u64);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o640);
+    }
+    // No temporary files are left behind
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+
+    // On failure, nothing is written
+    let missing = dir.join("missing/image.png");
+    let out_file = OutFile::from_path(missing.clone());
+    assert!(oxipng::optimize(&InFile::Path(path.clone()), &out_file, &Options::default()).is_err());
+    assert!(!missing.exists());
+    fs::remove_dir_all(&dir).ok();
+}
+
+#[test]
+fn dry_run_report() {
+    let file = fs::read("tests/files/strip_headers_none.png").unwrap();
+    let opts = Options {
+        strip: StripChunks::Safe,
+        force: true,
+        ..Options::default()
+    };
+    let dry_run = Options {
+        dry_run: true,
+        ..opts.clone()
+    };
+    let estimate = oxipng::optimize_from_memory_with_report(&file, &dry_run).unwrap();
+    assert_eq!(estimate.outcome, None);
+    let (output, report) = optimize_with_report(&file, &opts);
+    assert_eq!(estimate.output_size, output.len());
+    assert_eq!(estimate, report);
+
+    // An image that cannot be optimized further is reported as not being smaller
+    let file = fs::read("tests/files/fully_optimized.png").unwrap();
+    let dry_run = Options {
+        dry_run: true,
+        ..Options::default()
+    };
+    let estimate = oxipng::optimize_from_memory_with_report(&file, &dry_run).unwrap();
+    assert!(estimate.output_size >= estimate.input_size);
+}
+
+#[test]
+#[cfg(feature = "zopfli")]
+fn zopfli_timeout_falls_back() {
+    use std::{
+        num::NonZeroU8,
+        time::{Duration, Instant},
+    };
+
+    // Enough data for several Zopfli master blocks, which would take a long time to compress
+    let mut state = 1u32;
+    let data: Vec<u8> = (0..1024 * 1024 * 4)
+        .map(|i| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (i / 4 % 251) as u8 ^ (state >> 28) as u8
+        })
+        .collect();
+    let raw = RawImage::new(1024, 1024, ColorType::RGBA, BitDepth::Eight, data).unwrap();
+    let png = raw
+        .create_optimized_png(&Options {
+            deflate: Deflaters::Libdeflater { compression: 1 },
+            ..Options::default()
+        })
+        .unwrap();
+
+    let opts = Options {
+        deflate: Deflaters::Zopfli {
+            iterations: NonZeroU8::new(15).unwrap(),
+        },
+        timeout: Some(Duration::from_millis(500)),
+        ..Options::default()
+    };
+    let start = Instant::now();
+    let (output, report) = optimize_with_report(&png, &opts);
+    assert!(start.elapsed() < Duration::from_secs(30));
+    // The result of the evaluation is used instead
+    assert!(output.len() < png.len());
+    assert!(matches!(
+        report.chosen_deflater,
+        Some(Deflaters::Libdeflater { .. })
+    ));
+    // The output can be decoded again
+    oxipng::optimize_from_memory(&output, &Options::from_preset(0)).unwrap();
+}
+
+#[test]
+#[cfg(feature = "zopfli")]
+fn exact_brute_timeout() {
+    use std::{
+        num::NonZeroU8,
+        time::{Duration, Instant},
+    };
+
+    // Scoring every row with Zopfli would take a long time to complete
+    let mut state = 1u32;
+    let data: Vec<u8> = (0..512 * 512 * 3)
+        .map(|i| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (i / 3 % 251) as u8 ^ (state >> 29) as u8
+        })
+        .collect();
+    let color_type = ColorType::RGB {
+        transparent_color: None,
+    };
+    let raw = RawImage::new(512, 512, color_type, BitDepth::Eight, data).unwrap();
+    let png = raw.create_optimized_png(&Options::from_preset(0)).unwrap();
+
+    let opts = Options {
+        filter: indexset! {RowFilter::Brute},
+        exact_brute: true,
+        deflate: Deflaters::Zopfli {
+            iterations: NonZeroU8::new(15).unwrap(),
+        },
+        timeout: Some(Duration::from_millis(200)),
+        ..Options::default()
+    };
+    let start = Instant::now();
+    let output = oxipng::optimize_from_memory(&png, &opts).unwrap();
+    assert!(start.elapsed() < Duration::from_secs(10));
+    // The output can be decoded again
+    oxipng::optimize_from_memory(&output, &Options::from_preset(0)).unwrap();
+}
+
+#[test]
+#[cfg(feature = "zopfli")]
+fn zopfli_single_block_trial() {
+    use std::num::NonZeroU8;
+
+    // Uniform noise has the same statistics throughout, so a single Huffman table suffices
+    let mut state = 1u32;
+    let data: Vec<u8> = (0..100 * 100)
+        .map(|_| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            (state >> 29) as u8
+        })
+        .collect();
+    let raw = RawImage::new(
+        100,
+        100,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        data,
+    )
+    .unwrap();
+    let opts = Options {
+        deflate: Deflaters::Zopfli {
+            iterations: NonZeroU8::new(15).unwrap(),
+        },
+        filter: indexset! {RowFilter::None},
+        bit_depth_reduction: false,
+        color_type_reduction: false,
+        ..Options::default()
+    };
+    let split = raw.create_optimized_png(&opts).unwrap();
+    let single = raw
+        .create_optimized_png(&Options {
+            single_block_trial: true,
+            ..opts
+        })
+        .unwrap();
+    assert!(single.len() < split.len());
+    // The output can be decoded again
+    oxipng::optimize_from_memory(&single, &Options::from_preset(0)).unwrap();
+}
+
+#[test]
+fn splt_palette() {
+    let (red, green, blue) = ([255, 0, 0], [0, 255, 0], [0, 0, 255]);
+    let data: Vec<u8> = [red, green, blue, red].repeat(256).concat();
+    let mut raw = RawImage::new(
+        32,
+        32,
+        ColorType::RGB {
+            transparent_color: None,
+        },
+        BitDepth::Eight,
+        data,
+    )
+    .unwrap();
+    // A 16-bit suggested palette of blue then green
+    let mut splt = b"suggested\0\x10".to_vec();
+    for (r, g, b, freq) in [(0, 0, 0xFFFF, 100), (0, 0xFFFF, 0, 50)] {
+        for v in [r, g, b, 0xFFFFu16, freq] {
+            splt.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+    raw.add_png_chunk(*b"sPLT", splt);
+
+    let opts = Options {
+        splt_palette: true,
+        ..Options::default()
+    };
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+    let ColorType::Indexed { palette } = &png.raw.ihdr.color_type else {
+        panic!("Expected indexed color type");
+    };
+    assert_eq!(palette[0], RGBA8::new(0, 0, 255, 255));
+    assert_eq!(palette[1], RGBA8::new(0, 255, 0, 255));
+    assert_eq!(palette[2], RGBA8::new(255, 0, 0, 255));
+
+    // The sPLT chunk is preserved
+    let suggested = png.suggested_palettes();
+    assert_eq!(suggested.len(), 1);
+    assert_eq!(suggested[0].name, "suggested");
+    assert_eq!(suggested[0].sample_depth, 16);
+    assert_eq!(
+        suggested[0].entries[1],
+        SuggestedPaletteEntry {
+            color: RGBA16::new(0, 0xFFFF, 0, 0xFFFF),
+            frequency: 50,
+        }
+    );
+    assert_eq!(
+        suggested[0].colors_8bit(),
+        [RGBA8::new(0, 0, 255, 255), RGBA8::new(0, 255, 0, 255)]
+    );
+
+    // 8-bit entries are read directly, while invalid chunks are rejected
+    let splt = internal_tests::parse_splt_chunk(b"pal\0\x08\x01\x02\x03\x04\x00\x05").unwrap();
+    assert_eq!(splt.colors_8bit(), [RGBA8::new(1, 2, 3, 4)]);
+    assert_eq!(splt.entries[0].frequency, 5);
+    for invalid in [&b"pal\0\x08\x01\x02"[..], b"pal\0\x04", b"\0\x08", b"pal"] {
+        assert!(internal_tests::parse_splt_chunk(invalid).is_err());
+    }
+}
+
+#[test]
+fn candidate_selection() {
+    let (red, green, blue) = ([255, 0, 0], [0, 255, 0], [0, 0, 255]);
+    let data: Vec<u8> = [red, green, blue, red].repeat(256).concat();
+    let raw = RawImage::new(
+        32,
+        32,
+        ColorType::RGB {
+            transparent_color: None,
+        },
+        BitDepth::Eight,
+        data,
+    )
+    .unwrap();
+    let color_type = |opts: &Options| {
+        let output = raw.create_optimized_png(opts).unwrap();
+        let png = internal_tests::PngData::from_slice(&output, opts).unwrap();
+        png.raw.ihdr.color_type.png_header_code()
+    };
+
+    // The smallest candidate is indexed
+    assert_eq!(color_type(&Options::default()), 3);
+
+    // RGB is kept when preferred with a large enough tolerance
+    let prefer_rgb = |tolerance| Options {
+        candidate_selection: CandidateSelection::PreferColorType {
+            color_type: ColorType::RGB {
+                transparent_color: None,
+            },
+            tolerance,
+        },
+        ..Options::default()
+    };
+    assert_eq!(color_type(&prefer_rgb(1000.0)), 2);
+    assert_eq!(color_type(&prefer_rgb(0.0)), 3);
+}
+
+#[test]
+fn filter_trials_deterministic() {
+    let input = fs::read("tests/files/rgb_16_should_be_rgb_16.png").unwrap();
+    let opts = Options {
+        force: true,
+        fast_evaluation: false,
+        filter: indexset! {RowFilter::None, RowFilter::Sub, RowFilter::Up, RowFilter::Paeth},
+        ..Options::default()
+    };
+    let (first, report) = optimize_with_report(&input, &opts);
+    for _ in 0..3 {
+        let (output, other) = optimize_with_report(&input, &opts);
+        assert_eq!(output, first);
+        assert_eq!(other.chosen_filter, report.chosen_filter);
+    }
+}
+
+#[test]
+fn mark_optimized() {
+    let input = fs::read("tests/files/rgb_16_should_be_rgb_16.png").unwrap();
+    let opts = Options {
+        mark_optimized: true,
+        ..Options::default()
+    };
+    assert!(!has_optimized_marker(&input));
+
+    let marked = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    assert!(has_optimized_marker(&marked));
+    let original = internal_tests::PngData::from_slice(&input, &opts).unwrap();
+    let png = internal_tests::PngData::from_slice(&marked, &opts).unwrap();
+    assert_eq!(png.raw.data, original.raw.data);
+
+    // A marked file is returned as it is, even with different options
+    let again = oxipng::optimize_from_memory(
+        &marked,
+        &Options {
+            force: true,
+            ..Options::from_preset(6)
+        },
+    )
+    .unwrap();
+    assert!(!has_optimized_marker(&again));
+    let skipped = oxipng::optimize_from_memory(
+        &marked,
+        &Options {
+            force: true,
+            mark_optimized: true,
+            ..Options::from_preset(6)
+        },
+    )
+    .unwrap();
+    assert_eq!(skipped, marked);
+
+    // A marker that doesn't match the image data is ignored and replaced
+    let mut raw = RawImage::new(1, 1, ColorType::RGBA, BitDepth::Eight, vec![1, 2, 3, 4]).unwrap();
+    raw.add_png_chunk(MARKER_CHUNK, vec![0, 0, 0, 0]);
+    let stale = raw.create_optimized_png(&Options::default()).unwrap();
+    assert!(!has_optimized_marker(&stale));
+    let output = oxipng::optimize_from_memory(&stale, &opts).unwrap();
+    assert!(has_optimized_marker(&output));
+}
+
+#[test]
+fn effort_matches_presets() {
+    for level in 0..=6 {
+        let effort = Options::from_effort(level);
+        let preset = Options::from_preset(level);
+        assert_eq!(effort.deflate, preset.deflate);
+        assert_eq!(effort.filter, preset.filter);
+        assert_eq!(effort.interlace, preset.interlace);
+    }
+}
+
+#[test]
+#[cfg(feature = "zopfli")]
+fn effort_zopfli() {
+    use std::num::NonZeroU8;
+
+    let opts = Options::from_effort(7);
+    assert_eq!(
+        opts.deflate,
+        Deflaters::Zopfli {
+            iterations: NonZeroU8::new(15).unwrap()
+        }
+    );
+    assert!(!opts.single_block_trial);
+    assert_eq!(opts.filter, Options::max_compression().filter);
+
+    let opts = Options::from_effort(10);
+    assert_eq!(
+        opts.deflate,
+        Deflaters::Zopfli {
+            iterations: NonZeroU8::new(100).unwrap()
+        }
+    );
+    assert!(opts.single_block_trial);
+    assert_eq!(opts.auto_interlace, Some(AutoInterlacing::Trial));
+
+    // Explicit fields still override the effort
+    let opts = Options {
+        auto_interlace: None,
+        ..Options::from_effort(10)
+    };
+    assert_eq!(opts.auto_interlace, None);
+}
+
+#[test]
+fn max_idat_size() {
+    let input = fs::read("tests/files/rgb_8_should_be_rgb_8.png").unwrap();
+    let opts = Options {
+        force: true,
+        ..Options::default()
+    };
+    let single = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    let single_info = oxipng::parse_header(&single).unwrap();
+    let idat_sizes = |info: &PngInfo| -> Vec<usize> {
+        info.chunks
+            .iter()
+            .filter(|(name, _)| name == b"IDAT")
+            .map(|&(_, size)| size)
+            .collect()
+    };
+    let idat_size = idat_sizes(&single_info)[0];
+    assert_eq!(idat_sizes(&single_info).len(), 1);
+
+    let opts = Options {
+        max_idat_size: NonZeroU32::new(100),
+        ..opts
+    };
+    let split = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    let sizes = idat_sizes(&oxipng::parse_header(&split).unwrap());
+    assert_eq!(sizes.len(), idat_size.div_ceil(100));
+    assert!(sizes.iter().all(|&size| size <= 100));
+    assert_eq!(sizes.iter().sum::<usize>(), idat_size);
+    assert_eq!(split.len(), single.len() + 12 * (sizes.len() - 1));
+    let dry_run = Options {
+        dry_run: true,
+        ..opts.clone()
+    };
+    assert_eq!(
+        oxipng::optimize_from_memory_with_report(&input, &dry_run)
+            .unwrap()
+            .output_size,
+        split.len()
+    );
+
+    // The split chunks have valid CRCs and decode to the same image
+    let single_png = internal_tests::PngData::from_slice(&single, &Options::default()).unwrap();
+    let split_png = internal_tests::PngData::from_slice(&split, &Options::default()).unwrap();
+    assert_eq!(split_png.raw.data, single_png.raw.data);
+}
+
+#[test]
+fn verify_output() {
+    let input = fs::read("tests/files/rgba_8_should_be_rgba_8.png").unwrap();
+    let opts = Options {
+        force: true,
+        optimize_alpha: true,
+        verify: true,
+        ..Options::default()
+    };
+    let output = oxipng::optimize_from_memory(&input, &opts).unwrap();
+    let png = internal_tests::PngData::from_slice(&output, &opts).unwrap();
+
+    assert_eq!(png.raw.ihdr.color_type, ColorType::RGBA);
+
+    // Only the color of fully transparent pixels may differ with alpha optimization
+    let mut expected = (*png.raw).clone();
+    expected.data[..4].copy_from_slice(&[10, 20, 30, 0]);
+    expected.data[4..8].copy_from_slice(&[10, 20, 30, 255]);
+    let mut altered = expected.clone();
+    altered.data[0] = 11;
+    assert!(altered.same_pixels(&expected, true));
+    assert!(!altered.same_pixels(&expected, false));
+    altered.data[4] = 11;
+    assert!(!altered.same_pixels(&expected, true));
+
+    // The output is compared with the original image, so a wrong reduction is rejected
+    let input = fs::read("tests/files/rgba_8_should_be_palette_8.png").unwrap();
+    let original = internal_tests::PngData::from_slice(&input, &opts)
+        .unwrap()
+        .raw;
+    let indexed = internal_tests::color::reduced_to_indexed(&original, true).unwrap();
+    let encode = |png: &internal_tests::PngImage| {
+        let raw = RawImage::new(
+            png.ihdr.width,
+            png.ihdr.height,
+            png.ihdr.color_type.clone(),
+            png.ihdr.bit_depth,
+            png.data.clone(),
+        )
+        .unwrap();
+        raw.create_optimized_png(&Options::default()).unwrap()
+    };
+    assert!(internal_tests::verify_output(&encode(&indexed), &original, None, &opts).is_ok());
+    let mut wrong = indexed.clone();
+    let ColorType::Indexed { palette } = &mut wrong.ihdr.color_type else {
+        panic!("Expected an indexed image");
+    };
+    palette.swap(0, 1);
+    assert!(matches!(
+        internal_tests::verify_output(&encode(&wrong), &original, None, &opts),
+        Err(PngError::VerificationFailed)
+    ));
+}
+
+#[test]
+fn recover_image_data() {
+    let gray = ColorType::Grayscale {
+        transparent_shade: None,
+    };
+    let data: Vec<u8> = (0..64 * 64).map(|i| (i * 7 % 251) as u8).collect();
+    let raw = RawImage::new(64, 64, gray, BitDepth::Eight, data.clone()).unwrap();
+    let opts = Options {
+        filter: indexset! {RowFilter::None},
+        deflate: Deflaters::Libdeflater { compression: 0 },
+        ..Options::default()
+    };
+    let stored = raw.create_optimized_png(&opts).unwrap();
+    let mut png = internal_tests::PngData::from_slice(&stored, &opts).unwrap();
+    assert_eq!(png.recovered, None);
+    let full_idat = png.idat_data.clone();
+
+    // Truncated image data is rejected unless recovery is enabled
+    png.idat_data.truncate(full_idat.len() / 2);
+    let truncated = png.output(&opts);
+    assert!(oxipng::optimize_from_memory(&truncated, &Options::default()).is_err());
+    let recover_opts = Options {
+        recover_image_data: true,
+        force: true,
+        ..Options::default()
+    };
+    let (output, report) = optimize_with_report(&truncated, &recover_opts);
+    let recovered = report.recovered.unwrap();
+    assert_eq!(recovered.total_rows, 64);
+    assert!(recovered.rows > 0 && recovered.rows < 64);
+    let row_bytes = 64 * recovered.rows;
+    let decoded = internal_tests::PngData::from_slice(&output, &Options::default()).unwrap();
+    assert_eq!(decoded.recovered, None);
+    assert_eq!(decoded.raw.data[..row_bytes], data[..row_bytes]);
+    assert!(decoded.raw.data[64 * (recovered.rows + 1)..]
+        .iter()
+        .all(|&b| b == 0));
+
+    // A bad checksum still allows the full image to be recovered
+    png.idat_data = full_idat;
+    *png.idat_data.last_mut().unwrap() ^= 1;
+    let bad_checksum = png.output(&opts);
+    assert!(oxipng::optimize_from_memory(&bad_checksum, &Options::default()).is_err());
+    let (output, report) = optimize_with_report(&bad_checksum, &recover_opts);
+    assert_eq!(report.recovered.unwrap().rows, 64);
+    let decoded = internal_tests::PngData::from_slice(&output, &Options::default()).unwrap();
+    assert_eq!(decoded.raw.data, data);
+}
+
+#[test]
+fn libdeflater_level_bounds() {
+    assert!(Deflaters::libdeflater(LIBDEFLATER_MIN_LEVEL).is_ok());
+    assert!(Deflaters::libdeflater(LIBDEFLATER_MAX_LEVEL).is_ok());
+    assert!(matches!(
+        Deflaters::libdeflater(LIBDEFLATER_MAX_LEVEL + 1),
+        Err(PngError::InvalidCompressionLevel(13))
+    ));
+
+    // An invalid level is reported rather than clamped
+    let file = fs::read("tests/files/fully_optimized.png").unwrap();
+    let opts = Options {
+        deflate: Deflaters::Libdeflater { compression: 20 },
+        ..Options::default()
+    };
+    let result = oxipng::optimize_from_memory(&file, &opts);
+    assert!(matches!(result, Err(PngError::InvalidCompressionLevel(20))));
+    let opts = Options {
+        aux_deflate: Some(Deflaters::Libdeflater { compression: 255 }),
+        ..Options::default()
+    };
+    let result = oxipng::optimize_from_memory(&file, &opts);
+    assert!(matches!(
+        result,
+        Err(PngError::InvalidCompressionLevel(255))
+    ));
+}
+
+#[test]
+fn public_image_headers() {
+    let file = fs::read("tests/files/apng_file.png").unwrap();
+    let png = DecodedPng::from_slice(&file, &Options::default()).unwrap();
+    let ihdr: &IhdrData = png.ihdr();
+    assert_eq!(png.dimensions(), (ihdr.width, ihdr.height));
+    assert_eq!(png.color_type(), &ihdr.color_type);
+    assert_eq!(png.bit_depth(), ihdr.bit_depth);
+    let names: Vec<_> = png.ancillary_chunks().map(|c| &c.name).collect();
+    assert!(names.contains(&b"acTL"));
+    assert!(!names.contains(&b"IDAT"));
+    assert!(!names.contains(&b"PLTE"));
+
+    let output = oxipng::optimize_from_memory(&file, &Options::default()).unwrap();
+    let new_png = DecodedPng::from_slice(&output, &Options::default()).unwrap();
+    assert_eq!(new_png.dimensions(), png.dimensions());
+    assert!(new_png.ancillary_chunks().any(|c| &c.name == b"acTL"));
+}
@@ -94,3 +94,1068 @@ fn incorrect_length() {
     )
     .expect_err("Expected incorrect data length");
 }
+
+#[test]
+fn gray_indexed_with_transparency() {
+    // The conversion is only attempted when evaluations are not cheap
+    let mut opts = get_opts();
+    opts.fast_evaluation = false;
+
+    let raw = RawImage::new(
+        4,
+        4,
+        ColorType::Indexed {
+            palette: vec![
+                RGBA8::new(0, 0, 0, 255),
+                RGBA8::new(255, 255, 255, 255),
+                RGBA8::new(85, 85, 85, 0),
+            ],
+        },
+        BitDepth::Eight,
+        vec![0, 0, 1, 1, 0, 0, 1, 1, 2, 2, 2, 2, 2, 2, 2, 2],
+    )
+    .unwrap();
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(
+        new.raw.ihdr.color_type,
+        ColorType::Grayscale {
+            transparent_shade: Some(1)
+        }
+    );
+    assert_eq!(new.raw.ihdr.bit_depth, BitDepth::Two);
+
+    opts.fast_evaluation = true;
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert!(matches!(new.raw.ihdr.color_type, ColorType::Indexed { .. }));
+}
+
+#[test]
+fn low_depth_palette_reduction() {
+    let mut opts = get_opts();
+    opts.bit_depth_reduction = false;
+    opts.color_type_reduction = false;
+
+    let raw = RawImage::new(
+        8,
+        2,
+        ColorType::Indexed {
+            palette: vec![RGBA8::new(255, 0, 0, 255), RGBA8::new(0, 0, 255, 255)],
+        },
+        BitDepth::One,
+        vec![0, 0],
+    )
+    .unwrap();
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(
+        new.raw.ihdr.color_type,
+        ColorType::Indexed {
+            palette: vec![RGBA8::new(255, 0, 0, 255)]
+        }
+    );
+    assert_eq!(new.raw.ihdr.bit_depth, BitDepth::One);
+}
+
+#[test]
+fn low_depth_palette_remapping() {
+    let mut opts = get_opts();
+    opts.bit_depth_reduction = false;
+    opts.color_type_reduction = false;
+
+    let red = RGBA8::new(255, 0, 0, 255);
+    let green = RGBA8::new(0, 255, 0, 255);
+    let raw = RawImage::new(
+        4,
+        1,
+        ColorType::Indexed {
+            palette: vec![red, RGBA8::new(0, 0, 255, 255), red, green],
+        },
+        BitDepth::Two,
+        vec![0b00_10_11_10],
+    )
+    .unwrap();
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(
+        new.raw.ihdr.color_type,
+        ColorType::Indexed {
+            palette: vec![red, green]
+        }
+    );
+    assert_eq!(new.raw.ihdr.bit_depth, BitDepth::Two);
+    assert_eq!(new.raw.data, vec![0b00_00_01_00]);
+}
+
+#[test]
+fn low_depth_palette_remapping_clears_padding() {
+    let mut opts = get_opts();
+    opts.bit_depth_reduction = false;
+    opts.color_type_reduction = false;
+
+    let red = RGBA8::new(255, 0, 0, 255);
+    let green = RGBA8::new(0, 255, 0, 255);
+    // The last two bits are padding, but set to an index that gets remapped
+    let raw = RawImage::new(
+        3,
+        2,
+        ColorType::Indexed {
+            palette: vec![red, RGBA8::new(0, 0, 255, 255), red, green],
+        },
+        BitDepth::Two,
+        vec![0b00_10_11_11, 0b11_00_10_11],
+    )
+    .unwrap();
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(
+        new.raw.ihdr.color_type,
+        ColorType::Indexed {
+            palette: vec![red, green]
+        }
+    );
+    assert_eq!(new.raw.data, vec![0b00_00_01_00, 0b01_00_00_00]);
+}
+
+#[test]
+fn palette_masked_by_sbit() {
+    let opts = get_opts();
+
+    let mut raw = RawImage::new(
+        2,
+        2,
+        ColorType::Indexed {
+            palette: vec![RGBA8::new(255, 128, 0, 255), RGBA8::new(252, 131, 3, 255)],
+        },
+        BitDepth::Eight,
+        vec![0, 1, 1, 0],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"sBIT", vec![5, 5, 5]);
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    // The first color is kept unchanged
+    assert_eq!(
+        new.raw.ihdr.color_type,
+        ColorType::Indexed {
+            palette: vec![RGBA8::new(255, 128, 0, 255)]
+        }
+    );
+    // Red and blue are constant and can be reproduced from a single bit
+    let sbit = new.aux_chunks.iter().find(|c| &c.name == b"sBIT").unwrap();
+    assert_eq!(sbit.data, vec![1, 5, 1]);
+}
+
+#[test]
+fn palette_masked_by_sbit_keeps_colors() {
+    let opts = get_opts();
+
+    let colors = vec![
+        RGBA8::new(255, 255, 255, 255),
+        RGBA8::new(0, 0, 0, 255),
+        RGBA8::new(7, 3, 0, 255),
+    ];
+    let mut raw = RawImage::new(
+        3,
+        1,
+        ColorType::Indexed {
+            palette: colors.clone(),
+        },
+        BitDepth::Eight,
+        vec![0, 1, 2],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"sBIT", vec![5, 5, 5]);
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    let ColorType::Indexed { palette } = &new.raw.ihdr.color_type else {
+        panic!("Expected an indexed image");
+    };
+    // Bit-replicated white is not changed, and black absorbs the color differing in low bits
+    assert_eq!(palette.len(), 2);
+    assert!(palette.contains(&colors[0]));
+    assert!(palette.contains(&colors[1]));
+    let sbit = new.aux_chunks.iter().find(|c| &c.name == b"sBIT").unwrap();
+    assert_eq!(sbit.data, vec![5, 5, 5]);
+}
+
+#[test]
+fn rgba_shared_transparent_color() {
+    let mut opts = get_opts();
+    opts.bit_depth_reduction = false;
+
+    // Transparent pixels all share a color that is not used by any opaque pixel
+    let transparent = [0x12, 0x34, 0x56, 0x78, 0x9A, 0xBC, 0, 0];
+    let opaque = [0xFF, 0x01, 0x80, 0x02, 0x00, 0x03, 0xFF, 0xFF];
+    let data = [transparent, opaque].repeat(512).concat();
+    let raw = RawImage::new(32, 32, ColorType::RGBA, BitDepth::Sixteen, data).unwrap();
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(
+        new.raw.ihdr.color_type,
+        ColorType::RGB {
+            transparent_color: Some(RGB16::new(0x1234, 0x5678, 0x9ABC))
+        }
+    );
+    assert_eq!(new.raw.ihdr.bit_depth, BitDepth::Sixteen);
+}
+
+#[test]
+fn redundant_16_bit_transparency_and_background() {
+    let mut opts = get_opts();
+    opts.color_type_reduction = false;
+
+    let data = [[0x12, 0x12, 0x34, 0x34, 0x56, 0x56], [0xFF; 6]]
+        .repeat(8)
+        .concat();
+    let mut raw = RawImage::new(
+        4,
+        4,
+        ColorType::RGB {
+            transparent_color: Some(RGB16::new(0x1212, 0x3434, 0x5656)),
+        },
+        BitDepth::Sixteen,
+        data,
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"bKGD", vec![0x80, 0x80, 0x00, 0x00, 0xFF, 0xFF]);
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(new.raw.ihdr.bit_depth, BitDepth::Eight);
+    assert_eq!(
+        new.raw.ihdr.color_type,
+        ColorType::RGB {
+            transparent_color: Some(RGB16::new(0x12, 0x34, 0x56))
+        }
+    );
+    let bkgd = new.aux_chunks.iter().find(|c| &c.name == b"bKGD").unwrap();
+    assert_eq!(bkgd.data, vec![0x00, 0x80, 0x00, 0x00, 0x00, 0xFF]);
+}
+
+#[test]
+fn force_color_type_indexed_to_rgba() {
+    let mut opts = get_opts();
+    opts.force_color_type = Some(ColorType::RGBA);
+
+    let mut raw = RawImage::new(
+        2,
+        2,
+        ColorType::Indexed {
+            palette: vec![RGBA8::new(255, 0, 0, 255), RGBA8::new(0, 0, 255, 128)],
+        },
+        BitDepth::Eight,
+        vec![0, 1, 1, 0],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"bKGD", vec![1]);
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(new.raw.ihdr.color_type, ColorType::RGBA);
+    assert_eq!(new.raw.ihdr.bit_depth, BitDepth::Eight);
+    let red = [255, 0, 0, 255];
+    let blue = [0, 0, 255, 128];
+    assert_eq!(new.raw.data, [red, blue, blue, red].concat());
+    let bkgd = new.aux_chunks.iter().find(|c| &c.name == b"bKGD").unwrap();
+    assert_eq!(bkgd.data, vec![0x00, 0x00, 0x00, 0x00, 0x00, 0xFF]);
+}
+
+#[test]
+fn force_color_type_grayscale_to_rgb() {
+    let mut opts = get_opts();
+    opts.force_color_type = Some(ColorType::RGB {
+        transparent_color: None,
+    });
+
+    let mut raw = RawImage::new(
+        4,
+        1,
+        ColorType::Grayscale {
+            transparent_shade: Some(1),
+        },
+        BitDepth::Two,
+        vec![0b00_01_10_11],
+    )
+    .unwrap();
+    raw.add_png_chunk(*b"bKGD", vec![0x00, 0x02]);
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(
+        new.raw.ihdr.color_type,
+        ColorType::RGB {
+            transparent_color: Some(RGB16::new(0x55, 0x55, 0x55))
+        }
+    );
+    assert_eq!(new.raw.ihdr.bit_depth, BitDepth::Eight);
+    assert_eq!(
+        new.raw.data,
+        [[0x00; 3], [0x55; 3], [0xAA; 3], [0xFF; 3]].concat()
+    );
+    let bkgd = new.aux_chunks.iter().find(|c| &c.name == b"bKGD").unwrap();
+    assert_eq!(bkgd.data, vec![0x00, 0xAA, 0x00, 0xAA, 0x00, 0xAA]);
+}
+
+#[test]
+fn force_color_type_lossy_is_skipped() {
+    let mut opts = get_opts();
+    opts.force_color_type = Some(ColorType::RGB {
+        transparent_color: None,
+    });
+
+    let raw = RawImage::new(
+        1,
+        2,
+        ColorType::RGBA,
+        BitDepth::Eight,
+        vec![1, 2, 3, 4, 5, 6, 7, 8],
+    )
+    .unwrap();
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(new.raw.ihdr.color_type, ColorType::RGBA);
+}
+
+#[test]
+fn lossy_bit_depth_dithering() {
+    let mut opts = get_opts();
+    opts.color_type_reduction = false;
+
+    // A flat gray that sits between two 8-bit values
+    let data = 0x8040u16.to_be_bytes().repeat(16 * 16);
+    let raw = RawImage::new(
+        16,
+        16,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Sixteen,
+        data,
+    )
+    .unwrap();
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(new.raw.ihdr.bit_depth, BitDepth::Sixteen);
+
+    opts.allow_lossy_bit_depth = true;
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(new.raw.ihdr.bit_depth, BitDepth::Eight);
+    // Both neighbouring values should be used, averaging out close to the original
+    assert!(new.raw.data.iter().all(|&v| v == 0x7F || v == 0x80));
+    let mean = new.raw.data.iter().map(|&v| f64::from(v)).sum::<f64>() / 256.0;
+    assert!((mean - f64::from(0x8040) * 255.0 / 65535.0).abs() < 0.05);
+    assert_eq!(raw.create_optimized_png(&opts).unwrap(), output);
+}
+
+#[test]
+fn lossy_bit_depth_dithering_avoids_transparent_key() {
+    let mut opts = get_opts();
+    opts.color_type_reduction = false;
+    opts.allow_lossy_bit_depth = true;
+
+    // A flat gray between two 8-bit values, one of which is the transparent shade
+    let mut data = 0x8040u16.to_be_bytes().repeat(16 * 16);
+    data[0..2].copy_from_slice(&0x8080u16.to_be_bytes());
+    let raw = RawImage::new(
+        16,
+        16,
+        ColorType::Grayscale {
+            transparent_shade: Some(0x8080),
+        },
+        BitDepth::Sixteen,
+        data,
+    )
+    .unwrap();
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(new.raw.ihdr.bit_depth, BitDepth::Eight);
+    assert_eq!(
+        new.raw.ihdr.color_type,
+        ColorType::Grayscale {
+            transparent_shade: Some(0x80)
+        }
+    );
+    // Only the transparent pixel has the transparent shade, and the rest still average out
+    assert_eq!(new.raw.data[0], 0x80);
+    assert!(new.raw.data[1..].iter().all(|&v| v != 0x80));
+    let mean = new.raw.data[1..].iter().map(|&v| f64::from(v)).sum::<f64>() / 255.0;
+    assert!((mean - f64::from(0x8040) * 255.0 / 65535.0).abs() < 0.1);
+}
+
+#[test]
+fn grayscale_depth_reduction_row_padding() {
+    let mut opts = get_opts();
+    opts.color_type_reduction = false;
+
+    // Each row of 3 pixels should be packed into a single padded byte at 2-bit depth
+    let raw = RawImage::new(
+        3,
+        2,
+        ColorType::Grayscale {
+            transparent_shade: None,
+        },
+        BitDepth::Eight,
+        vec![0, 85, 170, 255, 170, 85],
+    )
+    .unwrap();
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(new.raw.ihdr.bit_depth, BitDepth::Two);
+    assert_eq!(new.raw.data, vec![0b00_01_10_00, 0b11_10_01_00]);
+}
+
+#[test]
+fn palette_transparency_is_minimal() {
+    let mut opts = get_opts();
+    opts.bit_depth_reduction = false;
+    opts.preserve_palette_order = true;
+
+    let palette = vec![
+        RGBA8::new(255, 0, 0, 255),
+        RGBA8::new(0, 255, 0, 128),
+        RGBA8::new(0, 0, 255, 0),
+        RGBA8::new(255, 255, 0, 255),
+        RGBA8::new(0, 255, 255, 255),
+    ];
+    let raw = RawImage::new(
+        5,
+        1,
+        ColorType::Indexed { palette },
+        BitDepth::Eight,
+        vec![0, 1, 2, 3, 4],
+    )
+    .unwrap();
+    let output = raw.create_optimized_png(&opts).unwrap();
+
+    // The palette is written as RGB, with alpha only up to the last transparent entry
+    let mut chunks = Vec::new();
+    let mut offset = 8;
+    while offset < output.len() {
+        let length = u32::from_be_bytes(output[offset..offset + 4].try_into().unwrap()) as usize;
+        let name = &output[offset + 4..offset + 8];
+        chunks.push((
+            name.to_vec(),
+            output[offset + 8..offset + 8 + length].to_vec(),
+        ));
+        offset += length + 12;
+    }
+    let plte = chunks.iter().find(|(name, _)| name == b"PLTE").unwrap();
+    let trns = chunks.iter().find(|(name, _)| name == b"tRNS").unwrap();
+    assert_eq!(plte.1.len(), 5 * 3);
+    assert_eq!(trns.1, vec![255, 128, 0]);
+}
+
+#[test]
+fn composite_background() {
+    let mut opts = get_opts();
+    opts.composite_background = true;
+
+    let data = [
+        [10, 20, 30, 255],
+        [0, 0, 0, 0],
+        [40, 50, 60, 255],
+        [1, 2, 3, 0],
+    ]
+    .concat();
+    let mut raw = RawImage::new(2, 2, ColorType::RGBA, BitDepth::Eight, data.clone()).unwrap();
+    raw.add_png_chunk(*b"bKGD", vec![0, 200, 0, 100, 0, 0]);
+
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(
+        new.raw.ihdr.color_type,
+        ColorType::RGB {
+            transparent_color: None
+        }
+    );
+    assert_eq!(
+        new.raw.data,
+        [[10, 20, 30], [200, 100, 0], [40, 50, 60], [200, 100, 0]].concat()
+    );
+    let bkgd = new.aux_chunks.iter().find(|c| &c.name == b"bKGD").unwrap();
+    assert_eq!(bkgd.data, vec![0, 200, 0, 100, 0, 0]);
+
+    // The output is checked against the composited image, not the original
+    let original = raw.create_optimized_png(&get_opts()).unwrap();
+    let verify_opts = Options {
+        verify: true,
+        ..opts.clone()
+    };
+    let output = oxipng::optimize_from_memory(&original, &verify_opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert!(matches!(new.raw.ihdr.color_type, ColorType::RGB { .. }));
+
+    // Partial transparency cannot be composited exactly
+    let mut data = data;
+    data[7] = 128;
+    let mut raw = RawImage::new(2, 2, ColorType::RGBA, BitDepth::Eight, data).unwrap();
+    raw.add_png_chunk(*b"bKGD", vec![0, 200, 0, 100, 0, 0]);
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(new.raw.ihdr.color_type, ColorType::RGBA);
+}
+
+#[test]
+fn opaque_palette_drops_trns() {
+    let opts = get_opts();
+    let has_trns = |output: &[u8]| {
+        let info = parse_header(output).unwrap();
+        info.chunks.iter().any(|(name, _)| name == b"tRNS")
+    };
+
+    // The only transparent entry is unused, so it is removed along with the tRNS chunk
+    let palette = vec![
+        RGBA8::new(255, 0, 0, 255),
+        RGBA8::new(0, 255, 0, 0),
+        RGBA8::new(0, 0, 255, 255),
+    ];
+    let raw = RawImage::new(
+        4,
+        1,
+        ColorType::Indexed { palette },
+        BitDepth::Eight,
+        vec![0, 2, 2, 0],
+    )
+    .unwrap();
+    assert!(!has_trns(&raw.create_optimized_png(&opts).unwrap()));
+
+    // A tRNS chunk shorter than the palette leaves the remaining entries opaque
+    let mut input = vec![0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+    let mut chunk = |name: &[u8; 4], data: &[u8]| {
+        let mut body = name.to_vec();
+        body.extend_from_slice(data);
+        input.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        input.extend_from_slice(&body);
+        input.extend_from_slice(&crc32(&body).to_be_bytes());
+    };
+    chunk(b"IHDR", &[0, 0, 0, 4, 0, 0, 0, 1, 8, 3, 0, 0, 0]);
+    chunk(b"PLTE", &[255, 0, 0, 0, 255, 0, 0, 0, 255]);
+    chunk(b"tRNS", &[255]);
+    chunk(b"IDAT", &store(&[0, 0, 1, 2, 1]));
+    chunk(b"IEND", &[]);
+    assert!(has_trns(&input));
+    let png = PngData::from_slice(&input, &opts).unwrap();
+    let ColorType::Indexed { palette } = &png.raw.ihdr.color_type else {
+        panic!("Image should be indexed");
+    };
+    assert!(palette.iter().all(|c| c.a == 255));
+    assert!(!has_trns(&optimize_from_memory(&input, &opts).unwrap()));
+}
+
+#[test]
+fn transparent_palette_color() {
+    let mut opts = get_opts();
+    opts.optimize_alpha = true;
+    opts.color_type_reduction = false;
+    opts.transparent_palette_color = RGB8::new(255, 0, 255);
+
+    let palette = vec![
+        RGBA8::new(255, 0, 0, 255),
+        RGBA8::new(0, 255, 0, 0),
+        RGBA8::new(0, 0, 255, 0),
+    ];
+    let raw = RawImage::new(
+        3,
+        1,
+        ColorType::Indexed { palette },
+        BitDepth::Eight,
+        vec![0, 1, 2],
+    )
+    .unwrap();
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    let ColorType::Indexed { palette } = &new.raw.ihdr.color_type else {
+        panic!("Image should remain indexed");
+    };
+    // The transparent entries are merged into one of the chosen color
+    assert_eq!(palette.len(), 2);
+    assert!(palette.contains(&RGBA8::new(255, 0, 255, 0)));
+}
+
+fn bkgd_after_reduction(
+    width: u32,
+    color_type: ColorType,
+    bit_depth: BitDepth,
+    data: Vec<u8>,
+    bkgd: Vec<u8>,
+) -> (ColorType, BitDepth, Option<Vec<u8>>) {
+    let opts = get_opts();
+    let mut raw = RawImage::new(width, 1, color_type, bit_depth, data).unwrap();
+    raw.add_png_chunk(*b"bKGD", bkgd);
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    let bkgd = new.aux_chunks.iter().find(|c| &c.name == b"bKGD");
+    (
+        new.raw.ihdr.color_type.clone(),
+        new.raw.ihdr.bit_depth,
+        bkgd.map(|c| c.data.clone()),
+    )
+}
+
+#[test]
+fn background_follows_reductions() {
+    let rgb = ColorType::RGB {
+        transparent_color: None,
+    };
+
+    // RGB to grayscale, with a gray background
+    let data = [[10; 3], [20; 3], [30; 3], [40; 3]].concat();
+    let (color_type, _, bkgd) = bkgd_after_reduction(
+        4,
+        rgb.clone(),
+        BitDepth::Eight,
+        data,
+        vec![0, 20, 0, 20, 0, 20],
+    );
+    assert!(matches!(color_type, ColorType::Grayscale { .. }));
+    assert_eq!(bkgd, Some(vec![0, 20]));
+
+    // RGB to grayscale, with a background that cannot be represented
+    let data = [[10; 3], [20; 3], [30; 3], [40; 3]].concat();
+    let (color_type, _, bkgd) = bkgd_after_reduction(
+        4,
+        rgb.clone(),
+        BitDepth::Eight,
+        data,
+        vec![0, 20, 0, 30, 0, 20],
+    );
+    assert!(matches!(color_type, ColorType::Grayscale { .. }));
+    assert_eq!(bkgd, None);
+
+    // RGB to indexed, with a background from the palette
+    let colors = [[10, 0, 0], [0, 20, 0], [0, 0, 30], [40, 40, 0]];
+    let data = (0..256u32)
+        .flat_map(|i| colors[(i.wrapping_mul(2_654_435_761) >> 30) as usize])
+        .collect();
+    let (color_type, _, bkgd) =
+        bkgd_after_reduction(256, rgb, BitDepth::Eight, data, vec![0, 0, 0, 20, 0, 0]);
+    let ColorType::Indexed { palette } = color_type else {
+        panic!("Image should be indexed");
+    };
+    let index = bkgd.unwrap()[0] as usize;
+    assert_eq!(palette[index], RGBA8::new(0, 20, 0, 255));
+
+    // 16-bit grayscale to 8-bit
+    let data = [0x1212u16, 0x3434, 0x5656, 0x7878]
+        .iter()
+        .flat_map(|v| v.to_be_bytes())
+        .collect();
+    let gray = ColorType::Grayscale {
+        transparent_shade: None,
+    };
+    let (_, bit_depth, bkgd) =
+        bkgd_after_reduction(4, gray, BitDepth::Sixteen, data, vec![0xAA, 0xAA]);
+    assert_eq!(bit_depth, BitDepth::Eight);
+    assert_eq!(bkgd, Some(vec![0, 0xAA]));
+}
+
+#[test]
+fn transparency_key_16_to_8() {
+    let reduce = |key: u16, data: &[u16], opts: &Options| {
+        let color_type = ColorType::Grayscale {
+            transparent_shade: Some(key),
+        };
+        let data = data.iter().flat_map(|v| v.to_be_bytes()).collect();
+        let raw = RawImage::new(4, 1, color_type, BitDepth::Sixteen, data).unwrap();
+        let opts = Options {
+            color_type_reduction: false,
+            ..opts.clone()
+        };
+        let output = raw.create_optimized_png(&opts).unwrap();
+        let new = PngData::from_slice(&output, &opts).unwrap();
+        assert_eq!(new.raw.ihdr.bit_depth, BitDepth::Eight);
+        let ColorType::Grayscale { transparent_shade } = new.raw.ihdr.color_type else {
+            panic!("Image should remain grayscale");
+        };
+        (transparent_shade, new.raw.data.clone())
+    };
+    let data = [0x1212, 0x3434, 0x5656, 0x7878];
+
+    // The key is reduced along with the pixels
+    let (key, pixels) = reduce(0x3434, &data, &get_opts());
+    assert_eq!(key, Some(0x34));
+    assert_eq!(pixels, [0x12, 0x34, 0x56, 0x78]);
+
+    // A key that no pixel has is dropped
+    let (key, _) = reduce(0x9999, &data, &get_opts());
+    assert_eq!(key, None);
+
+    // When scaling, other pixels must not become transparent
+    let data = [0x1234, 0x1200, 0x5678, 0x9ABC];
+    let mut opts = get_opts();
+    opts.scale_16 = true;
+    let (key, pixels) = reduce(0x1234, &data, &opts);
+    assert_eq!(key, Some(0x12));
+    assert_eq!(pixels, [0x12, 0x13, 0x56, 0x9A]);
+
+    // The same applies to dithering
+    let mut opts = get_opts();
+    opts.allow_lossy_bit_depth = true;
+    let (key, pixels) = reduce(0x1234, &data, &opts);
+    assert_eq!(key, Some(0x12));
+    assert_eq!(pixels[0], 0x12);
+    assert!(pixels[1..].iter().all(|&v| v != 0x12));
+}
+
+#[test]
+fn grayscale_alpha_to_grayscale() {
+    let opts = Options {
+        bit_depth_reduction: false,
+        color_type_reduction: false,
+        ..get_opts()
+    };
+    let raw = RawImage::new(4, 1, ColorType::GrayscaleAlpha, BitDepth::Eight, vec![0; 8]).unwrap();
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let ihdr = PngData::from_slice(&output, &opts)
+        .unwrap()
+        .raw
+        .ihdr
+        .clone();
+    let gray_alpha = |data: Vec<u8>| PngImage {
+        ihdr: ihdr.clone(),
+        data,
+    };
+
+    // Fully opaque alpha is removed
+    let png = gray_alpha([[10, 255], [20, 255], [30, 255], [40, 255]].concat());
+    let reduced = alpha::reduced_alpha_channel(&png, false).unwrap();
+    assert_eq!(
+        reduced.ihdr.color_type,
+        ColorType::Grayscale {
+            transparent_shade: None
+        }
+    );
+    assert_eq!(reduced.ihdr.bit_depth, BitDepth::Eight);
+    assert_eq!(reduced.data, [10, 20, 30, 40]);
+
+    // Binary alpha with a single transparent shade becomes tRNS
+    let png = gray_alpha([[10, 255], [50, 0], [30, 255], [50, 0]].concat());
+    let reduced = alpha::reduced_alpha_channel(&png, false).unwrap();
+    assert_eq!(
+        reduced.ihdr.color_type,
+        ColorType::Grayscale {
+            transparent_shade: Some(50)
+        }
+    );
+    assert_eq!(reduced.data, [10, 50, 30, 50]);
+
+    // Continuous alpha is kept
+    let png = gray_alpha([[10, 255], [20, 128], [30, 255], [40, 0]].concat());
+    assert_eq!(
+        alpha::reduced_alpha_channel(&png, false).err(),
+        Some(Rejection::PartialTransparency)
+    );
+
+    // The background is kept through the reduction
+    let data = [[10, 255], [20, 255], [30, 255], [40, 255]].concat();
+    let (color_type, _, bkgd) = bkgd_after_reduction(
+        4,
+        ColorType::GrayscaleAlpha,
+        BitDepth::Eight,
+        data,
+        vec![0, 20],
+    );
+    assert!(matches!(color_type, ColorType::Grayscale { .. }));
+    assert_eq!(bkgd, Some(vec![0, 20]));
+}
+
+#[test]
+fn lossy_quantize() {
+    let data: Vec<u8> = (0..64u8)
+        .flat_map(|y| (0..64u8).flat_map(move |x| [x * 4, y * 4, x + y]))
+        .collect();
+    let color_type = ColorType::RGB {
+        transparent_color: None,
+    };
+    let raw = RawImage::new(64, 64, color_type, BitDepth::Eight, data).unwrap();
+
+    // The image is left as truecolor by default
+    let output = raw.create_optimized_png(&get_opts()).unwrap();
+    let new = PngData::from_slice(&output, &get_opts()).unwrap();
+    assert!(matches!(new.raw.ihdr.color_type, ColorType::RGB { .. }));
+
+    for dither in [
+        Dithering::None,
+        Dithering::FloydSteinberg,
+        Dithering::Ordered,
+    ] {
+        let mut opts = get_opts();
+        opts.lossy_quantize = Some(16);
+        opts.quantize_dither = dither;
+        let output = raw.create_optimized_png(&opts).unwrap();
+        let new = PngData::from_slice(&output, &opts).unwrap();
+        let ColorType::Indexed { palette } = &new.raw.ihdr.color_type else {
+            panic!("Image should be quantized to indexed");
+        };
+        assert!(palette.len() <= 16);
+        // The result is deterministic
+        assert_eq!(raw.create_optimized_png(&opts).unwrap(), output);
+    }
+}
+
+#[test]
+fn alpha_levels() {
+    let data = [
+        [10, 20, 30, 255],
+        [40, 50, 60, 250],
+        [70, 80, 90, 5],
+        [100, 110, 120, 100],
+    ]
+    .concat();
+    let raw = RawImage::new(2, 2, ColorType::RGBA, BitDepth::Eight, data.clone()).unwrap();
+
+    // Alpha is left alone by default
+    let output = raw.create_optimized_png(&get_opts()).unwrap();
+    let new = PngData::from_slice(&output, &get_opts()).unwrap();
+    assert_eq!(new.raw.data, data);
+
+    let png = PngImage {
+        ihdr: new.raw.ihdr.clone(),
+        data,
+    };
+    let quantized = alpha::quantized_alpha(&png, 4).unwrap();
+    assert_eq!(
+        quantized.data,
+        [
+            [10, 20, 30, 255],
+            [40, 50, 60, 255],
+            [0, 0, 0, 0],
+            [100, 110, 120, 85]
+        ]
+        .concat()
+    );
+    assert_eq!(
+        alpha::quantized_alpha(&quantized, 4).err(),
+        Some(Rejection::Unchanged)
+    );
+    assert_eq!(
+        alpha::quantized_alpha(&png, 1).err(),
+        Some(Rejection::InvalidParameter)
+    );
+
+    // Two levels make the alpha binary
+    let mut opts = get_opts();
+    opts.alpha_levels = Some(2);
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let new = PngData::from_slice(&output, &opts).unwrap();
+    let rgba = color::expanded_to_color_type(&new.raw, &ColorType::RGBA)
+        .map_or(new.raw.data.clone(), |png| png.data);
+    assert_eq!(
+        rgba,
+        [
+            [10, 20, 30, 255],
+            [40, 50, 60, 255],
+            [0, 0, 0, 0],
+            [0, 0, 0, 0]
+        ]
+        .concat()
+    );
+}
+
+#[test]
+fn filter_image() {
+    let data: Vec<u8> = (0..32u8)
+        .flat_map(|y| (0..32u8).flat_map(move |x| [x * 8, y * 8, x ^ y]))
+        .collect();
+    let color_type = ColorType::RGB {
+        transparent_color: None,
+    };
+    let raw = RawImage::new(32, 32, color_type, BitDepth::Eight, data).unwrap();
+    let opts = Options::default();
+
+    let filtered = raw.filter_image(Some(RowFilter::Sub), &opts).unwrap();
+    assert_eq!(filtered.len(), 32 * (1 + 32 * 3));
+    assert!(filtered.chunks(1 + 32 * 3).all(|row| row[0] == 1));
+
+    // The best filter is chosen from those in the options
+    let best = raw.filter_image(None, &opts).unwrap();
+    let candidates: Vec<_> = opts
+        .filter
+        .iter()
+        .map(|&f| raw.filter_image(Some(f), &opts).unwrap())
+        .collect();
+    assert!(candidates.contains(&best));
+}
+
+#[test]
+fn raw_image_validation() {
+    let gray = ColorType::Grayscale {
+        transparent_shade: None,
+    };
+    // Rows of sub-byte pixels are padded to a whole byte
+    assert!(RawImage::new(3, 2, gray.clone(), BitDepth::Two, vec![0; 2]).is_ok());
+    assert!(matches!(
+        RawImage::new(3, 2, gray, BitDepth::Two, vec![0; 3]),
+        Err(PngError::IncorrectDataLength(3, 2))
+    ));
+    assert!(matches!(
+        RawImage::new(1, 1, ColorType::RGBA, BitDepth::Four, vec![0; 2]),
+        Err(PngError::InvalidDepthForType(
+            BitDepth::Four,
+            ColorType::RGBA
+        ))
+    ));
+}
+
+#[test]
+fn raw_image_with_chunks() {
+    let data = (0..16u8).flat_map(|i| [i, i * 2, i * 3]).collect();
+    let color_type = ColorType::RGB {
+        transparent_color: None,
+    };
+    let mut raw = RawImage::new(4, 4, color_type, BitDepth::Eight, data).unwrap();
+    raw.add_png_chunk(*b"tEXt", b"Software\0renderer".to_vec());
+    raw.add_icc_profile(&b"Not a real ICC profile. ".repeat(8));
+    let output = raw.create_optimized_png(&Options::default()).unwrap();
+
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    let text = png.aux_chunks.iter().find(|c| &c.name == b"tEXt").unwrap();
+    assert_eq!(text.data, b"Software\0renderer");
+    assert!(png.aux_chunks.iter().any(|c| &c.name == b"iCCP"));
+}
+
+#[test]
+fn set_icc_profile() {
+    let gray = ColorType::Grayscale {
+        transparent_shade: None,
+    };
+    let mut raw = RawImage::new(1, 1, gray, BitDepth::Eight, vec![0]).unwrap();
+    let icc = b"Not a real ICC profile. ".repeat(8);
+    raw.add_icc_profile(&icc);
+    raw.set_icc_profile("Display P3", &icc).unwrap();
+    assert!(raw.set_icc_profile("", &icc).is_err());
+    assert!(raw.set_icc_profile(&"x".repeat(80), &icc).is_err());
+    assert!(raw.set_icc_profile("Tab\tname", &icc).is_err());
+
+    let output = raw.create_optimized_png(&Options::default()).unwrap();
+    let png = PngData::from_slice(&output, &Options::default()).unwrap();
+    let iccp: Vec<_> = png
+        .aux_chunks
+        .iter()
+        .filter(|c| &c.name == b"iCCP")
+        .collect();
+    // The existing profile is replaced
+    assert_eq!(iccp.len(), 1);
+    assert_eq!(&iccp[0].data[..12], b"Display P3\0\0");
+}
+
+#[test]
+fn serialized_size() {
+    let palette = vec![
+        RGBA8::new(255, 0, 0, 255),
+        RGBA8::new(0, 255, 0, 128),
+        RGBA8::new(0, 0, 255, 0),
+    ];
+    let data = (0..64u8).map(|i| i % 3).collect();
+    let mut raw =
+        RawImage::new(8, 8, ColorType::Indexed { palette }, BitDepth::Eight, data).unwrap();
+    raw.add_png_chunk(*b"tEXt", b"Software\0renderer".to_vec());
+    let opts = Options::default();
+    let output = raw.create_optimized_png(&opts).unwrap();
+    assert_eq!(raw.serialized_size(&opts).unwrap(), output.len());
+
+    let data = (0..16u8).flat_map(|i| [i, i * 2, i * 3, 255]).collect();
+    let raw = RawImage::new(4, 4, ColorType::RGBA, BitDepth::Eight, data).unwrap();
+    let output = raw.create_optimized_png(&opts).unwrap();
+    assert_eq!(raw.serialized_size(&opts).unwrap(), output.len());
+}
+
+#[test]
+fn compress_image_data() {
+    // An RGBA image that would otherwise be reduced to grayscale
+    let data: Vec<u8> = (0..16u8).flat_map(|i| [i * 8, i * 8, i * 8, 255]).collect();
+    let raw = RawImage::new(4, 4, ColorType::RGBA, BitDepth::Eight, data).unwrap();
+    let opts = Options {
+        filter: indexset! { RowFilter::Sub },
+        ..Default::default()
+    };
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let png = PngData::from_slice(&output, &opts).unwrap();
+    assert_ne!(png.raw.ihdr.color_type, ColorType::RGBA);
+
+    // The payload is a zlib stream of the filtered rows of the original color type
+    let idat_data = raw.compress_image_data(&opts).unwrap();
+    let filtered = inflate(&idat_data, 4 * (1 + 4 * 4)).unwrap();
+    let expected = raw.filter_image(Some(RowFilter::Sub), &opts).unwrap();
+    assert_eq!(filtered, expected);
+}
+
+#[test]
+fn palette_pin() {
+    let palette = vec![
+        RGBA8::new(255, 0, 0, 255),
+        RGBA8::new(0, 255, 0, 255),
+        RGBA8::new(0, 0, 255, 255),
+        RGBA8::new(9, 9, 9, 0),
+    ];
+    let data: Vec<u8> = (0..64u8).map(|i| i % 4).collect();
+    let mut raw = RawImage::new(
+        8,
+        8,
+        ColorType::Indexed {
+            palette: palette.clone(),
+        },
+        BitDepth::Eight,
+        data,
+    )
+    .unwrap();
+    // Background of blue
+    raw.add_png_chunk(*b"bKGD", vec![2]);
+    let read = |output: &[u8]| {
+        let png = PngData::from_slice(output, &Options::default()).unwrap();
+        let ColorType::Indexed { palette } = png.raw.ihdr.color_type.clone() else {
+            panic!("Expected indexed color type");
+        };
+        let bkgd = png.aux_chunks.iter().find(|c| &c.name == b"bKGD").unwrap();
+        assert_eq!(palette[bkgd.data[0] as usize], RGBA8::new(0, 0, 255, 255));
+        let expanded = bit_depth::expanded_bit_depth_to_8(&png.raw).unwrap_or((*png.raw).clone());
+        let pixels: Vec<_> = expanded.data.iter().map(|&b| palette[b as usize]).collect();
+        (palette, pixels)
+    };
+    let (_, expected) = read(&raw.create_optimized_png(&Options::default()).unwrap());
+
+    // An existing color is moved to the front
+    let opts = Options {
+        palette_pin: Some(PalettePin::Color(RGBA8::new(0, 255, 0, 255))),
+        ..Options::default()
+    };
+    let (pinned, pixels) = read(&raw.create_optimized_png(&opts).unwrap());
+    assert_eq!(pinned[0], RGBA8::new(0, 255, 0, 255));
+    assert_eq!(pixels, expected);
+
+    let opts = Options {
+        palette_pin: Some(PalettePin::Transparent),
+        ..Options::default()
+    };
+    let (pinned, pixels) = read(&raw.create_optimized_png(&opts).unwrap());
+    assert_eq!(pinned[0], RGBA8::new(9, 9, 9, 0));
+    assert_eq!(pixels, expected);
+
+    // A missing color is inserted, increasing the bit depth if required
+    let pin = RGBA8::new(1, 2, 3, 255);
+    let opts = Options {
+        palette_pin: Some(PalettePin::Color(pin)),
+        ..Options::default()
+    };
+    let output = raw.create_optimized_png(&opts).unwrap();
+    let (pinned, pixels) = read(&output);
+    assert_eq!(pinned[0], pin);
+    assert_eq!(pinned.len(), 5);
+    assert_eq!(pixels, expected);
+    let png = PngData::from_slice(&output, &opts).unwrap();
+    assert_eq!(png.raw.ihdr.bit_depth, BitDepth::Four);
+}
@@ -1133,3 +1133,208 @@ fn grayscale_alpha_8_reduce_alpha() {
         BitDepth::Eight,
     );
 }
+
+#[test]
+fn palette_sort_frequency() {
+    let input = PathBuf::from("tests/files/palette_8_should_be_palette_8.png");
+    let (_, opts) = get_opts(&input);
+    let png = PngData::new(&input, &opts).unwrap();
+
+    let sorted = palette::sorted_palette(&png.raw, PaletteSort::Frequency).unwrap();
+    let ColorType::Indexed { palette } = &sorted.ihdr.color_type else {
+        panic!("Expected indexed color type");
+    };
+    let mut counts = vec![0; palette.len()];
+    for &b in &sorted.data {
+        counts[b as usize] += 1;
+    }
+    assert!(counts.windows(2).all(|w| w[0] >= w[1]));
+}
+
+#[test]
+fn palette_sort_alpha() {
+    let input = PathBuf::from("tests/files/palette_8_should_be_palette_8.png");
+    let (_, opts) = get_opts(&input);
+    let png = PngData::new(&input, &opts).unwrap();
+    let mut raw = (*png.raw).clone();
+    let ColorType::Indexed { palette } = &mut raw.ihdr.color_type else {
+        panic!("Expected indexed color type");
+    };
+    // Scatter some transparent entries through the palette
+    let last = palette.len() - 1;
+    palette[last].a = 0;
+    palette[last / 2].a = 0;
+    palette[last / 3].a = 254;
+    let palette = palette.clone();
+
+    let sorted = palette::sorted_palette(&raw, PaletteSort::Alpha).unwrap();
+    let ColorType::Indexed {
+        palette: sorted_palette,
+    } = &sorted.ihdr.color_type
+    else {
+        panic!("Expected indexed color type");
+    };
+    // Fully transparent entries are grouped first, then the rest in ascending alpha
+    assert!(sorted_palette.windows(2).all(|w| w[0].a <= w[1].a));
+    assert_eq!(sorted_palette[0].a, 0);
+    assert_eq!(sorted_palette[1].a, 0);
+    // Opaque entries keep their existing order
+    let opaque: Vec<_> = palette.iter().filter(|c| c.a == 255).collect();
+    let sorted_opaque: Vec<_> = sorted_palette.iter().filter(|c| c.a == 255).collect();
+    assert_eq!(opaque, sorted_opaque);
+    // The pixels are unchanged
+    assert!(raw
+        .data
+        .iter()
+        .zip(&sorted.data)
+        .all(|(&a, &b)| palette[a as usize] == sorted_palette[b as usize]));
+}
+
+#[test]
+fn palette_sort_weights() {
+    let input = PathBuf::from("tests/files/palette_8_should_be_palette_8.png");
+    let (_, opts) = get_opts(&input);
+    let png = PngData::new(&input, &opts).unwrap();
+    let raw = (*png.raw).clone();
+    let ColorType::Indexed { palette } = &raw.ihdr.color_type else {
+        panic!("Expected indexed color type");
+    };
+    let sorted_palette = |weights: &PaletteSortWeights, background| {
+        let sorted =
+            palette::sorted_palette_weighted(&raw, PaletteSort::Luma, weights, background).unwrap();
+        let ColorType::Indexed { palette } = sorted.ihdr.color_type else {
+            panic!("Expected indexed color type");
+        };
+        palette
+    };
+
+    // The default weights reproduce the luma sort
+    let default = sorted_palette(&PaletteSortWeights::default(), None);
+    let luma = palette::sorted_palette(&raw, PaletteSort::Luma).unwrap();
+    assert_eq!(
+        luma.ihdr.color_type,
+        ColorType::Indexed { palette: default }
+    );
+
+    // The background color is moved to the front, after the most popular edge color
+    let background = palette[palette.len() / 2];
+    let weights = PaletteSortWeights {
+        background: 1,
+        ..Default::default()
+    };
+    let sorted = sorted_palette(&weights, Some(background.rgb()));
+    assert!(sorted[..2].contains(&background));
+
+    // Luma is ignored without its weight, leaving the most common colors first
+    let weights = PaletteSortWeights {
+        luma: 0,
+        frequency: 1,
+        ..Default::default()
+    };
+    let sorted = sorted_palette(&weights, None);
+    let frequency = palette::sorted_palette(&raw, PaletteSort::Frequency).unwrap();
+    let ColorType::Indexed {
+        palette: by_frequency,
+    } = &frequency.ihdr.color_type
+    else {
+        panic!("Expected indexed color type");
+    };
+    let expected: Vec<_> = by_frequency
+        .iter()
+        .filter(|&&c| c != sorted[0])
+        .take(3)
+        .copied()
+        .collect();
+    assert_eq!(sorted[1..4], expected);
+}
+
+#[test]
+fn palette_merge_similar() {
+    let input = PathBuf::from("tests/files/palette_8_should_be_palette_8.png");
+    let (_, opts) = get_opts(&input);
+    let png = PngData::new(&input, &opts).unwrap();
+    let ColorType::Indexed { palette } = &png.raw.ihdr.color_type else {
+        panic!("Expected indexed color type");
+    };
+
+    assert_eq!(
+        palette::merged_similar_palette(&png.raw, 0).err(),
+        Some(Rejection::InvalidParameter)
+    );
+
+    let merged = palette::merged_similar_palette(&png.raw, 32).unwrap();
+    let ColorType::Indexed {
+        palette: merged_palette,
+    } = &merged.ihdr.color_type
+    else {
+        panic!("Expected indexed color type");
+    };
+    assert!(merged_palette.len() < palette.len());
+    assert!(merged_palette.iter().all(|c| palette.contains(c)));
+    assert!(merged
+        .data
+        .iter()
+        .all(|&b| (b as usize) < merged_palette.len()));
+}
+
+#[test]
+fn palette_sort_nearest() {
+    let input = PathBuf::from("tests/files/palette_8_should_be_palette_8.png");
+    let (_, opts) = get_opts(&input);
+    let png = PngData::new(&input, &opts).unwrap();
+    let ColorType::Indexed { palette } = &png.raw.ihdr.color_type else {
+        panic!("Expected indexed color type");
+    };
+
+    let sorted = palette::sorted_palette(&png.raw, PaletteSort::Nearest).unwrap();
+    let ColorType::Indexed {
+        palette: sorted_palette,
+    } = &sorted.ihdr.color_type
+    else {
+        panic!("Expected indexed color type");
+    };
+    assert_eq!(sorted_palette.len(), palette.len());
+    assert!(palette.iter().all(|c| sorted_palette.contains(c)));
+
+    // The tour should start from the darkest opaque color
+    let luma = |c: &RGBA8| c.r as u32 * 299 + c.g as u32 * 587 + c.b as u32 * 114;
+    let darkest = palette
+        .iter()
+        .filter(|c| c.a == 255)
+        .map(luma)
+        .min()
+        .unwrap();
+    assert_eq!(luma(&sorted_palette[0]), darkest);
+}
+
+#[test]
+fn rejection_reasons() {
+    let input = PathBuf::from("tests/files/rgba_16_should_be_rgba_16.png");
+    let (_, opts) = get_opts(&input);
+    let png = PngData::new(&input, &opts).unwrap();
+    assert_eq!(
+        bit_depth::reduced_bit_depth_16_to_8(&png.raw, false).err(),
+        Some(Rejection::LowBytesDiffer)
+    );
+    assert_eq!(
+        alpha::reduced_alpha_channel(&png.raw, true).err(),
+        Some(Rejection::PartialTransparency)
+    );
+    assert_eq!(
+        palette::reduced_palette(&png.raw, None).err(),
+        Some(Rejection::UnsupportedColorType)
+    );
+
+    let input = PathBuf::from("tests/files/rgb_16_should_be_rgb_16.png");
+    let (_, opts) = get_opts(&input);
+    let png = PngData::new(&input, &opts).unwrap();
+    assert_eq!(
+        color::reduced_rgb_to_grayscale(&png.raw).err(),
+        Some(Rejection::NotGray)
+    );
+    assert_eq!(
+        bit_depth::reduced_bit_depth_8_or_less(&png.raw).err(),
+        Some(Rejection::UnsupportedBitDepth)
+    );
+    assert_eq!(Rejection::LowBytesDiffer.to_string(), "low bytes differ");
+}